@@ -5,19 +5,30 @@
 use clap::crate_version;
 use tectonic;
 
-use clap::{App, Arg, ArgMatches};
-use std::fs::File;
-use std::path::Path;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use tectonic::config::PersistentConfig;
-use tectonic::driver::{OutputFormat, PassSetting, ProcessingSessionBuilder};
+use tectonic::digest::DigestData;
+use tectonic::driver::{OutputFormat, PassSetting, ProcessingSession, ProcessingSessionBuilder};
+use tectonic::engines::xdvipdfmx::parse_length;
+use tectonic::engines::{PageRange, PdfEncryption, PdfOptions, PdfPermissions};
 use tectonic::errors::{ErrorKind, Result};
-use tectonic::io::zipbundle::ZipBundle;
+use tectonic::io::bundle_builder::BundleBuilder;
+use tectonic::io::bundle_cache::{BundleCache, CacheProblemKind, CleanupStats};
+use tectonic::io::{Bundle, InputHandle, IoProvider, OpenResult};
 use tectonic::status::termcolor::TermcolorStatusBackend;
-use tectonic::status::{ChatterLevel, StatusBackend};
+use tectonic::status::{ChatterLevel, NoopStatusBackend, StatusBackend};
 
-use tectonic::{ctry, errmsg, tt_error, tt_error_styled, tt_note};
+use tectonic::{ctry, errmsg, tt_error, tt_error_styled, tt_note, tt_warning};
 
 fn inner(
     args: ArgMatches,
@@ -31,13 +42,15 @@ fn inner(
         .keep_logs(args.is_present("keep_logs"))
         .keep_intermediates(args.is_present("keep_intermediates"))
         .format_cache_path(config.format_cache_path()?)
-        .synctex(args.is_present("synctex"));
+        .synctex(args.is_present("synctex"))
+        .reproducible(args.is_present("reproducible"));
 
     let output_format = match args.value_of("outfmt").unwrap() {
         "aux" => OutputFormat::Aux,
         "html" => OutputFormat::Html,
         "xdv" => OutputFormat::Xdv,
         "pdf" => OutputFormat::Pdf,
+        "pdfa" => OutputFormat::PdfA,
         "format" => OutputFormat::Format,
         _ => unreachable!(),
     };
@@ -59,6 +72,68 @@ fn inner(
         sess_builder.makefile_output_path(p);
     }
 
+    if let Some(p) = args.value_of_os("report") {
+        sess_builder.report_path(p);
+    }
+
+    if let Some(s) = args.value_of("index_style") {
+        sess_builder.index_style(s);
+    }
+
+    if let Some(s) = args.value_of("pages") {
+        sess_builder.page_ranges(&PageRange::parse_list(s)?);
+    }
+
+    let mut pdf_options = PdfOptions {
+        paper: args.value_of("paper").map(|s| s.to_owned()),
+        landscape: args.is_present("landscape"),
+        ..PdfOptions::default()
+    };
+
+    if let Some(s) = args.value_of("pdf_x_offset") {
+        pdf_options.x_offset = parse_length(s)?;
+    }
+
+    if let Some(s) = args.value_of("pdf_y_offset") {
+        pdf_options.y_offset = parse_length(s)?;
+    }
+
+    if let Some(s) = args.value_of("mag") {
+        pdf_options.mag = ctry!(s.parse(); "invalid magnification \"{}\"", s);
+    }
+
+    if let Some(s) = args.value_of("annot_grow") {
+        pdf_options.annot_grow = parse_length(s)?;
+    }
+
+    if let Some(s) = args.value_of("bookmark_open") {
+        pdf_options.bookmark_open = ctry!(s.parse(); "invalid outline depth \"{}\"", s);
+    }
+
+    if let Some(s) = args.value_of("pdf_decimal_digits") {
+        pdf_options.decimal_digits = ctry!(s.parse(); "invalid number of digits \"{}\"", s);
+    }
+
+    sess_builder.pdf_options(pdf_options);
+
+    if args.is_present("encrypt") {
+        let mut encryption = PdfEncryption {
+            owner_password: args.value_of("owner_password").unwrap_or("").to_owned(),
+            user_password: args.value_of("user_password").unwrap_or("").to_owned(),
+            ..PdfEncryption::default()
+        };
+
+        if let Some(s) = args.value_of("key_bits") {
+            encryption.key_length = s.parse()?;
+        }
+
+        if let Some(s) = args.value_of("pdf_permissions") {
+            encryption.permissions = PdfPermissions::parse_list(s)?;
+        }
+
+        sess_builder.pdf_encryption(encryption);
+    }
+
     // Input and path setup
 
     let input_path = args.value_of_os("INPUT").unwrap();
@@ -119,8 +194,8 @@ fn inner(
         tt_note!(status, "using only cached resource files");
     }
     if let Some(p) = args.value_of("bundle") {
-        let zb = ctry!(ZipBundle::<File>::open(Path::new(&p)); "error opening bundle");
-        sess_builder.bundle(Box::new(zb));
+        let bundle = ctry!(config.make_bundle(p, only_cached, status); "error opening bundle");
+        sess_builder.bundle(bundle);
     } else if let Some(u) = args.value_of("web_bundle") {
         sess_builder.bundle(Box::new(config.make_cached_url_provider(
             &u,
@@ -132,7 +207,45 @@ fn inner(
         sess_builder.bundle(config.default_bundle(only_cached, status)?);
     }
 
+    let watch = args.is_present("watch");
+    if watch && input_path == "-" {
+        return Err(errmsg!(
+            "cannot watch for changes when reading from standard input"
+        ));
+    }
+
     let mut sess = sess_builder.create(status)?;
+
+    if !watch {
+        return run_session(&mut sess, status);
+    }
+
+    // Watch mode: keep the session (and with it the bundle and format cache)
+    // alive, and rebuild whenever one of the inputs that the previous build
+    // actually read changes.
+
+    let mut watcher = InputWatcher::default();
+
+    loop {
+        let started = SystemTime::now();
+
+        if let Err(ref e) = run_session(&mut sess, status) {
+            status.bare_error(e);
+        }
+
+        let paths = sess.filesystem_inputs();
+        tt_note!(
+            status,
+            "watching {} input files for changes; press Ctrl-C to stop",
+            paths.len()
+        );
+        watcher.wait(&paths, started);
+        sess.reset();
+    }
+}
+
+/// Run a processing session, dumping the engine's output if it fails badly.
+fn run_session(sess: &mut ProcessingSession, status: &mut TermcolorStatusBackend) -> Result<()> {
     let result = sess.run(status);
 
     if let Err(e) = &result {
@@ -153,6 +266,356 @@ fn inner(
     result
 }
 
+/// The `bundle create` subcommand: put the files from some directory trees
+/// into a Zip and/or an indexed tar bundle.
+fn bundle_create(args: &ArgMatches, status: &mut TermcolorStatusBackend) -> Result<()> {
+    let mut builder = BundleBuilder::new();
+
+    for dir in args.values_of_os("DIR").unwrap() {
+        builder.add_tree(dir, status)?;
+    }
+
+    if builder.is_empty() {
+        return Err(errmsg!("there are no files to put in the bundle"));
+    }
+
+    if let Some(p) = args.value_of_os("zip") {
+        builder.write_zip(p, status)?;
+    }
+
+    if let Some(p) = args.value_of_os("itar") {
+        builder.write_itar(p, status)?;
+    }
+
+    Ok(())
+}
+
+/// Open the bundle that a `bundle` subcommand should work on: the one given
+/// with `--bundle` or `--web-bundle`, or else the default.
+fn open_bundle(
+    args: &ArgMatches,
+    config: &PersistentConfig,
+    only_cached: bool,
+    status: &mut dyn StatusBackend,
+) -> Result<Box<dyn Bundle>> {
+    if let Some(p) = args.value_of("bundle") {
+        Ok(ctry!(config.make_bundle(p, only_cached, status); "error opening bundle"))
+    } else if let Some(u) = args.value_of("web_bundle") {
+        Ok(Box::new(config.make_cached_url_provider(
+            u,
+            only_cached,
+            None,
+            status,
+        )?))
+    } else {
+        config.default_bundle(only_cached, status)
+    }
+}
+
+/// A bundle that keeps track of the names that it couldn't provide.
+struct MissRecorder {
+    inner: Box<dyn Bundle>,
+    missing: Rc<RefCell<HashSet<String>>>,
+}
+
+impl IoProvider for MissRecorder {
+    fn input_open_name(
+        &mut self,
+        name: &OsStr,
+        status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        let r = self.inner.input_open_name(name, status);
+
+        if let (OpenResult::NotAvailable, Some(name)) = (&r, name.to_str()) {
+            self.missing.borrow_mut().insert(name.to_owned());
+        }
+
+        r
+    }
+}
+
+impl Bundle for MissRecorder {
+    fn get_digest(&mut self, status: &mut dyn StatusBackend) -> Result<DigestData> {
+        self.inner.get_digest(status)
+    }
+}
+
+/// The `bundle prefetch` subcommand: download files from the bundle ahead of
+/// time, so that later runs can use `--only-cached`.
+fn bundle_prefetch(
+    args: &ArgMatches,
+    config: &PersistentConfig,
+    status: &mut TermcolorStatusBackend,
+) -> Result<()> {
+    let mut bundle = open_bundle(args, config, false, status)?;
+
+    let mut n = if args.is_present("all") {
+        bundle.prefetch_all(status)?
+    } else {
+        let names: Vec<String> = args
+            .values_of("NAME")
+            .map(|v| v.map(|s| s.to_owned()).collect())
+            .unwrap_or_default();
+        bundle.prefetch(&names, status)?
+    };
+    bundle.flush(status)?;
+
+    // We don't know which files a format needs without running TeX, so we
+    // try to generate it from what's cached, note what TeX couldn't find,
+    // fetch all of that at once, and go again, until TeX gets no further.
+    // If it still fails, a normal run fetches whatever remains, and reports
+    // the problem.
+
+    for name in args.values_of("format").into_iter().flatten() {
+        let stem = name.split('.').next().unwrap();
+        let input = format!("\\input tectonic-format-{}.tex\n", stem);
+
+        let format_session = |bundle: Box<dyn Bundle>| -> Result<ProcessingSessionBuilder> {
+            let mut sess_builder = ProcessingSessionBuilder::default();
+            sess_builder
+                .primary_input_buffer(input.as_bytes())
+                .tex_input_name("texput.tex")
+                .format_name(name)
+                .format_cache_path(config.format_cache_path()?)
+                .output_format(OutputFormat::Format)
+                .do_not_write_output_files()
+                .bundle(bundle);
+            Ok(sess_builder)
+        };
+
+        tt_note!(status, "fetching the files needed by format \"{}\"", name);
+        let mut tried = HashSet::new();
+        let mut generated = false;
+
+        loop {
+            let mut quiet = NoopStatusBackend::new();
+            let missing = Rc::new(RefCell::new(HashSet::new()));
+            let recorder = MissRecorder {
+                inner: open_bundle(args, config, true, &mut quiet)?,
+                missing: missing.clone(),
+            };
+
+            let mut sess = format_session(Box::new(recorder))?.create(&mut quiet)?;
+
+            if sess.run(&mut quiet).is_ok() {
+                generated = true;
+                break;
+            }
+
+            let names: Vec<String> = missing
+                .borrow()
+                .iter()
+                .filter(|n| !tried.contains(*n))
+                .cloned()
+                .collect();
+
+            if names.is_empty() {
+                break;
+            }
+
+            tried.extend(names.iter().cloned());
+            let fetched = bundle.prefetch(&names, status)?;
+            bundle.flush(status)?;
+            n += fetched;
+
+            if fetched == 0 {
+                break;
+            }
+        }
+
+        if !generated {
+            let mut sess =
+                format_session(open_bundle(args, config, false, status)?)?.create(status)?;
+            ctry!(run_session(&mut sess, status); "couldn't generate format \"{}\"", name);
+        }
+    }
+
+    tt_note!(status, "downloaded {} files", n);
+    Ok(())
+}
+
+/// Parse a size like "500M" or "2G"; the suffixes are binary multiples.
+fn parse_size(s: &str) -> Result<u64> {
+    let t = s.trim();
+    let (digits, shift) = match t.chars().last() {
+        Some('k') | Some('K') => (&t[..t.len() - 1], 10),
+        Some('m') | Some('M') => (&t[..t.len() - 1], 20),
+        Some('g') | Some('G') => (&t[..t.len() - 1], 30),
+        _ => (t, 0),
+    };
+
+    let n: u64 = ctry!(digits.parse(); "invalid size \"{}\"", s);
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| errmsg!("size \"{}\" is too large", s))
+}
+
+/// Format a number of bytes for people.
+fn format_size(n: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+
+    if n < 1024 {
+        return format!("{} bytes", n);
+    }
+
+    let mut size = n as f64 / 1024.;
+    let mut unit = 0;
+
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn note_cleanup(stats: CleanupStats, status: &mut TermcolorStatusBackend) {
+    tt_note!(
+        status,
+        "removed {} bundles and {} files, freeing {}",
+        stats.bundles,
+        stats.files,
+        format_size(stats.bytes)
+    );
+}
+
+/// The `cache` subcommands: look after the cache of files downloaded from
+/// web bundles.
+fn cache_command(
+    command: &str,
+    args: &ArgMatches,
+    status: &mut TermcolorStatusBackend,
+) -> Result<()> {
+    let cache = BundleCache::new(None)?;
+
+    match command {
+        "list" => {
+            for bundle in cache.bundles()? {
+                let urls = if bundle.is_current() {
+                    bundle.urls.join(", ")
+                } else {
+                    "(superseded)".to_owned()
+                };
+
+                tt_note!(
+                    status,
+                    "{}: {} files, {}; {}",
+                    bundle.digest,
+                    bundle.n_files,
+                    format_size(bundle.size),
+                    urls
+                );
+            }
+
+            tt_note!(
+                status,
+                "cached files take up {} in total",
+                format_size(cache.files_size()?)
+            );
+        }
+
+        "verify" => {
+            let repair = args.is_present("repair");
+            let problems = cache.verify(repair)?;
+
+            for p in &problems {
+                let what = match p.kind {
+                    CacheProblemKind::Missing => "is missing",
+                    CacheProblemKind::Corrupt => "is corrupt",
+                };
+
+                tt_warning!(status, "\"{}\" from bundle {} {}", p.name, p.bundle, what);
+            }
+
+            if problems.is_empty() {
+                tt_note!(status, "all cached files are OK");
+            } else if repair {
+                tt_note!(
+                    status,
+                    "removed {} bad entries; they will be downloaded again when needed",
+                    problems.len()
+                );
+            } else {
+                return Err(errmsg!(
+                    "found {} problems in the cache; use --repair to fix them",
+                    problems.len()
+                ));
+            }
+        }
+
+        "gc" => note_cleanup(cache.gc()?, status),
+
+        "prune" => {
+            let mut stats = cache.prune()?;
+
+            if let Some(s) = args.value_of("max_size") {
+                stats.add(cache.limit_size(parse_size(s)?)?);
+            }
+
+            note_cleanup(stats, status);
+        }
+
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Polls the files that a build read, to decide when to rebuild.
+///
+/// We just poll the modification times, which is cheap for the handful of
+/// files that a typical document reads. A file that disappears counts as
+/// changed once; after that we wait for it to reappear rather than
+/// rebuilding over and over while it's gone. The build that follows can't
+/// read it, so it won't be among that build's inputs; we keep watching it
+/// anyway, so that bringing it back triggers a rebuild.
+#[derive(Debug, Default)]
+struct InputWatcher {
+    missing: HashSet<PathBuf>,
+}
+
+impl InputWatcher {
+    /// Check whether any of the given files, or any file that has gone
+    /// missing, has changed since `since`.
+    fn poll(&mut self, paths: &[PathBuf], since: SystemTime) -> bool {
+        let mut changed = false;
+        let gone: Vec<PathBuf> = self
+            .missing
+            .iter()
+            .filter(|p| !paths.contains(p))
+            .cloned()
+            .collect();
+
+        for p in paths.iter().chain(&gone) {
+            match fs::metadata(p).and_then(|m| m.modified()) {
+                Ok(t) => {
+                    if self.missing.remove(p) || t > since {
+                        changed = true;
+                    }
+                }
+                Err(_) => {
+                    if self.missing.insert(p.clone()) {
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Block until one of the given files has changed since `since`.
+    fn wait(&mut self, paths: &[PathBuf], since: SystemTime) {
+        while !self.poll(paths, since) {
+            thread::sleep(WATCH_POLL_INTERVAL);
+        }
+
+        // Give editors that write files in several steps a moment to finish.
+        thread::sleep(WATCH_POLL_INTERVAL);
+    }
+}
+
 fn main() {
     let matches = App::new("Tectonic")
         .version(crate_version!())
@@ -166,7 +629,7 @@ fn main() {
              .long("bundle")
              .short("b")
              .value_name("PATH")
-             .help("Use this Zip-format bundle file to find resource files instead of the default")
+             .help("Use this bundle (a Zip or tar file, or a directory) to find resource files instead of the default")
              .takes_value(true))
         .arg(Arg::with_name("web_bundle")
              .long("web-bundle")
@@ -182,12 +645,16 @@ fn main() {
              .long("outfmt")
              .value_name("FORMAT")
              .help("The kind of output to generate")
-             .possible_values(&["pdf", "html", "xdv", "aux", "format"])
+             .possible_values(&["pdf", "pdfa", "html", "xdv", "aux", "format"])
              .default_value("pdf"))
         .arg(Arg::with_name("makefile_rules")
              .long("makefile-rules")
              .value_name("PATH")
              .help("Write Makefile-format rules expressing the dependencies of this run to <PATH>"))
+        .arg(Arg::with_name("report")
+             .long("report")
+             .value_name("PATH")
+             .help("Write a JSON report describing the passes and file accesses of this run to <PATH>"))
         .arg(Arg::with_name("pass")
              .long("pass")
              .value_name("PASS")
@@ -199,6 +666,70 @@ fn main() {
              .short("r")
              .value_name("COUNT")
              .help("Rerun the TeX engine exactly this many times after the first"))
+        .arg(Arg::with_name("index_style")
+             .long("index-style")
+             .value_name("NAME")
+             .help("Use this makeindex style file (.ist) when generating the index"))
+        .arg(Arg::with_name("pages")
+             .long("pages")
+             .value_name("RANGES")
+             .help("Only include these pages in the PDF output, e.g. \"3-7,12\""))
+        .arg(Arg::with_name("paper")
+             .long("paper")
+             .value_name("SIZE")
+             .help("The paper size of the PDF output, as a name like \"a4\" or dimensions like \"210mm,297mm\""))
+        .arg(Arg::with_name("landscape")
+             .long("landscape")
+             .help("Swap the width and height of the PDF output's paper"))
+        .arg(Arg::with_name("pdf_x_offset")
+             .long("pdf-x-offset")
+             .value_name("LENGTH")
+             .help("How far from the left edge of the paper to put the TeX origin [default: 1in]"))
+        .arg(Arg::with_name("pdf_y_offset")
+             .long("pdf-y-offset")
+             .value_name("LENGTH")
+             .help("How far from the top edge of the paper to put the TeX origin [default: 1in]"))
+        .arg(Arg::with_name("mag")
+             .long("mag")
+             .value_name("FACTOR")
+             .help("Magnify the PDF output by this factor"))
+        .arg(Arg::with_name("annot_grow")
+             .long("annot-grow")
+             .value_name("LENGTH")
+             .help("Grow the clickable areas of links in the PDF output by this much"))
+        .arg(Arg::with_name("bookmark_open")
+             .long("bookmark-open")
+             .value_name("DEPTH")
+             .help("Open this many levels of the PDF outline initially; negative for all"))
+        .arg(Arg::with_name("pdf_decimal_digits")
+             .long("pdf-decimal-digits")
+             .value_name("COUNT")
+             .help("Use this many decimal digits for numbers in the PDF output [default: 5]"))
+        .arg(Arg::with_name("encrypt")
+             .long("encrypt")
+             .help("Encrypt the PDF output"))
+        .arg(Arg::with_name("owner_password")
+             .long("owner-password")
+             .value_name("PASSWORD")
+             .requires("encrypt")
+             .help("The password that grants full access to the encrypted PDF"))
+        .arg(Arg::with_name("user_password")
+             .long("user-password")
+             .value_name("PASSWORD")
+             .requires("encrypt")
+             .help("The password needed to open the encrypted PDF"))
+        .arg(Arg::with_name("key_bits")
+             .long("key-bits")
+             .value_name("BITS")
+             .possible_values(&["40", "128", "256"])
+             .requires("encrypt")
+             .help("The length of the encryption key [default: 128]"))
+        .arg(Arg::with_name("pdf_permissions")
+             .long("pdf-permissions")
+             .value_name("LIST")
+             .requires("encrypt")
+             .help("What readers of the encrypted PDF may do, e.g. \"print,copy\" \
+                    [default: print,modify,copy,annotate]"))
         .arg(Arg::with_name("keep_intermediates")
              .short("k")
              .long("keep-intermediates")
@@ -209,6 +740,12 @@ fn main() {
         .arg(Arg::with_name("synctex")
              .long("synctex")
              .help("Generate SyncTeX data"))
+        .arg(Arg::with_name("reproducible")
+             .long("reproducible")
+             .help("Make the outputs reproducible, dating them by $SOURCE_DATE_EPOCH"))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("Rebuild the document whenever one of its input files changes"))
         .arg(Arg::with_name("hide")
              .long("hide")
              .value_name("PATH")
@@ -235,6 +772,77 @@ fn main() {
              .help("The file to process, or \"-\" to process the standard input stream")
              .required(true)
              .index(1))
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .subcommand(SubCommand::with_name("bundle")
+             .about("Work with bundles of TeX support files")
+             .setting(AppSettings::SubcommandRequiredElseHelp)
+             .subcommand(SubCommand::with_name("create")
+                  .about("Create a bundle from directory trees, such as a TeXLive installation")
+                  .arg(Arg::with_name("zip")
+                       .long("zip")
+                       .value_name("PATH")
+                       .help("Write a Zip-format bundle to this file")
+                       .takes_value(true)
+                       .required_unless("itar"))
+                  .arg(Arg::with_name("itar")
+                       .long("itar")
+                       .value_name("PATH")
+                       .help("Write an indexed tar bundle to this file, and its index to PATH.index.gz")
+                       .takes_value(true))
+                  .arg(Arg::with_name("DIR")
+                       .help("The directory trees to take files from; files in earlier trees take priority")
+                       .required(true)
+                       .multiple(true)))
+             .subcommand(SubCommand::with_name("prefetch")
+                  .about("Download resource files ahead of time, so that they're available with --only-cached")
+                  .arg(Arg::with_name("bundle")
+                       .long("bundle")
+                       .short("b")
+                       .value_name("PATH")
+                       .help("Fetch from this bundle instead of the default")
+                       .takes_value(true))
+                  .arg(Arg::with_name("web_bundle")
+                       .long("web-bundle")
+                       .short("w")
+                       .value_name("URL")
+                       .help("Fetch from the bundle at this URL instead of the default")
+                       .takes_value(true)
+                       .conflicts_with("bundle"))
+                  .arg(Arg::with_name("all")
+                       .long("all")
+                       .help("Download every file in the bundle")
+                       .conflicts_with("NAME"))
+                  .arg(Arg::with_name("format")
+                       .long("format")
+                       .value_name("NAME")
+                       .help("Download the files needed to generate this format, such as \"latex\"")
+                       .takes_value(true)
+                       .multiple(true)
+                       .number_of_values(1))
+                  .arg(Arg::with_name("NAME")
+                       .help("The names of the files to download")
+                       .multiple(true)
+                       .required_unless_one(&["all", "format"]))))
+        .subcommand(SubCommand::with_name("cache")
+             .about("Look after the cache of files downloaded from web bundles")
+             .setting(AppSettings::SubcommandRequiredElseHelp)
+             .subcommand(SubCommand::with_name("list")
+                  .about("List the bundles that have files in the cache"))
+             .subcommand(SubCommand::with_name("verify")
+                  .about("Check the cached files against their digests")
+                  .arg(Arg::with_name("repair")
+                       .long("repair")
+                       .help("Remove bad files from the cache, so that they're downloaded again")))
+             .subcommand(SubCommand::with_name("gc")
+                  .about("Delete cached files that no bundle uses"))
+             .subcommand(SubCommand::with_name("prune")
+                  .about("Delete bundles that have been superseded by updates, along with their files")
+                  .arg(Arg::with_name("max_size")
+                       .long("max-size")
+                       .value_name("SIZE")
+                       .help("Then delete more bundles, least recently used first, until the cache is at most this big, e.g. \"500M\"")
+                       .takes_value(true))))
         .get_matches ();
 
     let chatter = match matches.value_of("chatter_level").unwrap() {
@@ -291,8 +899,97 @@ fn main() {
     // function ... all so that we can print out the word "error:" in red.
     // This code parallels various bits of the `error_chain` crate.
 
-    if let Err(ref e) = inner(matches, config, &mut status) {
+    let result = match matches.subcommand() {
+        ("bundle", Some(bundle_args)) => match bundle_args.subcommand() {
+            ("create", Some(args)) => bundle_create(args, &mut status),
+            ("prefetch", Some(args)) => bundle_prefetch(args, &config, &mut status),
+            _ => unreachable!(),
+        },
+        ("cache", Some(cache_args)) => match cache_args.subcommand() {
+            (command, Some(args)) => cache_command(command, args, &mut status),
+            _ => unreachable!(),
+        },
+        _ => inner(matches, config, &mut status),
+    };
+
+    if let Err(ref e) = result {
         status.bare_error(e);
         process::exit(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watcher_missing_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("input.tex");
+        fs::write(&path, "a").unwrap();
+        let since = fs::metadata(&path).unwrap().modified().unwrap();
+        let paths = vec![path.clone()];
+
+        let mut watcher = InputWatcher::default();
+        assert!(!watcher.poll(&paths, since));
+
+        // A deleted file is a change, but only the first time we notice.
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.poll(&paths, since));
+        assert!(!watcher.poll(&paths, since));
+        assert!(!watcher.poll(&paths, since));
+
+        // Its reappearance is another one.
+        fs::write(&path, "b").unwrap();
+        assert!(watcher.poll(&paths, since));
+    }
+
+    #[test]
+    fn watcher_restored_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("chapter.tex");
+        fs::write(&path, "a").unwrap();
+        let since = fs::metadata(&path).unwrap().modified().unwrap();
+        let paths = vec![path.clone()];
+
+        let mut watcher = InputWatcher::default();
+        assert!(!watcher.poll(&paths, since));
+
+        // Deleting the file triggers a rebuild, which can't read it, so it
+        // isn't among the inputs of that build ...
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.poll(&paths, since));
+        assert!(!watcher.poll(&[], since));
+
+        // ... but bringing it back still triggers the next one.
+        fs::write(&path, "b").unwrap();
+        assert!(watcher.poll(&[], since));
+        assert!(!watcher.poll(&[], since));
+    }
+
+    #[test]
+    fn watcher_modified_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("input.tex");
+        fs::write(&path, "a").unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let paths = vec![path];
+
+        let mut watcher = InputWatcher::default();
+        assert!(!watcher.poll(&paths, modified));
+        assert!(watcher.poll(&paths, modified - Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("500").unwrap(), 500);
+        assert_eq!(parse_size(" 2k ").unwrap(), 2048);
+        assert_eq!(parse_size("3M").unwrap(), 3 << 20);
+        assert_eq!(parse_size("16G").unwrap(), 16 << 30);
+        assert_eq!(parse_size("17179869183G").unwrap(), 17_179_869_183 << 30);
+        assert!(parse_size("17179869184G").is_err());
+        assert!(parse_size("99999999999999999999").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+}
@@ -9,20 +9,34 @@
 //! we at least need a mechanism for specifying the default bundle to use when
 //! running the command-line client. So we begrudgingly have a *little*
 //! configuration.
+//!
+//! The default bundle may be made of several bundles layered on top of each
+//! other. Each one is opened by a factory chosen according to its URL scheme
+//! or file extension; see [`BundleFactories`]. Bundles that are downloaded
+//! over HTTP use the settings in [`HttpSettings`], and may list mirrors to
+//! fall back on when their server is unavailable.
 
+use reqwest::header::HeaderMap;
+use reqwest::Url;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use crate::app_dirs;
 use crate::errors::{ErrorKind, Result};
 use crate::io::cached_itarbundle::CachedITarBundle;
+use crate::io::dirbundle::DirBundle;
+use crate::io::layeredbundle::LayeredBundle;
+use crate::io::tarbundle::TarBundle;
 use crate::io::zipbundle::ZipBundle;
 use crate::io::Bundle;
 use crate::status::StatusBackend;
+use crate::{ctry, errmsg};
 
 /// Awesome hack time!!!
 ///
@@ -39,12 +53,350 @@ pub fn activate_config_test_mode(forced: bool) {
 
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct PersistentConfig {
+    /// The bundles that make up the default bundle, in priority order.
     default_bundles: Vec<BundleInfo>,
+
+    #[cfg_attr(feature = "serde", serde(default))]
+    http: HttpSettings,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    bundle_factories: BundleFactories,
 }
 
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct BundleInfo {
+    /// A URL, or a path on the local filesystem.
     url: String,
+
+    /// Other URLs that serve exactly the same bundle, to fall back on if the
+    /// first one is unavailable. They are tried in order.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    mirrors: Vec<String>,
+}
+
+/// How to talk to the servers that web bundles are downloaded from. These
+/// go in the `[http]` section of the configuration file; everything is
+/// optional.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct HttpSettings {
+    /// The proxy to send all requests through, like
+    /// `http://proxy.example.com:3128`. If there isn't one, the usual
+    /// `http_proxy` and `https_proxy` environment variables are obeyed.
+    pub proxy: Option<String>,
+
+    /// The user name with which to authenticate to the proxy, if it wants
+    /// one ...
+    pub proxy_username: Option<String>,
+
+    /// ... and the password.
+    pub proxy_password: Option<String>,
+
+    /// PEM files with the certificates of extra certificate authorities to
+    /// trust, such as a company's internal one.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub ca_certificates: Vec<PathBuf>,
+
+    /// How long to wait for a connection to be made, in seconds.
+    pub connect_timeout: Option<u64>,
+
+    /// How long to wait for a whole request to finish, in seconds. The
+    /// default is 30 seconds.
+    pub timeout: Option<u64>,
+
+    /// A token to send with requests to the bundle's own servers, in an
+    /// `Authorization: Bearer` header. It isn't sent to the servers that they
+    /// redirect us to.
+    pub bearer_token: Option<String>,
+
+    /// Other headers to send with requests to the bundle's own servers, like
+    /// `bearer_token`.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "BTreeMap::is_empty"))]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl HttpSettings {
+    /// Start building an HTTP client that uses these settings. The bearer
+    /// token and the other headers aren't part of it; get them from
+    /// `headers_for` for each request.
+    pub fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        use reqwest::{Certificate, ClientBuilder, Proxy};
+
+        let mut builder = ClientBuilder::new();
+
+        if let Some(ref url) = self.proxy {
+            let mut proxy = ctry!(Proxy::all(url.as_str()); "invalid proxy URL \"{}\"", url);
+
+            if let Some(ref user) = self.proxy_username {
+                let password = self.proxy_password.as_ref().map_or("", |p| p.as_str());
+                proxy = proxy.basic_auth(user, password);
+            }
+
+            builder = builder.proxy(proxy);
+        }
+
+        for path in &self.ca_certificates {
+            let pem =
+                ctry!(fs::read(path); "couldn't read the CA certificate \"{}\"", path.display());
+            let cert =
+                ctry!(Certificate::from_pem(&pem); "invalid CA certificate \"{}\"", path.display());
+            builder = builder.add_root_certificate(cert);
+        }
+
+        if let Some(secs) = self.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+
+        if let Some(secs) = self.timeout {
+            builder = builder.timeout(Duration::from_secs(secs));
+        }
+
+        Ok(builder)
+    }
+
+    /// Build an HTTP client that uses these settings.
+    pub fn client(&self) -> Result<reqwest::Client> {
+        Ok(ctry!(self.client_builder()?.build(); "couldn't set up the HTTP client"))
+    }
+
+    /// Get the headers to send with a request to `url` for the bundle that
+    /// lives at `bundle_urls`. The bearer token and the other headers are
+    /// only sent to the same origins as those URLs, so that they don't leak
+    /// to the servers that the bundle redirects us to.
+    pub fn headers_for(&self, bundle_urls: &[String], url: &str) -> Result<HeaderMap> {
+        use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION};
+
+        let mut headers = HeaderMap::new();
+        let origin = ctry!(Url::parse(url); "invalid URL \"{}\"", url).origin();
+        let trusted = bundle_urls
+            .iter()
+            .filter_map(|u| Url::parse(u).ok())
+            .any(|u| u.origin() == origin);
+
+        if !trusted {
+            return Ok(headers);
+        }
+
+        if let Some(ref token) = self.bearer_token {
+            let value =
+                ctry!(HeaderValue::from_str(&format!("Bearer {}", token)); "invalid bearer token");
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        for (name, value) in &self.headers {
+            let name = ctry!(HeaderName::from_bytes(name.as_bytes()); "invalid HTTP header name \"{}\"", name);
+            let value =
+                ctry!(HeaderValue::from_str(value); "invalid value for HTTP header \"{}\"", name);
+            headers.insert(name, value);
+        }
+
+        Ok(headers)
+    }
+}
+
+/// What a [`BundleFactory`] needs to know to open a bundle.
+pub struct BundleSpec<'a> {
+    /// The location of the bundle, as it was given to us.
+    pub location: &'a str,
+
+    /// Other URLs of the same bundle, to be tried in order if `location`
+    /// doesn't work out. Only bundles that are downloaded use these.
+    pub mirrors: &'a [String],
+
+    /// The path of the bundle, if it lives on the local filesystem.
+    pub path: Option<&'a Path>,
+
+    /// If true, the bundle should avoid the network and only use files that
+    /// have already been cached.
+    pub only_cached: bool,
+
+    /// Where a caching bundle should keep its files, if not in the default
+    /// location.
+    pub custom_cache_root: Option<&'a Path>,
+
+    /// How to make HTTP requests, for bundles that need to.
+    pub http: &'a HttpSettings,
+}
+
+/// A function that opens a bundle.
+pub type BundleFactory =
+    Box<dyn Fn(&BundleSpec, &mut dyn StatusBackend) -> Result<Box<dyn Bundle>>>;
+
+/// The registry of the different kinds of bundles that Tectonic knows how to
+/// open.
+///
+/// Bundles given as URLs are opened by the factory registered for the URL’s
+/// scheme, except that `file:` URLs are treated just like local paths. Local
+/// directories are opened by the directory factory, and other local files by
+/// the factory registered for their extension. Files whose extension isn’t
+/// registered are opened as Zip files, since that is what Tectonic has always
+/// expected of them.
+///
+/// The default registry opens `http:` and `https:` URLs as indexed tar
+/// bundles with a local cache, `.zip` and `.tar` files as Zip and tar
+/// bundles, and directories as [`DirBundle`]s.
+pub struct BundleFactories {
+    schemes: HashMap<String, BundleFactory>,
+    extensions: HashMap<String, BundleFactory>,
+    directory: Option<BundleFactory>,
+}
+
+impl BundleFactories {
+    /// Create a registry that doesn't know how to open any bundles at all.
+    pub fn empty() -> BundleFactories {
+        BundleFactories {
+            schemes: HashMap::new(),
+            extensions: HashMap::new(),
+            directory: None,
+        }
+    }
+
+    /// Register the factory that opens bundles with URLs of the given scheme,
+    /// replacing any previous one.
+    pub fn register_scheme<F>(&mut self, scheme: &str, factory: F) -> &mut Self
+    where
+        F: 'static + Fn(&BundleSpec, &mut dyn StatusBackend) -> Result<Box<dyn Bundle>>,
+    {
+        self.schemes
+            .insert(scheme.to_lowercase(), Box::new(factory));
+        self
+    }
+
+    /// Register the factory that opens local bundle files with the given
+    /// extension, replacing any previous one. The extension is given without
+    /// the leading dot.
+    pub fn register_extension<F>(&mut self, extension: &str, factory: F) -> &mut Self
+    where
+        F: 'static + Fn(&BundleSpec, &mut dyn StatusBackend) -> Result<Box<dyn Bundle>>,
+    {
+        self.extensions
+            .insert(extension.to_lowercase(), Box::new(factory));
+        self
+    }
+
+    /// Register the factory that opens local directories as bundles,
+    /// replacing any previous one.
+    pub fn register_directory<F>(&mut self, factory: F) -> &mut Self
+    where
+        F: 'static + Fn(&BundleSpec, &mut dyn StatusBackend) -> Result<Box<dyn Bundle>>,
+    {
+        self.directory = Some(Box::new(factory));
+        self
+    }
+
+    /// Open the bundle at the given location, which is either a URL or a
+    /// local path, possibly with mirrors.
+    pub fn open(
+        &self,
+        location: &str,
+        mirrors: &[String],
+        only_cached: bool,
+        custom_cache_root: Option<&Path>,
+        http: &HttpSettings,
+        status: &mut dyn StatusBackend,
+    ) -> Result<Box<dyn Bundle>> {
+        use reqwest::Url;
+
+        let mut spec = BundleSpec {
+            location,
+            mirrors,
+            path: None,
+            only_cached,
+            custom_cache_root,
+            http,
+        };
+
+        let url = match Url::parse(location) {
+            // Windows paths like `C:\texmf` look like URLs with one-letter
+            // schemes.
+            Ok(ref url) if url.scheme().len() < 2 => None,
+            Ok(url) => Some(url),
+            Err(_) => None,
+        };
+
+        let path = match url {
+            None => PathBuf::from(location),
+
+            Some(ref url) if url.scheme() == "file" => match url.to_file_path() {
+                Ok(p) => p,
+                Err(_) => return Err(errmsg!("failed to parse local path in \"{}\"", location)),
+            },
+
+            Some(url) => {
+                let factory = match self.schemes.get(url.scheme()) {
+                    Some(f) => f,
+                    None => {
+                        return Err(errmsg!(
+                            "don't know how to open bundles with URL scheme \"{}\" (at \"{}\")",
+                            url.scheme(),
+                            location
+                        ));
+                    }
+                };
+
+                return factory(&spec, status);
+            }
+        };
+
+        let factory = if path.is_dir() {
+            self.directory.as_ref()
+        } else {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .and_then(|e| self.extensions.get(&e.to_lowercase()))
+                .or_else(|| self.extensions.get("zip"))
+        };
+
+        let factory = match factory {
+            Some(f) => f,
+            None => {
+                return Err(errmsg!(
+                    "don't know how to open the bundle at \"{}\"",
+                    location
+                ))
+            }
+        };
+
+        spec.path = Some(&path);
+        factory(&spec, status)
+    }
+}
+
+impl Default for BundleFactories {
+    fn default() -> Self {
+        fn itar(spec: &BundleSpec, status: &mut dyn StatusBackend) -> Result<Box<dyn Bundle>> {
+            let mut urls = vec![spec.location.to_owned()];
+            urls.extend(spec.mirrors.iter().cloned());
+
+            let bundle = CachedITarBundle::with_mirrors(
+                &urls,
+                spec.only_cached,
+                spec.custom_cache_root,
+                spec.http,
+                status,
+            )?;
+            Ok(Box::new(bundle))
+        }
+
+        let mut factories = BundleFactories::empty();
+
+        factories
+            .register_scheme("http", itar)
+            .register_scheme("https", itar)
+            .register_extension("zip", |spec, _status| {
+                Ok(Box::new(ZipBundle::<File>::open(spec.path.unwrap())?))
+            })
+            .register_extension("tar", |spec, _status| {
+                Ok(Box::new(TarBundle::<File>::open(spec.path.unwrap())?))
+            })
+            .register_directory(|spec, _status| Ok(Box::new(DirBundle::new(spec.path.unwrap())?)));
+
+        factories
+    }
 }
 
 impl PersistentConfig {
@@ -105,6 +457,34 @@ impl PersistentConfig {
         Ok(PersistentConfig::default())
     }
 
+    /// Get the registry of bundle factories, to teach Tectonic how to open
+    /// new kinds of bundles.
+    pub fn bundle_factories_mut(&mut self) -> &mut BundleFactories {
+        &mut self.bundle_factories
+    }
+
+    /// Get the settings for HTTP requests.
+    pub fn http_settings(&self) -> &HttpSettings {
+        &self.http
+    }
+
+    /// Get the settings for HTTP requests, to change them.
+    pub fn http_settings_mut(&mut self) -> &mut HttpSettings {
+        &mut self.http
+    }
+
+    /// Open the bundle at the given location, which is either a URL or a
+    /// local path, using the registered bundle factories.
+    pub fn make_bundle(
+        &self,
+        location: &str,
+        only_cached: bool,
+        status: &mut dyn StatusBackend,
+    ) -> Result<Box<dyn Bundle>> {
+        self.bundle_factories
+            .open(location, &[], only_cached, None, &self.http, status)
+    }
+
     pub fn make_cached_url_provider(
         &self,
         url: &str,
@@ -112,7 +492,8 @@ impl PersistentConfig {
         custom_cache_root: Option<&Path>,
         status: &mut dyn StatusBackend,
     ) -> Result<Box<dyn Bundle>> {
-        let bundle = CachedITarBundle::new(url, only_cached, custom_cache_root, status)?;
+        let bundle =
+            CachedITarBundle::new(url, only_cached, custom_cache_root, &self.http, status)?;
 
         Ok(Box::new(bundle) as _)
     }
@@ -127,39 +508,42 @@ impl PersistentConfig {
         Ok(Box::new(zip_bundle) as _)
     }
 
+    /// Open the default bundle. If several bundles are configured, they are
+    /// layered on top of each other, the first one taking priority.
     pub fn default_bundle(
         &self,
         only_cached: bool,
         status: &mut dyn StatusBackend,
     ) -> Result<Box<dyn Bundle>> {
-        use reqwest::Url;
-        use std::io;
-
         if CONFIG_TEST_MODE_ACTIVATED.load(Ordering::SeqCst) {
             return Ok(Box::new(crate::test_util::TestBundle::default()));
         }
 
-        if self.default_bundles.len() != 1 {
+        if self.default_bundles.is_empty() {
             return Err(ErrorKind::Msg(
-                "exactly one default_bundle item must be specified (for now)".to_owned(),
+                "at least one default_bundles item must be specified".to_owned(),
             )
             .into());
         }
 
-        let url = Url::parse(&self.default_bundles[0].url)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "failed to parse url"))?;
-        if url.scheme() == "file" {
-            // load the local zip file.
-            let file_path = url.to_file_path().map_err(|_| {
-                io::Error::new(io::ErrorKind::InvalidInput, "failed to parse local path")
-            })?;
-            let zip_bundle = self.make_local_file_provider(file_path.as_os_str(), status)?;
+        let mut layers = Vec::new();
 
-            return Ok(Box::new(zip_bundle) as _);
+        for info in &self.default_bundles {
+            layers.push(self.bundle_factories.open(
+                &info.url,
+                &info.mirrors,
+                only_cached,
+                None,
+                &self.http,
+                status,
+            )?);
         }
-        let bundle =
-            self.make_cached_url_provider(&self.default_bundles[0].url, only_cached, None, status)?;
-        Ok(Box::new(bundle) as _)
+
+        if layers.len() == 1 {
+            return Ok(layers.pop().unwrap());
+        }
+
+        Ok(Box::new(LayeredBundle::new(layers)))
     }
 
     pub fn format_cache_path(&self) -> Result<PathBuf> {
@@ -176,7 +560,182 @@ impl Default for PersistentConfig {
         PersistentConfig {
             default_bundles: vec![BundleInfo {
                 url: String::from("https://archive.org/services/purl/net/pkgwpub/tectonic-default"),
+                mirrors: Vec::new(),
             }],
+            http: HttpSettings::default(),
+            bundle_factories: BundleFactories::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::OpenResult;
+    use crate::status::NoopStatusBackend;
+    use std::fs;
+    use std::io::{Read, Write};
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn read(bundle: &mut dyn Bundle, name: &str) -> Option<String> {
+        let mut status = NoopStatusBackend::new();
+
+        match bundle.input_open_name(OsStr::new(name), &mut status) {
+            OpenResult::Ok(mut h) => {
+                let mut s = String::new();
+                h.read_to_string(&mut s).unwrap();
+                Some(s)
+            }
+            OpenResult::NotAvailable => None,
+            OpenResult::Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn layered_bundles() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let dir = tempdir.path().join("custom");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a.cls"), "custom").unwrap();
+
+        let zip_path = tempdir.path().join("standard.zip");
+        let mut zip = ZipWriter::new(File::create(&zip_path).unwrap());
+        for name in &["a.cls", "b.cls"] {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(b"standard").unwrap();
         }
+        zip.finish().unwrap();
+
+        let config = PersistentConfig {
+            default_bundles: vec![
+                BundleInfo {
+                    url: dir.to_str().unwrap().to_owned(),
+                    mirrors: Vec::new(),
+                },
+                BundleInfo {
+                    url: reqwest::Url::from_file_path(&zip_path).unwrap().to_string(),
+                    mirrors: Vec::new(),
+                },
+            ],
+            http: HttpSettings::default(),
+            bundle_factories: BundleFactories::default(),
+        };
+
+        let mut status = NoopStatusBackend::new();
+        let mut bundle = config.default_bundle(false, &mut status).unwrap();
+        assert_eq!(read(&mut *bundle, "a.cls").unwrap(), "custom");
+        assert_eq!(read(&mut *bundle, "b.cls").unwrap(), "standard");
+        assert_eq!(read(&mut *bundle, "c.cls"), None);
+    }
+
+    #[test]
+    fn custom_factories() {
+        let mut config = PersistentConfig::default();
+        let mut status = NoopStatusBackend::new();
+        assert!(config
+            .make_bundle("s3://bucket/bundle", false, &mut status)
+            .is_err());
+
+        config
+            .bundle_factories_mut()
+            .register_scheme("s3", |spec, _status| {
+                assert_eq!(spec.location, "s3://bucket/bundle");
+                assert!(spec.mirrors.is_empty());
+                assert!(spec.path.is_none());
+                Ok(Box::new(TarBundle::new(std::io::Cursor::new(Vec::new()))?))
+            });
+        assert!(config
+            .make_bundle("s3://bucket/bundle", false, &mut status)
+            .is_ok());
+    }
+
+    #[cfg(feature = "serialization")]
+    #[test]
+    fn bundle_mirrors() {
+        let mut config: PersistentConfig = toml::from_str(
+            "[[default_bundles]]\n\
+             url = \"s3://bucket/bundle\"\n\
+             mirrors = [\"s3://other/bundle\", \"s3://third/bundle\"]\n",
+        )
+        .unwrap();
+
+        config
+            .bundle_factories_mut()
+            .register_scheme("s3", |spec, _status| {
+                assert_eq!(spec.location, "s3://bucket/bundle");
+                assert_eq!(spec.mirrors, ["s3://other/bundle", "s3://third/bundle"]);
+                Ok(Box::new(TarBundle::new(std::io::Cursor::new(Vec::new()))?))
+            });
+
+        let mut status = NoopStatusBackend::new();
+        assert!(config.default_bundle(false, &mut status).is_ok());
+        assert!(toml::to_string(&config).unwrap().contains("mirrors"));
+        assert!(!toml::to_string(&PersistentConfig::default())
+            .unwrap()
+            .contains("mirrors"));
+    }
+
+    #[cfg(feature = "serialization")]
+    #[test]
+    fn http_settings() {
+        let config: PersistentConfig = toml::from_str(
+            "[[default_bundles]]\n\
+             url = \"https://example.com/bundle.tar\"\n",
+        )
+        .unwrap();
+        assert_eq!(*config.http_settings(), HttpSettings::default());
+
+        let config: PersistentConfig = toml::from_str(
+            "[[default_bundles]]\n\
+             url = \"https://example.com/bundle.tar\"\n\
+             [http]\n\
+             proxy = \"http://proxy.example.com:3128\"\n\
+             timeout = 300\n\
+             bearer_token = \"secret\"\n\
+             [http.headers]\n\
+             X-Team = \"typesetting\"\n",
+        )
+        .unwrap();
+        let http = config.http_settings();
+        assert_eq!(
+            http.proxy.as_ref().unwrap(),
+            "http://proxy.example.com:3128"
+        );
+        assert_eq!(http.timeout, Some(300));
+        assert_eq!(http.connect_timeout, None);
+        assert_eq!(http.headers["X-Team"], "typesetting");
+        assert!(http.client().is_ok());
+
+        // The credentials only go to the bundle's own servers.
+        let urls = vec!["https://example.com/bundle.tar".to_owned()];
+        let headers = http
+            .headers_for(&urls, "https://example.com/bundle.tar.index.gz")
+            .unwrap();
+        assert_eq!(headers["Authorization"], "Bearer secret");
+        assert_eq!(headers["X-Team"], "typesetting");
+        let headers = http
+            .headers_for(&urls, "https://cdn.example.com/bundle.tar")
+            .unwrap();
+        assert!(headers.is_empty());
+        let headers = http
+            .headers_for(&urls, "http://example.com/bundle.tar")
+            .unwrap();
+        assert!(headers.is_empty());
+
+        // Settings round-trip, and the defaults don't clutter up the file.
+        let text = toml::to_string(&config).unwrap();
+        let again: PersistentConfig = toml::from_str(&text).unwrap();
+        assert_eq!(again.http_settings(), http);
+        assert!(!toml::to_string(&PersistentConfig::default())
+            .unwrap()
+            .contains("ca_certificates"));
+
+        let mut http = HttpSettings::default();
+        http.ca_certificates.push("/nonexistent/ca.pem".into());
+        assert!(http.client().is_err());
+        let mut http = HttpSettings::default();
+        http.headers.insert("Bad Header".to_owned(), "x".to_owned());
+        assert!(http.headers_for(&urls, &urls[0]).is_err());
     }
 }
@@ -48,7 +48,7 @@ pub fn create() -> DigestComputer {
     Default::default()
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct DigestData([u8; N_BYTES]);
 
 impl DigestData {
@@ -75,6 +75,27 @@ impl DigestData {
     }
 }
 
+/// Compute the digest of a bundle from the names and digests of its files,
+/// which must be sorted by name. This is the scheme used by
+/// builder/make-zipfile.py in the `tectonic-staging` module: the bundle
+/// digest is the SHA256 of each file name, a NUL byte, and the raw SHA256
+/// of the file’s contents, in turn. The `SHA256SUM` file itself is not
+/// included.
+pub fn bundle_digest<'a, I>(files: I) -> DigestData
+where
+    I: IntoIterator<Item = (&'a str, &'a DigestData)>,
+{
+    let mut dc = create();
+
+    for (name, digest) in files {
+        dc.input(name.as_bytes());
+        dc.input([0]);
+        dc.input(digest.0);
+    }
+
+    DigestData::from(dc)
+}
+
 impl ToString for DigestData {
     fn to_string(&self) -> String {
         bytes_to_hex(&self.0)
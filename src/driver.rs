@@ -11,24 +11,31 @@
 //! For an example of how to use this module, see `src/bin/tectonic.rs`, which contains tectonic's main
 //! CLI program.
 
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::digest::DigestData;
-use crate::engines::IoEventBackend;
+use crate::engines::{IoEventBackend, PageRange, PdfEncryption, PdfOptions};
 use crate::errors::{ErrorKind, Result, ResultExt};
 use crate::io::{Bundle, InputOrigin, IoProvider, IoSetup, IoSetupBuilder, OpenResult};
-use crate::status::StatusBackend;
+use crate::status::{Diagnostic, StatusBackend};
+use crate::tex_log;
 use crate::{ctry, errmsg, tt_error, tt_note, tt_warning};
 use crate::{BibtexEngine, Spx2HtmlEngine, TexEngine, TexResult, XdvipdfmxEngine};
+use crate::{ExternalToolEngine, MakeindexEngine};
 
 /// Different patterns with which files may have been accessed by the
 /// underlying engines. Once a file is marked as ReadThenWritten or
 /// WrittenThenRead, its pattern does not evolve further.
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AccessPattern {
     /// This file is only ever read.
@@ -195,6 +202,111 @@ impl IoEventBackend for IoEvents {
     }
 }
 
+/// A record of one engine pass that was run during a processing session.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PassReport {
+    /// The name of the engine, e.g. `"TeX"` or `"BibTeX"`.
+    pub engine: String,
+
+    /// If this pass was a rerun, the reason why it was needed.
+    pub rerun_explanation: Option<String>,
+
+    /// The outcome reported by the engine. This is `None` for engines that
+    /// don't report one, and for passes that failed outright.
+    pub result: Option<TexResult>,
+}
+
+/// A summary of the I/O that happened on one file, as recorded in [`IoEvents`].
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileReport {
+    /// The name of the file, as the engines know it.
+    pub name: String,
+
+    /// How the file was accessed.
+    pub access_pattern: AccessPattern,
+
+    /// Where the file came from, if it was read.
+    pub input_origin: InputOrigin,
+
+    /// The hex-encoded digest of the file when it was first read, if known.
+    pub read_digest: Option<String>,
+
+    /// The hex-encoded digest of the file when it was last written, if known.
+    pub write_digest: Option<String>,
+}
+
+/// The reasons why a file produced by the engines might not be written to disk.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// The file is an intermediate file and intermediates are not being kept.
+    Intermediate,
+
+    /// The file is a log file and logs are not being kept.
+    Log,
+
+    /// The file would have been empty.
+    Empty,
+
+    /// The file is not among the outputs that were asked for.
+    NotRequested,
+}
+
+/// A file that was produced by the engines but not written to disk.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SkippedFile {
+    /// The name of the file, as the engines know it.
+    pub name: String,
+
+    /// Why it wasn't written.
+    pub reason: SkipReason,
+}
+
+/// A machine-readable summary of a processing session.
+///
+/// This can be obtained from [`ProcessingSession::report`], or saved as JSON
+/// at the end of [`ProcessingSession::run`] by setting
+/// [`ProcessingSessionBuilder::report_path`].
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BuildReport {
+    /// Every engine pass that was run, in order.
+    pub passes: Vec<PassReport>,
+
+    /// Every file that the engines touched, sorted by name.
+    pub files: Vec<FileReport>,
+
+    /// The paths of the files that were written to disk.
+    pub written_files: Vec<String>,
+
+    /// The files that were produced but not written to disk.
+    pub skipped_files: Vec<SkippedFile>,
+
+    /// The diagnostics found in the log of the last TeX pass.
+    pub diagnostics: Vec<Diagnostic>,
+
+    /// If processing failed, the error message followed by its causes.
+    pub errors: Vec<String>,
+}
+
+impl BuildReport {
+    fn record_pass(
+        &mut self,
+        engine: &str,
+        rerun_explanation: Option<&str>,
+        result: Option<TexResult>,
+    ) {
+        self.passes.push(PassReport {
+            engine: engine.to_owned(),
+            rerun_explanation: rerun_explanation.map(|s| s.to_owned()),
+            result,
+        });
+    }
+}
+
 /// The different types of output files that tectonic knows how to produce.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum OutputFormat {
@@ -206,6 +318,8 @@ pub enum OutputFormat {
     Xdv,
     /// A '.pdf' file.
     Pdf,
+    /// A '.pdf' file that conforms to PDF/A-2b, for archiving.
+    PdfA,
     /// A '.fmt' file, for initializing the TeX engine.
     Format,
 }
@@ -285,6 +399,7 @@ pub struct ProcessingSessionBuilder {
     format_cache_path: Option<PathBuf>,
     output_format: OutputFormat,
     makefile_output_path: Option<PathBuf>,
+    report_path: Option<PathBuf>,
     hidden_input_paths: HashSet<PathBuf>,
     pass: PassSetting,
     reruns: Option<usize>,
@@ -293,6 +408,13 @@ pub struct ProcessingSessionBuilder {
     keep_intermediates: bool,
     keep_logs: bool,
     synctex: bool,
+    biber_program: Option<String>,
+    index_style: Option<String>,
+    page_ranges: Vec<PageRange>,
+    pdf_options: PdfOptions,
+    pdf_encryption: Option<PdfEncryption>,
+    reproducible: bool,
+    build_date: Option<SystemTime>,
 }
 
 impl ProcessingSessionBuilder {
@@ -376,6 +498,16 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// If set, a JSON report describing the session will be written out at the given path.
+    ///
+    /// The report lists the engine passes that were run, the I/O performed on
+    /// each file, and which files were or weren't written to disk. See
+    /// [`BuildReport`]. It is written even if processing fails.
+    pub fn report_path<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.report_path = Some(p.as_ref().to_owned());
+        self
+    }
+
     /// Which kind of pass should the `ProcessingSession` run? Defaults to `PassSetting::Default`
     /// (duh).
     pub fn pass(&mut self, p: PassSetting) -> &mut Self {
@@ -431,6 +563,63 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// Sets the program to run when a document uses `biblatex` with the
+    /// `biber` backend. Defaults to `biber`, found through `$PATH`; any
+    /// program with a compatible command-line interface will do.
+    pub fn biber_program(&mut self, program: &str) -> &mut Self {
+        self.biber_program = Some(program.to_owned());
+        self
+    }
+
+    /// Sets the `.ist` style file used when generating the index of a
+    /// document. The file is looked up like any other input, so it may come
+    /// from the bundle. By default, the standard `makeindex` style is used.
+    pub fn index_style(&mut self, name: &str) -> &mut Self {
+        self.index_style = Some(name.to_owned());
+        self
+    }
+
+    /// Only include these pages in the PDF output. By default, all pages
+    /// are included.
+    pub fn page_ranges(&mut self, ranges: &[PageRange]) -> &mut Self {
+        self.page_ranges = ranges.to_vec();
+        self
+    }
+
+    /// Sets the options that control the layout of the PDF output, such as
+    /// its paper size.
+    pub fn pdf_options(&mut self, options: PdfOptions) -> &mut Self {
+        self.pdf_options = options;
+        self
+    }
+
+    /// Encrypts the PDF output with the given passwords and permissions. By
+    /// default it is not encrypted.
+    pub fn pdf_encryption(&mut self, encryption: PdfEncryption) -> &mut Self {
+        self.pdf_encryption = Some(encryption);
+        self
+    }
+
+    /// If set to `true`, processing the same inputs always gives the same
+    /// outputs, byte for byte. The dates that TeX reports (as in `\today`)
+    /// and that go into the PDF are all set to the build date, and the PDF's
+    /// `/ID` and font subset tags no longer vary from run to run.
+    ///
+    /// Unless a build date is given explicitly, it's taken from the
+    /// `SOURCE_DATE_EPOCH` environment variable, or is the start of 1970 if
+    /// that isn't set.
+    pub fn reproducible(&mut self, r: bool) -> &mut Self {
+        self.reproducible = r;
+        self
+    }
+
+    /// Sets the date and time to use as the time of the build. By default,
+    /// the current time is used, unless in reproducible mode.
+    pub fn build_date(&mut self, date: SystemTime) -> &mut Self {
+        self.build_date = Some(date);
+        self
+    }
+
     /// Creates a `ProcessingSession`.
     pub fn create(self, status: &mut dyn StatusBackend) -> Result<ProcessingSession> {
         let mut io = IoSetupBuilder::default();
@@ -476,6 +665,8 @@ impl ProcessingSessionBuilder {
             }
         };
 
+        let filesystem_root = default_output_path.clone();
+
         let output_path = match self.output_dest {
             OutputDestination::Default => Some(default_output_path),
             OutputDestination::Path(p) => Some(p),
@@ -499,29 +690,70 @@ impl ProcessingSessionBuilder {
         });
         let mut pdf_path = aux_path.clone();
         pdf_path.set_extension("pdf");
+        let mut log_path = aux_path.clone();
+        log_path.set_extension("log");
+        let mut bcf_path = aux_path.clone();
+        bcf_path.set_extension("bcf");
+        let mut idx_path = aux_path.clone();
+        idx_path.set_extension("idx");
+
+        let build_date = match self.build_date {
+            Some(d) => Some(d),
+            None if self.reproducible => Some(source_date_epoch()?.unwrap_or(UNIX_EPOCH)),
+            None => None,
+        };
 
         Ok(ProcessingSession {
             io: io.create(status)?,
             events: IoEvents::new(),
             pass: self.pass,
             primary_input_path,
+            filesystem_root,
             primary_input_tex_path: tex_input_name,
             format_name: self.format_name.unwrap(),
             tex_aux_path: aux_path.into_os_string(),
             tex_xdv_path: xdv_path.into_os_string(),
             tex_pdf_path: pdf_path.into_os_string(),
+            tex_log_path: log_path.into_os_string(),
+            tex_prior_writes: HashMap::new(),
+            tex_bcf_path: bcf_path.into_os_string(),
+            biber_program: self.biber_program.unwrap_or_else(|| "biber".to_owned()),
+            biber_bcf_digest: None,
+            tex_idx_path: idx_path.into_os_string(),
+            index_style: self.index_style,
+            makeindex_idx_digest: None,
+            page_ranges: self.page_ranges,
+            pdf_options: self.pdf_options,
+            pdf_encryption: self.pdf_encryption,
             output_format: self.output_format,
             makefile_output_path: self.makefile_output_path,
+            report_path: self.report_path,
+            report: BuildReport::default(),
             output_path,
             tex_rerun_specification: self.reruns,
             keep_intermediates: self.keep_intermediates,
             keep_logs: self.keep_logs,
             noted_tex_warnings: false,
             synctex_enabled: self.synctex,
+            reproducible: self.reproducible,
+            build_date,
         })
     }
 }
 
+/// Get the build date given by the `SOURCE_DATE_EPOCH` environment variable,
+/// as specified at <https://reproducible-builds.org/specs/source-date-epoch/>,
+/// if it's set.
+fn source_date_epoch() -> Result<Option<SystemTime>> {
+    let value = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(v) => v,
+        Err(_) => return Ok(None),
+    };
+
+    let secs: u64 = ctry!(value.trim().parse(); "invalid SOURCE_DATE_EPOCH \"{}\"", value);
+    Ok(Some(UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
 /// The ProcessingSession struct runs the whole show when we're actually
 /// processing a file. It understands, for example, the need to re-run the TeX
 /// engine if the `.aux` file changed.
@@ -538,6 +770,10 @@ pub struct ProcessingSession {
     /// If our primary input is an actual file on disk, this is its path.
     primary_input_path: Option<PathBuf>,
 
+    /// The directory that the filesystem I/O layer searches for inputs. The
+    /// names recorded in `events` are relative to it.
+    filesystem_root: PathBuf,
+
     /// This is the name of the input that we tell TeX. It is the basename of
     /// the UTF8-ified version of `primary_input_path`; or something anodyne
     /// if the latter is None. (Name, "texput.tex").
@@ -555,11 +791,40 @@ pub struct ProcessingSession {
     tex_aux_path: OsString,
     tex_xdv_path: OsString,
     tex_pdf_path: OsString,
+    tex_log_path: OsString,
+
+    /// The write digests of the files that existed at the start of the
+    /// current TeX rerun, for `rerun_needed`.
+    tex_prior_writes: HashMap<OsString, DigestData>,
+
+    /// The control file that `biblatex` writes for `biber`, the program to
+    /// run on it, and the digest of the version it was last run on.
+    tex_bcf_path: OsString,
+    biber_program: String,
+    biber_bcf_digest: Option<DigestData>,
+
+    /// Likewise for the index entries that TeX writes for `makeindex`.
+    tex_idx_path: OsString,
+    index_style: Option<String>,
+    makeindex_idx_digest: Option<DigestData>,
+
+    /// The pages to include in the PDF output, or all of them if empty, how
+    /// to lay them out, and how to encrypt them, if at all.
+    page_ranges: Vec<PageRange>,
+    pdf_options: PdfOptions,
+    pdf_encryption: Option<PdfEncryption>,
 
     /// If we're writing out Makefile rules, this is where they go. The TeX
     /// engine doesn't know about this path at all.
     makefile_output_path: Option<PathBuf>,
 
+    /// If we're writing out a JSON build report, this is where it goes.
+    report_path: Option<PathBuf>,
+
+    /// The parts of the build report that can't be reconstructed from
+    /// `events` after the fact.
+    report: BuildReport,
+
     /// This is the path that the processed file will be saved at. It defaults
     /// to the path of `primary_input_path` or `.` if STDIN is used. If set to
     /// None, the output files will not be saved to disk — in which case, the
@@ -574,6 +839,11 @@ pub struct ProcessingSession {
     keep_logs: bool,
     noted_tex_warnings: bool,
     synctex_enabled: bool,
+
+    /// Whether to make the outputs reproducible, and the build date to use
+    /// instead of the current time, if any.
+    reproducible: bool,
+    build_date: Option<SystemTime>,
 }
 
 const DEFAULT_MAX_TEX_PASSES: usize = 6;
@@ -582,36 +852,88 @@ const ALWAYS_INTERMEDIATE_EXTENSIONS: &[&str] = &[
 ];
 
 impl ProcessingSession {
-    /// Assess whether we need to rerun an engine. This is the case if there
-    /// was a file that the engine read and then rewrote, and the rewritten
-    /// version is different than the version that it read in.
+    /// Assess whether we need to rerun the TeX engine, returning an
+    /// explanation of why if so. This is the case if:
+    ///
+    /// - there was a file that the engine read and then rewrote, and the
+    ///   rewritten version is different than the version that it read in;
+    /// - there was a file left over from a previous pass that the engine read
+    ///   and then rewrote with different contents; or
+    /// - the log of the last pass contains a message asking for a rerun, as
+    ///   LaTeX and many packages print when they notice that something is
+    ///   out of date.
+    ///
+    /// Either way, the decision is explained through the status backend, so
+    /// that we have a chance of figuring out why rerun detection is breaking
+    /// when it does.
     fn rerun_needed<S: StatusBackend>(&mut self, status: &mut S) -> Option<String> {
-        // TODO: we should probably wire up diagnostics since I expect this
-        // stuff could get finicky and we're going to want to be able to
-        // figure out why rerun detection is breaking.
+        let mut names: Vec<_> = self.events.0.keys().collect();
+        names.sort();
+        let mut n_checked = 0;
+
+        for name in names {
+            let info = &self.events.0[name];
+
+            match info.access_pattern {
+                AccessPattern::ReadThenWritten => {
+                    n_checked += 1;
+
+                    let file_changed = match (&info.read_digest, &info.write_digest) {
+                        (&Some(ref d1), &Some(ref d2)) => d1 != d2,
+                        (&None, &Some(_)) => true,
+                        (_, _) => {
+                            // Other cases shouldn't happen.
+                            tt_warning!(
+                                status,
+                                "internal consistency problem when checking if {} changed",
+                                name.to_string_lossy()
+                            );
+                            true
+                        }
+                    };
 
-        for (name, info) in &self.events.0 {
-            if info.access_pattern == AccessPattern::ReadThenWritten {
-                let file_changed = match (&info.read_digest, &info.write_digest) {
-                    (&Some(ref d1), &Some(ref d2)) => d1 != d2,
-                    (&None, &Some(_)) => true,
-                    (_, _) => {
-                        // Other cases shouldn't happen.
-                        tt_warning!(
-                            status,
-                            "internal consistency problem when checking if {} changed",
-                            name.to_string_lossy()
-                        );
-                        true
+                    if file_changed {
+                        return Some(format!("\"{}\" changed", name.to_string_lossy()));
                     }
-                };
+                }
 
-                if file_changed {
-                    return Some(name.to_string_lossy().into_owned());
+                AccessPattern::Written | AccessPattern::WrittenThenRead => {
+                    // These files only matter if this pass read what a
+                    // previous pass left behind, and then replaced it. A
+                    // file that's written and read back within a single pass
+                    // won't have a digest from before the pass.
+                    let prior = match self.tex_prior_writes.get(name) {
+                        Some(d) => d,
+                        None => continue,
+                    };
+
+                    n_checked += 1;
+
+                    if info.read_digest.as_ref() == Some(prior)
+                        && info.write_digest.as_ref() != Some(prior)
+                    {
+                        return Some(format!(
+                            "\"{}\" changed after it was read",
+                            name.to_string_lossy()
+                        ));
+                    }
                 }
+
+                AccessPattern::Read => {}
             }
         }
 
+        if let Some(data) = self.io.mem.files.borrow().get(&self.tex_log_path) {
+            if let Some(line) = tex_log::find_rerun_request(data) {
+                return Some(format!("the TeX log says \"{}\"", line));
+            }
+        }
+
+        tt_note!(
+            status,
+            "no TeX rerun needed: {} intermediate files are unchanged and the log asks for none",
+            n_checked
+        );
         None
     }
 
@@ -649,7 +971,29 @@ impl ProcessingSession {
     /// - run BibTeX, if it seems to be required
     /// - repeat the last two steps as often as needed
     /// - write the output files to disk, including a Makefile if it was requested.
+    ///
+    /// Finally, the build report is written, if one was requested, and the
+    /// bundle gets to save anything that it has learned, such as records of the
+    /// files that it has downloaded. Both happen even if the run failed.
     pub fn run<S: StatusBackend>(&mut self, status: &mut S) -> Result<()> {
+        let result = self.run_passes(status);
+
+        self.report.errors = match result {
+            Ok(_) => Vec::new(),
+            Err(ref e) => e.iter().map(|item| item.to_string()).collect(),
+        };
+
+        let reported = self.write_report(status);
+
+        let flushed = match self.io.bundle {
+            Some(ref mut b) => b.flush(status),
+            None => Ok(()),
+        };
+
+        result.and(reported).and(flushed)
+    }
+
+    fn run_passes<S: StatusBackend>(&mut self, status: &mut S) -> Result<()> {
         // Do we need to generate the format file?
 
         let generate_format = if self.output_format == OutputFormat::Format {
@@ -683,6 +1027,14 @@ impl ProcessingSession {
             PassSetting::BibtexFirst => self.default_pass(true, status),
         };
 
+        // Earlier TeX passes are expected to complain about things like
+        // undefined references, so we only pass along what the last one
+        // had to say.
+
+        for diag in &self.report.diagnostics {
+            status.report_diagnostic(diag);
+        }
+
         if let Err(e) = result {
             self.write_files(None, status, true)?;
             return Err(e);
@@ -762,6 +1114,49 @@ impl ProcessingSession {
         Ok(())
     }
 
+    /// Get a machine-readable summary of what this session has done so far.
+    pub fn report(&self) -> BuildReport {
+        let mut report = self.report.clone();
+
+        for (name, info) in &self.events.0 {
+            report.files.push(FileReport {
+                name: name.to_string_lossy().into_owned(),
+                access_pattern: info.access_pattern,
+                input_origin: info.input_origin,
+                read_digest: info.read_digest.map(|d| d.to_string()),
+                write_digest: info.write_digest.map(|d| d.to_string()),
+            });
+        }
+
+        report.files.sort_by(|a, b| a.name.cmp(&b.name));
+        report
+    }
+
+    #[cfg(feature = "serialization")]
+    fn write_report<S: StatusBackend>(&self, status: &mut S) -> Result<()> {
+        let path = match self.report_path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+
+        status.note_highlighted("Writing ", &path.to_string_lossy(), " (build report)");
+        let f = ctry!(File::create(path); "couldn't open build report file \"{}\"", path.display());
+        ctry!(serde_json::to_writer_pretty(f, &self.report()); "couldn't write build report file \"{}\"", path.display());
+        Ok(())
+    }
+
+    #[cfg(not(feature = "serialization"))]
+    fn write_report<S: StatusBackend>(&self, status: &mut S) -> Result<()> {
+        if self.report_path.is_some() {
+            tt_warning!(
+                status,
+                "this build of Tectonic was built without the `serde` feature and cannot write build reports"
+            );
+        }
+
+        Ok(())
+    }
+
     fn write_files<S: StatusBackend>(
         &mut self,
         mut mf_dest_maybe: Option<&mut File>,
@@ -785,7 +1180,13 @@ impl ProcessingSession {
             }
 
             let sname = name.to_string_lossy();
-            let summ = self.events.0.get_mut(name).unwrap();
+
+            // Files carried over from a previous run by `reset()` that this
+            // run never touched are stale; don't write them out.
+            let summ = match self.events.0.get_mut(name) {
+                Some(s) => s,
+                None => continue,
+            };
 
             if !only_logs && (self.output_format == OutputFormat::Aux) {
                 // In this mode we're only writing the .aux file. I initially
@@ -794,6 +1195,10 @@ impl ProcessingSession {
                 // for my use case, which involves using Ninja to manage
                 // dependencies.
                 if !sname.ends_with(".aux") {
+                    self.report.skipped_files.push(SkippedFile {
+                        name: sname.into_owned(),
+                        reason: SkipReason::NotRequested,
+                    });
                     continue;
                 }
             } else if !self.keep_intermediates
@@ -803,21 +1208,38 @@ impl ProcessingSession {
                         .any(|ext| sname.ends_with(ext)))
             {
                 n_skipped_intermediates += 1;
+                self.report.skipped_files.push(SkippedFile {
+                    name: sname.into_owned(),
+                    reason: SkipReason::Intermediate,
+                });
                 continue;
             }
 
-            let is_logfile = sname.ends_with(".log") || sname.ends_with(".blg");
+            let is_logfile =
+                sname.ends_with(".log") || sname.ends_with(".blg") || sname.ends_with(".ilg");
 
             if is_logfile && !self.keep_logs {
+                self.report.skipped_files.push(SkippedFile {
+                    name: sname.into_owned(),
+                    reason: SkipReason::Log,
+                });
                 continue;
             }
 
             if !is_logfile && only_logs {
+                self.report.skipped_files.push(SkippedFile {
+                    name: sname.into_owned(),
+                    reason: SkipReason::NotRequested,
+                });
                 continue;
             }
 
             if contents.is_empty() {
                 status.note_highlighted("Not writing ", &sname, ": it would be empty.");
+                self.report.skipped_files.push(SkippedFile {
+                    name: sname.into_owned(),
+                    reason: SkipReason::Empty,
+                });
                 continue;
             }
 
@@ -831,6 +1253,9 @@ impl ProcessingSession {
             let mut f = File::create(&real_path)?;
             f.write_all(contents)?;
             summ.got_written_to_disk = true;
+            self.report
+                .written_files
+                .push(real_path.to_string_lossy().into_owned());
 
             if let Some(ref mut mf_dest) = mf_dest_maybe {
                 // Maybe it'd be better to have this just be a warning? But if
@@ -861,14 +1286,16 @@ impl ProcessingSession {
 
         let mut rerun_result = if bibtex_first {
             self.bibtex_pass(status)?;
-            Some(String::new())
+            Some("bibtex was run".to_owned())
         } else {
             self.tex_pass(None, status)?;
 
             if self.use_bibtex() {
                 self.bibtex_pass(status)?;
-                Some(String::new())
+                Some("bibtex was run".to_owned())
             } else {
+                self.biber_pass(status)?;
+                self.makeindex_pass(status)?;
                 self.rerun_needed(status)
             }
         };
@@ -885,13 +1312,7 @@ impl ProcessingSession {
                 "I was told to".to_owned()
             } else {
                 match rerun_result {
-                    Some(ref s) => {
-                        if s == "" {
-                            "bibtex was run".to_owned()
-                        } else {
-                            format!("\"{}\" changed", s)
-                        }
-                    }
+                    Some(ref s) => s.clone(),
                     None => {
                         break;
                     }
@@ -903,12 +1324,22 @@ impl ProcessingSession {
             // e.g., that bibtex wrote out the .bbl file, since that way we
             // can later know that it's OK to delete. I am not super confident
             // that the access_pattern data can just be left as-is when we do
-            // this, but, uh, so far it seems to work.
-            for summ in self.events.0.values_mut() {
+            // this, but, uh, so far it seems to work. We do remember what
+            // the files that were written looked like going into the new
+            // pass, so that `rerun_needed` can tell if it read stale data.
+            self.tex_prior_writes.clear();
+
+            for (name, summ) in self.events.0.iter_mut() {
                 summ.read_digest = None;
+
+                if let Some(d) = summ.write_digest {
+                    self.tex_prior_writes.insert(name.clone(), d);
+                }
             }
 
             self.tex_pass(Some(&rerun_explanation), status)?;
+            self.biber_pass(status)?;
+            self.makeindex_pass(status)?;
 
             if !reruns_fixed {
                 rerun_result = self.rerun_needed(status);
@@ -926,10 +1357,14 @@ impl ProcessingSession {
 
         // And finally, xdvipdfmx or spx2html. Maybe.
 
-        if let OutputFormat::Pdf = self.output_format {
-            self.xdvipdfmx_pass(status)?;
-        } else if let OutputFormat::Html = self.output_format {
-            self.spx2html_pass(status)?;
+        match self.output_format {
+            OutputFormat::Pdf | OutputFormat::PdfA => {
+                self.xdvipdfmx_pass(status)?;
+            }
+            OutputFormat::Html => {
+                self.spx2html_pass(status)?;
+            }
+            _ => {}
         }
 
         Ok(0)
@@ -951,6 +1386,139 @@ impl ProcessingSession {
             .unwrap_or(false)
     }
 
+    /// Get the names of the data sources listed in the `.bcf` file that
+    /// `biblatex` writes for `biber`, if there is one.
+    fn biber_datasources(&self) -> Option<Vec<String>> {
+        const OPEN: &str = "<bcf:datasource";
+        const CLOSE: &str = "</bcf:datasource>";
+
+        let files = self.io.mem.files.borrow();
+        let data = files.get(&self.tex_bcf_path)?;
+        let text = String::from_utf8_lossy(data);
+        let mut sources = Vec::new();
+        let mut rest = &text[..];
+
+        while let Some(start) = rest.find(OPEN) {
+            rest = &rest[start + OPEN.len()..];
+
+            let body_start = match rest.find('>') {
+                Some(i) => i + 1,
+                None => break,
+            };
+            let body_end = match rest.find(CLOSE) {
+                Some(i) => i,
+                None => break,
+            };
+
+            if body_start <= body_end {
+                sources.push(rest[body_start..body_end].trim().to_owned());
+            }
+
+            rest = &rest[body_end..];
+        }
+
+        Some(sources)
+    }
+
+    /// Run `biber`, if the document uses `biblatex` and the `.bcf` file has
+    /// changed since the last time we did.
+    ///
+    /// Unlike BibTeX, `biber` is an external program, so we run it through
+    /// an `ExternalToolEngine`. Its `.bbl` output is read by the next TeX
+    /// pass, so `rerun_needed` will notice if it changed.
+    fn biber_pass<S: StatusBackend>(&mut self, status: &mut S) -> Result<i32> {
+        let bcf_digest = match self.events.0.get(&self.tex_bcf_path) {
+            Some(summ) => summ.write_digest,
+            None => return Ok(0),
+        };
+
+        if bcf_digest.is_none() || bcf_digest == self.biber_bcf_digest {
+            return Ok(0);
+        }
+
+        let sources = match self.biber_datasources() {
+            Some(s) => s,
+            None => return Ok(0),
+        };
+
+        let bcf = self.tex_bcf_path.to_string_lossy().into_owned();
+        let stem = &bcf[..bcf.len() - ".bcf".len()];
+        let bbl = format!("{}.bbl", stem);
+        let blg = format!("{}.blg", stem);
+
+        let mut inputs = vec![&bcf[..]];
+        inputs.extend(sources.iter().map(|s| &s[..]));
+
+        let result = {
+            let mut stack = self.io.as_stack();
+            status.note_highlighted("Running ", &self.biber_program, " ...");
+            ExternalToolEngine::new(&self.biber_program)
+                .arg(stem)
+                .process(&mut stack, &mut self.events, status, &inputs, &[&bbl, &blg])
+        };
+
+        self.report
+            .record_pass("biber", None, result.as_ref().ok().cloned());
+        self.biber_bcf_digest = bcf_digest;
+
+        // The engine has already shown the program's output if it failed.
+        if let Err(e) = result {
+            return Err(e.chain_err(|| ErrorKind::EngineError("biber")));
+        }
+
+        Ok(0)
+    }
+
+    /// Generate the index, if TeX wrote out index entries and they've changed
+    /// since the last time we did. As with `biber`, the next TeX pass reads
+    /// the result, so `rerun_needed` takes care of the rest.
+    fn makeindex_pass<S: StatusBackend>(&mut self, status: &mut S) -> Result<i32> {
+        let idx_digest = match self.events.0.get(&self.tex_idx_path) {
+            Some(summ) => summ.write_digest,
+            None => return Ok(0),
+        };
+
+        if idx_digest.is_none() || idx_digest == self.makeindex_idx_digest {
+            return Ok(0);
+        }
+
+        let result = {
+            let mut stack = self.io.as_stack();
+            let mut engine = MakeindexEngine::new();
+            status.note_highlighted("Running ", "makeindex", " ...");
+
+            if let Some(ref style) = self.index_style {
+                engine.style(style);
+            }
+
+            engine.process(
+                &mut stack,
+                &mut self.events,
+                status,
+                &self.tex_idx_path.to_string_lossy(),
+            )
+        };
+
+        self.report
+            .record_pass("makeindex", None, result.as_ref().ok().cloned());
+        self.makeindex_idx_digest = idx_digest;
+
+        match result {
+            Ok(TexResult::Spotless) => {}
+            Ok(_) => {
+                tt_note!(
+                    status,
+                    "warnings were issued by makeindex; use --keep-logs for details."
+                );
+            }
+            Err(e) => {
+                return Err(e.chain_err(|| ErrorKind::EngineError("makeindex")));
+            }
+        }
+
+        Ok(0)
+    }
+
     /// Use the TeX engine to generate a format file.
     fn make_format_pass<S: StatusBackend>(&mut self, status: &mut S) -> Result<i32> {
         if self.io.bundle.is_none() {
@@ -988,6 +1556,9 @@ impl ProcessingSession {
                 .process(&mut stack, &mut self.events, status, "UNUSED.fmt", "texput")
         };
 
+        self.report
+            .record_pass("TeX (format)", None, result.as_ref().ok().cloned());
+
         match result {
             Ok(TexResult::Spotless) => {}
             Ok(TexResult::Warnings) => {
@@ -1043,30 +1614,47 @@ impl ProcessingSession {
                 status.note_highlighted("Running ", "TeX", " ...");
             }
 
-            TexEngine::new()
+            let mut engine = TexEngine::new();
+            engine
                 .halt_on_error_mode(true)
                 .initex_mode(self.output_format == OutputFormat::Format)
                 .synctex(self.synctex_enabled)
-                .semantic_pagination(self.output_format == OutputFormat::Html)
-                .process(
-                    &mut stack,
-                    &mut self.events,
-                    status,
-                    &self.format_name,
-                    &self.primary_input_tex_path,
-                )
+                .semantic_pagination(self.output_format == OutputFormat::Html);
+
+            if let Some(date) = self.build_date {
+                engine.build_date(date);
+            }
+
+            engine.process(
+                &mut stack,
+                &mut self.events,
+                status,
+                &self.format_name,
+                &self.primary_input_tex_path,
+            )
         };
 
+        self.report
+            .record_pass("TeX", rerun_explanation, result.as_ref().ok().cloned());
+        self.report.diagnostics = match self.io.mem.files.borrow().get(&self.tex_log_path) {
+            Some(data) => tex_log::parse_log(data),
+            None => Vec::new(),
+        };
+
+        // If we found specific problems in the log, those get reported at the
+        // end of the run and there's no need to point people at the log.
+        let point_at_log = !self.noted_tex_warnings && self.report.diagnostics.is_empty();
+
         match result {
             Ok(TexResult::Spotless) => {}
             Ok(TexResult::Warnings) => {
-                if !self.noted_tex_warnings {
+                if point_at_log {
                     tt_note!(status, "warnings were issued by the TeX engine; use --print and/or --keep-logs for details.");
                     self.noted_tex_warnings = true;
                 }
             }
             Ok(TexResult::Errors) => {
-                if !self.noted_tex_warnings {
+                if point_at_log {
                     // Weakness: if a first pass produces warnings and a
                     // second pass produces ignored errors, we won't say so.
                     tt_warning!(
@@ -1098,6 +1686,9 @@ impl ProcessingSession {
             )
         };
 
+        self.report
+            .record_pass("BibTeX", None, result.as_ref().ok().cloned());
+
         match result {
             Ok(TexResult::Spotless) => {}
             Ok(TexResult::Warnings) => {
@@ -1122,9 +1713,21 @@ impl ProcessingSession {
     }
 
     fn xdvipdfmx_pass<S: StatusBackend>(&mut self, status: &mut S) -> Result<i32> {
+        self.report.record_pass("xdvipdfmx", None, None);
+
         {
             let mut stack = self.io.as_stack();
-            let mut engine = XdvipdfmxEngine::new();
+            let mut engine = XdvipdfmxEngine::new()
+                .with_deterministic_tags(self.reproducible)
+                .with_pdfa(self.output_format == OutputFormat::PdfA)
+                .with_page_ranges(&self.page_ranges)
+                .with_pdf_options(&self.pdf_options)
+                .with_encryption(self.pdf_encryption.as_ref());
+
+            if let Some(date) = self.build_date {
+                engine = engine.with_build_date(date);
+            }
+
             status.note_highlighted("Running ", "xdvipdfmx", " ...");
             engine.process(
                 &mut stack,
@@ -1140,6 +1743,8 @@ impl ProcessingSession {
     }
 
     fn spx2html_pass<S: StatusBackend>(&mut self, status: &mut S) -> Result<i32> {
+        self.report.record_pass("spx2html", None, None);
+
         {
             let mut stack = self.io.as_stack();
             let mut engine = Spx2HtmlEngine::new();
@@ -1156,6 +1761,61 @@ impl ProcessingSession {
         Ok(0)
     }
 
+    /// Get the paths of the filesystem files that were read during the last
+    /// run of this session.
+    ///
+    /// This includes the primary input, if it lives on disk, and every file
+    /// that the engines opened with an origin of [`InputOrigin::Filesystem`].
+    /// Files that the session wrote to disk itself are left out, since
+    /// otherwise each build would look like a change to the next one. This
+    /// is the set of files that a "watch" loop should keep an eye on.
+    pub fn filesystem_inputs(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+
+        if let Some(ref pip) = self.primary_input_path {
+            paths.push(pip.clone());
+        }
+
+        for (name, info) in &self.events.0 {
+            if info.input_origin != InputOrigin::Filesystem || info.got_written_to_disk {
+                continue;
+            }
+
+            paths.push(self.filesystem_root.join(name));
+        }
+
+        paths
+    }
+
+    /// Prepare this session to be [`run`] again.
+    ///
+    /// The I/O setup, including the bundle and the format cache, is kept
+    /// as-is, so that subsequent runs don't pay for opening them again.
+    /// Circular intermediate files like the `.aux` file are kept in the
+    /// memory layer, so that the next run can often converge in a single TeX
+    /// pass. Everything else that was produced by the previous run is
+    /// discarded, as are the recorded I/O events.
+    ///
+    /// [`run`]: ProcessingSession::run
+    pub fn reset(&mut self) {
+        let events = &self.events;
+
+        self.io.mem.files.borrow_mut().retain(|name, _| {
+            events
+                .0
+                .get(name)
+                .map(|summ| summ.access_pattern == AccessPattern::ReadThenWritten)
+                .unwrap_or(false)
+        });
+
+        self.events = IoEvents::new();
+        self.tex_prior_writes.clear();
+        self.biber_bcf_digest = None;
+        self.makeindex_idx_digest = None;
+        self.report = BuildReport::default();
+        self.noted_tex_warnings = false;
+    }
+
     /// Consume this session and return the current set of files in memory.
     ///
     /// This convenience function tries to help with the annoyances of getting
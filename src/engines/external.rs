@@ -0,0 +1,193 @@
+// src/engines/external.rs -- running external programs as engine passes
+// Copyright 2019 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Running programs that aren't built into Tectonic, such as `biber`, as if
+//! they were engines.
+//!
+//! The external program can't see Tectonic's I/O stack, so we copy the files
+//! that it needs into a temporary directory, run it there, and copy the
+//! files that it produces back out. Both directions go through the I/O stack
+//! and are reported to the `IoEventBackend`, so that the driver's rerun and
+//! output logic works just as it does for the built-in engines.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use super::tex::TexResult;
+use super::IoEventBackend;
+use crate::errors::{ErrorKind, Result};
+use crate::io::IoStack;
+use crate::status::StatusBackend;
+use crate::{ctry, tt_error, tt_warning};
+
+pub struct ExternalToolEngine {
+    program: String,
+    args: Vec<String>,
+}
+
+impl ExternalToolEngine {
+    pub fn new(program: &str) -> ExternalToolEngine {
+        ExternalToolEngine {
+            program: program.to_owned(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Add an argument to the program's command line.
+    pub fn arg(&mut self, arg: &str) -> &mut Self {
+        self.args.push(arg.to_owned());
+        self
+    }
+
+    /// Run the program.
+    ///
+    /// The files named in `inputs` are looked up in `io` and made available
+    /// to the program in its working directory; ones that can't be found are
+    /// just left out. Once the program has finished, any of the files named
+    /// in `outputs` that it created are written to `io`. Names that would
+    /// point outside of the working directory, such as absolute paths or ones
+    /// containing `..`, are skipped with a warning.
+    pub fn process(
+        &mut self,
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+        inputs: &[&str],
+        outputs: &[&str],
+    ) -> Result<TexResult> {
+        let workdir = ctry!(tempfile::Builder::new().prefix("tectonic_").tempdir();
+                            "failed to create a temporary directory for {}", self.program);
+
+        for name in inputs {
+            let path = match self.path_in(workdir.path(), name, status) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let data = match super::read_whole_input(io, events, status, name)? {
+                Some(d) => d,
+                None => continue,
+            };
+
+            if let Some(parent) = path.parent() {
+                ctry!(fs::create_dir_all(parent); "failed to create directory \"{}\"", parent.display());
+            }
+            ctry!(File::create(&path).and_then(|mut f| f.write_all(&data));
+                  "failed to write temporary file \"{}\"", path.display());
+        }
+
+        let output = ctry!(Command::new(&self.program)
+                           .args(&self.args)
+                           .current_dir(workdir.path())
+                           .output();
+                           "failed to run the external program \"{}\"", self.program);
+
+        for name in outputs {
+            let path = match self.path_in(workdir.path(), name, status) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let data = match fs::read(&path) {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            if !super::write_whole_output(io, events, name, &data)? {
+                tt_warning!(
+                    status,
+                    "no place to put the output file \"{}\" of {}",
+                    name,
+                    self.program
+                );
+            }
+        }
+
+        if !output.status.success() {
+            tt_error!(
+                status,
+                "{} failed; its output follows:\n{}{}",
+                self.program,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(
+                ErrorKind::Msg(format!("{} exited with {}", self.program, output.status)).into(),
+            );
+        }
+
+        Ok(TexResult::Spotless)
+    }
+
+    /// Get the path of the file `name` inside the directory `dir`.
+    ///
+    /// The names come from files that the document controls, like the
+    /// `.bcf` file, so we only accept plain relative paths.
+    fn path_in(&self, dir: &Path, name: &str, status: &mut dyn StatusBackend) -> Option<PathBuf> {
+        let rel = Path::new(name);
+        let plain = rel.components().all(|c| match c {
+            Component::Normal(_) | Component::CurDir => true,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => false,
+        });
+
+        if name.is_empty() || !plain {
+            tt_warning!(
+                status,
+                "not passing the file \"{}\" to {}: only plain relative paths are allowed",
+                name,
+                self.program
+            );
+            return None;
+        }
+
+        Some(dir.join(rel))
+    }
+}
+
+// The test runs a shell command as its external program.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::engines::NoopIoEventBackend;
+    use crate::io::memory::MemoryIo;
+    use crate::status::NoopStatusBackend;
+    use std::env;
+    use std::ffi::{OsStr, OsString};
+
+    #[test]
+    fn stages_inputs_and_collects_outputs() {
+        let escaped = format!("tectonic-external-test-{}.txt", std::process::id());
+
+        let mut mem = MemoryIo::new(false);
+        mem.create_entry(OsStr::new("in.txt"), b"hello\n".to_vec());
+        mem.create_entry(OsStr::new(&format!("../{}", escaped)), b"oops\n".to_vec());
+
+        let mut status = NoopStatusBackend::new();
+        let mut events = NoopIoEventBackend::new();
+
+        let result = {
+            let mut stack = IoStack::new(vec![&mut mem]);
+            ExternalToolEngine::new("sh")
+                .arg("-c")
+                .arg("cp in.txt out.txt")
+                .process(
+                    &mut stack,
+                    &mut events,
+                    &mut status,
+                    &["in.txt", &format!("../{}", escaped)],
+                    &["out.txt", "/etc/passwd", "../in.txt"],
+                )
+        };
+
+        assert_eq!(result.unwrap(), TexResult::Spotless);
+        assert!(!env::temp_dir().join(&escaped).exists());
+
+        let files = mem.files.borrow();
+        assert_eq!(files[&OsString::from("out.txt")], b"hello\n");
+        assert!(!files.contains_key(OsStr::new("/etc/passwd")));
+        assert!(!files.contains_key(OsStr::new("../in.txt")));
+    }
+}
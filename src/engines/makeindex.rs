@@ -0,0 +1,944 @@
+// src/engines/makeindex.rs -- a makeindex-compatible index processor
+// Copyright 2019 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A reimplementation of the core of `makeindex`.
+//!
+//! This turns the `\indexentry` commands that LaTeX writes into a `.idx` file
+//! into a sorted `.ind` file ready to be `\input` by `\printindex`. It
+//! understands the usual key syntax (`sort@display`, `!` for subentries, `|`
+//! for page encapsulators and `|(`/`|)` for explicit page ranges, `"` for
+//! quoting) and the `.ist` style file parameters that control it. Sorting is
+//! simpler than the original: keys are compared case-insensitively, with
+//! symbols before numbers before letters.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::tex::TexResult;
+use super::IoEventBackend;
+use crate::errors::Result;
+use crate::io::IoStack;
+use crate::status::StatusBackend;
+use crate::{errmsg, tt_warning};
+
+#[derive(Default)]
+pub struct MakeindexEngine {
+    style: Option<String>,
+}
+
+impl MakeindexEngine {
+    pub fn new() -> MakeindexEngine {
+        Default::default()
+    }
+
+    /// Use the named `.ist` style file, rather than the default style.
+    pub fn style(&mut self, name: &str) -> &mut Self {
+        self.style = Some(name.to_owned());
+        self
+    }
+
+    /// Process the index file `idx`. The output is written to a file with the
+    /// same stem and the extension `.ind`, and a transcript to one with the
+    /// extension `.ilg`.
+    pub fn process(
+        &mut self,
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+        idx: &str,
+    ) -> Result<TexResult> {
+        let stem = idx.trim_end_matches(".idx");
+        let ind = format!("{}.ind", stem);
+        let ilg = format!("{}.ilg", stem);
+        let mut transcript = Transcript::default();
+        let mut style = Style::default();
+
+        if let Some(ref name) = self.style {
+            let data = match super::read_whole_input(io, events, status, name)? {
+                Some(d) => d,
+                None => return Err(errmsg!("index style file \"{}\" not found", name)),
+            };
+
+            writeln!(transcript.text, "Scanning style file {}...", name).unwrap();
+            style.parse(&String::from_utf8_lossy(&data), &mut transcript);
+        }
+
+        let data = match super::read_whole_input(io, events, status, idx)? {
+            Some(d) => d,
+            None => return Err(errmsg!("index file \"{}\" not found", idx)),
+        };
+
+        writeln!(transcript.text, "Scanning input file {}...", idx).unwrap();
+        let output = make_index(&String::from_utf8_lossy(&data), &style, &mut transcript);
+        writeln!(transcript.text, "Output written in {}.", ind).unwrap();
+
+        if !super::write_whole_output(io, events, &ind, output.as_bytes())? {
+            tt_warning!(status, "no place to put the index file \"{}\"", ind);
+        }
+
+        super::write_whole_output(io, events, &ilg, transcript.text.as_bytes())?;
+
+        if transcript.n_warnings > 0 {
+            Ok(TexResult::Warnings)
+        } else {
+            Ok(TexResult::Spotless)
+        }
+    }
+}
+
+/// The log of a makeindex run, destined for the `.ilg` file.
+#[derive(Debug, Default)]
+struct Transcript {
+    text: String,
+    n_warnings: usize,
+}
+
+impl Transcript {
+    fn warn(&mut self, msg: &str) {
+        self.n_warnings += 1;
+        writeln!(self.text, "## {}", msg).unwrap();
+    }
+}
+
+/// The parameters that can be set in a `.ist` file, with the same names and
+/// defaults as in `makeindex`.
+#[derive(Clone, Debug)]
+struct Style {
+    // Input.
+    keyword: String,
+    arg_open: char,
+    arg_close: char,
+    actual: char,
+    encap: char,
+    level: char,
+    quote: char,
+    escape: char,
+    range_open: char,
+    range_close: char,
+
+    // Output.
+    preamble: String,
+    postamble: String,
+    group_skip: String,
+    headings_flag: i64,
+    heading_prefix: String,
+    heading_suffix: String,
+    symhead_positive: String,
+    symhead_negative: String,
+    numhead_positive: String,
+    numhead_negative: String,
+    item_0: String,
+    item_1: String,
+    item_2: String,
+    item_01: String,
+    item_x1: String,
+    item_12: String,
+    item_x2: String,
+    delim_0: String,
+    delim_1: String,
+    delim_2: String,
+    delim_n: String,
+    delim_r: String,
+    delim_t: String,
+    encap_prefix: String,
+    encap_infix: String,
+    encap_suffix: String,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            keyword: "\\indexentry".to_owned(),
+            arg_open: '{',
+            arg_close: '}',
+            actual: '@',
+            encap: '|',
+            level: '!',
+            quote: '"',
+            escape: '\\',
+            range_open: '(',
+            range_close: ')',
+
+            preamble: "\\begin{theindex}\n".to_owned(),
+            postamble: "\n\n\\end{theindex}\n".to_owned(),
+            group_skip: "\n\n  \\indexspace\n".to_owned(),
+            headings_flag: 0,
+            heading_prefix: String::new(),
+            heading_suffix: String::new(),
+            symhead_positive: "Symbols".to_owned(),
+            symhead_negative: "symbols".to_owned(),
+            numhead_positive: "Numbers".to_owned(),
+            numhead_negative: "numbers".to_owned(),
+            item_0: "\n  \\item ".to_owned(),
+            item_1: "\n    \\subitem ".to_owned(),
+            item_2: "\n      \\subsubitem ".to_owned(),
+            item_01: "\n    \\subitem ".to_owned(),
+            item_x1: "\n    \\subitem ".to_owned(),
+            item_12: "\n      \\subsubitem ".to_owned(),
+            item_x2: "\n      \\subsubitem ".to_owned(),
+            delim_0: ", ".to_owned(),
+            delim_1: ", ".to_owned(),
+            delim_2: ", ".to_owned(),
+            delim_n: ", ".to_owned(),
+            delim_r: "--".to_owned(),
+            delim_t: String::new(),
+            encap_prefix: "\\".to_owned(),
+            encap_infix: "{".to_owned(),
+            encap_suffix: "}".to_owned(),
+        }
+    }
+}
+
+/// A value in a style file.
+#[derive(Clone, Debug, PartialEq)]
+enum StyleValue {
+    Str(String),
+    Char(char),
+    Number(i64),
+}
+
+impl Style {
+    /// Update this style from the text of a `.ist` file.
+    fn parse(&mut self, text: &str, transcript: &mut Transcript) {
+        let mut chars = text.chars().peekable();
+        let mut n_set = 0;
+        let mut n_ignored = 0;
+
+        loop {
+            // Skip whitespace and comments.
+            while let Some(&c) = chars.peek() {
+                if c == '%' {
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                } else if c.is_whitespace() {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            let mut key = String::new();
+
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                key.push(c);
+                chars.next();
+            }
+
+            if key.is_empty() {
+                break;
+            }
+
+            while let Some(&c) = chars.peek() {
+                if !c.is_whitespace() {
+                    break;
+                }
+                chars.next();
+            }
+
+            let value = match chars.peek() {
+                Some('"') => {
+                    chars.next();
+                    let mut s = String::new();
+
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '"' => break,
+                            '\\' => match chars.next() {
+                                Some('n') => s.push('\n'),
+                                Some('t') => s.push('\t'),
+                                Some(c) => s.push(c),
+                                None => break,
+                            },
+                            c => s.push(c),
+                        }
+                    }
+
+                    Some(StyleValue::Str(s))
+                }
+
+                Some('\'') => {
+                    chars.next();
+                    let c = match chars.next() {
+                        Some('\\') => chars.next(),
+                        c => c,
+                    };
+
+                    if chars.peek() == Some(&'\'') {
+                        chars.next();
+                    }
+
+                    c.map(StyleValue::Char)
+                }
+
+                _ => {
+                    let mut s = String::new();
+
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() {
+                            break;
+                        }
+                        s.push(c);
+                        chars.next();
+                    }
+
+                    s.parse().ok().map(StyleValue::Number)
+                }
+            };
+
+            let ok = match value {
+                Some(v) => self.set(&key, v),
+                None => false,
+            };
+
+            if ok {
+                n_set += 1;
+            } else {
+                n_ignored += 1;
+                transcript.warn(&format!("Ignoring bad style parameter \"{}\".", key));
+            }
+        }
+
+        writeln!(
+            transcript.text,
+            "done ({} attributes redefined, {} ignored).",
+            n_set, n_ignored
+        )
+        .unwrap();
+    }
+
+    fn set(&mut self, key: &str, value: StyleValue) -> bool {
+        match (key, value) {
+            ("keyword", StyleValue::Str(s)) => self.keyword = s,
+            ("arg_open", StyleValue::Char(c)) => self.arg_open = c,
+            ("arg_close", StyleValue::Char(c)) => self.arg_close = c,
+            ("actual", StyleValue::Char(c)) => self.actual = c,
+            ("encap", StyleValue::Char(c)) => self.encap = c,
+            ("level", StyleValue::Char(c)) => self.level = c,
+            ("quote", StyleValue::Char(c)) => self.quote = c,
+            ("escape", StyleValue::Char(c)) => self.escape = c,
+            ("range_open", StyleValue::Char(c)) => self.range_open = c,
+            ("range_close", StyleValue::Char(c)) => self.range_close = c,
+            ("preamble", StyleValue::Str(s)) => self.preamble = s,
+            ("postamble", StyleValue::Str(s)) => self.postamble = s,
+            ("group_skip", StyleValue::Str(s)) => self.group_skip = s,
+            ("headings_flag", StyleValue::Number(n)) => self.headings_flag = n,
+            ("lethead_flag", StyleValue::Number(n)) => self.headings_flag = n,
+            ("heading_prefix", StyleValue::Str(s)) => self.heading_prefix = s,
+            ("lethead_prefix", StyleValue::Str(s)) => self.heading_prefix = s,
+            ("heading_suffix", StyleValue::Str(s)) => self.heading_suffix = s,
+            ("lethead_suffix", StyleValue::Str(s)) => self.heading_suffix = s,
+            ("symhead_positive", StyleValue::Str(s)) => self.symhead_positive = s,
+            ("symhead_negative", StyleValue::Str(s)) => self.symhead_negative = s,
+            ("numhead_positive", StyleValue::Str(s)) => self.numhead_positive = s,
+            ("numhead_negative", StyleValue::Str(s)) => self.numhead_negative = s,
+            ("item_0", StyleValue::Str(s)) => self.item_0 = s,
+            ("item_1", StyleValue::Str(s)) => self.item_1 = s,
+            ("item_2", StyleValue::Str(s)) => self.item_2 = s,
+            ("item_01", StyleValue::Str(s)) => self.item_01 = s,
+            ("item_x1", StyleValue::Str(s)) => self.item_x1 = s,
+            ("item_12", StyleValue::Str(s)) => self.item_12 = s,
+            ("item_x2", StyleValue::Str(s)) => self.item_x2 = s,
+            ("delim_0", StyleValue::Str(s)) => self.delim_0 = s,
+            ("delim_1", StyleValue::Str(s)) => self.delim_1 = s,
+            ("delim_2", StyleValue::Str(s)) => self.delim_2 = s,
+            ("delim_n", StyleValue::Str(s)) => self.delim_n = s,
+            ("delim_r", StyleValue::Str(s)) => self.delim_r = s,
+            ("delim_t", StyleValue::Str(s)) => self.delim_t = s,
+            ("encap_prefix", StyleValue::Str(s)) => self.encap_prefix = s,
+            ("encap_infix", StyleValue::Str(s)) => self.encap_infix = s,
+            ("encap_suffix", StyleValue::Str(s)) => self.encap_suffix = s,
+            // Parameters that only affect line wrapping and page setup, which
+            // we don't do, are accepted and ignored.
+            ("line_max", StyleValue::Number(_))
+            | ("indent_length", StyleValue::Number(_))
+            | ("indent_space", StyleValue::Str(_))
+            | ("setpage_prefix", StyleValue::Str(_))
+            | ("setpage_suffix", StyleValue::Str(_))
+            | ("page_compositor", StyleValue::Str(_))
+            | ("page_precedence", StyleValue::Str(_)) => {}
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+/// Where a page reference sits with respect to an explicit page range.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RangeMark {
+    None,
+    Open,
+    Close,
+}
+
+/// A single page reference of an index entry.
+#[derive(Clone, Debug)]
+struct PageRef {
+    page: String,
+    encap: Option<String>,
+    range: RangeMark,
+}
+
+/// One level of an index key: what it's sorted by and what's printed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct KeyPart {
+    sort: String,
+    display: String,
+}
+
+impl Ord for KeyPart {
+    fn cmp(&self, other: &KeyPart) -> Ordering {
+        sort_class(&self.sort)
+            .cmp(&sort_class(&other.sort))
+            .then_with(|| self.sort.to_lowercase().cmp(&other.sort.to_lowercase()))
+            .then_with(|| self.sort.cmp(&other.sort))
+            .then_with(|| self.display.cmp(&other.display))
+    }
+}
+
+impl PartialOrd for KeyPart {
+    fn partial_cmp(&self, other: &KeyPart) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Symbols sort before numbers, which sort before letters.
+fn sort_class(key: &str) -> u8 {
+    match key.chars().next() {
+        Some(c) if c.is_alphabetic() => 2,
+        Some(c) if c.is_ascii_digit() => 1,
+        _ => 0,
+    }
+}
+
+/// Parse the key argument of an `\indexentry` into its levels and page
+/// encapsulator.
+fn parse_key(arg: &str, style: &Style) -> Option<(Vec<KeyPart>, Option<String>, RangeMark)> {
+    let mut levels = Vec::new();
+    let mut sort = String::new();
+    let mut display = String::new();
+    let mut seen_actual = false;
+    let mut encap = None;
+    let mut chars = arg.chars().peekable();
+    let mut prev = None;
+
+    while let Some(c) = chars.next() {
+        let escaped = prev == Some(style.escape);
+        prev = Some(c);
+
+        if c == style.quote && !escaped {
+            // The quoted character is taken literally, and the quote itself
+            // disappears.
+            if let Some(q) = chars.next() {
+                if seen_actual {
+                    display.push(q);
+                } else {
+                    sort.push(q);
+                }
+                prev = None;
+            }
+        } else if c == style.actual && !escaped {
+            seen_actual = true;
+        } else if c == style.level && !escaped {
+            levels.push(finish_part(&mut sort, &mut display, seen_actual));
+            seen_actual = false;
+        } else if c == style.encap && !escaped {
+            encap = Some(chars.by_ref().collect::<String>());
+            break;
+        } else if seen_actual {
+            display.push(c);
+        } else {
+            sort.push(c);
+        }
+    }
+
+    levels.push(finish_part(&mut sort, &mut display, seen_actual));
+
+    if levels.len() > 3 || levels.iter().any(|p| p.sort.is_empty()) {
+        return None;
+    }
+
+    let (encap, range) = match encap {
+        None => (None, RangeMark::None),
+        Some(e) => {
+            let (range, rest) = if e.starts_with(style.range_open) {
+                (RangeMark::Open, &e[style.range_open.len_utf8()..])
+            } else if e.starts_with(style.range_close) {
+                (RangeMark::Close, &e[style.range_close.len_utf8()..])
+            } else {
+                (RangeMark::None, &e[..])
+            };
+
+            if rest.is_empty() {
+                (None, range)
+            } else {
+                (Some(rest.to_owned()), range)
+            }
+        }
+    };
+
+    Some((levels, encap, range))
+}
+
+fn finish_part(sort: &mut String, display: &mut String, seen_actual: bool) -> KeyPart {
+    let sort = std::mem::take(sort).trim().to_owned();
+    let display = std::mem::take(display).trim().to_owned();
+
+    if seen_actual {
+        KeyPart { sort, display }
+    } else {
+        KeyPart {
+            display: sort.clone(),
+            sort,
+        }
+    }
+}
+
+/// Read a balanced argument starting just after its opening delimiter.
+/// Returns the argument and the remaining text.
+fn read_arg<'a>(text: &'a str, style: &Style) -> Option<(&'a str, &'a str)> {
+    let mut depth = 0;
+    let mut prev = None;
+
+    for (i, c) in text.char_indices() {
+        let escaped = prev == Some(style.escape) || prev == Some(style.quote);
+        prev = if escaped { None } else { Some(c) };
+
+        if escaped {
+            continue;
+        }
+
+        if c == style.arg_open {
+            depth += 1;
+        } else if c == style.arg_close {
+            if depth == 0 {
+                return Some((&text[..i], &text[i + c.len_utf8()..]));
+            }
+            depth -= 1;
+        }
+    }
+
+    None
+}
+
+/// The sort key of a page number: lowercase roman numerals come first, then
+/// arabic numbers, then anything else.
+fn page_key(page: &str) -> (u8, u64, String) {
+    if let Ok(n) = page.parse::<u64>() {
+        return (1, n, String::new());
+    }
+
+    if let Some(n) = roman_value(page) {
+        return (0, n, String::new());
+    }
+
+    (2, 0, page.to_owned())
+}
+
+fn roman_value(s: &str) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+
+    let mut total = 0;
+    let mut prev = 0;
+
+    for c in s.chars().rev() {
+        let v = match c {
+            'i' => 1,
+            'v' => 5,
+            'x' => 10,
+            'l' => 50,
+            'c' => 100,
+            'd' => 500,
+            'm' => 1000,
+            _ => return None,
+        };
+
+        if v < prev {
+            total -= v;
+        } else {
+            total += v;
+            prev = v;
+        }
+    }
+
+    Some(total)
+}
+
+fn encapsulate(out: &mut String, text: &str, encap: &Option<String>, style: &Style) {
+    match encap {
+        Some(e) => {
+            out.push_str(&style.encap_prefix);
+            out.push_str(e);
+            out.push_str(&style.encap_infix);
+            out.push_str(text);
+            out.push_str(&style.encap_suffix);
+        }
+        None => out.push_str(text),
+    }
+}
+
+/// Format the page list of one index entry.
+fn format_pages(refs: &[PageRef], style: &Style, transcript: &mut Transcript) -> String {
+    let mut refs = refs.to_vec();
+    refs.sort_by_key(|r| page_key(&r.page));
+
+    // First, turn explicit ranges and runs of consecutive pages into
+    // (start, end, encap) items.
+
+    let mut items: Vec<(String, Option<String>, Option<String>)> = Vec::new();
+    let mut open: Option<(String, Option<String>)> = None;
+    let mut run: Vec<&PageRef> = Vec::new();
+
+    fn flush_run(
+        run: &mut Vec<&PageRef>,
+        items: &mut Vec<(String, Option<String>, Option<String>)>,
+    ) {
+        if run.len() >= 3 {
+            let first = run[0];
+            let last = run[run.len() - 1];
+            items.push((
+                first.page.clone(),
+                Some(last.page.clone()),
+                first.encap.clone(),
+            ));
+        } else {
+            for r in run.iter() {
+                items.push((r.page.clone(), None, r.encap.clone()));
+            }
+        }
+
+        run.clear();
+    }
+
+    for r in &refs {
+        match r.range {
+            RangeMark::Open => {
+                flush_run(&mut run, &mut items);
+
+                if open.is_none() {
+                    open = Some((r.page.clone(), r.encap.clone()));
+                }
+            }
+
+            RangeMark::Close => {
+                flush_run(&mut run, &mut items);
+
+                match open.take() {
+                    Some((start, encap)) => {
+                        let end = if start == r.page {
+                            None
+                        } else {
+                            Some(r.page.clone())
+                        };
+                        items.push((start, end, encap));
+                    }
+                    None => transcript.warn(&format!(
+                        "Unmatched range closing operator on page {}.",
+                        r.page
+                    )),
+                }
+            }
+
+            RangeMark::None => {
+                if open.is_some() {
+                    // Absorbed by the enclosing explicit range.
+                    continue;
+                }
+
+                if let Some(last) = run.last() {
+                    if last.page == r.page && last.encap == r.encap {
+                        continue;
+                    }
+
+                    let consecutive = match (last.page.parse::<u64>(), r.page.parse::<u64>()) {
+                        (Ok(a), Ok(b)) => b == a + 1 && last.encap == r.encap,
+                        _ => false,
+                    };
+
+                    if !consecutive {
+                        flush_run(&mut run, &mut items);
+                    }
+                }
+
+                run.push(r);
+            }
+        }
+    }
+
+    flush_run(&mut run, &mut items);
+
+    if let Some((start, encap)) = open {
+        transcript.warn(&format!(
+            "Unmatched range opening operator on page {}.",
+            start
+        ));
+        items.push((start, None, encap));
+    }
+
+    let mut out = String::new();
+
+    for (i, (start, end, encap)) in items.iter().enumerate() {
+        if i > 0 {
+            out.push_str(&style.delim_n);
+        }
+
+        let text = match end {
+            Some(e) => format!("{}{}{}", start, style.delim_r, e),
+            None => start.clone(),
+        };
+
+        encapsulate(&mut out, &text, encap, style);
+    }
+
+    out
+}
+
+/// Generate the contents of the `.ind` file from those of the `.idx` file.
+fn make_index(idx: &str, style: &Style, transcript: &mut Transcript) -> String {
+    let mut entries: BTreeMap<Vec<KeyPart>, Vec<PageRef>> = BTreeMap::new();
+    let mut n_accepted = 0;
+    let mut n_rejected = 0;
+    let mut rest = idx;
+
+    while let Some(pos) = rest.find(&style.keyword) {
+        rest = &rest[pos + style.keyword.len()..];
+
+        let parsed = rest
+            .trim_start()
+            .strip_prefix(style.arg_open)
+            .and_then(|r| read_arg(r, style))
+            .and_then(|(key, r)| {
+                let r = r.trim_start().strip_prefix(style.arg_open)?;
+                let (page, r) = read_arg(r, style)?;
+                Some((key, page, r))
+            });
+
+        let (key, page, remainder) = match parsed {
+            Some(p) => p,
+            None => {
+                n_rejected += 1;
+                transcript.warn("Malformed index entry.");
+                continue;
+            }
+        };
+
+        rest = remainder;
+
+        match parse_key(key, style) {
+            Some((levels, encap, range)) => {
+                n_accepted += 1;
+                entries.entry(levels).or_default().push(PageRef {
+                    page: page.trim().to_owned(),
+                    encap,
+                    range,
+                });
+            }
+            None => {
+                n_rejected += 1;
+                transcript.warn(&format!("Bad index key \"{}\".", key));
+            }
+        }
+    }
+
+    writeln!(
+        transcript.text,
+        "done ({} entries accepted, {} rejected).",
+        n_accepted, n_rejected
+    )
+    .unwrap();
+
+    // Now generate the output.
+
+    let mut out = style.preamble.clone();
+    let mut prev_levels: Vec<KeyPart> = Vec::new();
+    let mut prev_group = None;
+    // The depth of the last item written, and whether it had page numbers.
+    let mut last_item: Option<(usize, bool)> = None;
+
+    for (levels, refs) in &entries {
+        let group = group_of(&levels[0].sort);
+
+        if prev_group != Some(group) {
+            if prev_group.is_some() {
+                out.push_str(&style.group_skip);
+            }
+
+            if style.headings_flag != 0 {
+                out.push_str(&style.heading_prefix);
+                out.push_str(&group_heading(group, &levels[0].sort, style));
+                out.push_str(&style.heading_suffix);
+            }
+
+            prev_group = Some(group);
+            prev_levels.clear();
+        }
+
+        // Figure out which levels we need to write: the shared prefix with
+        // the previous entry has already been written.
+        let first_new = levels
+            .iter()
+            .zip(prev_levels.iter())
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(levels.len() - 1);
+
+        for depth in first_new..levels.len() {
+            let is_entry = depth == levels.len() - 1;
+
+            let item = match (depth, last_item) {
+                (0, _) => &style.item_0,
+                (1, Some((0, true))) => &style.item_01,
+                (1, Some((0, false))) => &style.item_x1,
+                (1, _) => &style.item_1,
+                (_, Some((1, true))) => &style.item_12,
+                (_, Some((1, false))) => &style.item_x2,
+                _ => &style.item_2,
+            };
+
+            out.push_str(item);
+            out.push_str(&levels[depth].display);
+
+            let has_pages = is_entry && !refs.is_empty();
+
+            if has_pages {
+                out.push_str(match depth {
+                    0 => &style.delim_0,
+                    1 => &style.delim_1,
+                    _ => &style.delim_2,
+                });
+                out.push_str(&format_pages(refs, style, transcript));
+                out.push_str(&style.delim_t);
+            }
+
+            last_item = Some((depth, has_pages));
+        }
+
+        prev_levels = levels.clone();
+    }
+
+    out.push_str(&style.postamble);
+    out
+}
+
+/// The kinds of groups that index entries are divided into.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Group {
+    Symbols,
+    Numbers,
+    Letter(char),
+}
+
+fn group_of(key: &str) -> Group {
+    match key.chars().next() {
+        Some(c) if c.is_alphabetic() => Group::Letter(c.to_lowercase().next().unwrap_or(c)),
+        Some(c) if c.is_ascii_digit() => Group::Numbers,
+        _ => Group::Symbols,
+    }
+}
+
+fn group_heading(group: Group, key: &str, style: &Style) -> String {
+    let positive = style.headings_flag > 0;
+
+    match group {
+        Group::Symbols if positive => style.symhead_positive.clone(),
+        Group::Symbols => style.symhead_negative.clone(),
+        Group::Numbers if positive => style.numhead_positive.clone(),
+        Group::Numbers => style.numhead_negative.clone(),
+        Group::Letter(_) => {
+            let c = key.chars().next().unwrap();
+
+            if positive {
+                c.to_uppercase().collect()
+            } else {
+                c.to_lowercase().collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(idx: &str, style: &Style) -> String {
+        let mut transcript = Transcript::default();
+        make_index(idx, style, &mut transcript)
+    }
+
+    #[test]
+    fn basic_index() {
+        let idx = r#"\indexentry{beta}{3}
+\indexentry{alpha}{2}
+\indexentry{Alpha@\textit{Alpha}}{7}
+\indexentry{beta!gamma}{4}
+\indexentry{beta}{4}
+\indexentry{beta}{5}
+\indexentry{delta|textbf}{1}
+\indexentry{delta|(}{10}
+\indexentry{delta}{11}
+\indexentry{delta|)}{12}
+\indexentry{"!bang}{9}
+"#;
+
+        assert_eq!(
+            run(idx, &Style::default()),
+            r#"\begin{theindex}
+
+  \item !bang, 9
+
+  \indexspace
+
+  \item \textit{Alpha}, 7
+  \item alpha, 2
+
+  \indexspace
+
+  \item beta, 3--5
+    \subitem gamma, 4
+
+  \indexspace
+
+  \item delta, \textbf{1}, 10--12
+
+\end{theindex}
+"#
+        );
+    }
+
+    #[test]
+    fn style_file() {
+        let mut style = Style::default();
+        let mut transcript = Transcript::default();
+        style.parse(
+            r#"% a comment
+headings_flag 1
+heading_prefix "\\textbf{"
+heading_suffix "}\n"
+delim_0 "\\dotfill "
+actual '='
+bogus "x"
+"#,
+            &mut transcript,
+        );
+
+        assert_eq!(transcript.n_warnings, 1);
+        assert_eq!(
+            run(
+                "\\indexentry{zeta=$\\zeta$}{1}\n\\indexentry{3d}{ii}\n",
+                &style
+            ),
+            "\\begin{theindex}\n\\textbf{Numbers}\n\n  \\item 3d\\dotfill ii\n\n  \\indexspace\n\
+             \\textbf{Z}\n\n  \\item $\\zeta$\\dotfill 1\n\n\\end{theindex}\n"
+        );
+    }
+}
@@ -22,6 +22,7 @@ use std::ffi::{CStr, OsStr, OsString};
 use std::io::{Read, SeekFrom, Write};
 use std::path::Path;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{io, ptr, slice};
 
 use crate::digest::DigestData;
@@ -33,14 +34,20 @@ use crate::{tt_error, tt_warning};
 // Public sub-modules and reexports.
 
 pub mod bibtex;
+pub mod external;
+pub mod makeindex;
 pub mod spx2html;
 pub mod tex;
 pub mod xdvipdfmx;
 
 pub use self::bibtex::BibtexEngine;
+pub use self::external::ExternalToolEngine;
+pub use self::makeindex::MakeindexEngine;
 pub use self::spx2html::Spx2HtmlEngine;
 pub use self::tex::TexEngine;
-pub use self::xdvipdfmx::XdvipdfmxEngine;
+pub use self::xdvipdfmx::{
+    PageRange, PdfEncryption, PdfKeyLength, PdfOptions, PdfPermissions, XdvipdfmxEngine,
+};
 
 #[cfg(unix)]
 fn osstr_from_cstr(s: &CStr) -> Cow<OsStr> {
@@ -106,6 +113,71 @@ impl NoopIoEventBackend {
 
 impl IoEventBackend for NoopIoEventBackend {}
 
+// Helpers for the engines implemented in Rust, which deal with whole files at
+// a time rather than going through the bridge API.
+
+/// Read the entire contents of the named input file, reporting the access to
+/// `events`. Returns `Ok(None)` if the file isn't available.
+fn read_whole_input(
+    io: &mut dyn IoProvider,
+    events: &mut dyn IoEventBackend,
+    status: &mut dyn StatusBackend,
+    name: &str,
+) -> Result<Option<Vec<u8>>> {
+    let mut ih = match io.input_open_name(OsStr::new(name), status) {
+        OpenResult::Ok(ih) => ih,
+        OpenResult::NotAvailable => {
+            events.input_not_available(OsStr::new(name));
+            return Ok(None);
+        }
+        OpenResult::Err(e) => return Err(e),
+    };
+
+    events.input_opened(ih.name(), ih.origin());
+
+    let mut data = Vec::new();
+    let result = ih.read_to_end(&mut data);
+    let (name, digest) = ih.into_name_digest();
+    events.input_closed(name, digest);
+    result?;
+    Ok(Some(data))
+}
+
+/// Write the named output file in one go, reporting the access to `events`.
+/// Returns `Ok(false)` if there was nowhere to put it.
+fn write_whole_output(
+    io: &mut dyn IoProvider,
+    events: &mut dyn IoEventBackend,
+    name: &str,
+    data: &[u8],
+) -> Result<bool> {
+    let mut oh = match io.output_open_name(OsStr::new(name)) {
+        OpenResult::Ok(oh) => oh,
+        OpenResult::NotAvailable => return Ok(false),
+        OpenResult::Err(e) => return Err(e),
+    };
+
+    events.output_opened(oh.name());
+    let result = oh.write_all(data);
+    let (name, digest) = oh.into_name_digest();
+    events.output_closed(name, digest);
+    result?;
+    Ok(true)
+}
+
+/// Convert a build date into the form that the C/C++ engines use: seconds
+/// since the Unix epoch, or -1 to mean that they should use the current
+/// time. Dates before the epoch are treated as the epoch itself.
+fn build_date_to_time_t(date: Option<SystemTime>) -> libc::time_t {
+    match date {
+        Some(d) => d
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as libc::time_t)
+            .unwrap_or(0),
+        None => -1,
+    }
+}
+
 // Now, the private interfaces for executing various engines implemented in C/C++.
 
 // The C/C++ engines currently maintain global state, which means that we can
@@ -488,6 +560,30 @@ struct TectonicBridgeApi {
     input_close: *const libc::c_void,
 }
 
+#[repr(C)]
+struct DvipdfmxOptions {
+    paperspec: *const libc::c_char,
+    landscape: bool,
+    x_offset: libc::c_double,
+    y_offset: libc::c_double,
+    mag: libc::c_double,
+    annot_grow: libc::c_double,
+    bookmark_open: libc::c_int,
+    decimal_digits: libc::c_int,
+    encrypt: bool,
+    key_bits: libc::c_int,
+    permission: i32,
+    owner_password: *const libc::c_char,
+    user_password: *const libc::c_char,
+    pdfa: bool,
+    icc_profile_name: *const libc::c_char,
+    icc_profile: *const u8,
+    icc_profile_length: libc::size_t,
+    icc_components: libc::c_int,
+    random_seed: [u8; 32],
+    random_seeded: bool,
+}
+
 extern "C" {
     fn tt_get_error_message() -> *const libc::c_char;
     fn tt_xetex_set_int_variable(var_name: *const libc::c_char, value: libc::c_int) -> libc::c_int;
@@ -496,13 +592,17 @@ extern "C" {
         api: *const TectonicBridgeApi,
         dump_name: *const libc::c_char,
         input_file_name: *const libc::c_char,
+        build_date: libc::time_t,
     ) -> libc::c_int;
     fn dvipdfmx_simple_main(
         api: *const TectonicBridgeApi,
         dviname: *const libc::c_char,
         pdfname: *const libc::c_char,
+        pagespec: *const libc::c_char,
+        options: *const DvipdfmxOptions,
         enable_compression: bool,
         deterministic_tags: bool,
+        build_date: libc::time_t,
     ) -> libc::c_int;
     fn bibtex_simple_main(
         api: *const TectonicBridgeApi,
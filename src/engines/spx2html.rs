@@ -4,16 +4,22 @@
 //! Convert Tectonic’s SPX format to HTML
 //!
 //! Yay, an engine actually written in pure Rust!
+//!
+//! SPX files describe where glyphs go, not what the document means, so the
+//! structure of the HTML is inferred: each semantic page becomes a
+//! `<section>`, paragraphs are recovered from the vertical spacing and
+//! indentation of lines, and each distinct font becomes a CSS class.
 
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::Write;
-use tectonic_xdv::{FileType, XdvEvents, XdvParser};
+use std::fmt::Write;
+use tectonic_xdv::{FileType, NativeFont, TfmFont, XdvEvents, XdvParser};
 
 use super::IoEventBackend;
+use crate::errmsg;
 use crate::errors::{Error, Result};
-use crate::io::{IoProvider, IoStack, OpenResult, OutputHandle};
+use crate::io::{IoProvider, IoStack};
 use crate::status::StatusBackend;
-use crate::{errmsg, tt_warning};
 
 #[derive(Default)]
 pub struct Spx2HtmlEngine {}
@@ -46,67 +52,232 @@ impl Spx2HtmlEngine {
             s
         };
 
-        {
-            let state = State::new(outname, io, events, status);
-            let (state, _n_bytes) = XdvParser::process(&mut input, state)?;
-            state.finished();
-        }
-
+        let result = XdvParser::process(&mut input, Converter::default());
         let (name, digest_opt) = input.into_name_digest();
         events.input_closed(name, digest_opt);
+        let (converter, _n_bytes) = result?;
+
+        if !super::write_whole_output(io, events, &outname, converter.into_html().as_bytes())? {
+            return Err(errmsg!("no way to write output file \"{}\"", outname));
+        }
 
         Ok(())
     }
 }
 
-struct State<'a, 'b: 'a> {
-    outname: String,
-    io: &'a mut IoStack<'b>,
-    events: &'a mut dyn IoEventBackend,
-    status: &'a mut dyn StatusBackend,
-    cur_output: Option<OutputHandle>,
-    warned_lost_chars: bool,
-    buf: Vec<u8>,
+/// The size assumed for text in fonts that we know nothing about: 10pt.
+const DEFAULT_FONT_SIZE: i32 = 10 * 65536;
+
+/// How a font is presented in CSS.
+#[derive(Clone, Debug, PartialEq)]
+struct FontClass {
+    family: String,
+    size: i32,
+    bold: bool,
+    italic: bool,
+    color_rgba: Option<u32>,
+}
+
+impl FontClass {
+    fn new(font: &NativeFont) -> Self {
+        // XeTeX records the name of the font file, perhaps in brackets.
+        let name = font.name.trim_start_matches('[').trim_end_matches(']');
+        let name = name.rsplit('/').next().unwrap_or(name);
+        let family = match name.rfind('.') {
+            Some(i) if i > 0 => &name[..i],
+            _ => name,
+        };
+        let lower = family.to_lowercase();
+
+        FontClass {
+            family: family.replace(&['"', '\\'][..], ""),
+            size: font.size,
+            bold: font.embolden.is_some() || lower.contains("bold"),
+            italic: font.slant.is_some() || lower.contains("italic") || lower.contains("oblique"),
+            color_rgba: font.color_rgba,
+        }
+    }
+
+    fn write_css(&self, index: usize, dest: &mut String) {
+        write!(
+            dest,
+            ".f{} {{ font-family: \"{}\"; font-size: {}pt;",
+            index,
+            self.family,
+            f64::from(self.size) / 65536.
+        )
+        .unwrap();
+
+        if self.bold {
+            dest.push_str(" font-weight: bold;");
+        }
+
+        if self.italic {
+            dest.push_str(" font-style: italic;");
+        }
+
+        if let Some(rgba) = self.color_rgba {
+            if rgba & 0xFF == 0xFF {
+                write!(dest, " color: #{:06x};", rgba >> 8).unwrap();
+            } else {
+                write!(dest, " color: #{:08x};", rgba).unwrap();
+            }
+        }
+
+        dest.push_str(" }\n");
+    }
+}
+
+/// Where the last text on the current page went.
+#[derive(Clone, Copy, Debug)]
+struct LinePosition {
+    /// The horizontal position at which the current line started.
+    start_h: i32,
+
+    /// The baseline of the current line.
+    v: i32,
+
+    /// The horizontal position at which the last text ended.
+    end_h: i32,
+}
+
+/// Turns SPX events into an HTML document.
+#[derive(Debug, Default)]
+struct Converter {
+    classes: Vec<FontClass>,
+    font_classes: HashMap<i32, usize>,
+    font_sizes: HashMap<i32, i32>,
+    cur_font: Option<i32>,
+    body: String,
+    n_pages: usize,
+    in_paragraph: bool,
+    cur_span: Option<usize>,
+    line: Option<LinePosition>,
 }
 
-impl<'a, 'b: 'a> State<'a, 'b> {
-    pub fn new(
-        outname: String,
-        io: &'a mut IoStack<'b>,
-        events: &'a mut dyn IoEventBackend,
-        status: &'a mut dyn StatusBackend,
-    ) -> Self {
-        Self {
-            outname,
-            io,
-            events,
-            status,
-            cur_output: None,
-            warned_lost_chars: false,
-            buf: Vec::new(),
+impl Converter {
+    fn into_html(self) -> String {
+        let mut html =
+            String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n");
+
+        for (index, class) in self.classes.iter().enumerate() {
+            class.write_css(index, &mut html);
+        }
+
+        html.push_str("</style>\n</head>\n<body>\n");
+        html.push_str(&self.body);
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    fn font_size(&self) -> i32 {
+        self.cur_font
+            .and_then(|f| self.font_sizes.get(&f))
+            .cloned()
+            .unwrap_or(DEFAULT_FONT_SIZE)
+    }
+
+    fn open_paragraph(&mut self) {
+        if !self.in_paragraph {
+            self.body.push_str("<p>");
+            self.in_paragraph = true;
+        }
+    }
+
+    fn close_paragraph(&mut self) {
+        if self.cur_span.take().is_some() {
+            self.body.push_str("</span>");
+        }
+
+        if self.in_paragraph {
+            self.body.push_str("</p>\n");
+            self.in_paragraph = false;
+        }
+    }
+
+    /// Decide how text starting at (*h*, *v*) relates to the text before it,
+    /// adding a space or a paragraph break as needed.
+    fn move_to(&mut self, h: i32, v: i32) {
+        let size = self.font_size();
+
+        let prev = match self.line {
+            Some(p) => p,
+            None => {
+                self.line = Some(LinePosition {
+                    start_h: h,
+                    v,
+                    end_h: h,
+                });
+                return;
+            }
+        };
+
+        if (v - prev.v).abs() < size / 2 {
+            // Same line, perhaps with a super- or subscript. Interword spaces
+            // are just movements, but kerns are much narrower than those.
+            if h > prev.end_h + size / 10 {
+                self.push_text(" ");
+            }
+        } else {
+            // A new line. We start a new paragraph if it's separated from the
+            // previous one by more than normal line spacing, if it's indented
+            // relative to it, or if we've gone back up the page.
+            let dv = v - prev.v;
+
+            if dv < 0 || dv > size * 3 / 2 || h > prev.start_h + size / 2 {
+                self.close_paragraph();
+            } else {
+                self.push_text(" ");
+            }
+
+            self.line = Some(LinePosition {
+                start_h: h,
+                v,
+                end_h: h,
+            });
+        }
+    }
+
+    /// Add text to the current paragraph, in the current font.
+    fn push_text(&mut self, text: &str) {
+        let class = self
+            .cur_font
+            .and_then(|f| self.font_classes.get(&f).cloned());
+        self.open_paragraph();
+
+        if class != self.cur_span {
+            if self.cur_span.is_some() {
+                self.body.push_str("</span>");
+            }
+
+            if let Some(c) = class {
+                write!(self.body, "<span class=\"f{}\">", c).unwrap();
+            }
+
+            self.cur_span = class;
+        }
+
+        for c in text.chars() {
+            match c {
+                '&' => self.body.push_str("&amp;"),
+                '<' => self.body.push_str("&lt;"),
+                '>' => self.body.push_str("&gt;"),
+                c => self.body.push(c),
+            }
         }
     }
 }
 
 #[inline(always)]
-fn as_printable_ascii(c: i32) -> Option<u8> {
+fn as_printable_ascii(c: i32) -> Option<char> {
     if c > 0x20 && c < 0x7F {
-        Some(c as u8)
+        Some(c as u8 as char)
     } else {
         None
     }
 }
 
-impl<'a, 'b: 'a> State<'a, 'b> {
-    pub fn finished(self) {
-        if let Some(oh) = self.cur_output {
-            let (name, digest) = oh.into_name_digest();
-            self.events.output_closed(name, digest);
-        }
-    }
-}
-
-impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
+impl XdvEvents for Converter {
     type Error = Error;
 
     fn handle_header(&mut self, filetype: FileType, _comment: &[u8]) -> Result<()> {
@@ -114,50 +285,185 @@ impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
             return Err(errmsg!("file should be SPX format but got {}", filetype));
         }
 
-        self.cur_output = Some(match self.io.output_open_name(OsStr::new(&self.outname)) {
-            OpenResult::Ok(h) => h,
-            OpenResult::NotAvailable => {
-                return Err(errmsg!("no way to write output file \"{}\"", self.outname));
-            }
-            OpenResult::Err(e) => {
-                return Err(e);
-            }
-        });
+        Ok(())
+    }
 
-        self.events.output_opened(OsStr::new(&self.outname));
+    fn handle_begin_page(&mut self, _counters: &[i32], _previous_bop: i32) -> Result<()> {
+        self.n_pages += 1;
+        writeln!(self.body, "<section id=\"page-{}\">", self.n_pages).unwrap();
+        self.line = None;
+        self.cur_font = None;
+        Ok(())
+    }
 
+    fn handle_end_page(&mut self) -> Result<()> {
+        self.close_paragraph();
+        self.body.push_str("</section>\n");
         Ok(())
     }
 
-    fn handle_char_run(&mut self, chars: &[i32]) -> Result<()> {
-        let dest = match self.cur_output {
-            Some(ref mut h) => h,
+    fn handle_define_native_font(&mut self, font_num: i32, font: &NativeFont) -> Result<()> {
+        let class = FontClass::new(font);
+
+        let index = match self.classes.iter().position(|c| *c == class) {
+            Some(i) => i,
             None => {
-                if !self.warned_lost_chars {
-                    tt_warning!(
-                        self.status,
-                        "losing characters in SPX file: no current output"
-                    );
-                    self.warned_lost_chars = true;
-                }
-
-                return Ok(());
+                self.classes.push(class);
+                self.classes.len() - 1
             }
         };
 
-        self.buf.clear();
+        self.font_classes.insert(font_num, index);
+        self.font_sizes.insert(font_num, font.size);
+        Ok(())
+    }
 
-        for c in chars.iter() {
-            if let Some(b) = as_printable_ascii(*c) {
-                self.buf.push(b);
-            }
+    fn handle_define_font(&mut self, font_num: i32, font: &TfmFont) -> Result<()> {
+        self.font_sizes.insert(font_num, font.scale_factor);
+        Ok(())
+    }
+
+    fn handle_select_font(&mut self, font_num: i32) -> Result<()> {
+        self.cur_font = Some(font_num);
+        Ok(())
+    }
+
+    fn handle_text_run(
+        &mut self,
+        _font_num: i32,
+        text: &str,
+        h: i32,
+        v: i32,
+        width: i32,
+    ) -> Result<()> {
+        self.move_to(h, v);
+        self.push_text(text);
+
+        if let Some(ref mut line) = self.line {
+            line.end_h = h + width;
+        }
+
+        Ok(())
+    }
+
+    fn handle_tfm_char_run(
+        &mut self,
+        _font_num: i32,
+        chars: &[i32],
+        x: &[i32],
+        v: i32,
+    ) -> Result<()> {
+        // These come from traditional TeX fonts, whose encodings we don't
+        // know. We don't know the widths of their characters either, so the
+        // positions only account for the movements between them. That's
+        // still enough to tell the spaces between words from the kerns
+        // within them.
+        let text: String = chars
+            .iter()
+            .filter_map(|c| as_printable_ascii(*c))
+            .collect();
+
+        if text.is_empty() {
+            return Ok(());
         }
 
-        if !self.buf.is_empty() {
-            self.buf.push(0x0a); // newline
-            dest.write_all(&self.buf)?;
+        self.move_to(x[0], v);
+        self.push_text(&text);
+
+        if let Some(ref mut line) = self.line {
+            line.end_h = x[x.len() - 1];
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PT: i32 = 65536;
+
+    fn font(name: &str) -> NativeFont {
+        NativeFont {
+            name: name.to_owned(),
+            size: 10 * PT,
+            face_index: 0,
+            vertical: false,
+            color_rgba: None,
+            extend: None,
+            slant: None,
+            embolden: None,
+        }
+    }
+
+    fn chars(text: &str) -> Vec<i32> {
+        text.chars().map(|c| c as i32).collect()
+    }
+
+    #[test]
+    fn tfm_words() {
+        let cmr10 = TfmFont {
+            area: String::new(),
+            name: "cmr10".to_owned(),
+            checksum: 0,
+            scale_factor: 10 * PT,
+            design_size: 10 * PT,
+        };
+
+        let mut c = Converter::default();
+        c.handle_header(FileType::Spx, b"").unwrap();
+        c.handle_begin_page(&[1; 10], -1).unwrap();
+        c.handle_define_font(3, &cmr10).unwrap();
+        c.handle_select_font(3).unwrap();
+
+        // Without character widths, the characters of a run all sit where
+        // it starts. Interword spaces are a few points wide, while kerns are
+        // small or negative.
+        c.handle_tfm_char_run(3, &chars("Hello"), &[20 * PT; 5], 100 * PT)
+            .unwrap();
+        c.handle_tfm_char_run(3, &chars("W"), &[23 * PT], 100 * PT)
+            .unwrap();
+        c.handle_tfm_char_run(3, &chars("orld"), &[23 * PT - PT / 2; 4], 100 * PT)
+            .unwrap();
+        c.handle_tfm_char_run(3, &chars("again"), &[20 * PT; 5], 112 * PT)
+            .unwrap();
+        c.handle_end_page().unwrap();
+        let html = c.into_html();
+
+        assert!(html.contains("<p>Hello World again</p>\n"));
+    }
+
+    #[test]
+    fn paragraphs_and_fonts() {
+        let mut c = Converter::default();
+        c.handle_header(FileType::Spx, b"").unwrap();
+        c.handle_begin_page(&[1; 10], -1).unwrap();
+        c.handle_define_native_font(1, &font("[fonts/lmroman10-regular.otf]"))
+            .unwrap();
+        c.handle_define_native_font(2, &font("lmroman10-bold.otf"))
+            .unwrap();
+        c.handle_select_font(1).unwrap();
+        c.handle_text_run(1, "Café", 20 * PT, 100 * PT, 20 * PT)
+            .unwrap();
+        c.handle_text_run(1, "<&>", 43 * PT, 100 * PT, 10 * PT)
+            .unwrap();
+        c.handle_select_font(2).unwrap();
+        c.handle_text_run(2, "bold", 0, 130 * PT, 20 * PT).unwrap();
+        c.handle_select_font(1).unwrap();
+        c.handle_text_run(1, "Next", 0, 142 * PT, 20 * PT).unwrap();
+        c.handle_end_page().unwrap();
+        let html = c.into_html();
+
+        assert!(html.contains(".f0 { font-family: \"lmroman10-regular\"; font-size: 10pt; }"));
+        assert!(html.contains(
+            ".f1 { font-family: \"lmroman10-bold\"; font-size: 10pt; font-weight: bold; }"
+        ));
+        assert!(html.contains(
+            "<section id=\"page-1\">\n\
+             <p><span class=\"f0\">Café &lt;&amp;&gt;</span></p>\n\
+             <p><span class=\"f1\">bold</span><span class=\"f0\"> Next</span></p>\n\
+             </section>\n"
+        ));
+    }
+}
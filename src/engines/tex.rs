@@ -2,13 +2,17 @@
 // Copyright 2017-2018 the Tectonic Project
 // Licensed under the MIT License.
 
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use std::ffi::{CStr, CString};
+use std::time::SystemTime;
 
 use super::{ExecutionState, IoEventBackend, TectonicBridgeApi};
 use crate::errors::{DefinitelySame, ErrorKind, Result};
 use crate::io::IoStack;
 use crate::status::StatusBackend;
 
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TexResult {
     // The Errors possibility should only occur if halt_on_error_p is false --
@@ -35,6 +39,7 @@ pub struct TexEngine {
     initex_mode: bool,
     synctex_enabled: bool,
     semantic_pagination_enabled: bool,
+    build_date: Option<SystemTime>,
 }
 
 impl Default for TexEngine {
@@ -44,6 +49,7 @@ impl Default for TexEngine {
             initex_mode: false,
             synctex_enabled: false,
             semantic_pagination_enabled: false,
+            build_date: None,
         }
     }
 }
@@ -87,6 +93,15 @@ impl TexEngine {
         self
     }
 
+    /// Set the date and time that TeX reports through `\year`, `\time` and
+    /// friends, and thus `\today`. The date is taken in UTC. By default, the
+    /// engine uses the current local time, except in initex mode, where
+    /// these are all zero so that format files are reproducible.
+    pub fn build_date(&mut self, date: SystemTime) -> &mut Self {
+        self.build_date = Some(date);
+        self
+    }
+
     // This function can't be generic across the IoProvider trait, for now,
     // since the global pointer that stashes the ExecutionState must have a
     // complete type.
@@ -130,7 +145,12 @@ impl TexEngine {
         }
 
         unsafe {
-            match super::tex_simple_main(&bridge, cformat.as_ptr(), cinput.as_ptr()) {
+            match super::tex_simple_main(
+                &bridge,
+                cformat.as_ptr(),
+                cinput.as_ptr(),
+                super::build_date_to_time_t(self.build_date),
+            ) {
                 0 => Ok(TexResult::Spotless),
                 1 => Ok(TexResult::Warnings),
                 2 => Ok(TexResult::Errors),
@@ -3,15 +3,377 @@
 // Licensed under the MIT License.
 
 use std::ffi::{CStr, CString};
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::ops::BitOr;
+use std::ptr;
+use std::str::FromStr;
+use std::time::SystemTime;
+use tectonic_xdv::{XdvEvents, XdvParser};
 
-use super::{ExecutionState, IoEventBackend, TectonicBridgeApi};
-use crate::errors::{ErrorKind, Result};
+use super::{DvipdfmxOptions, ExecutionState, IoEventBackend, TectonicBridgeApi};
+use crate::errmsg;
+use crate::errors::{Error, ErrorKind, Result};
 use crate::io::IoStack;
 use crate::status::StatusBackend;
 
+/// A range of pages to include in the PDF output.
+///
+/// Pages are numbered from 1 by their position in the document, not by the
+/// page numbers that the document prints. A range whose `first` page comes
+/// after its `last` one includes its pages in reverse order.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PageRange {
+    /// The first page of the range.
+    pub first: u32,
+
+    /// The last page of the range, or `None` to continue through the last
+    /// page of the document.
+    pub last: Option<u32>,
+}
+
+impl PageRange {
+    /// Parse a comma-separated list of page ranges, such as `3-7,12,20-`.
+    /// A range with no first page, like `-5`, starts at the first page.
+    pub fn parse_list(spec: &str) -> Result<Vec<PageRange>> {
+        spec.split(',').map(|r| r.parse()).collect()
+    }
+
+    /// Check that every page of this range exists in a document with
+    /// `n_pages` pages.
+    pub fn check(&self, n_pages: u32) -> Result<()> {
+        let last = self.last.unwrap_or(self.first);
+
+        if self.first > n_pages || last > n_pages {
+            return Err(errmsg!(
+                "page range \"{}\" is outside of the document, which has {} pages",
+                self,
+                n_pages
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for PageRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        fn page(s: &str, range: &str) -> Result<u32> {
+            match s.trim().parse() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(errmsg!("invalid page range \"{}\"", range)),
+            }
+        }
+
+        let mut parts = s.splitn(2, '-');
+        let first = parts.next().unwrap().trim();
+        let first = if first.is_empty() && s.contains('-') {
+            1
+        } else {
+            page(first, s)?
+        };
+
+        let last = match parts.next() {
+            None => Some(first),
+            Some(l) if l.trim().is_empty() => None,
+            Some(l) => Some(page(l, s)?),
+        };
+
+        Ok(PageRange { first, last })
+    }
+}
+
+impl Display for PageRange {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), FmtError> {
+        match self.last {
+            Some(l) if l == self.first => write!(f, "{}", self.first),
+            Some(l) => write!(f, "{}-{}", self.first, l),
+            None => write!(f, "{}-", self.first),
+        }
+    }
+}
+
+/// Count the pages of an XDV file.
+fn count_pages(xdv: &[u8]) -> Result<u32> {
+    struct PageCounter(u32);
+
+    impl XdvEvents for PageCounter {
+        type Error = Error;
+
+        fn handle_begin_page(&mut self, _counters: &[i32], _previous_bop: i32) -> Result<()> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+
+    let (counter, _) = XdvParser::process(xdv, PageCounter(0))?;
+    Ok(counter.0)
+}
+
+/// Parse a length such as `1in` or `25.4mm` into PostScript points (`bp`),
+/// the unit that PDF uses. A number without a unit is taken to be in points.
+pub fn parse_length(s: &str) -> Result<f64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let value: f64 = match number.parse() {
+        Ok(v) => v,
+        Err(_) => return Err(errmsg!("invalid length \"{}\"", s)),
+    };
+
+    let bp_per_unit = match unit.trim() {
+        "" | "bp" => 1.,
+        "pt" => 72. / 72.27,
+        "in" => 72.,
+        "cm" => 72. / 2.54,
+        "mm" => 72. / 25.4,
+        "pc" => 12. * 72. / 72.27,
+        "dd" => 1238. / 1157. * 72. / 72.27,
+        "cc" => 12. * 1238. / 1157. * 72. / 72.27,
+        "sp" => 72. / 72.27 / 65536.,
+        _ => return Err(errmsg!("invalid unit in length \"{}\"", s)),
+    };
+
+    Ok(value * bp_per_unit)
+}
+
+/// Settings that control how xdvipdfmx lays out the PDF.
+///
+/// Lengths are in PostScript points (`bp`), 72 to the inch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PdfOptions {
+    /// The paper size: either a name like `"a4"` or `"letter"`, or a width
+    /// and height like `"210mm,297mm"`. If unset, US letter is used.
+    /// Documents that set their own paper size, as most LaTeX documents do,
+    /// override this.
+    pub paper: Option<String>,
+
+    /// Whether to swap the paper's width and height.
+    pub landscape: bool,
+
+    /// How far right of the paper's left edge TeX's origin goes.
+    pub x_offset: f64,
+
+    /// How far down from the paper's top edge TeX's origin goes.
+    pub y_offset: f64,
+
+    /// A factor by which to magnify the whole document.
+    pub mag: f64,
+
+    /// How far to grow the clickable areas of annotations, such as links,
+    /// beyond the boxes that TeX gives for them.
+    pub annot_grow: f64,
+
+    /// How many levels of the document outline to open initially. Negative
+    /// values open all of them.
+    pub bookmark_open: i32,
+
+    /// How many decimal digits to use for numbers in the PDF's content
+    /// streams, up to 8. Fixing this keeps output comparable across runs.
+    pub decimal_digits: u8,
+}
+
+impl Default for PdfOptions {
+    fn default() -> Self {
+        PdfOptions {
+            paper: None,
+            landscape: false,
+            x_offset: 72.,
+            y_offset: 72.,
+            mag: 1.,
+            annot_grow: 0.,
+            bookmark_open: 0,
+            decimal_digits: 5,
+        }
+    }
+}
+
+/// The length of the key used to encrypt a PDF.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PdfKeyLength {
+    /// A 40-bit RC4 key. This is easily broken, and is only useful for
+    /// readers that predate PDF 1.4.
+    Rc4_40,
+
+    /// A 128-bit AES key.
+    Aes128,
+
+    /// A 256-bit AES key. This needs PDF 1.7, which is used automatically.
+    Aes256,
+}
+
+impl PdfKeyLength {
+    /// The length of the key in bits.
+    pub fn bits(self) -> u32 {
+        match self {
+            PdfKeyLength::Rc4_40 => 40,
+            PdfKeyLength::Aes128 => 128,
+            PdfKeyLength::Aes256 => 256,
+        }
+    }
+}
+
+impl FromStr for PdfKeyLength {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "40" => Ok(PdfKeyLength::Rc4_40),
+            "128" => Ok(PdfKeyLength::Aes128),
+            "256" => Ok(PdfKeyLength::Aes256),
+            _ => Err(errmsg!(
+                "invalid encryption key length \"{}\"; it must be 40, 128 or 256",
+                s
+            )),
+        }
+    }
+}
+
+/// What readers of an encrypted PDF may do without the owner password.
+///
+/// These are the bits of the `/P` entry of the PDF's encryption dictionary.
+/// They can be combined with `|`. Honoring them is up to the reader.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PdfPermissions(pub u32);
+
+impl PdfPermissions {
+    pub const NONE: PdfPermissions = PdfPermissions(0);
+    pub const PRINT: PdfPermissions = PdfPermissions(1 << 2);
+    pub const MODIFY: PdfPermissions = PdfPermissions(1 << 3);
+    pub const COPY: PdfPermissions = PdfPermissions(1 << 4);
+    pub const ANNOTATE: PdfPermissions = PdfPermissions(1 << 5);
+    pub const FILL_FORMS: PdfPermissions = PdfPermissions(1 << 8);
+    pub const EXTRACT: PdfPermissions = PdfPermissions(1 << 9);
+    pub const ASSEMBLE: PdfPermissions = PdfPermissions(1 << 10);
+    pub const PRINT_HIGH_QUALITY: PdfPermissions = PdfPermissions(1 << 11);
+
+    const NAMES: &'static [(&'static str, PdfPermissions)] = &[
+        ("print", PdfPermissions::PRINT),
+        ("modify", PdfPermissions::MODIFY),
+        ("copy", PdfPermissions::COPY),
+        ("annotate", PdfPermissions::ANNOTATE),
+        ("fill-forms", PdfPermissions::FILL_FORMS),
+        ("extract", PdfPermissions::EXTRACT),
+        ("assemble", PdfPermissions::ASSEMBLE),
+        ("print-high-quality", PdfPermissions::PRINT_HIGH_QUALITY),
+    ];
+
+    /// Parse a comma-separated list of permission names, such as
+    /// `print,copy`. The names are `print`, `modify`, `copy`, `annotate`,
+    /// `fill-forms`, `extract`, `assemble` and `print-high-quality`; `none`
+    /// grants nothing.
+    pub fn parse_list(spec: &str) -> Result<PdfPermissions> {
+        let mut perms = PdfPermissions::NONE;
+
+        for name in spec.split(',').map(|n| n.trim()) {
+            if name == "none" {
+                continue;
+            }
+
+            match PdfPermissions::NAMES.iter().find(|(n, _)| *n == name) {
+                Some((_, p)) => perms = perms | *p,
+                None => return Err(errmsg!("unknown PDF permission \"{}\"", name)),
+            }
+        }
+
+        Ok(perms)
+    }
+
+    /// Whether all of the permissions in `other` are granted.
+    pub fn contains(self, other: PdfPermissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for PdfPermissions {
+    /// Printing, modifying, copying and annotating, as xdvipdfmx allows by
+    /// default.
+    fn default() -> Self {
+        PdfPermissions::PRINT
+            | PdfPermissions::MODIFY
+            | PdfPermissions::COPY
+            | PdfPermissions::ANNOTATE
+    }
+}
+
+impl BitOr for PdfPermissions {
+    type Output = PdfPermissions;
+
+    fn bitor(self, other: PdfPermissions) -> PdfPermissions {
+        PdfPermissions(self.0 | other.0)
+    }
+}
+
+/// Settings for encrypting the PDF output.
+///
+/// Documents can also ask for encryption with a `pdf:encrypt` special, in
+/// which case the special's settings win.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PdfEncryption {
+    /// The password that unlocks everything. If it's empty, readers that
+    /// know the user password can do anything.
+    pub owner_password: String,
+
+    /// The password needed to open the document at all. If it's empty,
+    /// anyone can open it, subject to the permissions.
+    pub user_password: String,
+
+    /// The length of the encryption key.
+    pub key_length: PdfKeyLength,
+
+    /// What readers that only know the user password may do.
+    pub permissions: PdfPermissions,
+}
+
+impl Default for PdfEncryption {
+    fn default() -> Self {
+        PdfEncryption {
+            owner_password: String::new(),
+            user_password: String::new(),
+            key_length: PdfKeyLength::Aes128,
+            permissions: PdfPermissions::default(),
+        }
+    }
+}
+
+/// The longest password that xdvipdfmx can use, in bytes.
+const MAX_PASSWORD_LEN: usize = 127;
+
+/// The ICC profile that PDF/A output uses for its output intent, unless told
+/// otherwise.
+pub const DEFAULT_OUTPUT_INTENT_PROFILE: &str = "sRGB.icc";
+
+/// Get the number of color components of the color space that an ICC profile
+/// describes, as PDF needs to know it.
+fn icc_color_components(profile: &[u8]) -> Result<i32> {
+    if profile.len() < 128 || &profile[36..40] != b"acsp" {
+        return Err(errmsg!("not an ICC color profile"));
+    }
+
+    match &profile[16..20] {
+        b"GRAY" => Ok(1),
+        b"RGB " => Ok(3),
+        b"CMYK" => Ok(4),
+        other => Err(errmsg!(
+            "unsupported ICC profile color space \"{}\"",
+            String::from_utf8_lossy(other)
+        )),
+    }
+}
+
 pub struct XdvipdfmxEngine {
     enable_compression: bool,
     deterministic_tags: bool,
+    page_ranges: Vec<PageRange>,
+    pdf_options: PdfOptions,
+    encryption: Option<PdfEncryption>,
+    build_date: Option<SystemTime>,
+    pdfa: bool,
+    output_intent_profile: String,
 }
 
 impl XdvipdfmxEngine {
@@ -19,6 +381,12 @@ impl XdvipdfmxEngine {
         XdvipdfmxEngine {
             enable_compression: true,
             deterministic_tags: false,
+            page_ranges: Vec::new(),
+            pdf_options: PdfOptions::default(),
+            encryption: None,
+            build_date: None,
+            pdfa: false,
+            output_intent_profile: DEFAULT_OUTPUT_INTENT_PROFILE.to_owned(),
         }
     }
 
@@ -32,6 +400,65 @@ impl XdvipdfmxEngine {
         self
     }
 
+    /// Only include the given pages in the PDF, in the order given. If the
+    /// list is empty, which is the default, all pages are included.
+    ///
+    /// It's an error for a range to include pages that the document doesn't
+    /// have, so each range ends up contributing at least one page.
+    pub fn with_page_ranges(mut self, ranges: &[PageRange]) -> Self {
+        self.page_ranges = ranges.to_vec();
+        self
+    }
+
+    /// Lay out the PDF with the given paper size, offsets, magnification and
+    /// other settings. The default is `PdfOptions::default()`, which matches
+    /// what xdvipdfmx does on its own.
+    pub fn with_pdf_options(mut self, options: &PdfOptions) -> Self {
+        self.pdf_options = options.clone();
+        self
+    }
+
+    /// Encrypt the PDF with the given settings, or not at all if `None`,
+    /// which is the default.
+    ///
+    /// Keys, salts and initialization vectors are random, so encrypting the
+    /// same document twice gives different results even in reproducible
+    /// mode.
+    pub fn with_encryption(mut self, encryption: Option<&PdfEncryption>) -> Self {
+        self.encryption = encryption.cloned();
+        self
+    }
+
+    /// Set the time to record as the PDF's creation and modification dates,
+    /// which also seeds its `/ID`. The random parts of its encryption come
+    /// from the operating system regardless, so that they can't be guessed
+    /// from the date. By default, this is taken from the `SOURCE_DATE_EPOCH` environment
+    /// variable if it's set, and the current time otherwise.
+    pub fn with_build_date(mut self, date: SystemTime) -> Self {
+        self.build_date = Some(date);
+        self
+    }
+
+    /// Produce PDF/A-2b, for archiving. All fonts are embedded, whatever
+    /// their licenses say, and the PDF gets XMP metadata and an output intent
+    /// with the ICC profile named by `with_output_intent_profile`.
+    ///
+    /// PDF/A documents can't be encrypted, so asking for encryption too is an
+    /// error. Transparency is allowed but gets a warning, since it's an easy
+    /// way to break compliance.
+    pub fn with_pdfa(mut self, enabled: bool) -> Self {
+        self.pdfa = enabled;
+        self
+    }
+
+    /// Set the name of the ICC profile to use for the output intent of PDF/A
+    /// output. It's looked up like any other input file. The default is
+    /// `sRGB.icc`.
+    pub fn with_output_intent_profile(mut self, name: &str) -> Self {
+        self.output_intent_profile = name.to_owned();
+        self
+    }
+
     pub fn process(
         &mut self,
         io: &mut IoStack,
@@ -42,8 +469,123 @@ impl XdvipdfmxEngine {
     ) -> Result<i32> {
         let _guard = super::ENGINE_LOCK.lock().unwrap(); // until we're thread-safe ...
 
+        let opts = &self.pdf_options;
+
+        if !(opts.mag > 0. && opts.mag.is_finite()) {
+            return Err(errmsg!("invalid magnification {}", opts.mag));
+        }
+
+        if opts.decimal_digits > 8 {
+            return Err(errmsg!(
+                "PDFs can use at most 8 decimal digits, not {}",
+                opts.decimal_digits
+            ));
+        }
+
+        let cpaper = match opts.paper {
+            Some(ref p) => Some(CString::new(p.as_str())?),
+            None => None,
+        };
+
+        let icc_profile = if self.pdfa {
+            if self.encryption.is_some() {
+                return Err(errmsg!("PDF/A documents cannot be encrypted"));
+            }
+
+            let name = &self.output_intent_profile;
+            let data = match super::read_whole_input(io, events, status, name)? {
+                Some(d) => d,
+                None => {
+                    return Err(errmsg!(
+                        "couldn't find the ICC profile \"{}\" needed for PDF/A output",
+                        name
+                    ))
+                }
+            };
+            let components = match icc_color_components(&data) {
+                Ok(n) => n,
+                Err(e) => return Err(errmsg!("can't use \"{}\" for PDF/A output: {}", name, e)),
+            };
+            Some((CString::new(name.as_str())?, data, components))
+        } else {
+            None
+        };
+
+        let default_encryption = PdfEncryption::default();
+        let enc = self.encryption.as_ref().unwrap_or(&default_encryption);
+
+        for pw in &[&enc.owner_password, &enc.user_password] {
+            if pw.len() > MAX_PASSWORD_LEN {
+                return Err(errmsg!(
+                    "PDF passwords can be at most {} bytes long",
+                    MAX_PASSWORD_LEN
+                ));
+            }
+        }
+
+        // Encryption keys and salts need unpredictable bytes. Without them,
+        // we can still make unencrypted PDFs; if the document then asks for
+        // encryption with a special, xdvipdfmx fails when it gets there.
+        let mut random_seed = [0u8; 32];
+        let random_seeded = match getrandom::getrandom(&mut random_seed) {
+            Ok(()) => true,
+            Err(e) => {
+                if self.encryption.is_some() {
+                    return Err(errmsg!(
+                        "couldn't get random data to encrypt the PDF: {}",
+                        e
+                    ));
+                }
+
+                false
+            }
+        };
+
+        let cowner = CString::new(enc.owner_password.as_str())?;
+        let cuser = CString::new(enc.user_password.as_str())?;
+
+        let coptions = DvipdfmxOptions {
+            paperspec: cpaper.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+            landscape: opts.landscape,
+            x_offset: opts.x_offset,
+            y_offset: opts.y_offset,
+            mag: opts.mag,
+            annot_grow: opts.annot_grow,
+            bookmark_open: opts.bookmark_open,
+            decimal_digits: i32::from(opts.decimal_digits),
+            encrypt: self.encryption.is_some(),
+            key_bits: enc.key_length.bits() as i32,
+            permission: enc.permissions.0 as i32,
+            owner_password: cowner.as_ptr(),
+            user_password: cuser.as_ptr(),
+            pdfa: self.pdfa,
+            icc_profile_name: icc_profile.as_ref().map_or(ptr::null(), |p| p.0.as_ptr()),
+            icc_profile: icc_profile.as_ref().map_or(ptr::null(), |p| p.1.as_ptr()),
+            icc_profile_length: icc_profile.as_ref().map_or(0, |p| p.1.len()),
+            icc_components: icc_profile.as_ref().map_or(0, |p| p.2),
+            random_seed,
+            random_seeded,
+        };
+
+        if !self.page_ranges.is_empty() {
+            let n_pages = match super::read_whole_input(io, events, status, dvi)? {
+                Some(data) => count_pages(&data)?,
+                None => return Err(errmsg!("couldn't find the XDV file \"{}\"", dvi)),
+            };
+
+            for range in &self.page_ranges {
+                range.check(n_pages)?;
+            }
+        }
+
         let cdvi = CString::new(dvi)?;
         let cpdf = CString::new(pdf)?;
+        let cpagespec = if self.page_ranges.is_empty() {
+            None
+        } else {
+            let spec: Vec<_> = self.page_ranges.iter().map(|r| r.to_string()).collect();
+            Some(CString::new(spec.join(","))?)
+        };
 
         let /*mut*/ state = ExecutionState::new(io, events, status);
         let bridge = TectonicBridgeApi::new(&state);
@@ -53,8 +595,11 @@ impl XdvipdfmxEngine {
                 &bridge,
                 cdvi.as_ptr(),
                 cpdf.as_ptr(),
+                cpagespec.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                &coptions,
                 self.enable_compression,
                 self.deterministic_tags,
+                super::build_date_to_time_t(self.build_date),
             ) {
                 99 => {
                     let ptr = super::tt_get_error_message();
@@ -72,3 +617,92 @@ impl Default for XdvipdfmxEngine {
         XdvipdfmxEngine::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tectonic_xdv::{FileType, XdvWriter};
+
+    #[test]
+    fn page_ranges() {
+        let ranges = PageRange::parse_list("3-7, 12,20-,-2,9-4").unwrap();
+        let spec: Vec<_> = ranges.iter().map(|r| r.to_string()).collect();
+        assert_eq!(spec, vec!["3-7", "12", "20-", "1-2", "9-4"]);
+        assert_eq!(
+            ranges[2],
+            PageRange {
+                first: 20,
+                last: None
+            }
+        );
+
+        for bad in &["", "0", "3-x", "1-2-3", "5,"] {
+            assert!(PageRange::parse_list(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn page_ranges_in_document() {
+        let mut w = XdvWriter::new(Vec::new(), FileType::Xdv, b"").unwrap();
+        for i in 1..=3 {
+            w.begin_page(&[i, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+            w.end_page().unwrap();
+        }
+        let n_pages = count_pages(&w.finish().unwrap()).unwrap();
+        assert_eq!(n_pages, 3);
+
+        for good in &["1", "3", "1-3", "3-1", "2-", "-3"] {
+            let range: PageRange = good.parse().unwrap();
+            assert!(range.check(n_pages).is_ok(), "{:?}", good);
+        }
+
+        for bad in &["4", "4-", "2-4", "5-1", "-4"] {
+            let range: PageRange = bad.parse().unwrap();
+            assert!(range.check(n_pages).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn lengths() {
+        let close = |s: &str, bp: f64| (parse_length(s).unwrap() - bp).abs() < 1e-9;
+        assert!(close("72", 72.));
+        assert!(close("1in", 72.));
+        assert!(close("25.4mm", 72.));
+        assert!(close("-2.54 cm", -72.));
+        assert!(close("72.27pt", 72.));
+        assert!(parse_length("1furlong").is_err());
+        assert!(parse_length("in").is_err());
+    }
+
+    #[test]
+    fn permissions() {
+        let perms = PdfPermissions::parse_list("print, copy,print-high-quality").unwrap();
+        assert_eq!(perms.0, 0x814);
+        assert!(perms.contains(PdfPermissions::PRINT | PdfPermissions::COPY));
+        assert!(!perms.contains(PdfPermissions::MODIFY));
+        assert_eq!(
+            PdfPermissions::parse_list("none").unwrap(),
+            PdfPermissions::NONE
+        );
+        assert_eq!(PdfPermissions::default().0, 0x3C);
+        assert!(PdfPermissions::parse_list("print,steal").is_err());
+
+        assert_eq!("256".parse::<PdfKeyLength>().unwrap(), PdfKeyLength::Aes256);
+        assert!("64".parse::<PdfKeyLength>().is_err());
+    }
+
+    #[test]
+    fn icc_profiles() {
+        let mut profile = vec![0u8; 128];
+        profile[36..40].copy_from_slice(b"acsp");
+        profile[16..20].copy_from_slice(b"RGB ");
+        assert_eq!(icc_color_components(&profile).unwrap(), 3);
+        profile[16..20].copy_from_slice(b"CMYK");
+        assert_eq!(icc_color_components(&profile).unwrap(), 4);
+        profile[16..20].copy_from_slice(b"Lab ");
+        assert!(icc_color_components(&profile).is_err());
+        assert!(icc_color_components(&profile[..100]).is_err());
+        profile[36..40].copy_from_slice(b"nope");
+        assert!(icc_color_components(&profile).is_err());
+    }
+}
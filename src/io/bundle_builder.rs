@@ -0,0 +1,410 @@
+// src/io/bundle_builder.rs -- creating Zip and indexed tar bundles
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Creating bundles from files on the local filesystem.
+//!
+//! A bundle has a flat namespace: its files are known by their bare names,
+//! since that’s how TeX asks for them. When a directory tree is added, every
+//! file that a [`DirBundle`](super::dirbundle::DirBundle) lookup of its bare
+//! name would find goes into the bundle.
+//!
+//! Both kinds of bundles get a `SHA256SUM` file holding the digest of their
+//! contents, as computed by [`digest::bundle_digest`].
+//!
+//! An indexed tar bundle is an ordinary tar file that comes with an index,
+//! stored next to it with `.index.gz` appended to its name. Each line of the
+//! index gives the name of a file, the offset of its contents in the tar
+//! file, and their length, so that the file can be fetched with an HTTP
+//! range request. Since the fields are separated by whitespace, file names
+//! can't contain any.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use zip::write::{FileOptions, ZipWriter};
+use zip::CompressionMethod;
+
+use super::dirbundle::DirBundle;
+use crate::digest::{self, Digest, DigestData};
+use crate::errors::Result;
+use crate::status::StatusBackend;
+use crate::{ctry, errmsg, tt_note, tt_warning};
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// Collects the files that should go into a bundle, and writes it out.
+#[derive(Debug, Default)]
+pub struct BundleBuilder {
+    /// Maps the names of the files in the bundle to their paths.
+    files: BTreeMap<String, PathBuf>,
+}
+
+impl BundleBuilder {
+    pub fn new() -> BundleBuilder {
+        Default::default()
+    }
+
+    /// Add the files in a directory tree, such as a TeXLive `texmf-dist`
+    /// tree. Files with names that are already in the bundle are skipped, so
+    /// trees that are added first take priority. So are files with names
+    /// containing whitespace, which bundles can't hold.
+    pub fn add_tree<P: AsRef<Path>>(
+        &mut self,
+        root: P,
+        status: &mut dyn StatusBackend,
+    ) -> Result<&mut Self> {
+        let root = root.as_ref();
+        let tree = DirBundle::new(root)?;
+        let mut n_added = 0;
+        let mut n_skipped = 0;
+        let mut n_unnamable = 0;
+
+        for (name, path) in tree.resolved_files() {
+            if name.contains(char::is_whitespace) {
+                n_unnamable += 1;
+                continue;
+            }
+
+            match self.files.entry(name) {
+                Entry::Occupied(_) => n_skipped += 1,
+                Entry::Vacant(e) => {
+                    e.insert(path);
+                    n_added += 1;
+                }
+            }
+        }
+
+        tt_note!(
+            status,
+            "added {} files from \"{}\"",
+            n_added,
+            root.display()
+        );
+
+        if n_skipped > 0 {
+            tt_note!(
+                status,
+                "skipped {} files from \"{}\" that were already in the bundle",
+                n_skipped,
+                root.display()
+            );
+        }
+
+        if n_unnamable > 0 {
+            tt_warning!(
+                status,
+                "skipped {} files from \"{}\" with whitespace in their names",
+                n_unnamable,
+                root.display()
+            );
+        }
+
+        Ok(self)
+    }
+
+    /// Add a single file, replacing any file with the same name. Writing the
+    /// bundle fails if the name contains whitespace.
+    pub fn add_file<P: AsRef<Path>>(&mut self, name: &str, path: P) -> &mut Self {
+        self.files.insert(name.to_owned(), path.as_ref().to_owned());
+        self
+    }
+
+    /// Get the number of files in the bundle, not counting `SHA256SUM`.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Read each of the files, in order, passing its name and contents to
+    /// `f`, and compute the digest of the bundle as we go.
+    fn for_each_file<F>(&self, mut f: F) -> Result<DigestData>
+    where
+        F: FnMut(&str, &[u8]) -> Result<()>,
+    {
+        let mut digests = Vec::with_capacity(self.files.len());
+
+        for (name, path) in &self.files {
+            if name == digest::DIGEST_NAME {
+                return Err(errmsg!(
+                    "bundles can't contain files named \"{}\" of their own",
+                    name
+                ));
+            }
+
+            if name.contains(char::is_whitespace) {
+                return Err(errmsg!(
+                    "bundles can't contain files with whitespace in their names, like \"{}\"",
+                    name
+                ));
+            }
+
+            let data = ctry!(fs::read(path); "couldn't read \"{}\"", path.display());
+            let mut dc = digest::create();
+            dc.input(&data);
+            digests.push((name.as_str(), DigestData::from(dc)));
+            f(name, &data)?;
+        }
+
+        Ok(digest::bundle_digest(
+            digests.iter().map(|(name, d)| (*name, d)),
+        ))
+    }
+
+    /// Compute the digest that the bundle will have.
+    pub fn digest(&self) -> Result<DigestData> {
+        self.for_each_file(|_, _| Ok(()))
+    }
+
+    /// Write the bundle as a Zip file, returning its digest.
+    pub fn write_zip<P: AsRef<Path>>(
+        &self,
+        dest: P,
+        status: &mut dyn StatusBackend,
+    ) -> Result<DigestData> {
+        let dest = dest.as_ref();
+        let f = ctry!(File::create(dest); "couldn't create \"{}\"", dest.display());
+        let mut zip = ZipWriter::new(BufWriter::new(f));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        let digest = self.for_each_file(|name, data| {
+            zip.start_file(name, options)?;
+            zip.write_all(data)?;
+            Ok(())
+        })?;
+
+        zip.start_file(digest::DIGEST_NAME, options)?;
+        zip.write_all(digest.to_string().as_bytes())?;
+        zip.finish()?.flush()?;
+
+        tt_note!(
+            status,
+            "wrote Zip bundle \"{}\" with digest {}",
+            dest.display(),
+            digest.to_string()
+        );
+        Ok(digest)
+    }
+
+    /// Write the bundle as an indexed tar file, returning its digest. The
+    /// index is written next to `dest`, with `.index.gz` appended to its
+    /// name.
+    pub fn write_itar<P: AsRef<Path>>(
+        &self,
+        dest: P,
+        status: &mut dyn StatusBackend,
+    ) -> Result<DigestData> {
+        let dest = dest.as_ref();
+        let mut index_path = dest.as_os_str().to_owned();
+        index_path.push(".index.gz");
+        let index_path = PathBuf::from(index_path);
+
+        let f = ctry!(File::create(dest); "couldn't create \"{}\"", dest.display());
+        let mut tar = TarWriter::new(BufWriter::new(f));
+        let f = ctry!(File::create(&index_path); "couldn't create \"{}\"", index_path.display());
+        let mut index = GzEncoder::new(BufWriter::new(f), Compression::best());
+
+        let digest = self.for_each_file(|name, data| {
+            let offset = tar.append(name, data)?;
+            writeln!(index, "{} {} {}", name, offset, data.len())?;
+            Ok(())
+        })?;
+
+        let text = digest.to_string();
+        let offset = tar.append(digest::DIGEST_NAME, text.as_bytes())?;
+        writeln!(index, "{} {} {}", digest::DIGEST_NAME, offset, text.len())?;
+        tar.finish()?.flush()?;
+        index.finish()?.flush()?;
+
+        tt_note!(
+            status,
+            "wrote indexed tar bundle \"{}\" with digest {}",
+            dest.display(),
+            text
+        );
+        Ok(digest)
+    }
+}
+
+/// Writes uncompressed tar files in the ustar format, using GNU long-name
+/// records for names that don't fit in the header.
+struct TarWriter<W: Write> {
+    inner: W,
+    offset: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn new(inner: W) -> Self {
+        TarWriter { inner, offset: 0 }
+    }
+
+    fn header(name: &[u8], size: usize, kind: u8) -> [u8; TAR_BLOCK_SIZE] {
+        let mut h = [0u8; TAR_BLOCK_SIZE];
+        let n = name.len().min(100);
+        h[..n].copy_from_slice(&name[..n]);
+        h[100..107].copy_from_slice(b"0000644");
+        h[108..115].copy_from_slice(b"0000000");
+        h[116..123].copy_from_slice(b"0000000");
+        h[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        // The modification time stays zero, so that the output is
+        // reproducible.
+        h[136..147].copy_from_slice(b"00000000000");
+        h[156] = kind;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+
+        h[148..156].copy_from_slice(b"        ");
+        let sum: u32 = h.iter().map(|b| u32::from(*b)).sum();
+        h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        h
+    }
+
+    fn write_member(&mut self, header: &[u8], data: &[u8]) -> Result<()> {
+        self.inner.write_all(header)?;
+        self.inner.write_all(data)?;
+        let padding = (TAR_BLOCK_SIZE - data.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+        self.inner.write_all(&[0u8; TAR_BLOCK_SIZE][..padding])?;
+        self.offset += (header.len() + data.len() + padding) as u64;
+        Ok(())
+    }
+
+    /// Add a file, returning the offset at which its contents start.
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<u64> {
+        let name = name.as_bytes();
+
+        if name.len() > 100 {
+            let mut long_name = name.to_vec();
+            long_name.push(0);
+            let header = Self::header(b"././@LongLink", long_name.len(), b'L');
+            self.write_member(&header, &long_name)?;
+        }
+
+        let header = Self::header(name, data.len(), b'0');
+        let offset = self.offset + TAR_BLOCK_SIZE as u64;
+        self.write_member(&header, data)?;
+        Ok(offset)
+    }
+
+    /// Write the end-of-archive marker: two empty blocks.
+    fn finish(mut self) -> Result<W> {
+        self.inner.write_all(&[0u8; 2 * TAR_BLOCK_SIZE])?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::tarbundle::TarBundle;
+    use crate::io::zipbundle::ZipBundle;
+    use crate::io::{Bundle, OpenResult};
+    use crate::status::NoopStatusBackend;
+    use flate2::read::GzDecoder;
+    use std::ffi::OsStr;
+    use std::io::Read;
+
+    fn read(bundle: &mut dyn Bundle, name: &str) -> Option<Vec<u8>> {
+        let mut status = NoopStatusBackend::new();
+
+        match bundle.input_open_name(OsStr::new(name), &mut status) {
+            OpenResult::Ok(mut h) => {
+                let mut data = Vec::new();
+                h.read_to_end(&mut data).unwrap();
+                Some(data)
+            }
+            OpenResult::NotAvailable => None,
+            OpenResult::Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn zip_and_itar() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let custom = tempdir.path().join("custom");
+        let texlive = tempdir.path().join("texlive");
+        let long_name = format!("{}.sty", "x".repeat(120));
+        fs::create_dir_all(&custom).unwrap();
+        fs::create_dir_all(texlive.join("tex/latex/base")).unwrap();
+        fs::write(custom.join("article.cls"), "custom").unwrap();
+        fs::write(texlive.join("tex/latex/base/article.cls"), "standard").unwrap();
+        fs::write(texlive.join("tex/latex/base/size10.clo"), vec![b'%'; 1000]).unwrap();
+        fs::write(texlive.join(&long_name), "long").unwrap();
+
+        let mut status = NoopStatusBackend::new();
+        let mut builder = BundleBuilder::new();
+        builder
+            .add_tree(&custom, &mut status)
+            .unwrap()
+            .add_tree(&texlive, &mut status)
+            .unwrap();
+        assert_eq!(builder.len(), 3);
+
+        let zip_path = tempdir.path().join("bundle.zip");
+        let tar_path = tempdir.path().join("bundle.tar");
+        let d1 = builder.write_zip(&zip_path, &mut status).unwrap();
+        let d2 = builder.write_itar(&tar_path, &mut status).unwrap();
+        assert_eq!(d1, d2);
+        assert_eq!(d1, builder.digest().unwrap());
+
+        let mut zip = ZipBundle::open(&zip_path).unwrap();
+        let mut tar = TarBundle::open(&tar_path).unwrap();
+
+        for bundle in &mut [&mut zip as &mut dyn Bundle, &mut tar as &mut dyn Bundle] {
+            assert_eq!(bundle.get_digest(&mut status).unwrap(), d1);
+            assert_eq!(read(&mut **bundle, "article.cls").unwrap(), b"custom");
+            assert_eq!(read(&mut **bundle, "size10.clo").unwrap().len(), 1000);
+            assert_eq!(read(&mut **bundle, &long_name).unwrap(), b"long");
+        }
+
+        // Check that the index points at the right bytes.
+        let data = fs::read(&tar_path).unwrap();
+        let mut index = String::new();
+        GzDecoder::new(File::open(tempdir.path().join("bundle.tar.index.gz")).unwrap())
+            .read_to_string(&mut index)
+            .unwrap();
+        assert_eq!(index.lines().count(), 4);
+
+        for line in index.lines() {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let offset: usize = fields[1].parse().unwrap();
+            let length: usize = fields[2].parse().unwrap();
+            assert_eq!(
+                &data[offset..offset + length],
+                &read(&mut tar, fields[0]).unwrap()[..]
+            );
+        }
+
+        // The digest depends on the contents.
+        fs::write(custom.join("article.cls"), "changed").unwrap();
+        assert_ne!(builder.digest().unwrap(), d1);
+    }
+
+    #[test]
+    fn whitespace_names() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let tree = tempdir.path().join("tree");
+        fs::create_dir_all(&tree).unwrap();
+        fs::write(tree.join("my file.tex"), "spaced").unwrap();
+        fs::write(tree.join("plain.tex"), "plain").unwrap();
+
+        let mut status = NoopStatusBackend::new();
+        let mut builder = BundleBuilder::new();
+        builder.add_tree(&tree, &mut status).unwrap();
+        assert_eq!(builder.len(), 1);
+
+        let tar_path = tempdir.path().join("bundle.tar");
+        builder.write_itar(&tar_path, &mut status).unwrap();
+        let mut tar = TarBundle::open(&tar_path).unwrap();
+        assert_eq!(read(&mut tar, "plain.tex").unwrap(), b"plain");
+
+        builder.add_file("my\tfile.tex", tree.join("my file.tex"));
+        assert!(builder.digest().is_err());
+        assert!(builder.write_itar(&tar_path, &mut status).is_err());
+    }
+}
@@ -0,0 +1,646 @@
+// src/io/bundle_cache.rs -- maintenance of the local cache of web bundles
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Maintenance of the local cache used by `CachedITarBundle`.
+//!
+//! The cache consists of several directories under the cache root:
+//!
+//! - `urls` holds, for each bundle URL, the digest of the bundle that it
+//!   pointed to the last time we checked;
+//! - `redirects`, `indexes`, and `manifests` hold, for each bundle digest, the
+//!   resolved URL of its tar file, its index, and the list of the files that
+//!   we've downloaded from it along with their own digests;
+//! - `missing` holds, for each bundle digest, the names that the engines
+//!   have asked for but the bundle doesn't have;
+//! - `files` holds the contents of the downloaded files, named by their
+//!   digests, so that bundles share unchanged files.
+//!
+//! Nothing is removed from the cache in the course of normal operation, so it
+//! grows with every bundle update. The `BundleCache` type works out which
+//! parts are still in use and removes the rest. A file that has been saved
+//! but not yet entered in a manifest looks like it's unused, so bundles hold
+//! a shared lock on the `lock` file in `files` while they have such files,
+//! and `BundleCache` waits for an exclusive lock before deleting anything.
+
+use fs2::FileExt;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use super::cached_itarbundle::{cache_dir, lock_cache, make_txt_path, parse_manifest_line};
+use super::dirbundle::digest_of_file;
+use crate::ctry;
+use crate::digest::{self, DigestData};
+use crate::errors::{Result, ResultExt};
+
+/// A bundle that has something in the cache.
+#[derive(Clone, Debug)]
+pub struct CachedBundleInfo {
+    /// The digest of the bundle, in hex.
+    pub digest: String,
+
+    /// The URLs that point to this bundle, in the sanitized form used to
+    /// name the cache files. A bundle that no URL points to anymore has been
+    /// superseded by an update.
+    pub urls: Vec<String>,
+
+    /// The number of files from this bundle in the cache.
+    pub n_files: usize,
+
+    /// The total size of those files.
+    pub size: u64,
+
+    /// When we last downloaded a file from this bundle, if ever.
+    pub last_download: Option<SystemTime>,
+}
+
+impl CachedBundleInfo {
+    /// Whether some URL still points to this bundle.
+    pub fn is_current(&self) -> bool {
+        !self.urls.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheProblemKind {
+    /// The manifest lists a file that isn't in the cache.
+    Missing,
+
+    /// The cached file doesn't have the digest given in the manifest.
+    Corrupt,
+}
+
+/// A problem found by `BundleCache::verify`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CacheProblem {
+    /// The digest of the bundle whose manifest lists the file.
+    pub bundle: String,
+
+    /// The name of the file in the bundle.
+    pub name: String,
+
+    pub kind: CacheProblemKind,
+}
+
+/// What a cleanup of the cache removed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CleanupStats {
+    /// The number of bundles whose records were removed.
+    pub bundles: usize,
+
+    /// The number of cached files that were removed.
+    pub files: usize,
+
+    /// The total size of those files.
+    pub bytes: u64,
+}
+
+impl CleanupStats {
+    /// Add up the results of two cleanups.
+    pub fn add(&mut self, other: CleanupStats) {
+        self.bundles += other.bundles;
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+/// An entry of a manifest: a file name, its length, and its digest.
+type ManifestEntry = (String, u64, DigestData);
+
+pub struct BundleCache {
+    urls: PathBuf,
+    redirects: PathBuf,
+    indexes: PathBuf,
+    manifests: PathBuf,
+    missing: PathBuf,
+    files: PathBuf,
+}
+
+impl BundleCache {
+    /// Open the cache in the usual place, or under `custom_cache_root` if it
+    /// is given, as with `CachedITarBundle::new`.
+    pub fn new(custom_cache_root: Option<&Path>) -> Result<BundleCache> {
+        Ok(BundleCache {
+            urls: cache_dir("urls", custom_cache_root)?,
+            redirects: cache_dir("redirects", custom_cache_root)?,
+            indexes: cache_dir("indexes", custom_cache_root)?,
+            manifests: cache_dir("manifests", custom_cache_root)?,
+            missing: cache_dir("missing", custom_cache_root)?,
+            files: cache_dir("files", custom_cache_root)?,
+        })
+    }
+
+    /// The path of a cached file. Unlike `DigestData::create_two_part_path`,
+    /// this doesn't create any directories.
+    fn data_path(&self, digest: &DigestData) -> PathBuf {
+        let text = digest.to_string();
+        self.files.join(&text[..2]).join(&text[2..])
+    }
+
+    /// Map bundle digests to the URLs that point to them.
+    fn url_targets(&self) -> Result<HashMap<String, Vec<String>>> {
+        let mut targets: HashMap<String, Vec<String>> = HashMap::new();
+
+        for (name, path) in list_dir(&self.urls)? {
+            let mut text = String::with_capacity(digest::DIGEST_LEN);
+
+            if let Ok(f) = File::open(&path) {
+                let _ = f.take(digest::DIGEST_LEN as u64).read_to_string(&mut text);
+            }
+
+            if DigestData::from_str(&text).is_ok() {
+                targets.entry(text).or_default().push(name);
+            }
+        }
+
+        for urls in targets.values_mut() {
+            urls.sort();
+        }
+
+        Ok(targets)
+    }
+
+    /// List the bundles that have anything in the cache, sorted by digest.
+    pub fn bundles(&self) -> Result<Vec<CachedBundleInfo>> {
+        let mut targets = self.url_targets()?;
+        let mut digests: HashSet<String> = targets.keys().cloned().collect();
+
+        for dir in &[
+            &self.redirects,
+            &self.indexes,
+            &self.manifests,
+            &self.missing,
+        ] {
+            for (name, _) in list_dir(dir)? {
+                if name.ends_with(".txt") {
+                    let d = &name[..name.len() - 4];
+
+                    if DigestData::from_str(d).is_ok() {
+                        digests.insert(d.to_owned());
+                    }
+                }
+            }
+        }
+
+        let mut digests: Vec<_> = digests.into_iter().collect();
+        digests.sort();
+        let mut bundles = Vec::with_capacity(digests.len());
+
+        for digest in digests {
+            let manifest_path = make_txt_path(&self.manifests, &digest);
+            let entries = read_manifest(&manifest_path)?;
+            let last_download = fs::metadata(&manifest_path)
+                .and_then(|md| md.modified())
+                .ok();
+
+            let mut seen = HashSet::new();
+            let size = entries
+                .iter()
+                .filter(|(_, _, d)| seen.insert(*d))
+                .map(|(_, length, _)| length)
+                .sum();
+
+            bundles.push(CachedBundleInfo {
+                urls: targets.remove(&digest).unwrap_or_default(),
+                digest,
+                n_files: entries.len(),
+                size,
+                last_download,
+            });
+        }
+
+        Ok(bundles)
+    }
+
+    /// The total size of the cached files.
+    pub fn files_size(&self) -> Result<u64> {
+        Ok(self.cached_files()?.iter().map(|(_, _, size)| size).sum())
+    }
+
+    /// List the cached files, with their digests and sizes. Anything in the
+    /// file store that isn't named like a digest isn't ours, so it is left
+    /// out.
+    fn cached_files(&self) -> Result<Vec<(DigestData, PathBuf, u64)>> {
+        let mut files = Vec::new();
+
+        for (prefix, dir) in list_dir(&self.files)? {
+            if prefix.len() != 2 || !dir.is_dir() {
+                continue;
+            }
+
+            for (rest, path) in list_dir(&dir)? {
+                if let Ok(digest) = DigestData::from_str(&format!("{}{}", prefix, rest)) {
+                    let size = fs::metadata(&path)?.len();
+                    files.push((digest, path, size));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Check that every file listed in a manifest is in the cache with the
+    /// right digest. This reads the whole cache, so it's slow. If `repair`
+    /// is true, bad files are deleted and removed from the manifests, so
+    /// that they will be downloaded again when they are next needed.
+    pub fn verify(&self, repair: bool) -> Result<Vec<CacheProblem>> {
+        let mut problems = Vec::new();
+        let mut checked = HashMap::new();
+
+        for bundle in self.bundles()? {
+            let manifest_path = make_txt_path(&self.manifests, &bundle.digest);
+            let mut bad = HashSet::new();
+
+            for (name, _, digest) in read_manifest(&manifest_path)? {
+                // Bundles share files, so remember what we've already seen.
+                let kind = match checked.get(&digest) {
+                    Some(k) => *k,
+                    None => {
+                        let path = self.data_path(&digest);
+
+                        let kind = match digest_of_file(&path)? {
+                            None => Some(CacheProblemKind::Missing),
+                            Some(d) if d != digest => Some(CacheProblemKind::Corrupt),
+                            Some(_) => None,
+                        };
+
+                        if repair && kind == Some(CacheProblemKind::Corrupt) {
+                            remove_file(&path)?;
+                        }
+
+                        checked.insert(digest, kind);
+                        kind
+                    }
+                };
+
+                if let Some(kind) = kind {
+                    bad.insert(name.clone());
+                    problems.push(CacheProblem {
+                        bundle: bundle.digest.clone(),
+                        name,
+                        kind,
+                    });
+                }
+            }
+
+            if repair && !bad.is_empty() {
+                rewrite_manifest(&manifest_path, |name| !bad.contains(name))?;
+            }
+        }
+
+        Ok(problems)
+    }
+
+    /// Lock the cache against bundles that are downloading files, which
+    /// aren't in any manifest until the bundle is flushed.
+    fn lock(&self) -> Result<File> {
+        lock_cache(&self.files, true)
+    }
+
+    /// Delete the cached files that no manifest refers to. This waits for
+    /// the bundles that are downloading files to record them.
+    pub fn gc(&self) -> Result<CleanupStats> {
+        let _lock = self.lock()?;
+        self.gc_locked()
+    }
+
+    fn gc_locked(&self) -> Result<CleanupStats> {
+        let mut used = HashSet::new();
+
+        for (_, path) in list_dir(&self.manifests)? {
+            for (_, _, digest) in read_manifest(&path)? {
+                used.insert(digest);
+            }
+        }
+
+        let mut stats = CleanupStats::default();
+
+        for (digest, path, size) in self.cached_files()? {
+            if !used.contains(&digest) {
+                remove_file(&path)?;
+                stats.files += 1;
+                stats.bytes += size;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Remove every record of a bundle from the cache. The files that it
+    /// shares with other bundles aren't touched; `gc` gets rid of the rest.
+    fn remove_bundle(&self, digest: &str) -> Result<()> {
+        for dir in &[
+            &self.manifests,
+            &self.missing,
+            &self.indexes,
+            &self.redirects,
+        ] {
+            let path = make_txt_path(dir, digest);
+
+            match fs::remove_file(&path) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).chain_err(|| format!("couldn't remove \"{}\"", path.display()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the bundles that no URL points to anymore, which are left
+    /// behind when a bundle is updated, and then the files that only they
+    /// used.
+    pub fn prune(&self) -> Result<CleanupStats> {
+        let _lock = self.lock()?;
+        let mut stats = CleanupStats::default();
+
+        for bundle in self.bundles()? {
+            if !bundle.is_current() {
+                self.remove_bundle(&bundle.digest)?;
+                stats.bundles += 1;
+            }
+        }
+
+        stats.add(self.gc_locked()?);
+        Ok(stats)
+    }
+
+    /// Remove bundles from the cache until the cached files take up at most
+    /// `max_size` bytes. Bundles that no URL points to go first, then the
+    /// others, starting with those that we've downloaded from least
+    /// recently. For a current bundle only the manifest is removed, so that
+    /// the bundle can still be used: its files are downloaded again as they
+    /// are needed.
+    pub fn limit_size(&self, max_size: u64) -> Result<CleanupStats> {
+        let _lock = self.lock()?;
+        let mut stats = self.gc_locked()?;
+        let mut bundles = self.bundles()?;
+        bundles.retain(|b| b.n_files > 0 || !b.is_current());
+        bundles.sort_by_key(|b| (b.is_current(), b.last_download));
+        let mut bundles = bundles.into_iter();
+
+        while self.files_size()? > max_size {
+            let bundle = match bundles.next() {
+                Some(b) => b,
+                None => break,
+            };
+
+            if bundle.is_current() {
+                let path = make_txt_path(&self.manifests, &bundle.digest);
+                remove_file(&path)?;
+            } else {
+                self.remove_bundle(&bundle.digest)?;
+            }
+
+            stats.bundles += 1;
+            stats.add(self.gc_locked()?);
+        }
+
+        Ok(stats)
+    }
+}
+
+/// List a directory as (file name, path) pairs, sorted by name. A missing
+/// directory is empty.
+fn list_dir(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).chain_err(|| format!("couldn't read \"{}\"", dir.display())),
+    };
+
+    let mut items = Vec::new();
+
+    for entry in entries {
+        let entry = ctry!(entry; "couldn't read \"{}\"", dir.display());
+
+        if let Ok(name) = entry.file_name().into_string() {
+            items.push((name, entry.path()));
+        }
+    }
+
+    items.sort();
+    Ok(items)
+}
+
+/// Read the usable entries of a manifest. A missing manifest is empty.
+fn read_manifest(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).chain_err(|| format!("couldn't open \"{}\"", path.display())),
+    };
+
+    let mut entries = Vec::new();
+
+    for line in BufReader::new(f).lines() {
+        let line = ctry!(line; "couldn't read \"{}\"", path.display());
+
+        if let Some((name, length, digest)) = parse_manifest_line(&line) {
+            if let Ok(digest) = DigestData::from_str(digest) {
+                entries.push((name.to_owned(), length, digest));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Rewrite a manifest, keeping only the lines for files whose names pass
+/// the filter. We hold the lock throughout, since `CachedITarBundle` might
+/// be appending to the manifest at the same time.
+fn rewrite_manifest<F: Fn(&str) -> bool>(path: &Path, keep: F) -> Result<()> {
+    let mut f = ctry!(fs::OpenOptions::new().read(true).write(true).open(path);
+                      "couldn't open \"{}\"", path.display());
+    ctry!(f.lock_exclusive(); "failed to lock manifest file \"{}\" for writing", path.display());
+
+    let mut text = String::new();
+    f.read_to_string(&mut text)?;
+
+    let mut new_text = String::with_capacity(text.len());
+
+    for line in text.lines() {
+        // Lines that we can't parse aren't ours to judge.
+        let wanted = match parse_manifest_line(line) {
+            Some((name, _, _)) => keep(name),
+            None => true,
+        };
+
+        if wanted {
+            new_text.push_str(line);
+            new_text.push('\n');
+        }
+    }
+
+    f.set_len(0)?;
+    f.seek(SeekFrom::Start(0))?;
+    ctry!(f.write_all(new_text.as_bytes()); "couldn't write to \"{}\"", path.display());
+    Ok(())
+}
+
+/// Remove a file from the cache.
+fn remove_file(path: &Path) -> Result<()> {
+    // Cached files are read-only, which keeps Windows from deleting them.
+    #[cfg(windows)]
+    {
+        if let Ok(md) = fs::metadata(path) {
+            let mut perms = md.permissions();
+            perms.set_readonly(false);
+            let _ = fs::set_permissions(path, perms);
+        }
+    }
+
+    ctry!(fs::remove_file(path); "couldn't remove \"{}\"", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Digest;
+
+    struct TestCache {
+        dir: tempfile::TempDir,
+        cache: BundleCache,
+    }
+
+    impl TestCache {
+        fn new() -> TestCache {
+            let dir = tempfile::tempdir().unwrap();
+            let cache = BundleCache::new(Some(dir.path())).unwrap();
+            TestCache { dir, cache }
+        }
+
+        /// Set up a bundle with the given files, all of them downloaded.
+        fn add_bundle(&self, digest: &str, url: Option<&str>, files: &[(&str, &[u8])]) {
+            let c = &self.cache;
+
+            if let Some(url) = url {
+                fs::write(c.urls.join(url), format!("{}\n", digest)).unwrap();
+            }
+
+            fs::write(make_txt_path(&c.indexes, digest), "").unwrap();
+            fs::write(make_txt_path(&c.redirects, digest), "http://example/").unwrap();
+            let mut manifest = String::new();
+
+            for (name, content) in files {
+                let mut dc = digest::create();
+                dc.input(content);
+                let d = DigestData::from(dc);
+                fs::write(d.create_two_part_path(&c.files).unwrap(), content).unwrap();
+                manifest.push_str(&format!("{} {} {}\n", name, content.len(), d.to_string()));
+            }
+
+            fs::write(make_txt_path(&c.manifests, digest), manifest).unwrap();
+            fs::write(make_txt_path(&c.missing, digest), "missing.sty\n").unwrap();
+        }
+    }
+
+    fn digest_text(c: char) -> String {
+        c.to_string().repeat(digest::DIGEST_LEN)
+    }
+
+    #[test]
+    fn gc_waits_for_downloads() {
+        let t = TestCache::new();
+        let d = DigestData::from(digest::create());
+        let path = d.create_two_part_path(&t.cache.files).unwrap();
+
+        // A bundle has downloaded a file, but not recorded it yet.
+        let download = lock_cache(&t.cache.files, false).unwrap();
+        fs::write(&path, "").unwrap();
+
+        let cache = BundleCache::new(Some(t.dir.path())).unwrap();
+        let gc = std::thread::spawn(move || cache.gc().unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(path.exists());
+
+        // Had the bundle recorded it, it would be kept; it didn't.
+        drop(download);
+        assert_eq!(gc.join().unwrap().files, 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn list_prune_and_limit() {
+        let t = TestCache::new();
+        let (old, new) = (digest_text('0'), digest_text('1'));
+        t.add_bundle(&old, None, &[("a.tex", b"a"), ("b.tex", b"old b")]);
+        t.add_bundle(&new, Some("url"), &[("a.tex", b"a"), ("b.tex", b"new b")]);
+
+        let bundles = t.cache.bundles().unwrap();
+        assert_eq!(bundles.len(), 2);
+        assert_eq!(bundles[0].digest, old);
+        assert!(!bundles[0].is_current());
+        assert_eq!(bundles[1].urls, vec!["url".to_owned()]);
+        assert_eq!((bundles[1].n_files, bundles[1].size), (2, 6));
+        assert_eq!(t.cache.files_size().unwrap(), 11);
+
+        // Everything is in use.
+        assert_eq!(t.cache.gc().unwrap(), CleanupStats::default());
+
+        let stats = t.cache.prune().unwrap();
+        assert_eq!(
+            stats,
+            CleanupStats {
+                bundles: 1,
+                files: 1,
+                bytes: 5
+            }
+        );
+        assert_eq!(t.cache.bundles().unwrap().len(), 1);
+        assert!(!make_txt_path(&t.cache.missing, &old).exists());
+        assert_eq!(t.cache.files_size().unwrap(), 6);
+
+        assert_eq!(t.cache.limit_size(6).unwrap(), CleanupStats::default());
+        let stats = t.cache.limit_size(5).unwrap();
+        assert_eq!((stats.bundles, stats.files), (1, 2));
+        assert_eq!(t.cache.files_size().unwrap(), 0);
+
+        // The bundle can still be used, without its manifest.
+        let bundles = t.cache.bundles().unwrap();
+        assert_eq!((bundles.len(), bundles[0].n_files), (1, 0));
+        assert!(make_txt_path(&t.cache.indexes, &new).exists());
+    }
+
+    #[test]
+    fn verify() {
+        let t = TestCache::new();
+        let digest = digest_text('2');
+        t.add_bundle(
+            &digest,
+            Some("url"),
+            &[("a.tex", b"a"), ("b.tex", b"b"), ("c.tex", b"c")],
+        );
+        assert_eq!(t.cache.verify(false).unwrap(), Vec::new());
+
+        let path_of = |content: &[u8]| {
+            let mut dc = digest::create();
+            dc.input(content);
+            t.cache.data_path(&DigestData::from(dc))
+        };
+        remove_file(&path_of(b"a")).unwrap();
+        remove_file(&path_of(b"b")).unwrap();
+        fs::write(path_of(b"b"), "corrupted").unwrap();
+
+        let problems = t.cache.verify(true).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].name, "a.tex");
+        assert_eq!(problems[0].kind, CacheProblemKind::Missing);
+        assert_eq!(problems[1].name, "b.tex");
+        assert_eq!(problems[1].kind, CacheProblemKind::Corrupt);
+        assert!(!path_of(b"b").exists());
+
+        // The bad entries are gone from the manifest.
+        assert_eq!(t.cache.verify(false).unwrap(), Vec::new());
+        let manifest = fs::read_to_string(make_txt_path(&t.cache.manifests, &digest)).unwrap();
+        assert!(manifest.starts_with("c.tex 1 "));
+        assert_eq!(manifest.lines().count(), 1);
+    }
+}
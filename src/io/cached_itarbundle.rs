@@ -5,25 +5,45 @@
 use error_chain::bail;
 use flate2::read::GzDecoder;
 use fs2::FileExt;
-use reqwest::{header::HeaderMap, Client, RedirectPolicy, Response, StatusCode};
-use std::collections::HashMap;
+use reqwest::header::{HeaderMap, LOCATION};
+use reqwest::{Client, RedirectPolicy, Response, StatusCode, Url};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::ErrorKind as IoErrorKind;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
 use super::{try_open_file, Bundle, InputHandle, InputOrigin, IoProvider, OpenResult};
 use crate::app_dirs;
+use crate::config::HttpSettings;
 use crate::digest::{self, Digest, DigestData};
 use crate::errors::{Error, ErrorKind, Result, ResultExt};
 use crate::status::StatusBackend;
-use crate::{ctry, tt_note, tt_warning};
+use crate::{ctry, errmsg, tt_note, tt_warning};
 
 const MAX_HTTP_REDIRECTS_ALLOWED: usize = 10;
 const MAX_HTTP_ATTEMPTS: usize = 4;
 
+/// When prefetching, files that are at most this many bytes apart in the tar
+/// file are downloaded with a single range request, since fetching the few
+/// bytes in between costs less than another round trip ...
+const MAX_PREFETCH_GAP: u64 = 64 * 1024;
+
+/// ... as long as the request doesn't get bigger than this.
+const MAX_PREFETCH_REQUEST_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How many range requests we make at the same time.
+const MAX_PARALLEL_DOWNLOADS: usize = 4;
+
+/// When we download a file, we also get the files that have the same name
+/// with a different extension, up to this many bytes in all.
+const MAX_COMPANIONS_SIZE: u64 = 256 * 1024;
+
 /// A simple way to read chunks out of a big seekable byte stream. You could
 /// implement this for io::File pretty trivially but that's not currently
 /// needed.
@@ -31,22 +51,27 @@ const MAX_HTTP_ATTEMPTS: usize = 4;
 pub struct HttpRangeReader {
     url: String,
     client: Client,
+    headers: HeaderMap,
 }
 
 impl HttpRangeReader {
-    pub fn new(url: &str) -> HttpRangeReader {
-        HttpRangeReader {
+    /// Create a reader for `url`, which belongs to the bundle that lives at
+    /// `bundle_urls`, using the given HTTP settings. See
+    /// `HttpSettings::headers_for`.
+    pub fn new(url: &str, bundle_urls: &[String], http: &HttpSettings) -> Result<HttpRangeReader> {
+        Ok(HttpRangeReader {
             url: url.to_owned(),
-            client: Client::new(),
-        }
+            client: http.client()?,
+            headers: http.headers_for(bundle_urls, url)?,
+        })
     }
 }
 
 impl HttpRangeReader {
-    fn read_range(&mut self, offset: u64, length: usize) -> Result<Response> {
+    fn read_range(&self, offset: u64, length: usize) -> Result<Response> {
         let end_inclusive = offset + length as u64 - 1;
 
-        let mut headers = HeaderMap::new();
+        let mut headers = self.headers.clone();
         use headers::HeaderMapExt;
         headers.typed_insert(headers::Range::bytes(offset..=end_inclusive).unwrap());
 
@@ -64,24 +89,74 @@ impl HttpRangeReader {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 struct FileInfo {
     offset: u64,
     length: u64,
 }
 
+/// Files that are fetched from the tar file with a single range request.
+struct RangeGroup {
+    start: u64,
+    end: u64,
+    files: Vec<(String, FileInfo)>,
+}
+
+impl RangeGroup {
+    fn description(&self) -> String {
+        if self.files.len() == 1 {
+            self.files[0].0.clone()
+        } else {
+            format!("{} files", self.files.len())
+        }
+    }
+}
+
+/// Why a download failed: the server or the network let us down, which
+/// another mirror might fix, or we couldn't save what we got, which it
+/// won't.
+enum DownloadError {
+    Network(Error),
+    Local(Error),
+}
+
+impl DownloadError {
+    /// Add some context to the error, keeping its kind.
+    fn context(self, msg: String) -> DownloadError {
+        match self {
+            DownloadError::Network(e) => DownloadError::Network(Error::with_chain(e, msg)),
+            DownloadError::Local(e) => DownloadError::Local(Error::with_chain(e, msg)),
+        }
+    }
+}
+
+/// What the download threads tell the thread that's running the show, which
+/// is the one that can talk to the status backend.
+enum DownloadEvent {
+    Note(String),
+    Warning(String, Error),
+    Done(std::result::Result<Vec<(String, u64, DigestData)>, DownloadError>),
+}
+
 #[derive(Clone, Copy, Debug)]
 struct LocalCacheItem {
     _length: u64,
     digest: DigestData,
 }
 
-fn get_index(url: &str, status: &mut dyn StatusBackend) -> Result<GzDecoder<Response>> {
-    let index_url = format!("{}.index.gz", url);
+fn get_index(
+    data: &HttpRangeReader,
+    status: &mut dyn StatusBackend,
+) -> Result<GzDecoder<Response>> {
+    let index_url = format!("{}.index.gz", data.url);
 
     tt_note!(status, "downloading index {}", index_url);
 
-    let res = Client::new().get(&index_url).send()?;
+    let res = data
+        .client
+        .get(&index_url)
+        .headers(data.headers.clone())
+        .send()?;
     if !res.status().is_success() {
         return Err(Error::from(ErrorKind::UnexpectedHttpResponse(
             index_url.clone(),
@@ -93,41 +168,67 @@ fn get_index(url: &str, status: &mut dyn StatusBackend) -> Result<GzDecoder<Resp
     Ok(GzDecoder::new(res))
 }
 
-fn resolve_url(url: &str, status: &mut dyn StatusBackend) -> Result<String> {
+fn resolve_url(
+    url: &str,
+    bundle_urls: &[String],
+    http: &HttpSettings,
+    status: &mut dyn StatusBackend,
+) -> Result<String> {
     tt_note!(status, "connecting to {}", url);
 
     // First, we actually do a HEAD request on the URL for the data file.
     // If it's redirected, we update our URL to follow the redirects. If
     // we didn't do this separately, the index file would have to be the
     // one with the redirect setup, which would be confusing and annoying.
+    //
+    // We follow the redirects ourselves, so that we can decide which
+    // headers go with each request: the credentials are for the bundle's
+    // servers, not whichever ones they send us to.
+
+    let client = http
+        .client_builder()?
+        .redirect(RedirectPolicy::none())
+        .build()?;
+    let mut current = ctry!(Url::parse(url); "invalid bundle URL \"{}\"", url);
+    let mut n_redirects = 0;
+
+    let res = loop {
+        let res = client
+            .head(current.clone())
+            .headers(http.headers_for(bundle_urls, current.as_str())?)
+            .send()?;
+
+        if !res.status().is_redirection() {
+            break res;
+        }
+
+        let next = match res.headers().get(LOCATION).and_then(|l| l.to_str().ok()) {
+            Some(l) => ctry!(current.join(l); "bad redirect from {} to \"{}\"", current, l),
+            None => break res,
+        };
+
+        if n_redirects >= MAX_HTTP_REDIRECTS_ALLOWED {
+            bail!("too many redirects from {}", url);
+        }
 
-    let redirect_policy = RedirectPolicy::custom(|attempt| {
         // In the process of resolving the file url it might be neccesary
         // to stop at a certain level of redirection. This might be required
         // because some hosts might redirect to a version of the url where
         // it isn't possible to select the index file by appending .index.gz.
         // (This mostly happens because CDNs redirect to the file hash.)
-        if attempt.previous().len() >= MAX_HTTP_REDIRECTS_ALLOWED {
-            attempt.too_many_redirects()
-        } else if let Some(segments) = attempt.url().clone().path_segments() {
-            let follow = segments
-                .last()
-                .map(|file| file.contains('.'))
-                .unwrap_or(true);
-            if follow {
-                attempt.follow()
-            } else {
-                attempt.stop()
-            }
-        } else {
-            attempt.follow()
+        let follow = next
+            .path_segments()
+            .and_then(|segments| segments.last())
+            .map(|file| file.contains('.'))
+            .unwrap_or(true);
+
+        if !follow {
+            break res;
         }
-    });
-    let res = Client::builder()
-        .redirect(redirect_policy)
-        .build()?
-        .head(url)
-        .send()?;
+
+        n_redirects += 1;
+        current = next;
+    };
 
     if !(res.status().is_success() || res.status() == StatusCode::FOUND) {
         return Err(Error::from(ErrorKind::UnexpectedHttpResponse(
@@ -137,7 +238,7 @@ fn resolve_url(url: &str, status: &mut dyn StatusBackend) -> Result<String> {
         .chain_err(|| "couldn\'t probe".to_string());
     }
 
-    let final_url = res.url().clone().into_string();
+    let final_url = current.into_string();
 
     if final_url != url {
         tt_note!(status, "resolved to {}", final_url);
@@ -148,7 +249,7 @@ fn resolve_url(url: &str, status: &mut dyn StatusBackend) -> Result<String> {
 
 /// Attempts to download a file from the bundle.
 fn get_file(
-    data: &mut HttpRangeReader,
+    data: &HttpRangeReader,
     name: &str,
     offset: u64,
     length: usize,
@@ -172,6 +273,8 @@ fn get_file(
     let mut any_failed = false;
 
     for _ in 0..MAX_HTTP_ATTEMPTS {
+        buf.clear();
+
         let mut stream = match data.read_range(offset, length) {
             Ok(r) => r,
             Err(e) => {
@@ -205,6 +308,242 @@ fn get_file(
     Ok(buf)
 }
 
+/// Download groups of files, several groups at a time, streaming the files
+/// into the cache as they arrive. Returns the files that we got, with their
+/// lengths and digests, and the first error that we ran into, if any.
+fn download_groups(
+    data: &HttpRangeReader,
+    data_base: &Path,
+    groups: Vec<RangeGroup>,
+    status: &mut dyn StatusBackend,
+) -> (Vec<(String, u64, DigestData)>, Option<DownloadError>) {
+    let n_workers = groups.len().min(MAX_PARALLEL_DOWNLOADS);
+    let queue = Arc::new(Mutex::new(groups.into_iter()));
+    let (tx, rx) = mpsc::channel();
+    let mut workers = Vec::with_capacity(n_workers);
+
+    for _ in 0..n_workers {
+        let data = data.clone();
+        let data_base = data_base.to_owned();
+        let queue = queue.clone();
+        let tx = tx.clone();
+
+        workers.push(thread::spawn(move || loop {
+            let group = match queue.lock().unwrap().next() {
+                Some(g) => g,
+                None => break,
+            };
+
+            let result = download_group(&data, &data_base, &group, &tx);
+
+            if tx.send(DownloadEvent::Done(result)).is_err() {
+                break;
+            }
+        }));
+    }
+
+    // The loop below ends once all of the workers are done with their
+    // copies of the sender.
+    drop(tx);
+
+    let mut results = Vec::new();
+    let mut error = None;
+
+    for event in rx {
+        match event {
+            DownloadEvent::Note(msg) => tt_note!(status, "{}", msg),
+            DownloadEvent::Warning(msg, e) => tt_warning!(status, "{}", msg; e),
+            DownloadEvent::Done(Ok(mut r)) => results.append(&mut r),
+            DownloadEvent::Done(Err(e)) => {
+                if error.is_none() {
+                    error = Some(e);
+                }
+            }
+        }
+    }
+
+    for worker in workers {
+        if worker.join().is_err() && error.is_none() {
+            error = Some(DownloadError::Local(errmsg!("a download thread panicked")));
+        }
+    }
+
+    (results, error)
+}
+
+/// Download a group of files, retrying as `get_file` does. There's no point
+/// in retrying if we can't save the files.
+fn download_group(
+    data: &HttpRangeReader,
+    data_base: &Path,
+    group: &RangeGroup,
+    events: &mpsc::Sender<DownloadEvent>,
+) -> std::result::Result<Vec<(String, u64, DigestData)>, DownloadError> {
+    let desc = group.description();
+    let _ = events.send(DownloadEvent::Note(format!("downloading {}", desc)));
+    let mut any_failed = false;
+
+    for _ in 0..MAX_HTTP_ATTEMPTS {
+        match stream_group(data, data_base, group) {
+            Ok(r) => {
+                if any_failed {
+                    let msg = "download succeeded after retry".to_owned();
+                    let _ = events.send(DownloadEvent::Note(msg));
+                }
+
+                return Ok(r);
+            }
+
+            Err(DownloadError::Network(e)) => {
+                let msg = format!("failure downloading \"{}\" from network", desc);
+                let _ = events.send(DownloadEvent::Warning(msg, e));
+                any_failed = true;
+            }
+
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(DownloadError::Network(errmsg!(
+        "failed to retrieve \"{}\" from the network; \
+         this most probably is not Tectonic's fault \
+         -- please check your network connection.",
+        desc
+    )))
+}
+
+/// Request the range of a group of files and save the files into the cache
+/// as they come in, so that we never hold more than a buffer's worth of
+/// data in memory.
+fn stream_group(
+    data: &HttpRangeReader,
+    data_base: &Path,
+    group: &RangeGroup,
+) -> std::result::Result<Vec<(String, u64, DigestData)>, DownloadError> {
+    use DownloadError::{Local, Network};
+
+    let mut stream = data
+        .read_range(group.start, (group.end - group.start) as usize)
+        .map_err(Network)?;
+    let mut pos = group.start;
+    let mut results: Vec<(String, u64, DigestData)> = Vec::with_capacity(group.files.len());
+    let mut last: Option<(FileInfo, DigestData)> = None;
+
+    for (name, info) in &group.files {
+        // Several names can point at the same data.
+        if let Some((last_info, digest)) = last {
+            if last_info == *info {
+                results.push((name.clone(), info.length, digest));
+                continue;
+            }
+        }
+
+        // All of the mirrors use the same index.
+        if info.offset < pos {
+            return Err(Local(errmsg!(
+                "the bundle index has overlapping entries at \"{}\"",
+                name
+            )));
+        }
+
+        let gap = info.offset - pos;
+        let skipped = io::copy(&mut (&mut stream).take(gap), &mut io::sink())
+            .map_err(|e| Network(e.into()))?;
+
+        if skipped != gap {
+            return Err(Network(errmsg!(
+                "the server sent too little data for \"{}\"",
+                name
+            )));
+        }
+
+        let digest = save_to_cache(data_base, &mut (&mut stream).take(info.length), info.length)
+            .map_err(|e| e.context(format!("couldn't save \"{}\"", name)))?;
+
+        pos = info.offset + info.length;
+        last = Some((*info, digest));
+        results.push((name.clone(), info.length, digest));
+    }
+
+    Ok(results)
+}
+
+/// Save a file into the cache, computing its digest as we go. We write to
+/// a temporary file first, so that a failed download can't leave a bad
+/// file at the path for some digest.
+fn save_to_cache<R: Read>(
+    data_base: &Path,
+    reader: &mut R,
+    length: u64,
+) -> std::result::Result<DigestData, DownloadError> {
+    use DownloadError::{Local, Network};
+
+    let mut temp = tempfile::NamedTempFile::new_in(data_base)
+        .chain_err(|| {
+            format!(
+                "couldn't create a temporary file in {}",
+                data_base.display()
+            )
+        })
+        .map_err(Local)?;
+    let mut dc = digest::create();
+    let mut buf = vec![0u8; 65536];
+    let mut n_read = 0;
+
+    loop {
+        let n = reader.read(&mut buf).map_err(|e| Network(e.into()))?;
+
+        if n == 0 {
+            break;
+        }
+
+        dc.input(&buf[..n]);
+        temp.write_all(&buf[..n]).map_err(|e| Local(e.into()))?;
+        n_read += n as u64;
+    }
+
+    if n_read != length {
+        return Err(Network(errmsg!(
+            "expected {} bytes but only got {}",
+            length,
+            n_read
+        )));
+    }
+
+    let digest = DigestData::from(dc);
+    persist_in_cache(data_base, temp, &digest).map_err(Local)?;
+    Ok(digest)
+}
+
+/// Move a file that we've downloaded to its place in the cache.
+fn persist_in_cache(
+    data_base: &Path,
+    temp: tempfile::NamedTempFile,
+    digest: &DigestData,
+) -> Result<()> {
+    let final_path = digest.create_two_part_path(data_base)?;
+
+    // Perform a racy check for the destination existing, because this
+    // matters on Windows: if the destination is already there, we'll get
+    // an error because the destination is marked read-only. If someone
+    // else wins the race, they've saved the same data that we have.
+
+    if !final_path.exists() {
+        if let Err(e) = temp.persist(&final_path) {
+            if !final_path.exists() {
+                return Err(e.error)
+                    .chain_err(|| format!("couldn't save {}", final_path.display()));
+            }
+        } else {
+            let mut perms = fs::metadata(&final_path)?.permissions();
+            perms.set_readonly(true);
+            fs::set_permissions(&final_path, perms)?;
+        }
+    }
+
+    Ok(())
+}
+
 fn parse_index_line(line: &str) -> Result<Option<(String, FileInfo)>> {
     let mut bits = line.split_whitespace();
 
@@ -222,34 +561,42 @@ fn parse_index_line(line: &str) -> Result<Option<(String, FileInfo)>> {
     }
 }
 
+/// Find the location of the digest file in the text of an index.
+fn find_digest_info(index: &str) -> Result<FileInfo> {
+    for line in index.lines() {
+        if let Some((name, info)) = parse_index_line(line)? {
+            if name == digest::DIGEST_NAME {
+                return Ok(info);
+            }
+        }
+    }
+
+    bail!(
+        "backend does not provide needed {} file",
+        digest::DIGEST_NAME
+    );
+}
+
 /// Attempts to find the redirected url, download the index and digest.
-fn get_everything(url: &str, status: &mut dyn StatusBackend) -> Result<(String, String, String)> {
-    let url = resolve_url(url, status)?;
+fn get_everything(
+    url: &str,
+    bundle_urls: &[String],
+    http: &HttpSettings,
+    status: &mut dyn StatusBackend,
+) -> Result<(String, String, String)> {
+    let url = resolve_url(url, bundle_urls, http, status)?;
+    let range_reader = HttpRangeReader::new(&url, bundle_urls, http)?;
 
     let index = {
         let mut index = String::new();
-        get_index(&url, status)?.read_to_string(&mut index)?;
+        get_index(&range_reader, status)?.read_to_string(&mut index)?;
         index
     };
 
     let digest_text = {
-        // Find the location of the digest file.
-        let digest_info = {
-            let mut digest_info = None;
-            for line in index.lines() {
-                if let Some((name, info)) = parse_index_line(line)? {
-                    if name == digest::DIGEST_NAME {
-                        digest_info = Some(info);
-                        break;
-                    }
-                }
-            }
-            ctry!(digest_info; "backend does not provide needed {} file", digest::DIGEST_NAME)
-        };
-
-        let mut range_reader = HttpRangeReader::new(&url);
+        let digest_info = find_digest_info(&index)?;
         String::from_utf8(get_file(
-            &mut range_reader,
+            &range_reader,
             digest::DIGEST_NAME,
             digest_info.offset,
             digest_info.length as usize,
@@ -261,11 +608,94 @@ fn get_everything(url: &str, status: &mut dyn StatusBackend) -> Result<(String,
     Ok((digest_text, index, url))
 }
 
+/// Like `get_everything`, but trying each of the mirrors of the bundle in
+/// turn. Also returns which mirror worked. Only the main URL says which
+/// version of the bundle is current, since a mirror might lag behind, so a
+/// mirror that serves something other than `expected`, the version that we
+/// have cached, if any, is passed over.
+fn get_everything_from_mirrors(
+    urls: &[String],
+    http: &HttpSettings,
+    expected: Option<&DigestData>,
+    status: &mut dyn StatusBackend,
+) -> Result<(String, String, String, usize)> {
+    for (i, url) in urls.iter().enumerate() {
+        let result = get_everything(url, urls, http, status).and_then(|everything| {
+            let digest =
+                ctry!(DigestData::from_str(&everything.0); "bad SHA256 digest from {}", url);
+
+            match expected {
+                Some(expected) if i > 0 && digest != *expected => bail!(
+                    "{} serves a different version of the bundle (digest {})",
+                    url,
+                    digest.to_string()
+                ),
+                _ => Ok(everything),
+            }
+        });
+
+        match result {
+            Ok((digest_text, index, redirect_url)) => {
+                return Ok((digest_text, index, redirect_url, i));
+            }
+            Err(e) if i + 1 < urls.len() => {
+                tt_warning!(status, "couldn't get the bundle from {}; trying the next mirror", url; e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    bail!("no URLs were given for the bundle");
+}
+
+/// Check that a mirror serves the bundle with the expected digest, and get a
+/// reader for it. Since we use the same index for all of the mirrors, they
+/// have to serve identical tar files, so we look for the digest file just
+/// where our index says that it is.
+fn check_mirror(
+    url: &str,
+    bundle_urls: &[String],
+    http: &HttpSettings,
+    digest_info: FileInfo,
+    expected: &DigestData,
+    status: &mut dyn StatusBackend,
+) -> Result<HttpRangeReader> {
+    let redirect_url = resolve_url(url, bundle_urls, http, status)?;
+    let reader = HttpRangeReader::new(&redirect_url, bundle_urls, http)?;
+
+    let digest_text = String::from_utf8(get_file(
+        &reader,
+        digest::DIGEST_NAME,
+        digest_info.offset,
+        digest_info.length as usize,
+        status,
+    )?)
+    .map_err(|e| e.utf8_error())?;
+
+    let digest = ctry!(DigestData::from_str(&digest_text); "bad SHA256 digest from {}", url);
+
+    if digest != *expected {
+        bail!(
+            "{} serves a different version of the bundle (digest {})",
+            url,
+            digest.to_string()
+        );
+    }
+
+    Ok(reader)
+}
+
 #[derive(Clone, Debug)]
 struct CacheContent {
     digest_text: String,
     redirect_url: String,
-    index: HashMap<String, FileInfo>,
+}
+
+/// Read the digest of the bundle that a URL pointed to the last time that we
+/// checked, if we know it.
+fn load_digest(digest_path: &Path) -> Option<DigestData> {
+    let text = fs::read_to_string(digest_path).ok()?;
+    DigestData::from_str(text.trim()).ok()
 }
 
 /// Load cached data.
@@ -301,33 +731,77 @@ fn load_cache_inner(
     let redirect_path = make_txt_path(redirect_base, &digest_text);
     let redirect_url = fs::read_to_string(redirect_path)?;
 
-    let index_path = make_txt_path(index_base, &digest_text);
+    // The index itself is only read when it's needed, but it has to be there.
+    fs::metadata(make_txt_path(index_base, &digest_text))?;
 
-    let index = {
-        let f = File::open(index_path)?;
-        let mut index = HashMap::new();
-        for line in BufReader::new(f).lines() {
-            if let Some((name, info)) = parse_index_line(&line?)? {
-                index.insert(name, info);
-            }
-        }
-        index
-    };
     Ok(CacheContent {
         digest_text,
         redirect_url,
-        index,
     })
 }
 
-fn make_txt_path(base: &Path, digest_text: &str) -> PathBuf {
+fn read_index(path: &Path) -> Result<HashMap<String, FileInfo>> {
+    let f = ctry!(File::open(path); "couldn't open \"{}\"", path.display());
+    let mut index = HashMap::new();
+
+    for line in BufReader::new(f).lines() {
+        if let Some((name, info)) = parse_index_line(&line?)? {
+            index.insert(name, info);
+        }
+    }
+
+    Ok(index)
+}
+
+pub(crate) fn make_txt_path(base: &Path, digest_text: &str) -> PathBuf {
     base.join(&digest_text).with_extension("txt")
 }
 
+/// Split a manifest line into the file name, its length, and its digest.
+/// Malformed lines give `None`.
+pub(crate) fn parse_manifest_line(line: &str) -> Option<(&str, u64, &str)> {
+    let mut bits = line.rsplitn(3, ' ');
+
+    let (name, length, digest) = match (bits.next(), bits.next(), bits.next(), bits.next()) {
+        (Some(s), Some(t), Some(r), None) => (r, t, s),
+        _ => return None,
+    };
+
+    Some((name, length.parse().ok()?, digest))
+}
+
+/// The name of the lock file in the directory of cached files. Bundles hold
+/// a shared lock on it from when they start downloading files until those
+/// files are in the manifest, and `BundleCache` holds an exclusive lock
+/// while it deletes the files that no manifest refers to.
+pub(crate) const CACHE_LOCK_NAME: &str = "lock";
+
+/// Lock the directory of cached files, waiting for whoever holds the lock
+/// to let go. The lock is released when the file is closed.
+pub(crate) fn lock_cache(data_base: &Path, exclusive: bool) -> Result<File> {
+    let path = data_base.join(CACHE_LOCK_NAME);
+    let f = ctry!(fs::OpenOptions::new().write(true).create(true).truncate(false).open(&path);
+                  "couldn't open \"{}\"", path.display());
+
+    if exclusive {
+        ctry!(f.lock_exclusive(); "failed to lock \"{}\"", path.display());
+    } else {
+        ctry!(f.lock_shared(); "failed to lock \"{}\"", path.display());
+    }
+
+    Ok(f)
+}
+
 /// Bundle provided by an indexed tar file over http with a local cache.
 #[derive(Clone, Debug)]
 pub struct CachedITarBundle {
-    url: String,
+    /// The URLs of the bundle: the main one and then its mirrors.
+    urls: Vec<String>,
+
+    /// The mirror to try next if the one that we're using lets us down.
+    next_mirror: usize,
+
+    http: HttpSettings,
     redirect_url: String,
     digest_path: PathBuf,
     cached_digest: DigestData,
@@ -338,8 +812,31 @@ pub struct CachedITarBundle {
     contents: HashMap<String, LocalCacheItem>,
     only_cached: bool,
 
+    /// Names that the engines have asked for but the bundle doesn't have.
+    /// Most of these come from the engines trying each of the extensions
+    /// that a file might have, and the same ones come up on every run, so
+    /// we keep a list of them next to the manifest. It's separate so that
+    /// the manifest only lists files that are in the cache.
+    missing: HashSet<String>,
+    missing_path: PathBuf,
+
+    /// Manifest lines that we haven't written out yet. See `flush_manifest`.
+    pending_manifest: String,
+
+    /// Lines for the list of missing names that we haven't written out yet.
+    pending_missing: String,
+
+    /// Our shared lock on the cache, while there are downloaded files that
+    /// aren't in the manifest yet. See `CACHE_LOCK_NAME`.
+    cache_lock: Option<Rc<File>>,
+
     tar_data: HttpRangeReader,
-    index: HashMap<String, FileInfo>,
+    index_path: PathBuf,
+    index: Option<HashMap<String, FileInfo>>,
+
+    /// Maps the part of each name in the index before the first dot to the
+    /// names that start with it. See `companions`.
+    stems: Option<HashMap<String, Vec<String>>>,
 }
 
 impl CachedITarBundle {
@@ -347,37 +844,87 @@ impl CachedITarBundle {
         url: &str,
         only_cached: bool,
         custom_cache_root: Option<&Path>,
+        http: &HttpSettings,
         status: &mut dyn StatusBackend,
     ) -> Result<CachedITarBundle> {
+        Self::with_mirrors(
+            &[url.to_owned()],
+            only_cached,
+            custom_cache_root,
+            http,
+            status,
+        )
+    }
+
+    /// Open a bundle that is served from several places. The first URL is
+    /// the main one, which identifies the bundle in the cache; the others
+    /// are mirrors that we fall back on, in order, if it's unavailable. All
+    /// of them must serve the very same tar file.
+    pub fn with_mirrors(
+        urls: &[String],
+        only_cached: bool,
+        custom_cache_root: Option<&Path>,
+        http: &HttpSettings,
+        status: &mut dyn StatusBackend,
+    ) -> Result<CachedITarBundle> {
+        let url = match urls.first() {
+            Some(u) => u,
+            None => bail!("no URLs were given for the bundle"),
+        };
+
         let digest_path = cache_dir("urls", custom_cache_root)?.join(app_dirs::sanitized(url));
 
         let redirect_base = &cache_dir("redirects", custom_cache_root)?;
         let index_base = &cache_dir("indexes", custom_cache_root)?;
         let manifest_base = &cache_dir("manifests", custom_cache_root)?;
+        let missing_base = &cache_dir("missing", custom_cache_root)?;
         let data_base = &cache_dir("files", custom_cache_root)?;
 
         let mut checked_digest = false;
-        let CacheContent {digest_text, redirect_url, index} =
+
+        // If we load the redirect from the cache, we don't know which mirror
+        // it goes with, so all of them are worth a try if it stops working.
+        let mut next_mirror = 0;
+
+        let CacheContent {digest_text, redirect_url} =
             // Try loading the cached files.
             match load_cache(&digest_path, &redirect_base, &index_base)? {
                 Some(c) => c,
                 None => {
                     // At least one of the cached files does not exists. We fetch everything from
                     // scratch and save the files.
-                    let (digest_text, index, redirect_url) = get_everything(url, status)?;
-                    let _ = DigestData::from_str(&digest_text)?;
+                    let cached = load_digest(&digest_path);
+                    let (digest_text, index, redirect_url, mirror) = get_everything_from_mirrors(urls, http, cached.as_ref(), status)?;
+                    let digest = DigestData::from_str(&digest_text)?;
                     checked_digest = true;
+                    next_mirror = mirror + 1;
+
+                    // Now's the time to find out about mirrors that are out
+                    // of step with the rest, rather than when we need them.
+                    if next_mirror < urls.len() {
+                        let digest_info = find_digest_info(&index)?;
+
+                        for url in &urls[next_mirror..] {
+                            if let Err(e) = check_mirror(url, urls, http, digest_info, &digest, status) {
+                                tt_warning!(status, "the bundle mirror {} can't be used", url; e);
+                            }
+                        }
+                    }
+
+                    // A mirror can't tell us what the main URL points to now.
+                    if mirror == 0 {
+                        file_create_write(&digest_path, |f| writeln!(f, "{}", digest_text))?;
+                    }
 
-                    file_create_write(&digest_path, |f| writeln!(f, "{}", digest_text))?;
                     file_create_write(make_txt_path(&redirect_base, &digest_text), |f| f.write_all(redirect_url.as_bytes()))?;
                     file_create_write(make_txt_path(&index_base, &digest_text), |f| f.write_all(index.as_bytes()))?;
 
-                    // Reload the cached files now when they were saved.
-                    ctry!(load_cache(&digest_path, &redirect_base, &index_base)?; "cache files missing even after they were created")
+                    CacheContent {digest_text, redirect_url}
                 }
             };
 
         let cached_digest = DigestData::from_str(&digest_text)?;
+        let index_path = make_txt_path(index_base, &digest_text);
 
         // We can now figure out which manifest to use.
         let manifest_path = make_txt_path(manifest_base, &digest_text);
@@ -404,31 +951,19 @@ impl CachedITarBundle {
 
                 for res in f.lines() {
                     let line = res?;
-                    let mut bits = line.rsplitn(3, ' ');
-
-                    let (original_name, length, digest) =
-                        match (bits.next(), bits.next(), bits.next(), bits.next()) {
-                            (Some(s), Some(t), Some(r), None) => (r, t, s),
-                            _ => continue,
-                        };
-
-                    let name = original_name.to_owned();
 
-                    let length = match length.parse::<u64>() {
-                        Ok(l) => l,
-                        Err(_) => continue,
+                    let (original_name, length, digest) = match parse_manifest_line(&line) {
+                        Some(t) => t,
+                        None => continue,
                     };
 
-                    let digest = if digest == "-" {
-                        continue;
-                    } else {
-                        match DigestData::from_str(&digest) {
-                            Ok(d) => d,
-                            Err(e) => {
-                                tt_warning!(status, "ignoring bad digest data \"{}\" for \"{}\" in \"{}\"",
-                                            &digest, original_name, manifest_path.display() ; e);
-                                continue;
-                            }
+                    let name = original_name.to_owned();
+                    let digest = match DigestData::from_str(&digest) {
+                        Ok(d) => d,
+                        Err(e) => {
+                            tt_warning!(status, "ignoring bad digest data \"{}\" for \"{}\" in \"{}\"",
+                                        &digest, original_name, manifest_path.display() ; e);
+                            continue;
                         }
                     };
 
@@ -443,12 +978,18 @@ impl CachedITarBundle {
             }
         }
 
+        // And the names that we know the bundle doesn't have.
+        let missing_path = make_txt_path(missing_base, &digest_text);
+        let missing = read_missing(&missing_path)?;
+
         // All set.
 
-        let tar_data = HttpRangeReader::new(&redirect_url);
+        let tar_data = HttpRangeReader::new(&redirect_url, urls, http)?;
 
         Ok(CachedITarBundle {
-            url: url.to_owned(),
+            urls: urls.to_vec(),
+            next_mirror,
+            http: http.clone(),
             redirect_url,
             digest_path: digest_path.to_owned(),
             cached_digest,
@@ -458,38 +999,73 @@ impl CachedITarBundle {
             redirect_base: redirect_base.to_owned(),
             contents,
             only_cached,
+            missing,
+            missing_path,
+            pending_manifest: String::new(),
+            pending_missing: String::new(),
+            cache_lock: None,
             tar_data,
-            index,
+            index_path,
+            index: None,
+            stems: None,
         })
     }
 
-    fn record_cache_result(&mut self, name: &str, length: u64, digest: DigestData) -> Result<()> {
-        let digest_text = digest.to_string();
+    /// Read the index of the tar file, if we haven't already. We don't need
+    /// it when all of the files that the engines ask for are in the
+    /// manifest, which is the usual case when a document is rebuilt, and
+    /// big bundles have big indexes.
+    fn load_index(&mut self) -> Result<&HashMap<String, FileInfo>> {
+        if self.index.is_none() {
+            self.index = Some(read_index(&self.index_path)?);
+        }
 
-        // Due to a quirk about permissions for file locking on Windows, we
-        // need to add `.read(true)` to be able to lock a file opened in
-        // append mode.
+        Ok(self.index.as_ref().unwrap())
+    }
 
-        let mut man = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .read(true)
-            .open(&self.manifest_path)?;
+    /// Add records of downloaded files to our manifest. They are only
+    /// written out by `flush_manifest`, so that we don't have to lock the
+    /// manifest for every file.
+    fn record_cache_results(&mut self, results: &[(String, u64, DigestData)]) {
+        for (name, length, digest) in results {
+            if !name.contains(|c| c == '\n' || c == '\r') {
+                self.pending_manifest.push_str(&format!(
+                    "{} {} {}\n",
+                    name,
+                    length,
+                    digest.to_string()
+                ));
+            }
 
-        // Lock will be released when file is closed at the end of this function.
-        ctry!(man.lock_exclusive(); "failed to lock manifest file \"{}\" for writing", self.manifest_path.display());
+            self.contents.insert(
+                name.to_owned(),
+                LocalCacheItem {
+                    _length: *length,
+                    digest: *digest,
+                },
+            );
+        }
+    }
 
+    /// Record that the bundle doesn't have a file, so that next time we can
+    /// say so without reading the index.
+    fn record_missing(&mut self, name: &str) {
         if !name.contains(|c| c == '\n' || c == '\r') {
-            writeln!(man, "{} {} {}", name, length, digest_text)?;
+            self.pending_missing.push_str(name);
+            self.pending_missing.push('\n');
         }
-        self.contents.insert(
-            name.to_owned(),
-            LocalCacheItem {
-                _length: length,
-                digest,
-            },
-        );
-        Ok(())
+
+        self.missing.insert(name.to_owned());
+    }
+
+    /// Append the records that we've accumulated to the manifest and to the
+    /// list of missing names. Once the manifest has them, the files that
+    /// we've downloaded are safe from `gc`, so we let go of our lock on the
+    /// cache.
+    fn flush_manifest(&mut self) -> Result<()> {
+        append_locked(&self.manifest_path, &mut self.pending_manifest)?;
+        self.cache_lock = None;
+        append_locked(&self.missing_path, &mut self.pending_missing)
     }
 
     /// If we're going to make a request of the backend, we should check that
@@ -503,9 +1079,9 @@ impl CachedITarBundle {
         }
 
         // Do a quick and dirty check first and ignore errors.
-        if let Some(info) = self.index.get(digest::DIGEST_NAME) {
+        if let Some(info) = self.load_index()?.get(digest::DIGEST_NAME).cloned() {
             if let Ok(d) = get_file(
-                &mut self.tar_data,
+                &self.tar_data,
                 digest::DIGEST_NAME,
                 info.offset,
                 info.length as usize,
@@ -526,7 +1102,10 @@ impl CachedITarBundle {
 
         // The quick check failed. Try to pull all data to make sure that it wasn't a network
         // error or that the redirect url hasn't been updated.
-        let (digest_text, _index, redirect_url) = get_everything(&self.url, status)?;
+        // Mirrors that serve anything else are passed over, so a different
+        // digest can only come from the main URL.
+        let (digest_text, _index, redirect_url, mirror) =
+            get_everything_from_mirrors(&self.urls, &self.http, Some(&self.cached_digest), status)?;
 
         let current_digest =
             ctry!(DigestData::from_str(&digest_text); "bad SHA256 digest from bundle");
@@ -546,16 +1125,64 @@ impl CachedITarBundle {
             let redirect_path = make_txt_path(&self.redirect_base, &digest_text);
             file_create_write(&redirect_path, |f| f.write_all(redirect_url.as_bytes()))?;
 
+            self.tar_data = HttpRangeReader::new(&redirect_url, &self.urls, &self.http)?;
             self.redirect_url = redirect_url;
         }
 
         // Index should've changed as the digest hasn't.
 
         // Phew, the backend hasn't changed. Don't check again.
+        self.next_mirror = mirror + 1;
         self.checked_digest = true;
         Ok(())
     }
 
+    /// Switch to the next mirror that serves our bundle, after the one that
+    /// we were using has let us down. Returns false if there are none left.
+    fn fail_over(&mut self, status: &mut dyn StatusBackend) -> Result<bool> {
+        let digest_info = match self.load_index()?.get(digest::DIGEST_NAME) {
+            Some(info) => *info,
+            None => bail!(
+                "backend does not provide needed {} file",
+                digest::DIGEST_NAME
+            ),
+        };
+
+        while self.next_mirror < self.urls.len() {
+            let url = self.urls[self.next_mirror].clone();
+            self.next_mirror += 1;
+
+            match check_mirror(
+                &url,
+                &self.urls,
+                &self.http,
+                digest_info,
+                &self.cached_digest,
+                status,
+            ) {
+                Ok(reader) => {
+                    tt_note!(status, "switching to the bundle mirror {}", url);
+
+                    // Start with this mirror next time, too.
+                    let redirect_path =
+                        make_txt_path(&self.redirect_base, &self.cached_digest.to_string());
+                    file_create_write(&redirect_path, |f| f.write_all(reader.url.as_bytes()))?;
+
+                    self.redirect_url = reader.url.clone();
+                    self.tar_data = reader;
+                    self.checked_digest = true;
+                    return Ok(true);
+                }
+
+                Err(e) => {
+                    tt_warning!(status, "the bundle mirror {} can't be used", url; e);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
     /// Find the path in the local cache for the provided file. Download the file first if it is
     /// not in the local cache already.
     fn path_for_name(&mut self, name: &str, status: &mut dyn StatusBackend) -> OpenResult<PathBuf> {
@@ -566,94 +1193,195 @@ impl CachedITarBundle {
             };
         }
 
-        // The file is not in the cache and we are asked not to try to fetch it.
-        if self.only_cached {
+        // The file is not in the cache and we are asked not to try to fetch
+        // it, or we know that the bundle doesn't have it.
+        if self.only_cached || self.missing.contains(name) {
             return OpenResult::NotAvailable;
         }
 
-        let info = match self.index.get(name).cloned() {
-            Some(info) => info,
-            None => return OpenResult::NotAvailable,
+        let in_index = match self.load_index() {
+            Ok(index) => index.contains_key(name),
+            Err(e) => return OpenResult::Err(e),
         };
 
+        if !in_index {
+            self.record_missing(name);
+            return OpenResult::NotAvailable;
+        }
+
         // Bummer, we haven't seen this file before. We need to (try to) fetch
         // the item from the backend, saving it to disk and calculating its
         // digest ourselves, then enter it in the cache and in our manifest.
-        // Fun times. Because we're touching the backend, we need to verify that
-        // its digest is what we think.
+        // Fun times. Files that go with it are likely to be needed soon, so
+        // we fetch them at the same time; that's usually no more work for
+        // the server, since they're next to each other in the tar file.
+
+        let mut names = vec![name.to_owned()];
+        names.extend(self.companions(name));
+        let result = self.fetch_many(&names, status);
+
+        match (self.contents.get(name), result) {
+            (Some(info), result) => {
+                if let Err(e) = result {
+                    tt_warning!(status, "failed to download some files that go with \"{}\"", name; e);
+                }
 
-        if let Err(e) = self.check_digest(status) {
-            return OpenResult::Err(e);
+                match info.digest.create_two_part_path(&self.data_base) {
+                    Ok(p) => OpenResult::Ok(p),
+                    Err(e) => OpenResult::Err(e),
+                }
+            }
+            (None, Err(e)) => OpenResult::Err(e),
+            (None, Ok(_)) => OpenResult::Err(errmsg!("failed to download \"{}\"", name)),
         }
+    }
 
-        // The bundle's overall digest is OK. Now try open the file. If it's
-        // not available, cache that result, since LaTeX compilations commonly
-        // touch nonexistent files. If we didn't maintain the negative cache,
-        // we'd have to touch the network for virtually every compilation.
-
-        let content = match get_file(
-            &mut self.tar_data,
-            name,
-            info.offset,
-            info.length as usize,
-            status,
-        ) {
-            Ok(c) => c,
-            Err(e) => return OpenResult::Err(e),
+    /// Guess which other files will be needed along with the named one:
+    /// those with the same name but a different extension, such as the
+    /// Type 1 font that goes with a TFM file, or the configuration file of a
+    /// package. They're only worth fetching if they're small.
+    fn companions(&mut self, name: &str) -> Vec<String> {
+        let stem = match name.find('.') {
+            Some(i) => &name[..i],
+            None => return Vec::new(),
         };
 
-        // OK, we can stream the file to a temporary location on disk,
-        // computing its SHA256 as we go.
+        if self.stems.is_none() {
+            let mut stems: HashMap<String, Vec<String>> = HashMap::new();
 
-        let length = content.len();
+            if let Some(ref index) = self.index {
+                for n in index.keys() {
+                    if let Some(i) = n.find('.') {
+                        stems.entry(n[..i].to_owned()).or_default().push(n.clone());
+                    }
+                }
+            }
 
-        let mut digest_builder = digest::create();
-        digest_builder.input(&content);
+            self.stems = Some(stems);
+        }
 
-        let digest = DigestData::from(digest_builder);
+        let (index, stems) = match (&self.index, &self.stems) {
+            (Some(i), Some(s)) => (i, s),
+            _ => return Vec::new(),
+        };
 
-        let final_path = match digest.create_two_part_path(&self.data_base) {
-            Ok(p) => p,
-            Err(e) => return OpenResult::Err(e),
+        let mut candidates: Vec<&String> = match stems.get(stem) {
+            Some(c) => c
+                .iter()
+                .filter(|n| *n != name && !self.contents.contains_key(*n))
+                .collect(),
+            None => return Vec::new(),
         };
+        candidates.sort();
+
+        let mut companions = Vec::new();
+        let mut size = 0;
 
-        // Perform a racy check for the destination existing, because this
-        // matters on Windows: if the destination is already there, we'll get
-        // an error because the destination is marked read-only. Assuming
-        // non-pathological filesystem manipulation, though, we'll only be
-        // subject to the race once.
+        for n in candidates {
+            let length = index[n].length;
 
-        if !final_path.exists() {
-            if let Err(e) = file_create_write(&final_path, |f| f.write_all(&content)) {
-                return OpenResult::Err(e);
+            if size + length <= MAX_COMPANIONS_SIZE {
+                size += length;
+                companions.push(n.clone());
             }
+        }
 
-            // Now we can make the file readonly. It would be nice to set the
-            // permissions using the already-open file handle owned by the
-            // tempfile, but mkstemp doesn't give us access.
-            let mut perms = match fs::metadata(&final_path) {
-                Ok(p) => p,
-                Err(e) => {
-                    return OpenResult::Err(e.into());
-                }
+        companions
+    }
+
+    /// Download the named files that aren't cached yet. Files that lie close
+    /// together in the tar file are fetched together, and the manifest is
+    /// updated once at the end. If the server fails us, we carry on with the
+    /// next mirror.
+    fn fetch_many(&mut self, names: &[String], status: &mut dyn StatusBackend) -> Result<usize> {
+        let mut n_fetched = 0;
+
+        loop {
+            let groups = self.range_groups(names)?;
+
+            if groups.is_empty() {
+                return Ok(n_fetched);
             }
-            .permissions();
-            perms.set_readonly(true);
 
-            if let Err(e) = fs::set_permissions(&final_path, perms) {
-                return OpenResult::Err(e.into());
+            if self.only_cached {
+                bail!(
+                    "{} files are missing from the cache, but we were asked to only use cached files",
+                    groups.iter().map(|g| g.files.len()).sum::<usize>()
+                );
+            }
+
+            self.check_digest(status)?;
+
+            // Download them, saving each file as we go. If anything goes
+            // wrong, we still record the files that we got.
+
+            // Keep `gc` from deleting what we download before it's in the
+            // manifest.
+            if self.cache_lock.is_none() {
+                self.cache_lock = Some(Rc::new(lock_cache(&self.data_base, false)?));
+            }
+
+            let (results, error) = download_groups(&self.tar_data, &self.data_base, groups, status);
+            self.record_cache_results(&results);
+            n_fetched += results.len();
+
+            // Another mirror won't help if we can't save the files.
+            let e = match error {
+                Some(DownloadError::Network(e)) => e,
+                Some(DownloadError::Local(e)) => return Err(e),
+                None => return Ok(n_fetched),
+            };
+
+            if self.next_mirror >= self.urls.len() {
+                return Err(e);
+            }
+
+            tt_warning!(status, "failed to download files from {}", self.redirect_url; e);
+
+            if !self.fail_over(status)? {
+                bail!("failed to download files from any of the mirrors of the bundle");
             }
         }
+    }
 
-        // And finally add a record of this file to our manifest. Note that
-        // we're opening and closing this file every time we load a new file;
-        // not so efficient, but whatever.
+    /// Work out the range requests that fetch the named files that aren't
+    /// cached yet.
+    fn range_groups(&mut self, names: &[String]) -> Result<Vec<RangeGroup>> {
+        self.load_index()?;
+        let index = self.index.as_ref().unwrap();
+        let contents = &self.contents;
+
+        let mut wanted: Vec<(&String, FileInfo)> = names
+            .iter()
+            .filter(|n| !contents.contains_key(n.as_str()))
+            .filter_map(|n| Some((n, *index.get(n)?)))
+            .collect();
+        wanted.sort_by_key(|(name, info)| (info.offset, *name));
+        wanted.dedup_by_key(|(name, _)| *name);
+
+        let mut groups: Vec<RangeGroup> = Vec::new();
+
+        for (name, info) in wanted {
+            let end = info.offset + info.length;
+
+            if let Some(last) = groups.last_mut() {
+                if info.offset <= last.end + MAX_PREFETCH_GAP
+                    && end - last.start <= MAX_PREFETCH_REQUEST_SIZE
+                {
+                    last.end = last.end.max(end);
+                    last.files.push((name.clone(), info));
+                    continue;
+                }
+            }
 
-        if let Err(e) = self.record_cache_result(name, length as u64, digest) {
-            return OpenResult::Err(e);
+            groups.push(RangeGroup {
+                start: info.offset,
+                end,
+                files: vec![(name.clone(), info)],
+            });
         }
 
-        OpenResult::Ok(final_path)
+        Ok(groups)
     }
 }
 
@@ -692,6 +1420,65 @@ impl Bundle for CachedITarBundle {
     fn get_digest(&mut self, _status: &mut dyn StatusBackend) -> Result<DigestData> {
         Ok(self.cached_digest)
     }
+
+    fn prefetch(&mut self, names: &[String], status: &mut dyn StatusBackend) -> Result<usize> {
+        self.fetch_many(names, status)
+    }
+
+    fn prefetch_all(&mut self, status: &mut dyn StatusBackend) -> Result<usize> {
+        let names: Vec<String> = self.load_index()?.keys().cloned().collect();
+        self.fetch_many(&names, status)
+    }
+
+    fn flush(&mut self, _status: &mut dyn StatusBackend) -> Result<()> {
+        self.flush_manifest()
+    }
+}
+
+impl Drop for CachedITarBundle {
+    /// Bundles should be flushed explicitly, so that errors can be
+    /// reported, but we'd rather not forget about the files that we've
+    /// downloaded if they aren't.
+    fn drop(&mut self) {
+        let _ = self.flush_manifest();
+    }
+}
+
+/// Append text to a file in the cache, holding a lock on it, and clear the
+/// text once it's written.
+fn append_locked(path: &Path, text: &mut String) -> Result<()> {
+    if text.is_empty() {
+        return Ok(());
+    }
+
+    // Due to a quirk about permissions for file locking on Windows, we
+    // need to add `.read(true)` to be able to lock a file opened in append
+    // mode.
+
+    let mut f = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .read(true)
+        .open(path)?;
+
+    // Lock will be released when file is closed at the end of this function.
+    ctry!(f.lock_exclusive(); "failed to lock \"{}\" for writing", path.display());
+
+    // One write, so that other processes appending to the file at the same
+    // time can't interleave their lines with ours.
+    f.write_all(text.as_bytes())?;
+    text.clear();
+    Ok(())
+}
+
+/// Read the list of names that a bundle doesn't have. A missing list is
+/// empty.
+fn read_missing(path: &Path) -> Result<HashSet<String>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text.lines().map(|l| l.to_owned()).collect()),
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e).chain_err(|| format!("couldn't read \"{}\"", path.display())),
+    }
 }
 
 /// A convenience method to provide a better error message when writing to a created file.
@@ -708,7 +1495,7 @@ where
     Ok(())
 }
 
-fn cache_dir(path: &str, custom_cache_root: Option<&Path>) -> Result<PathBuf> {
+pub(crate) fn cache_dir(path: &str, custom_cache_root: Option<&Path>) -> Result<PathBuf> {
     if let Some(root) = custom_cache_root {
         if !root.is_dir() {
             bail!("Custom cache path {} is not a directory", root.display());
@@ -0,0 +1,400 @@
+// src/io/dirbundle.rs -- a bundle that is a plain directory tree
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A bundle whose files live in a directory tree on the filesystem.
+//!
+//! As with kpathsea’s TEXMF trees, files are found no matter which
+//! subdirectory they live in: asking for `article.cls` will find
+//! `tex/latex/base/article.cls`. If the top of the tree contains an `ls-R`
+//! file, as created by `mktexlsr`, it is used as the index of the tree;
+//! otherwise we scan the tree ourselves when the bundle is opened.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use super::{Bundle, InputHandle, InputOrigin, IoProvider, OpenResult};
+use crate::ctry;
+use crate::digest::{self, Digest, DigestData};
+use crate::errors::{Result, ResultExt};
+use crate::status::StatusBackend;
+
+/// The name of the kpathsea-style index file.
+const LS_R_NAME: &str = "ls-R";
+
+pub struct DirBundle {
+    root: PathBuf,
+
+    /// Maps file names to the paths, relative to the root, of the files with
+    /// those names, in the order in which they should be preferred.
+    index: HashMap<String, Vec<PathBuf>>,
+
+    digest: Option<DigestData>,
+}
+
+impl DirBundle {
+    /// Open the directory tree at `root` as a bundle, reading its `ls-R`
+    /// file or scanning it.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<DirBundle> {
+        let mut bundle = DirBundle {
+            root: root.as_ref().to_owned(),
+            index: HashMap::new(),
+            digest: None,
+        };
+
+        let ls_r = bundle.root.join(LS_R_NAME);
+
+        if ls_r.is_file() {
+            let f = ctry!(File::open(&ls_r); "couldn't open \"{}\"", ls_r.display());
+            bundle.read_ls_r(BufReader::new(f))?;
+        } else {
+            let root = bundle.root.clone();
+            ctry!(bundle.scan(&root, Path::new("")); "couldn't scan \"{}\"", root.display());
+        }
+
+        Ok(bundle)
+    }
+
+    /// Add a file to the index. The index file and the digest file aren't
+    /// part of the bundle contents, and nor is anything that would be
+    /// outside of the tree.
+    fn add(&mut self, rel: PathBuf) {
+        let rel = match relative_path(&rel) {
+            Some(r) => r,
+            None => return,
+        };
+
+        let name = match rel.file_name().and_then(|n| n.to_str()) {
+            Some(n) if n != LS_R_NAME && n != digest::DIGEST_NAME => n.to_owned(),
+            _ => return,
+        };
+
+        self.index.entry(name).or_default().push(rel);
+    }
+
+    /// Read an `ls-R` file. It consists of the listings of the directories
+    /// of the tree, each introduced by a line with the directory name and a
+    /// colon, and separated by blank lines. The listings include the names of
+    /// subdirectories, which we can't tell apart from files; that's OK, since
+    /// opening them will just fail.
+    fn read_ls_r<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        let mut dir = PathBuf::new();
+
+        for line in reader.lines() {
+            let line = line?;
+
+            if line.is_empty() || line.starts_with('%') {
+                continue;
+            }
+
+            if line.ends_with(':') {
+                let d = line[..line.len() - 1].trim_start_matches("./");
+                dir = PathBuf::from(if d == "." { "" } else { d });
+            } else {
+                self.add(dir.join(line));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scan a directory, in sorted order so that the results don't depend on
+    /// the whims of the filesystem.
+    fn scan(&mut self, dir: &Path, rel: &Path) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        let mut subdirs = Vec::new();
+
+        for entry in entries {
+            let rel = rel.join(entry.file_name());
+
+            // Follow symlinks, as kpathsea does.
+            if fs::metadata(entry.path())?.is_dir() {
+                subdirs.push((entry.path(), rel));
+            } else {
+                self.add(rel);
+            }
+        }
+
+        // Files closer to the top of the tree take priority.
+        for (path, rel) in subdirs {
+            self.scan(&path, &rel)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the path of the file with the given name. Names with directory
+    /// parts match files whose paths end with those parts. Only files in the
+    /// index can be found, so that what we serve is what the digest covers.
+    fn find(&self, name: &Path) -> Option<PathBuf> {
+        let name = relative_path(name)?;
+        let candidates = self.index.get(name.file_name()?.to_str()?)?;
+
+        candidates
+            .iter()
+            .filter(|rel| rel.ends_with(&name))
+            .map(|rel| self.root.join(rel))
+            .find(|p| p.is_file())
+    }
+
+    /// Compute the digest of the bundle contents in the same way as for the
+    /// Zip and indexed tar bundles. Files are named by their paths in the
+    /// tree, since moving a file can change which one a lookup finds. Only
+    /// the files that lookups can find count: a file that is shadowed by
+    /// another whose path ends with its own can never be served. Stale
+    /// entries in `ls-R`, and subdirectories listed in it, aren't found.
+    fn compute_digest(&self) -> Result<DigestData> {
+        let mut names: Vec<(String, PathBuf)> = self
+            .index
+            .values()
+            .flatten()
+            .filter_map(|rel| {
+                let path = self.find(rel)?;
+
+                if path != self.root.join(rel) {
+                    return None;
+                }
+
+                Some((rel.to_str()?.replace('\\', "/"), path))
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+
+        let mut files = Vec::with_capacity(names.len());
+
+        for (name, path) in names {
+            if let Some(d) = digest_of_file(&path)? {
+                files.push((name, d));
+            }
+        }
+
+        Ok(digest::bundle_digest(
+            files.iter().map(|(name, d)| (name.as_str(), d)),
+        ))
+    }
+
+    /// Get the files that lookups of bare names find, along with their paths.
+    /// This is what goes into a Zip or indexed tar bundle made from the tree.
+    pub(crate) fn resolved_files(&self) -> Vec<(String, PathBuf)> {
+        let mut files: Vec<_> = self
+            .index
+            .keys()
+            .filter_map(|name| Some((name.clone(), self.find(Path::new(name))?)))
+            .collect();
+        files.sort();
+        files
+    }
+}
+
+/// Get a path relative to the top of the tree, without any `.` parts, or
+/// `None` if it could refer to something outside of the tree.
+fn relative_path(path: &Path) -> Option<PathBuf> {
+    let mut rel = PathBuf::new();
+
+    for c in path.components() {
+        match c {
+            Component::Normal(part) => rel.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    if rel.as_os_str().is_empty() {
+        None
+    } else {
+        Some(rel)
+    }
+}
+
+/// Compute the SHA256 digest of a file's contents, or return `None` if it
+/// isn't a regular file.
+pub(crate) fn digest_of_file(path: &Path) -> Result<Option<DigestData>> {
+    let mut f = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).chain_err(|| format!("couldn't open \"{}\"", path.display()));
+        }
+    };
+
+    if !f.metadata()?.is_file() {
+        return Ok(None);
+    }
+
+    let mut dc = digest::create();
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        let n = ctry!(f.read(&mut buf); "error reading \"{}\"", path.display());
+
+        if n == 0 {
+            break;
+        }
+
+        dc.input(&buf[..n]);
+    }
+
+    Ok(Some(DigestData::from(dc)))
+}
+
+impl IoProvider for DirBundle {
+    fn input_open_name(
+        &mut self,
+        name: &OsStr,
+        _status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        let path = match self.find(Path::new(name)) {
+            Some(p) => p,
+            None => return OpenResult::NotAvailable,
+        };
+
+        match File::open(path) {
+            Ok(f) => OpenResult::Ok(InputHandle::new_read_only(
+                name,
+                BufReader::new(f),
+                InputOrigin::Other,
+            )),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => OpenResult::NotAvailable,
+            Err(e) => OpenResult::Err(e.into()),
+        }
+    }
+}
+
+impl Bundle for DirBundle {
+    /// Reading every file in the tree takes a while, so the digest is only
+    /// computed once: the tree shouldn't change while we're using it.
+    fn get_digest(&mut self, _status: &mut dyn StatusBackend) -> Result<DigestData> {
+        if let Some(d) = self.digest {
+            return Ok(d);
+        }
+
+        let d = self.compute_digest()?;
+        self.digest = Some(d);
+        Ok(d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::NoopStatusBackend;
+
+    fn read(bundle: &mut DirBundle, name: &str) -> Option<String> {
+        let mut status = NoopStatusBackend::new();
+
+        match bundle.input_open_name(OsStr::new(name), &mut status) {
+            OpenResult::Ok(mut h) => {
+                let mut s = String::new();
+                h.read_to_string(&mut s).unwrap();
+                Some(s)
+            }
+            OpenResult::NotAvailable => None,
+            OpenResult::Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn digest(root: &Path) -> DigestData {
+        let mut status = NoopStatusBackend::new();
+        DirBundle::new(root)
+            .unwrap()
+            .get_digest(&mut status)
+            .unwrap()
+    }
+
+    #[test]
+    fn recursive_search() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        fs::create_dir_all(root.join("tex/latex/base")).unwrap();
+        fs::create_dir_all(root.join("tex/latex/local")).unwrap();
+        fs::write(root.join("top.cfg"), "top").unwrap();
+        fs::write(root.join("tex/latex/top.cfg"), "nested").unwrap();
+        fs::write(root.join("tex/latex/base/article.cls"), "base").unwrap();
+        fs::write(root.join("tex/latex/local/article.cls"), "local").unwrap();
+
+        let mut bundle = DirBundle::new(root).unwrap();
+        assert_eq!(read(&mut bundle, "top.cfg").unwrap(), "top");
+        assert_eq!(read(&mut bundle, "article.cls").unwrap(), "base");
+        assert_eq!(read(&mut bundle, "local/article.cls").unwrap(), "local");
+        assert_eq!(read(&mut bundle, "other/article.cls"), None);
+        assert_eq!(read(&mut bundle, "latex"), None);
+        assert_eq!(read(&mut bundle, "missing.sty"), None);
+
+        // With an ls-R file, only what it lists can be found by name.
+        fs::write(
+            root.join(LS_R_NAME),
+            "% ls-R -- filename database.\n\n./:\nls-R\ntex\n\n./tex/latex/local:\narticle.cls\n",
+        )
+        .unwrap();
+        let mut bundle = DirBundle::new(root).unwrap();
+        assert_eq!(read(&mut bundle, "article.cls").unwrap(), "local");
+        assert_eq!(read(&mut bundle, "tex/latex/base/article.cls"), None);
+        assert_eq!(read(&mut bundle, "base/article.cls"), None);
+        assert_eq!(read(&mut bundle, "ls-R"), None);
+    }
+
+    #[test]
+    fn stays_inside_tree() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let outside = tempdir.path();
+        let root = outside.join("tree");
+        fs::create_dir_all(root.join("tex")).unwrap();
+        fs::write(outside.join("secret.tex"), "secret").unwrap();
+        fs::write(root.join("tex/plain.tex"), "plain").unwrap();
+
+        let mut bundle = DirBundle::new(&root).unwrap();
+        assert_eq!(read(&mut bundle, "./tex/plain.tex").unwrap(), "plain");
+        assert_eq!(read(&mut bundle, "../secret.tex"), None);
+        assert_eq!(read(&mut bundle, "tex/../../secret.tex"), None);
+        let absolute = outside.join("secret.tex");
+        assert_eq!(read(&mut bundle, absolute.to_str().unwrap()), None);
+
+        // Nor can an ls-R file point outside of the tree.
+        fs::write(
+            root.join(LS_R_NAME),
+            "./:\n../secret.tex\n\n../:\nsecret.tex\n",
+        )
+        .unwrap();
+        let mut bundle = DirBundle::new(&root).unwrap();
+        assert_eq!(read(&mut bundle, "secret.tex"), None);
+    }
+
+    #[test]
+    fn digests() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("sub/a.tex"), "a").unwrap();
+        fs::write(root.join("b.tex"), "b").unwrap();
+
+        let d1 = digest(root);
+        assert_eq!(d1, digest(root));
+        assert_ne!(d1, DigestData::of_nothing());
+
+        fs::write(root.join("sub/a.tex"), "A").unwrap();
+        let d2 = digest(root);
+        assert_ne!(d1, d2);
+
+        fs::rename(root.join("sub/a.tex"), root.join("a.tex")).unwrap();
+        assert_ne!(d2, digest(root));
+
+        // The digest file, if any, isn't part of the contents.
+        fs::write(root.join(digest::DIGEST_NAME), "whatever").unwrap();
+        fs::rename(root.join("a.tex"), root.join("sub/a.tex")).unwrap();
+        assert_eq!(d2, digest(root));
+
+        // Nor is a file that lookups can never find: any name that matches
+        // `sub/a.tex` also matches `a/sub/a.tex`, which comes first.
+        fs::create_dir_all(root.join("a/sub")).unwrap();
+        fs::write(root.join("a/sub/a.tex"), "A").unwrap();
+        let d3 = digest(root);
+        assert_ne!(d2, d3);
+        fs::write(root.join("sub/a.tex"), "changed").unwrap();
+        assert_eq!(d3, digest(root));
+    }
+}
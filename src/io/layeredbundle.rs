@@ -0,0 +1,98 @@
+// src/io/layeredbundle.rs -- several bundles stacked on top of each other
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A bundle composed of several other bundles.
+//!
+//! This makes it possible to put, say, a bundle of in-house document classes
+//! on top of the standard TeXLive bundle: files are looked up in each layer
+//! in turn, so the first layer that provides a file wins.
+
+use std::ffi::OsStr;
+
+use super::{Bundle, InputHandle, IoProvider, OpenResult};
+use crate::digest::{self, Digest, DigestData};
+use crate::errors::Result;
+use crate::status::StatusBackend;
+
+pub struct LayeredBundle {
+    layers: Vec<Box<dyn Bundle>>,
+}
+
+impl LayeredBundle {
+    /// Create a bundle from a list of layers in priority order: files are
+    /// taken from the first layer that has them.
+    pub fn new(layers: Vec<Box<dyn Bundle>>) -> LayeredBundle {
+        LayeredBundle { layers }
+    }
+
+    /// Get the layers of this bundle, in priority order.
+    pub fn layers(&self) -> &[Box<dyn Bundle>] {
+        &self.layers
+    }
+}
+
+impl IoProvider for LayeredBundle {
+    fn input_open_name(
+        &mut self,
+        name: &OsStr,
+        status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        for layer in &mut self.layers {
+            match layer.input_open_name(name, status) {
+                OpenResult::NotAvailable => continue,
+                r => return r,
+            }
+        }
+
+        OpenResult::NotAvailable
+    }
+}
+
+impl Bundle for LayeredBundle {
+    /// The digest of a layered bundle depends on the digests of all of its
+    /// layers and on their order, since either one affects which files the
+    /// engines see. A bundle with a single layer has the digest of that
+    /// layer, so that wrapping a bundle doesn't invalidate cached formats.
+    fn get_digest(&mut self, status: &mut dyn StatusBackend) -> Result<DigestData> {
+        if self.layers.len() == 1 {
+            return self.layers[0].get_digest(status);
+        }
+
+        let mut dc = digest::create();
+
+        for layer in &mut self.layers {
+            dc.input(layer.get_digest(status)?.to_string().as_bytes());
+        }
+
+        Ok(DigestData::from(dc))
+    }
+
+    fn prefetch(&mut self, names: &[String], status: &mut dyn StatusBackend) -> Result<usize> {
+        let mut n = 0;
+
+        for layer in &mut self.layers {
+            n += layer.prefetch(names, status)?;
+        }
+
+        Ok(n)
+    }
+
+    fn prefetch_all(&mut self, status: &mut dyn StatusBackend) -> Result<usize> {
+        let mut n = 0;
+
+        for layer in &mut self.layers {
+            n += layer.prefetch_all(status)?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self, status: &mut dyn StatusBackend) -> Result<()> {
+        for layer in &mut self.layers {
+            layer.flush(status)?;
+        }
+
+        Ok(())
+    }
+}
@@ -5,6 +5,8 @@
 //! Tectonic’s pluggable I/O backend.
 
 use flate2::read::GzDecoder;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
@@ -17,13 +19,18 @@ use crate::digest::{self, Digest, DigestData};
 use crate::errors::{Error, ErrorKind, Result};
 use crate::status::StatusBackend;
 
+pub mod bundle_builder;
+pub mod bundle_cache;
 pub mod cached_itarbundle;
+pub mod dirbundle;
 pub mod filesystem;
 pub mod format_cache;
+pub mod layeredbundle;
 pub mod memory;
 pub mod setup;
 pub mod stack;
 pub mod stdstreams;
+pub mod tarbundle;
 pub mod zipbundle;
 
 pub trait InputFeatures: Read {
@@ -35,6 +42,7 @@ pub trait InputFeatures: Read {
 /// this in order to be able to emit Makefile-style dependencies for input
 /// files. Right now, we only provide enough options to achieve this goal; we
 /// could add more.
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum InputOrigin {
     /// This file lives on the filesystem and might change under us. (That is
@@ -452,9 +460,8 @@ pub trait Bundle: IoProvider {
     ///
     /// The digest summarizes the exact contents of every file in the bundle.
     /// It is computed from the sorted names and SHA256 digests of the
-    /// component files [as implemented in the script
-    /// builder/make-zipfile.py](https://github.com/tectonic-typesetting/tectonic-staging/blob/master/builder/make-zipfile.py#L138)
-    /// in the `tectonic-staging` module.
+    /// component files by [`digest::bundle_digest`]. Bundles with the right
+    /// `SHA256SUM` can be created with the [`bundle_builder`] module.
     ///
     /// The default implementation gets the digest from a file name
     /// `SHA256SUM`, which is expected to contain the digest in hex-encoded
@@ -482,12 +489,47 @@ pub trait Bundle: IoProvider {
 
         Ok(ctry!(DigestData::from_str(&digest_text); "corrupted SHA256 digest data"))
     }
+
+    /// Make sure that the named files are available locally, so that they
+    /// can be used later without network access.
+    ///
+    /// This is meant for bundles that fetch their files lazily; names that
+    /// aren't in the bundle are ignored. Returns the number of files that
+    /// were fetched. The default implementation does nothing.
+    fn prefetch(&mut self, _names: &[String], _status: &mut dyn StatusBackend) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Make sure that all of the files in the bundle are available locally.
+    /// See [`Bundle::prefetch`].
+    fn prefetch_all(&mut self, _status: &mut dyn StatusBackend) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Save whatever state the bundle keeps in memory, such as its records
+    /// of the files that it has downloaded. This is called at the end of
+    /// each processing session. The default implementation does nothing.
+    fn flush(&mut self, _status: &mut dyn StatusBackend) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl<B: Bundle + ?Sized> Bundle for Box<B> {
     fn get_digest(&mut self, status: &mut dyn StatusBackend) -> Result<DigestData> {
         (**self).get_digest(status)
     }
+
+    fn prefetch(&mut self, names: &[String], status: &mut dyn StatusBackend) -> Result<usize> {
+        (**self).prefetch(names, status)
+    }
+
+    fn prefetch_all(&mut self, status: &mut dyn StatusBackend) -> Result<usize> {
+        (**self).prefetch_all(status)
+    }
+
+    fn flush(&mut self, status: &mut dyn StatusBackend) -> Result<()> {
+        (**self).flush(status)
+    }
 }
 
 // Some generically helpful InputFeatures impls
@@ -0,0 +1,294 @@
+// src/io/tarbundle.rs -- I/O on files in a plain tarball "bundle"
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! A bundle that is an uncompressed tar file.
+//!
+//! We scan the whole archive once, when the bundle is opened, to learn where
+//! each member lives; after that, opening a file is a seek and a read. The
+//! ustar, GNU long-name, and pax `path` conventions for file names are
+//! understood, which covers the tarballs that the usual tools create.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::{Bundle, InputHandle, InputOrigin, IoProvider, OpenResult};
+use crate::errors::Result;
+use crate::status::StatusBackend;
+use crate::{ctry, errmsg};
+
+const BLOCK_SIZE: u64 = 512;
+
+pub struct TarBundle<R: Read + Seek> {
+    reader: R,
+    index: HashMap<String, (u64, u64)>,
+}
+
+impl<R: Read + Seek> TarBundle<R> {
+    pub fn new(mut reader: R) -> Result<TarBundle<R>> {
+        let index = read_index(&mut reader)?;
+        Ok(TarBundle { reader, index })
+    }
+
+    /// Iterate over the names of the files in the bundle.
+    pub fn file_names(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(|k| k.as_str())
+    }
+}
+
+impl TarBundle<File> {
+    pub fn open(path: &Path) -> Result<TarBundle<File>> {
+        Self::new(File::open(path)?)
+    }
+}
+
+/// Parse an octal header field. Big sizes may be stored in base-256 instead,
+/// marked by the high bit of the first byte.
+fn parse_number(field: &[u8]) -> Result<u64> {
+    if !field.is_empty() && field[0] & 0x80 != 0 {
+        let mut n = u64::from(field[0] & 0x7F);
+
+        for b in &field[1..] {
+            n = (n << 8) | u64::from(*b);
+        }
+
+        return Ok(n);
+    }
+
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+
+    if text.is_empty() {
+        return Ok(0);
+    }
+
+    Ok(ctry!(u64::from_str_radix(text, 8); "bad number {:?} in tar header", text))
+}
+
+/// Get a NUL-terminated string out of a header field.
+fn parse_name(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// Find the `path` record in a pax extended header. Records look like
+/// `"<length> <key>=<value>\n"`, where the length includes itself.
+fn pax_path(mut data: &[u8]) -> Option<String> {
+    while !data.is_empty() {
+        let space = data.iter().position(|b| *b == b' ')?;
+        let len: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+
+        if len <= space || len > data.len() {
+            return None;
+        }
+
+        let record = &data[space + 1..len];
+
+        if record.starts_with(b"path=") && record.ends_with(b"\n") {
+            let value = &record[5..record.len() - 1];
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+
+        data = &data[len..];
+    }
+
+    None
+}
+
+fn read_index<R: Read + Seek>(reader: &mut R) -> Result<HashMap<String, (u64, u64)>> {
+    let mut index = HashMap::new();
+    let mut offset = 0;
+    let mut long_name = None;
+    let mut header = [0u8; BLOCK_SIZE as usize];
+
+    loop {
+        reader.seek(SeekFrom::Start(offset))?;
+
+        if let Err(e) = reader.read_exact(&mut header) {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                // Some writers leave out the trailing blocks of zeros.
+                break;
+            }
+
+            return Err(e.into());
+        }
+
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+
+        let checksum = parse_number(&header[148..156])?;
+        let actual: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, b)| u64::from(if (148..156).contains(&i) { b' ' } else { *b }))
+            .sum();
+
+        if checksum != actual {
+            return Err(errmsg!("bad tar header checksum at offset {}", offset));
+        }
+
+        let size = parse_number(&header[124..136])?;
+        let data_start = offset + BLOCK_SIZE;
+        offset = data_start + ((size + BLOCK_SIZE - 1) & !(BLOCK_SIZE - 1));
+
+        match header[156] {
+            // A GNU long name, or pax extended attributes, for the next member.
+            b'L' | b'x' => {
+                let mut data = vec![0; size as usize];
+                reader.read_exact(&mut data)?;
+
+                long_name = if header[156] == b'L' {
+                    Some(parse_name(&data))
+                } else {
+                    pax_path(&data)
+                };
+            }
+
+            // Regular files.
+            b'0' | b'\0' | b'7' => {
+                let name = match long_name.take() {
+                    Some(n) => n,
+                    None => {
+                        let name = parse_name(&header[..100]);
+
+                        if &header[257..262] == b"ustar" && header[345] != 0 {
+                            format!("{}/{}", parse_name(&header[345..500]), name)
+                        } else {
+                            name
+                        }
+                    }
+                };
+
+                let name = name.trim_start_matches("./").to_owned();
+                index.insert(name, (data_start, size));
+            }
+
+            // Directories, links, and so on. Bundles don't need them.
+            _ => {
+                long_name = None;
+            }
+        }
+    }
+
+    Ok(index)
+}
+
+impl<R: Read + Seek> IoProvider for TarBundle<R> {
+    fn input_open_name(
+        &mut self,
+        name: &OsStr,
+        _status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        let (start, size) = match name.to_str().and_then(|n| self.index.get(n)) {
+            Some(info) => *info,
+            None => return OpenResult::NotAvailable,
+        };
+
+        // As with Zip bundles, reading the whole file now is simplest.
+        let mut buf = vec![0; size as usize];
+
+        if let Err(e) = self.reader.seek(SeekFrom::Start(start)) {
+            return OpenResult::Err(e.into());
+        }
+
+        if let Err(e) = self.reader.read_exact(&mut buf) {
+            return OpenResult::Err(e.into());
+        }
+
+        OpenResult::Ok(InputHandle::new_read_only(
+            name,
+            Cursor::new(buf),
+            InputOrigin::Other,
+        ))
+    }
+}
+
+impl<R: Read + Seek> Bundle for TarBundle<R> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::NoopStatusBackend;
+
+    fn header(name: &str, size: usize, kind: u8, prefix: &str) -> Vec<u8> {
+        let mut h = vec![0u8; BLOCK_SIZE as usize];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        h[156] = kind;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        h[148..156].copy_from_slice(b"        ");
+        let sum: u32 = h.iter().map(|b| u32::from(*b)).sum();
+        h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        h
+    }
+
+    fn member(tar: &mut Vec<u8>, name: &str, kind: u8, prefix: &str, data: &[u8]) {
+        tar.extend(header(name, data.len(), kind, prefix));
+        tar.extend(data);
+        let padding = (BLOCK_SIZE as usize - data.len() % 512) % 512;
+        tar.extend(vec![0; padding]);
+    }
+
+    fn read(bundle: &mut TarBundle<Cursor<Vec<u8>>>, name: &str) -> Option<String> {
+        let mut status = NoopStatusBackend::new();
+
+        match bundle.input_open_name(OsStr::new(name), &mut status) {
+            OpenResult::Ok(mut h) => {
+                let mut s = String::new();
+                h.read_to_string(&mut s).unwrap();
+                Some(s)
+            }
+            OpenResult::NotAvailable => None,
+            OpenResult::Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn members() {
+        let long = "x".repeat(150);
+        let mut pax = Vec::new();
+        let record = format!(" path={}.sty\n", long);
+        pax.extend(format!("{}{}", record.len() + 3, record).as_bytes());
+
+        let mut tar = Vec::new();
+        member(&mut tar, "./plain.tex", b'0', "", b"\\dump\n");
+        member(&mut tar, "dir/", b'5', "", b"");
+        member(&mut tar, "file.cls", b'0', "tex/latex", b"class");
+        member(
+            &mut tar,
+            "././@LongLink",
+            b'L',
+            "",
+            format!("{}.cfg\0", long).as_bytes(),
+        );
+        member(&mut tar, "truncated.cfg", b'0', "", b"long");
+        member(&mut tar, "PaxHeader", b'x', "", &pax);
+        member(&mut tar, "truncated.sty", b'0', "", &[b'%'; 700]);
+        tar.extend(vec![0; 1024]);
+
+        let mut bundle = TarBundle::new(Cursor::new(tar)).unwrap();
+        assert_eq!(bundle.file_names().count(), 4);
+        assert_eq!(read(&mut bundle, "plain.tex").unwrap(), "\\dump\n");
+        assert_eq!(read(&mut bundle, "tex/latex/file.cls").unwrap(), "class");
+        assert_eq!(read(&mut bundle, &format!("{}.cfg", long)).unwrap(), "long");
+        assert_eq!(
+            read(&mut bundle, &format!("{}.sty", long)).unwrap().len(),
+            700
+        );
+        assert_eq!(read(&mut bundle, "truncated.cfg"), None);
+        assert_eq!(read(&mut bundle, "dir/"), None);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut tar = Vec::new();
+        member(&mut tar, "a.tex", b'0', "", b"a");
+        tar[0] = b'b';
+        assert!(TarBundle::new(Cursor::new(tar)).is_err());
+    }
+}
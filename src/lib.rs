@@ -56,6 +56,8 @@ pub mod engines;
 pub mod errors;
 pub mod io;
 pub mod status;
+pub mod synctex;
+pub mod tex_log;
 
 // Note: this module is intentionally *not* gated by #[cfg(test)] -- see its
 // docstring for details.
@@ -63,6 +65,8 @@ pub mod status;
 pub mod test_util;
 
 pub use crate::engines::bibtex::BibtexEngine;
+pub use crate::engines::external::ExternalToolEngine;
+pub use crate::engines::makeindex::MakeindexEngine;
 pub use crate::engines::spx2html::Spx2HtmlEngine;
 pub use crate::engines::tex::{TexEngine, TexResult};
 pub use crate::engines::xdvipdfmx::XdvipdfmxEngine;
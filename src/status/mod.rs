@@ -6,8 +6,10 @@
 
 pub mod termcolor;
 
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use std::cmp;
-use std::fmt::Arguments;
+use std::fmt::{self, Arguments};
 
 use crate::errors::Error;
 
@@ -46,6 +48,67 @@ pub enum MessageKind {
     Error,
 }
 
+/// The different kinds of problems that can be extracted from an engine's
+/// log output.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiagnosticKind {
+    /// A TeX error, i.e. a message introduced with `!`.
+    Error,
+    /// An `Overfull \hbox` or `Overfull \vbox` warning.
+    OverfullBox,
+    /// An `Underfull \hbox` or `Underfull \vbox` warning.
+    UnderfullBox,
+    /// A `\ref` to a label that isn't defined.
+    UndefinedReference,
+    /// A `\cite` of a key that isn't defined.
+    UndefinedCitation,
+    /// A character that isn't available in the current font.
+    MissingCharacter,
+}
+
+/// A single problem reported by an engine, with as much location information
+/// as we could figure out.
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+    /// What sort of problem this is.
+    pub kind: DiagnosticKind,
+
+    /// The engine's description of the problem, as it appeared in the log.
+    pub message: String,
+
+    /// The input file that the problem was found in, if known.
+    pub file: Option<String>,
+
+    /// The line of `file` that the problem was found on, if known.
+    pub line: Option<u32>,
+}
+
+impl Diagnostic {
+    /// How seriously the user should take this diagnostic.
+    pub fn severity(&self) -> MessageKind {
+        match self.kind {
+            DiagnosticKind::Error => MessageKind::Error,
+            DiagnosticKind::UnderfullBox => MessageKind::Note,
+            _ => MessageKind::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file, line)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            (None, Some(line)) => write!(f, "line {}: ", line)?,
+            (None, None) => {}
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
 pub trait StatusBackend {
     /// Report a message to the status backend.
     fn report(&mut self, kind: MessageKind, args: Arguments, err: Option<&Error>);
@@ -63,6 +126,16 @@ pub trait StatusBackend {
             None,
         )
     }
+
+    /// Report a structured diagnostic, such as one parsed out of a TeX log.
+    ///
+    /// The default implementation just reports it as a plain message whose
+    /// kind depends on the diagnostic's severity. Backends that want to do
+    /// something smarter, like presenting the diagnostics in an editor, can
+    /// override it.
+    fn report_diagnostic(&mut self, diag: &Diagnostic) {
+        self.report(diag.severity(), format_args!("{}", diag), None)
+    }
 }
 
 /// Report a formatted informational message to the user.
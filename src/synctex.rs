@@ -0,0 +1,625 @@
+// src/synctex.rs -- reading SyncTeX data
+// Copyright 2019 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Reading the SyncTeX data that the TeX engine writes, to map between
+//! places in the input files and places in the output.
+//!
+//! The engine writes this data to a `.synctex.gz` file when SyncTeX is
+//! enabled. A *forward search* finds where a line of input ended up in the
+//! output, so that an editor can show it in a PDF viewer; a *backward search*
+//! goes the other way, from a point on a page to a line of input.
+//!
+//! Positions on pages are given in PostScript points (`bp`), measured right
+//! and down from the top left corner of the page, as PDF viewers usually
+//! report them.
+
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::errors::{Error, Result};
+use crate::io::{IoProvider, OpenResult};
+use crate::status::StatusBackend;
+use crate::{ctry, errmsg};
+
+/// The number of TeX scaled points in a PostScript point.
+const SP_PER_BP: f64 = 65536. * 72.27 / 72.;
+
+/// The kinds of records in SyncTeX data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecordKind {
+    /// A vertical box with contents.
+    VBox,
+    /// A horizontal box with contents.
+    HBox,
+    /// An empty vertical box.
+    VoidVBox,
+    /// An empty horizontal box.
+    VoidHBox,
+    /// A position recorded for a character or similar.
+    Current,
+    /// A kern.
+    Kern,
+    /// Glue.
+    Glue,
+    /// A math formula.
+    Math,
+}
+
+impl RecordKind {
+    fn is_box(self) -> bool {
+        matches!(
+            self,
+            RecordKind::VBox | RecordKind::HBox | RecordKind::VoidVBox | RecordKind::VoidHBox
+        )
+    }
+}
+
+/// One record of SyncTeX data, in TeX scaled points.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Record {
+    kind: RecordKind,
+    input: u32,
+    line: u32,
+    column: Option<u32>,
+    h: i32,
+    v: i32,
+    width: i32,
+    height: i32,
+    depth: i32,
+
+    /// The index of the box that contains this record, if any.
+    parent: Option<usize>,
+}
+
+impl Record {
+    fn contains(&self, h: f64, v: f64) -> bool {
+        let (h0, v0) = (f64::from(self.h), f64::from(self.v));
+        h >= h0
+            && h <= h0 + f64::from(self.width)
+            && v >= v0 - f64::from(self.height)
+            && v <= v0 + f64::from(self.depth)
+    }
+
+    /// How far the point is from the record's extent, in scaled points.
+    fn distance(&self, h: f64, v: f64) -> f64 {
+        let (h0, v0) = (f64::from(self.h), f64::from(self.v));
+        let dh = if h < h0 {
+            h0 - h
+        } else {
+            (h - h0 - f64::from(self.width.max(0))).max(0.)
+        };
+        let dv = if v < v0 - f64::from(self.height) {
+            v0 - f64::from(self.height) - v
+        } else {
+            (v - v0 - f64::from(self.depth.max(0))).max(0.)
+        };
+        dh + dv
+    }
+}
+
+/// The records belonging to one page of output.
+#[derive(Clone, Debug, Default)]
+struct Sheet {
+    page: u32,
+    records: Vec<Record>,
+}
+
+/// A box on a page of output, found by a forward search.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncBox {
+    /// The page number, counting from 1.
+    pub page: u32,
+
+    /// The left edge of the box.
+    pub x: f64,
+
+    /// The baseline of the box.
+    pub y: f64,
+
+    /// The width of the box.
+    pub width: f64,
+
+    /// How far the box extends above its baseline.
+    pub height: f64,
+
+    /// How far the box extends below its baseline.
+    pub depth: f64,
+}
+
+/// A place in an input file, found by a backward search.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceLocation {
+    /// The name of the input file, as the TeX engine recorded it.
+    pub file: String,
+
+    /// The line number, counting from 1.
+    pub line: u32,
+
+    /// The column, if the engine recorded it.
+    pub column: Option<u32>,
+}
+
+/// The parsed contents of a SyncTeX file.
+#[derive(Clone, Debug, Default)]
+pub struct SyncTex {
+    inputs: BTreeMap<u32, String>,
+    sheets: Vec<Sheet>,
+
+    /// PostScript points per unit of position in the records.
+    unit: f64,
+    x_offset: f64,
+    y_offset: f64,
+}
+
+impl SyncTex {
+    /// Parse SyncTeX data, which may be gzipped.
+    pub fn parse(data: &[u8]) -> Result<SyncTex> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            let mut text = Vec::new();
+            ctry!(GzDecoder::new(data).read_to_end(&mut text); "couldn't decompress SyncTeX data");
+            Parser::default().parse(&String::from_utf8_lossy(&text))
+        } else {
+            Parser::default().parse(&String::from_utf8_lossy(data))
+        }
+    }
+
+    /// Read and parse the SyncTeX file at `path`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<SyncTex> {
+        let path = path.as_ref();
+        let data = ctry!(fs::read(path); "couldn't read SyncTeX file \"{}\"", path.display());
+        SyncTex::parse(&data)
+    }
+
+    /// Read and parse the named SyncTeX file from an I/O provider, such as
+    /// the memory layer of a processing session that has just run.
+    pub fn load(
+        io: &mut dyn IoProvider,
+        status: &mut dyn StatusBackend,
+        name: &str,
+    ) -> Result<SyncTex> {
+        let mut ih = match io.input_open_name(OsStr::new(name), status) {
+            OpenResult::Ok(ih) => ih,
+            OpenResult::NotAvailable => {
+                return Err(errmsg!("no SyncTeX file named \"{}\"", name));
+            }
+            OpenResult::Err(e) => return Err(e),
+        };
+
+        let mut data = Vec::new();
+        ctry!(ih.read_to_end(&mut data); "couldn't read SyncTeX file \"{}\"", name);
+        SyncTex::parse(&data)
+    }
+
+    /// The names of the input files that the data refers to.
+    pub fn input_names(&self) -> impl Iterator<Item = &str> {
+        self.inputs.values().map(|s| s.as_str())
+    }
+
+    /// The number of pages that the data covers.
+    pub fn num_pages(&self) -> usize {
+        self.sheets.len()
+    }
+
+    /// Find the boxes on the output pages that came from line `line` of the
+    /// input file `file`.
+    ///
+    /// The file may be named as the engine recorded it, or by a path ending
+    /// with that name, as editors usually give absolute paths. If nothing
+    /// came from that exact line, the nearest line that produced output is
+    /// used instead. The boxes are returned in page order.
+    pub fn forward_search(&self, file: &str, line: u32) -> Vec<SyncBox> {
+        let tags = self.input_tags(file);
+        if tags.is_empty() {
+            return Vec::new();
+        }
+
+        // The nearest line that produced anything, preferring the later one
+        // of two that are equally far away.
+        let nearest = self
+            .records()
+            .filter(|r| tags.contains(&r.input))
+            .map(|r| r.line)
+            .min_by_key(|&l| (l.max(line) - l.min(line), l < line));
+
+        match nearest {
+            Some(l) => self.boxes_for_line(&tags, l),
+            None => Vec::new(),
+        }
+    }
+
+    /// Find the place in the input that produced the output at (`x`, `y`) on
+    /// page `page`, counting from 1.
+    pub fn backward_search(&self, page: u32, x: f64, y: f64) -> Option<SourceLocation> {
+        let sheet = self.sheets.iter().find(|s| s.page == page)?;
+        let h = (x - self.x_offset) / self.unit;
+        let v = (y - self.y_offset) / self.unit;
+
+        // The smallest box containing the point, preferring horizontal ones,
+        // since those hold the actual text.
+        let container = sheet
+            .records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.kind.is_box() && r.contains(h, v))
+            .min_by(|(_, a), (_, b)| {
+                let key = |r: &Record| {
+                    let area = f64::from(r.width) * f64::from(r.height + r.depth);
+                    (r.kind != RecordKind::HBox, area)
+                };
+                key(a).partial_cmp(&key(b)).unwrap()
+            })
+            .map(|(i, _)| i);
+
+        // Then the record nearest the point among the contents of that box,
+        // or among everything on the page if no box contains it.
+        let record = sheet
+            .records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| match container {
+                Some(c) => r.parent == Some(c),
+                None => true,
+            })
+            .min_by(|(_, a), (_, b)| a.distance(h, v).partial_cmp(&b.distance(h, v)).unwrap())
+            .map(|(_, r)| r)
+            .or_else(|| container.map(|c| &sheet.records[c]))?;
+
+        Some(SourceLocation {
+            file: self.inputs.get(&record.input)?.clone(),
+            line: record.line,
+            column: record.column,
+        })
+    }
+
+    fn records(&self) -> impl Iterator<Item = &Record> {
+        self.sheets.iter().flat_map(|s| s.records.iter())
+    }
+
+    /// Find the tags of the inputs that `file` could refer to.
+    fn input_tags(&self, file: &str) -> Vec<u32> {
+        fn normalize(name: &str) -> String {
+            let name = name.replace('\\', "/");
+            name.trim_start_matches("./").to_owned()
+        }
+
+        let file = normalize(file);
+        let exact: Vec<_> = self
+            .inputs
+            .iter()
+            .filter(|(_, name)| normalize(name) == file)
+            .map(|(tag, _)| *tag)
+            .collect();
+
+        if !exact.is_empty() {
+            return exact;
+        }
+
+        self.inputs
+            .iter()
+            .filter(|(_, name)| {
+                let name = normalize(name);
+                file.ends_with(&format!("/{}", name)) || name.ends_with(&format!("/{}", file))
+            })
+            .map(|(tag, _)| *tag)
+            .collect()
+    }
+
+    /// Find the boxes for the given line. Horizontal boxes hold the actual
+    /// text, so vertical ones, which can be as big as the page, are only
+    /// used if there's nothing else.
+    fn boxes_for_line(&self, tags: &[u32], line: u32) -> Vec<SyncBox> {
+        let mut found: Vec<SyncBox> = Vec::new();
+        let mut vertical: Vec<SyncBox> = Vec::new();
+
+        for sheet in &self.sheets {
+            for record in &sheet.records {
+                if record.line != line || !tags.contains(&record.input) {
+                    continue;
+                }
+
+                // Things that aren't boxes are shown by the boxes around them.
+                let b = if record.kind.is_box() {
+                    record
+                } else {
+                    match record.parent {
+                        Some(p) => &sheet.records[p],
+                        None => record,
+                    }
+                };
+
+                let sb = self.to_sync_box(sheet.page, b);
+                let list = match b.kind {
+                    RecordKind::VBox | RecordKind::VoidVBox => &mut vertical,
+                    _ => &mut found,
+                };
+
+                if !list.contains(&sb) {
+                    list.push(sb);
+                }
+            }
+        }
+
+        if found.is_empty() {
+            vertical
+        } else {
+            found
+        }
+    }
+
+    fn to_sync_box(&self, page: u32, r: &Record) -> SyncBox {
+        SyncBox {
+            page,
+            x: f64::from(r.h) * self.unit + self.x_offset,
+            y: f64::from(r.v) * self.unit + self.y_offset,
+            width: f64::from(r.width) * self.unit,
+            height: f64::from(r.height) * self.unit,
+            depth: f64::from(r.depth) * self.unit,
+        }
+    }
+}
+
+/// The state of parsing SyncTeX data.
+#[derive(Default)]
+struct Parser {
+    result: SyncTex,
+    sheet: Option<Sheet>,
+
+    /// The indices of the boxes that are currently open.
+    open_boxes: Vec<usize>,
+}
+
+impl Parser {
+    fn parse(mut self, text: &str) -> Result<SyncTex> {
+        let mut lines = text.lines().enumerate();
+        let mut unit = 1.;
+        let mut magnification = 1000.;
+        let mut x_offset = 0.;
+        let mut y_offset = 0.;
+
+        match lines.next() {
+            Some((_, l)) if l.starts_with("SyncTeX Version:") => {}
+            _ => return Err(errmsg!("not SyncTeX data")),
+        }
+
+        // The preamble.
+        for (n, line) in &mut lines {
+            let bad = || -> Error { errmsg!("malformed SyncTeX data at line {}", n + 1) };
+
+            if line == "Content:" {
+                break;
+            } else if line.starts_with("Input:") {
+                self.input(line).ok_or_else(bad)?;
+            } else if let Some(v) = line.strip_prefix("Magnification:") {
+                magnification = v.trim().parse().map_err(|_| bad())?;
+            } else if let Some(v) = line.strip_prefix("Unit:") {
+                unit = v.trim().parse().map_err(|_| bad())?;
+            } else if let Some(v) = line.strip_prefix("X Offset:") {
+                x_offset = v.trim().parse().map_err(|_| bad())?;
+            } else if let Some(v) = line.strip_prefix("Y Offset:") {
+                y_offset = v.trim().parse().map_err(|_| bad())?;
+            }
+        }
+
+        self.result.unit = unit * magnification / 1000. / SP_PER_BP;
+        self.result.x_offset = x_offset * unit / SP_PER_BP;
+        self.result.y_offset = y_offset * unit / SP_PER_BP;
+
+        for (n, line) in lines {
+            if line.starts_with("Postamble:") {
+                break;
+            }
+
+            if self.content(line).is_none() {
+                return Err(errmsg!("malformed SyncTeX data at line {}", n + 1));
+            }
+        }
+
+        Ok(self.result)
+    }
+
+    /// Handle an `Input:TAG:NAME` line.
+    fn input(&mut self, line: &str) -> Option<()> {
+        let mut parts = line["Input:".len()..].splitn(2, ':');
+        let tag = parts.next()?.parse().ok()?;
+        let name = parts.next()?;
+        self.result.inputs.insert(tag, name.to_owned());
+        Some(())
+    }
+
+    /// Handle a line of the content section. Returns `None` if it's
+    /// malformed.
+    fn content(&mut self, line: &str) -> Option<()> {
+        let mut chars = line.chars();
+        let first = match chars.next() {
+            Some(c) => c,
+            None => return Some(()),
+        };
+        let rest = chars.as_str();
+
+        let kind = match first {
+            '{' => {
+                let page = rest.trim().parse().ok()?;
+                self.sheet = Some(Sheet {
+                    page,
+                    records: Vec::new(),
+                });
+                self.open_boxes.clear();
+                return Some(());
+            }
+            '}' => {
+                if let Some(sheet) = self.sheet.take() {
+                    self.result.sheets.push(sheet);
+                }
+                return Some(());
+            }
+            ']' | ')' => {
+                self.open_boxes.pop();
+                return Some(());
+            }
+            'I' if line.starts_with("Input:") => return self.input(line),
+            '[' => RecordKind::VBox,
+            '(' => RecordKind::HBox,
+            'v' => RecordKind::VoidVBox,
+            'h' => RecordKind::VoidHBox,
+            'x' => RecordKind::Current,
+            'k' => RecordKind::Kern,
+            'g' => RecordKind::Glue,
+            '$' => RecordKind::Math,
+            // Byte offsets, forms and other things that we don't use.
+            _ => return Some(()),
+        };
+
+        // Records look like `TAG,LINE[,COLUMN]:H,V[:W[,H,D]]`.
+        let mut parts = rest.split(':');
+        let mut link = parts.next()?.split(',');
+        let input = link.next()?.parse().ok()?;
+        let src_line = link.next()?.parse().ok()?;
+        let column = match link.next() {
+            Some(c) => Some(c.parse().ok()?),
+            None => None,
+        };
+
+        let mut point = parts.next()?.split(',');
+        let h = point.next()?.parse().ok()?;
+        let v = point.next()?.parse().ok()?;
+
+        let size: Vec<i32> = match parts.next() {
+            Some(s) => s
+                .split(',')
+                .map(|x| x.parse().ok())
+                .collect::<Option<_>>()?,
+            None => Vec::new(),
+        };
+
+        let sheet = self.sheet.as_mut()?;
+        sheet.records.push(Record {
+            kind,
+            input,
+            line: src_line,
+            column,
+            h,
+            v,
+            width: size.first().cloned().unwrap_or(0),
+            height: size.get(1).cloned().unwrap_or(0),
+            depth: size.get(2).cloned().unwrap_or(0),
+            parent: self.open_boxes.last().cloned(),
+        });
+
+        if kind == RecordKind::VBox || kind == RecordKind::HBox {
+            self.open_boxes.push(sheet.records.len() - 1);
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "SyncTeX Version:1
+Input:1:./doc.tex
+Input:2:chapter.tex
+Output:pdf
+Magnification:1000
+Unit:1
+X Offset:0
+Y Offset:0
+Content:
+!106
+{1
+[1,1:4736287,48462073:30785863,43725786,0
+(1,3:4736287,5391647:30785863,282168,0
+h1,3:4736287,5391647:1310720,0,0
+x1,3:6374688,5391647
+k1,3:35522150,5391647:29147462
+)
+(2,7:4736287,6391647:30785863,282168,0
+x2,7:5000000,6391647
+g2,8:9000000,6391647
+)
+]
+}1
+{2
+[1,1:4736287,48462073:30785863,43725786,0
+(1,10:4736287,5391647:30785863,282168,0
+x1,10:6000000,5391647
+)
+]
+}2
+Postamble:
+Count:21
+Post scriptum:
+";
+
+    fn bp(sp: i32) -> f64 {
+        f64::from(sp) / SP_PER_BP
+    }
+
+    #[test]
+    fn forward() {
+        let st = SyncTex::parse(SAMPLE.as_bytes()).unwrap();
+        assert_eq!(st.num_pages(), 2);
+        assert_eq!(
+            st.input_names().collect::<Vec<_>>(),
+            vec!["./doc.tex", "chapter.tex"]
+        );
+
+        let boxes = st.forward_search("/home/me/doc.tex", 3);
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(boxes[0].page, 1);
+        assert!((boxes[0].x - 72.).abs() < 1e-3);
+        assert!((boxes[0].y - bp(5_391_647)).abs() < 1e-9);
+        assert!((boxes[1].width - bp(1_310_720)).abs() < 1e-9);
+
+        // Line 9 produced nothing, so we get the nearest line that did.
+        let boxes = st.forward_search("doc.tex", 9);
+        assert_eq!(boxes.len(), 1);
+        assert_eq!(boxes[0].page, 2);
+
+        // Even when the line is far past the last one that produced output.
+        let boxes = st.forward_search("doc.tex", 1_000_000);
+        assert_eq!(boxes.len(), 1);
+        assert_eq!(boxes[0].page, 2);
+        assert!((boxes[0].x - bp(4_736_287)).abs() < 1e-9);
+
+        assert!(!st.forward_search("chapter.tex", 8).is_empty());
+        assert!(st.forward_search("other.tex", 3).is_empty());
+    }
+
+    #[test]
+    fn backward() {
+        let st = SyncTex::parse(SAMPLE.as_bytes()).unwrap();
+
+        let loc = st
+            .backward_search(1, bp(6_400_000), bp(5_391_647) - 1.)
+            .unwrap();
+        assert_eq!(
+            loc,
+            SourceLocation {
+                file: "./doc.tex".to_owned(),
+                line: 3,
+                column: None,
+            }
+        );
+
+        let loc = st.backward_search(1, bp(8_000_000), bp(6_391_647)).unwrap();
+        assert_eq!(loc.file, "chapter.tex");
+        assert_eq!(loc.line, 8);
+
+        let loc = st.backward_search(2, 100., 100.).unwrap();
+        assert_eq!(loc.line, 10);
+        assert!(st.backward_search(3, 100., 100.).is_none());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(SyncTex::parse(b"hello").is_err());
+        assert!(SyncTex::parse(b"SyncTeX Version:1\nContent:\n{1\n(1,x:1,2\n}1\n").is_err());
+    }
+}
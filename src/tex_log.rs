@@ -0,0 +1,352 @@
+// src/tex_log.rs -- extracting diagnostics from TeX log files
+// Copyright 2019 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Extracting structured diagnostics from the log files written by the TeX
+//! engine.
+//!
+//! TeX logs are not designed to be machine-readable, so this is necessarily
+//! a heuristic business. We recognize the handful of messages that users care
+//! about most — errors, bad boxes, undefined references and citations, and
+//! missing characters — and try to attribute each one to a file and line.
+
+use crate::status::{Diagnostic, DiagnosticKind};
+
+/// TeX hard-wraps its log output at this many characters (its
+/// `max_print_line` parameter).
+const MAX_PRINT_LINE: usize = 79;
+
+/// How many lines after an error message we look for its `l.NNN` context
+/// line.
+const ERROR_CONTEXT_LINES: usize = 20;
+
+/// Parse the contents of a TeX log file into a list of diagnostics, in the
+/// order that they appear in the log.
+pub fn parse_log(data: &[u8]) -> Vec<Diagnostic> {
+    let text = String::from_utf8_lossy(data);
+    let lines = unwrap_lines(&text);
+    let mut files = FileStack::default();
+    let mut diags = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = &lines[i];
+
+        if let Some(message) = line.strip_prefix("! ") {
+            // An error. The message is followed by some context, which ends
+            // with a line like "l.12 \foo" giving the input line number. The
+            // context echoes the source text, which can contain unbalanced
+            // parentheses, so we don't feed it to the file tracker.
+            let mut lineno = None;
+            let mut j = i + 1;
+
+            while j < lines.len() && j <= i + ERROR_CONTEXT_LINES {
+                if lines[j].starts_with("! ") {
+                    break;
+                }
+
+                if let Some(n) = parse_context_line(&lines[j]) {
+                    lineno = Some(n);
+                    // The context line is followed by the rest of the
+                    // offending source line.
+                    j += 1;
+                    break;
+                }
+
+                j += 1;
+            }
+
+            diags.push(Diagnostic {
+                kind: DiagnosticKind::Error,
+                message: message.trim().to_owned(),
+                file: files.current(),
+                line: lineno,
+            });
+
+            if lineno.is_some() {
+                i = j + 1;
+            } else {
+                i += 1;
+            }
+
+            continue;
+        }
+
+        if let Some(diag) = parse_warning(line, &files) {
+            diags.push(diag);
+        } else {
+            files.scan(line);
+        }
+
+        i += 1;
+    }
+
+    diags
+}
+
+/// Messages with which LaTeX and assorted packages ask for another run.
+const RERUN_REQUESTS: &[&str] = &["Rerun to get", "Rerun LaTeX", "rerun LaTeX"];
+
+/// Look for a message in a TeX log asking for the document to be processed
+/// again, returning the line that contains it.
+pub fn find_rerun_request(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+
+    unwrap_lines(&text)
+        .into_iter()
+        .find(|line| RERUN_REQUESTS.iter().any(|r| line.contains(r)))
+        .map(|line| line.trim().to_owned())
+}
+
+/// Undo TeX's hard-wrapping of long lines.
+fn unwrap_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut cur = String::new();
+
+    for line in text.lines() {
+        cur.push_str(line);
+
+        if line.chars().count() != MAX_PRINT_LINE {
+            lines.push(std::mem::take(&mut cur));
+        }
+    }
+
+    if !cur.is_empty() {
+        lines.push(cur);
+    }
+
+    lines
+}
+
+/// Parse a line of the form `l.123 some source text`.
+fn parse_context_line(line: &str) -> Option<u32> {
+    if !line.starts_with("l.") {
+        return None;
+    }
+
+    let digits: String = line[2..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+/// Parse the first integer that follows `marker` in `line`.
+fn number_after(line: &str, marker: &str) -> Option<u32> {
+    let start = line.find(marker)? + marker.len();
+    let digits: String = line[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+fn parse_warning(line: &str, files: &FileStack) -> Option<Diagnostic> {
+    let (kind, message, lineno) = if line.starts_with("Overfull \\") {
+        (
+            DiagnosticKind::OverfullBox,
+            line,
+            number_after(line, "at lines ").or_else(|| number_after(line, "at line ")),
+        )
+    } else if line.starts_with("Underfull \\") {
+        (
+            DiagnosticKind::UnderfullBox,
+            line,
+            number_after(line, "at lines ").or_else(|| number_after(line, "at line ")),
+        )
+    } else if line.starts_with("Missing character: ") {
+        (DiagnosticKind::MissingCharacter, line, None)
+    } else {
+        // Undefined references and citations come from LaTeX itself and from
+        // packages like natbib, which prefix the message differently but
+        // agree on the wording.
+        let idx = line.find("Warning: ")?;
+        let message = &line[idx + "Warning: ".len()..];
+
+        if !message.contains(" undefined") {
+            return None;
+        }
+
+        let kind = if message.starts_with("Reference `") {
+            DiagnosticKind::UndefinedReference
+        } else if message.starts_with("Citation `") {
+            DiagnosticKind::UndefinedCitation
+        } else {
+            return None;
+        };
+
+        (kind, message, number_after(message, "on input line "))
+    };
+
+    Some(Diagnostic {
+        kind,
+        message: message.trim().to_owned(),
+        file: files.current(),
+        line: lineno,
+    })
+}
+
+/// Tracks which input file TeX is currently reading.
+///
+/// TeX prints `(filename` when it starts reading a file and `)` when it's
+/// done with it. Other parenthesized text shows up in the log too, so we
+/// keep placeholder entries for groups that don't look like file names in
+/// order to keep things balanced.
+#[derive(Debug, Default)]
+struct FileStack {
+    stack: Vec<Option<String>>,
+}
+
+impl FileStack {
+    fn scan(&mut self, line: &str) {
+        let mut chars = line.char_indices().peekable();
+
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '(' => {
+                    let rest = &line[idx + 1..];
+                    let end = rest
+                        .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                        .unwrap_or(rest.len());
+                    let name = &rest[..end];
+
+                    if looks_like_file(name) {
+                        self.stack.push(Some(name.to_owned()));
+
+                        while let Some(&(j, _)) = chars.peek() {
+                            if j > idx + end {
+                                break;
+                            }
+                            chars.next();
+                        }
+                    } else {
+                        self.stack.push(None);
+                    }
+                }
+
+                ')' => {
+                    self.stack.pop();
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    fn current(&self) -> Option<String> {
+        self.stack.iter().rev().flatten().next().cloned()
+    }
+}
+
+fn looks_like_file(name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+
+    match name.rfind('.') {
+        // Require a plausible extension, so that things like "(1.5pt" or
+        // "(see" aren't mistaken for files.
+        Some(i) => {
+            let ext = &name[i + 1..];
+            ext.starts_with(|c: char| c.is_ascii_alphabetic())
+                && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"This is XeTeX, Version 3.14159265-2.6-0.99999 (Tectonic) (preloaded format=latex 2019.1.1)
+**
+(./main.tex
+LaTeX2e <2018-12-01>
+(article.cls
+Document Class: article 2018/09/03 v1.4i Standard LaTeX document class
+(size10.clo))
+(./main.aux)
+(./chapter.tex
+Overfull \hbox (12.34pt too wide) in paragraph at lines 10--12
+[]\TU/lmr/m/n/10 Some text
+
+LaTeX Warning: Reference `fig:missing' on page 1 undefined on input line 14.
+
+)
+Missing character: There is no ^^A in font cmr10!
+
+LaTeX Warning: Citation `knuth84' on page 1 undefined on input line 20.
+
+Underfull \hbox (badness 10000) in paragraph at lines 22--23
+
+! Undefined control sequence.
+l.25 \foo
+          bar
+[1] (./main.aux) )
+"#;
+
+    #[test]
+    fn sample_log() {
+        let diags = parse_log(SAMPLE.as_bytes());
+        let summary: Vec<_> = diags
+            .iter()
+            .map(|d| (d.kind, d.file.as_deref(), d.line))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (DiagnosticKind::OverfullBox, Some("./chapter.tex"), Some(10)),
+                (
+                    DiagnosticKind::UndefinedReference,
+                    Some("./chapter.tex"),
+                    Some(14)
+                ),
+                (DiagnosticKind::MissingCharacter, Some("./main.tex"), None),
+                (
+                    DiagnosticKind::UndefinedCitation,
+                    Some("./main.tex"),
+                    Some(20)
+                ),
+                (DiagnosticKind::UnderfullBox, Some("./main.tex"), Some(22)),
+                (DiagnosticKind::Error, Some("./main.tex"), Some(25)),
+            ]
+        );
+        assert_eq!(diags[5].message, "Undefined control sequence.");
+        assert_eq!(
+            diags[1].message,
+            "Reference `fig:missing' on page 1 undefined on input line 14."
+        );
+    }
+
+    #[test]
+    fn wrapped_lines() {
+        let long = format!("({}.tex", "x".repeat(MAX_PRINT_LINE - 1));
+        let log = format!(
+            "{}\n{}\n! Emergency stop.\nl.3 \n",
+            &long[..MAX_PRINT_LINE],
+            &long[MAX_PRINT_LINE..]
+        );
+        let diags = parse_log(log.as_bytes());
+
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].file.as_ref().unwrap(), &long[1..]);
+        assert_eq!(diags[0].line, Some(3));
+    }
+
+    #[test]
+    fn rerun_requests() {
+        assert_eq!(find_rerun_request(SAMPLE.as_bytes()), None);
+
+        let log = "(./main.aux)\n\nLaTeX Warning: Label(s) may have changed. \
+                   Rerun to get cross-references right.\n\n )\n";
+        assert_eq!(
+            find_rerun_request(log.as_bytes()).unwrap(),
+            "LaTeX Warning: Label(s) may have changed. Rerun to get cross-references right."
+        );
+
+        let log = "Package biblatex Warning: Please rerun LaTeX.\n";
+        assert!(find_rerun_request(log.as_bytes()).is_some());
+    }
+}
@@ -8,6 +8,8 @@
 
 #include "core-foundation.h"
 
+#include <time.h>
+
 /* Both XeTeX and bibtex use this enum: */
 
 typedef enum {
@@ -80,6 +82,31 @@ typedef struct tt_bridge_api_t {
     int (*input_close)(void *context, rust_input_handle_t handle);
 } tt_bridge_api_t;
 
+/* Settings for the xdvipdfmx engine that the Rust side can control. Lengths
+ * are in PostScript points (bp). */
+typedef struct tt_dvipdfmx_options_t {
+    const char *paperspec; /* NULL to use the default */
+    bool landscape;
+    double x_offset;
+    double y_offset;
+    double mag;
+    double annot_grow;
+    int bookmark_open;
+    int decimal_digits;
+    bool encrypt;
+    int key_bits; /* 40, 128 or 256 */
+    int32_t permission; /* the PDF's /P flags */
+    const char *owner_password;
+    const char *user_password;
+    bool pdfa; /* produce PDF/A-2b */
+    const char *icc_profile_name; /* the PDF/A output intent's profile */
+    const unsigned char *icc_profile;
+    size_t icc_profile_length;
+    int icc_components;
+    unsigned char random_seed[32]; /* unpredictable bytes for encryption keys and salts */
+    bool random_seeded; /* false if the OS couldn't provide random_seed */
+} tt_dvipdfmx_options_t;
+
 
 BEGIN_EXTERN_C
 
@@ -87,8 +114,8 @@ BEGIN_EXTERN_C
  * API that we expose to the Rust side of things. */
 
 const char *tt_get_error_message(void);
-int tex_simple_main(tt_bridge_api_t *api, char *dump_name, char *input_file_name);
-int dvipdfmx_simple_main(tt_bridge_api_t *api, char *dviname, char *pdfname, bool compress, bool deterministic_tags);
+int tex_simple_main(tt_bridge_api_t *api, char *dump_name, char *input_file_name, time_t build_date);
+int dvipdfmx_simple_main(tt_bridge_api_t *api, char *dviname, char *pdfname, char *pagespec, const tt_dvipdfmx_options_t *options, bool compress, bool deterministic_tags, time_t build_date);
 int bibtex_simple_main(tt_bridge_api_t *api, char *aux_file_name);
 
 /* The internal, C/C++ interface: */
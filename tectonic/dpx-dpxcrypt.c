@@ -1226,6 +1226,53 @@ AES_ecb_encrypt (const unsigned char *key,    size_t  key_len,
   }
 }
 
+/* The random bytes are SHA-256(seed || counter), one block at a time. The
+ * seed comes from the operating system by way of the Rust side: rand()
+ * seeded with the build date, as upstream does it, would make the keys of a
+ * reproducible build easy to guess. */
+static unsigned char random_seed[32];
+static uint64_t      random_counter = 0;
+static int           random_seeded  = 0;
+
+void
+dpx_random_seed (const unsigned char *seed, size_t len)
+{
+  SHA256_CONTEXT sha;
+
+  SHA256_init (&sha);
+  SHA256_write(&sha, seed, len);
+  SHA256_final(random_seed, &sha);
+  random_counter = 0;
+  random_seeded  = 1;
+}
+
+void
+dpx_random_bytes (unsigned char *buf, size_t len)
+{
+  SHA256_CONTEXT sha;
+  unsigned char  block[32], counter[8];
+  size_t         i, n;
+
+  if (!random_seeded)
+    _tt_abort("couldn't get random data to encrypt the PDF");
+
+  while (len > 0) {
+    for (i = 0; i < 8; i++)
+      counter[i] = (random_counter >> (8 * i)) & 0xff;
+    random_counter++;
+
+    SHA256_init (&sha);
+    SHA256_write(&sha, random_seed, 32);
+    SHA256_write(&sha, counter, 8);
+    SHA256_final(block, &sha);
+
+    n = len < 32 ? len : 32;
+    memcpy(buf, block, n);
+    buf += n;
+    len -= n;
+  }
+}
+
 /* NULL iv means here "use random IV". */
 void
 AES_cbc_encrypt_tectonic (const unsigned char *key,    size_t  key_len,
@@ -1244,10 +1291,8 @@ AES_cbc_encrypt_tectonic (const unsigned char *key,    size_t  key_len,
 
   if (iv)
     memcpy(ctx->iv, iv, AES_BLOCKSIZE);
-  else {
-    for (i = 0; i < AES_BLOCKSIZE; i++)
-      ctx->iv[i] = rand() % 256;
-  }
+  else
+    dpx_random_bytes(ctx->iv, AES_BLOCKSIZE);
   /* 16 bytes aligned.
    * Note that when padding is enabled there can be excess 16-byte
    * filled with 0x10. It occurs when size of the input data is multiple
@@ -91,4 +91,9 @@ void AES_cbc_encrypt_tectonic (const unsigned char *key,    size_t  key_len,
                                const unsigned char *plain,  size_t  plain_len,
                                unsigned char      **cipher, size_t *cipher_len);
 
+/* Random bytes for encryption keys, salts and IVs. The generator must be
+ * seeded with unpredictable data before it is used. */
+void dpx_random_seed  (const unsigned char *seed, size_t len);
+void dpx_random_bytes (unsigned char *buf, size_t len);
+
 #endif /* _DPXCRYPT_H_ */
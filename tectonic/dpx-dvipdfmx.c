@@ -36,6 +36,7 @@
 #include "core-bridge.h"
 #include "dpx-cid.h"
 #include "dpx-dpxconf.h"
+#include "dpx-dpxcrypt.h"
 #include "dpx-dpxfile.h"
 #include "dpx-dpxutil.h"
 #include "dpx-dvi.h"
@@ -366,10 +367,12 @@ dvipdfmx_main (
   const char *pdf_filename,
   const char *dvi_filename,
   const char *pagespec,
+  const tt_dvipdfmx_options_t *options,
   int opt_flags,
   bool translate,
   bool compress,
   bool deterministic_tags,
+  time_t build_date,
   bool quiet,
   unsigned int verbose)
 {
@@ -407,6 +410,7 @@ dvipdfmx_main (
 
   pdf_set_compression(compress ? 9 : 0);
   pdf_font_set_deterministic_unique_tags(deterministic_tags ? 1 : 0);
+  set_unique_time(build_date);
 
   system_default();
 
@@ -420,6 +424,8 @@ dvipdfmx_main (
   select_paper("letter");
   annot_grow = 0;
   bookmark_open = 0;
+  do_encryption = 0;
+  always_embed = 0;
   key_bits = 40;
   permission = 0x003C;
   font_dpi = 600;
@@ -429,6 +435,36 @@ dvipdfmx_main (
   pdf_load_fontmap_file("kanjix.map", FONTMAP_RMODE_APPEND);
   pdf_load_fontmap_file("ckx.map", FONTMAP_RMODE_APPEND);
 
+  /* These are command-line options in standalone dvipdfmx, so the config
+   * file doesn't touch them, but they need resetting all the same. */
+  landscape_mode = 0;
+  x_offset = 72.0;
+  y_offset = 72.0;
+  mag = 1.0;
+
+  if (options) {
+    if (options->paperspec)
+      select_paper(options->paperspec);
+    landscape_mode = options->landscape ? 1 : 0;
+    x_offset = options->x_offset;
+    y_offset = options->y_offset;
+    mag = options->mag;
+    annot_grow = options->annot_grow;
+    bookmark_open = options->bookmark_open;
+    pdfdecimaldigits = options->decimal_digits;
+    if (options->random_seeded)
+      dpx_random_seed(options->random_seed, sizeof(options->random_seed));
+  }
+
+  if (options && options->pdfa) {
+    always_embed = 1;
+    pdf_doc_set_pdfa(1, options->icc_profile_name, options->icc_profile,
+                     options->icc_profile_length, options->icc_components);
+  } else {
+    pdf_doc_set_pdfa(0, NULL, NULL, 0, 0);
+  }
+  pdf_obj_set_transparency_check(options && options->pdfa);
+
   if (pagespec) {
     select_pages(pagespec, &page_ranges, &num_page_ranges);
   }
@@ -450,7 +486,23 @@ dvipdfmx_main (
 
   {
     int ver_major = 0,  ver_minor = 0;
-    char owner_pw[MAX_PWD_LEN], user_pw[MAX_PWD_LEN];
+    char owner_pw[MAX_PWD_LEN + 1], user_pw[MAX_PWD_LEN + 1];
+
+    memset(owner_pw, 0, sizeof(owner_pw));
+    memset(user_pw, 0, sizeof(user_pw));
+
+    /* Encryption settings from the caller can still be overridden by a
+     * pdf:encrypt special in the document. */
+    if (options && options->encrypt) {
+      do_encryption = 1;
+      key_bits = options->key_bits;
+      permission = options->permission;
+      if (options->owner_password)
+        strncpy(owner_pw, options->owner_password, MAX_PWD_LEN);
+      if (options->user_password)
+        strncpy(user_pw, options->user_password, MAX_PWD_LEN);
+    }
+
     /* Dependency between DVI and PDF side is rather complicated... */
     dvi2pts = dvi_init(dvi_filename, mag);
     if (dvi2pts == 0.0)
@@ -466,6 +518,8 @@ dvipdfmx_main (
     if (ver_minor >= PDF_VERSION_MIN && ver_minor <= PDF_VERSION_MAX) {
       pdf_set_version(ver_minor);
     }
+    if (do_encryption && options && options->pdfa)
+      _tt_abort("PDF/A documents cannot be encrypted");
     if (do_encryption) {
       if (!(key_bits >= 40 && key_bits <= 128 && (key_bits % 8 == 0)) &&
             key_bits != 256)
@@ -473,6 +527,9 @@ dvipdfmx_main (
       else if (key_bits > 40 && pdf_get_version() < 4)
         _tt_abort("Chosen key length requires at least PDF 1.4. " \
               "Use \"-V 4\" to change.");
+      /* Without PDF 1.7, 256-bit keys silently fall back to 128 bits. */
+      if (key_bits == 256 && pdf_get_version() < 7)
+        pdf_set_version(7);
       do_encryption = 1;
       pdf_enc_set_passwd(key_bits, permission, owner_pw, user_pw);
     }
@@ -518,6 +575,10 @@ dvipdfmx_main (
   /* pdf_close_document flushes XObject (image) and other resources. */
   pdf_close_document();
 
+  if (options && options->pdfa && pdf_obj_transparency_found())
+    dpx_warning("This document uses transparency. PDF/A-2b allows it, "
+                "but check the output with a PDF/A validator.");
+
   pdf_close_fontmaps(); /* pdf_font may depend on fontmap. */
 
   dvi_close();
@@ -39,10 +39,12 @@ int dvipdfmx_main(
   const char *pdfname,
   const char *dviname,
   const char *pagespec,
+  const tt_dvipdfmx_options_t *options,
   int opt_flags,
   bool translate,
   bool compress,
   bool deterministic_tags,
+  time_t build_date,
   bool quiet,
   unsigned int verbose);
 
@@ -248,6 +248,27 @@ typedef struct pdf_doc
 } pdf_doc;
 static pdf_doc pdoc;
 
+/* Settings for PDF/A-2b output. */
+static struct {
+  int enabled;
+  const char *icc_name;
+  const unsigned char *icc_profile;
+  size_t icc_length;
+  int icc_components;
+} pdfa;
+
+void
+pdf_doc_set_pdfa (int enable, const char *icc_name,
+                  const unsigned char *icc_profile, size_t icc_length,
+                  int icc_components)
+{
+  pdfa.enabled = enable;
+  pdfa.icc_name = icc_name;
+  pdfa.icc_profile = icc_profile;
+  pdfa.icc_length = icc_length;
+  pdfa.icc_components = icc_components;
+}
+
 static void
 pdf_doc_init_catalog (pdf_doc *p)
 {
@@ -303,6 +324,29 @@ pdf_doc_close_catalog (pdf_doc *p)
     p->root.pagelabels = NULL;
   }
 
+  if (pdfa.enabled && !pdf_lookup_dict(p->root.dict, "OutputIntents")) {
+    pdf_obj *intent, *profile, *intents;
+
+    profile = pdf_new_stream(STREAM_COMPRESS);
+    pdf_add_dict(pdf_stream_dict(profile),
+                 pdf_new_name("N"), pdf_new_number(pdfa.icc_components));
+    pdf_add_stream(profile, pdfa.icc_profile, pdfa.icc_length);
+
+    intent = pdf_new_dict();
+    pdf_add_dict(intent, pdf_new_name("Type"), pdf_new_name("OutputIntent"));
+    pdf_add_dict(intent, pdf_new_name("S"), pdf_new_name("GTS_PDFA1"));
+    pdf_add_dict(intent, pdf_new_name("OutputConditionIdentifier"),
+                 pdf_new_string(pdfa.icc_name, strlen(pdfa.icc_name)));
+    pdf_add_dict(intent, pdf_new_name("Info"),
+                 pdf_new_string(pdfa.icc_name, strlen(pdfa.icc_name)));
+    pdf_add_dict(intent, pdf_new_name("DestOutputProfile"), pdf_ref_obj(profile));
+    pdf_release_obj(profile);
+
+    intents = pdf_new_array();
+    pdf_add_array(intents, intent);
+    pdf_add_dict(p->root.dict, pdf_new_name("OutputIntents"), intents);
+  }
+
   pdf_add_dict(p->root.dict,
                pdf_new_name("Type"), pdf_new_name("Catalog"));
   pdf_release_obj(p->root.dict);
@@ -491,6 +535,237 @@ asn_date (char *date_string)
   return strlen(date_string);
 }
 
+/*
+ * XMP metadata for PDF/A. It has to agree with the DocInfo dictionary.
+ */
+
+/* PDFDocEncoding agrees with Latin-1 except for these, from 0x80 to 0xA0.
+ * 0x9F is undefined. */
+static const unsigned int pdfdoc_to_unicode[] = {
+  0x2022, 0x2020, 0x2021, 0x2026, 0x2014, 0x2013, 0x0192, 0x2044,
+  0x2039, 0x203A, 0x2212, 0x2030, 0x201E, 0x201C, 0x201D, 0x2018,
+  0x2019, 0x201A, 0x2122, 0xFB01, 0xFB02, 0x0141, 0x0152, 0x0160,
+  0x0178, 0x017D, 0x0131, 0x0142, 0x0153, 0x0161, 0x017E, 0xFFFD,
+  0x20AC
+};
+
+static void
+xmp_add (pdf_obj *xmp, const char *text)
+{
+  pdf_add_stream(xmp, text, strlen(text));
+}
+
+/* Write one character as UTF-8, escaped for XML. Control characters are not
+ * allowed in XML, so we drop them. */
+static void
+xmp_add_char (pdf_obj *xmp, unsigned int c)
+{
+  char buf[4];
+  int len;
+
+  if (c < 0x20 && c != '\t' && c != '\n' && c != '\r')
+    return;
+
+  switch (c) {
+  case '<': xmp_add(xmp, "&lt;"); return;
+  case '>': xmp_add(xmp, "&gt;"); return;
+  case '&': xmp_add(xmp, "&amp;"); return;
+  }
+
+  if (c < 0x80) {
+    buf[0] = c;
+    len = 1;
+  } else if (c < 0x800) {
+    buf[0] = 0xC0 | (c >> 6);
+    buf[1] = 0x80 | (c & 0x3F);
+    len = 2;
+  } else if (c < 0x10000) {
+    buf[0] = 0xE0 | (c >> 12);
+    buf[1] = 0x80 | ((c >> 6) & 0x3F);
+    buf[2] = 0x80 | (c & 0x3F);
+    len = 3;
+  } else {
+    buf[0] = 0xF0 | (c >> 18);
+    buf[1] = 0x80 | ((c >> 12) & 0x3F);
+    buf[2] = 0x80 | ((c >> 6) & 0x3F);
+    buf[3] = 0x80 | (c & 0x3F);
+    len = 4;
+  }
+
+  pdf_add_stream(xmp, buf, len);
+}
+
+/* Write a PDF text string, in UTF-16BE or PDFDocEncoding, as XML text. */
+static void
+xmp_add_text (pdf_obj *xmp, pdf_obj *string)
+{
+  const unsigned char *s = pdf_string_value(string);
+  unsigned int len = pdf_string_length(string);
+  unsigned int i, c;
+
+  if (len >= 2 && s[0] == 0xFE && s[1] == 0xFF) {
+    for (i = 2; i + 1 < len; i += 2) {
+      c = (s[i] << 8) | s[i + 1];
+
+      if (c >= 0xD800 && c < 0xDC00 && i + 3 < len) {
+        unsigned int lo = (s[i + 2] << 8) | s[i + 3];
+
+        if (lo >= 0xDC00 && lo < 0xE000) {
+          c = 0x10000 + ((c - 0xD800) << 10) + (lo - 0xDC00);
+          i += 2;
+        }
+      }
+
+      if (c >= 0xD800 && c < 0xE000)
+        c = 0xFFFD;
+      xmp_add_char(xmp, c);
+    }
+  } else {
+    for (i = 0; i < len; i++) {
+      c = s[i];
+      if (c >= 0x80 && c <= 0xA0)
+        c = pdfdoc_to_unicode[c - 0x80];
+      else if (c == 0xAD || c == 0x7F)
+        c = 0xFFFD;
+      xmp_add_char(xmp, c);
+    }
+  }
+}
+
+/* Convert a PDF date, like D:20160224085452+01'00', into the form that XMP
+ * uses, like 2016-02-24T08:54:52+01:00. Missing parts are left out, except
+ * that hours get minutes, and a zero time zone offset becomes Z. */
+static void
+xmp_add_date (pdf_obj *xmp, pdf_obj *string)
+{
+  static const char *seps[] = { "", "-", "-", "T", ":", ":" };
+  static const int widths[] = { 4, 2, 2, 2, 2, 2 };
+  const char *s = pdf_string_value(string);
+  const char *end = s + pdf_string_length(string);
+  char buf[8];
+  int part, n;
+
+  if (end - s >= 2 && s[0] == 'D' && s[1] == ':')
+    s += 2;
+
+  for (part = 0; part < 6; part++) {
+    for (n = 0; n < widths[part] && s + n < end && s[n] >= '0' && s[n] <= '9'; n++)
+      ;
+    if (n < widths[part])
+      break;
+    xmp_add(xmp, seps[part]);
+    pdf_add_stream(xmp, s, n);
+    s += n;
+  }
+
+  /* XMP times have hours and minutes, or nothing, and then a time zone. */
+  if (part == 4)
+    xmp_add(xmp, ":00");
+  if (part < 4)
+    return;
+
+  if (s < end && *s == 'Z') {
+    xmp_add(xmp, "Z");
+  } else if (end - s >= 3 && (*s == '+' || *s == '-')) {
+    const char *hours = s + 1, *minutes = "00";
+
+    s += 3;
+    if (s < end && *s == '\'')
+      s++;
+    if (end - s >= 2)
+      minutes = s;
+
+    /* dvipdfmx writes UTC as -00'00', but in XMP that means "unknown". */
+    if (!strncmp(hours, "00", 2) && !strncmp(minutes, "00", 2)) {
+      xmp_add(xmp, "Z");
+    } else {
+      snprintf(buf, sizeof(buf), "%c%.2s:%.2s", hours[-1], hours, minutes);
+      xmp_add(xmp, buf);
+    }
+  }
+}
+
+static void
+xmp_add_property (pdf_obj *xmp, pdf_obj *docinfo, const char *key,
+                  const char *prop, const char *open, const char *close)
+{
+  pdf_obj *value = pdf_lookup_dict(docinfo, key);
+
+  if (!value)
+    return;
+
+  xmp_add(xmp, "   <");
+  xmp_add(xmp, prop);
+  xmp_add(xmp, ">");
+  xmp_add(xmp, open);
+  if (strstr(key, "Date"))
+    xmp_add_date(xmp, value);
+  else
+    xmp_add_text(xmp, value);
+  xmp_add(xmp, close);
+  xmp_add(xmp, "</");
+  xmp_add(xmp, prop);
+  xmp_add(xmp, ">\n");
+}
+
+static void
+pdf_doc_add_xmp_metadata (pdf_doc *p, pdf_obj *docinfo)
+{
+  pdf_obj *xmp, *dict;
+  pdf_obj *date;
+
+  /* Packages like pdfx provide their own, more complete, metadata. */
+  if (pdf_lookup_dict(p->root.dict, "Metadata"))
+    return;
+
+  /* The metadata must not be compressed, so that tools that don't understand
+   * PDF can find it. */
+  xmp = pdf_new_stream(0);
+  dict = pdf_stream_dict(xmp);
+  pdf_add_dict(dict, pdf_new_name("Type"), pdf_new_name("Metadata"));
+  pdf_add_dict(dict, pdf_new_name("Subtype"), pdf_new_name("XML"));
+
+  xmp_add(xmp,
+          "<?xpacket begin=\"\xEF\xBB\xBF\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n"
+          "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n"
+          " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n"
+          "  <rdf:Description rdf:about=\"\"\n"
+          "    xmlns:pdfaid=\"http://www.aiim.org/pdfa/ns/id/\"\n"
+          "    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n"
+          "    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n"
+          "    xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\">\n"
+          "   <pdfaid:part>2</pdfaid:part>\n"
+          "   <pdfaid:conformance>B</pdfaid:conformance>\n");
+
+  xmp_add_property(xmp, docinfo, "Title", "dc:title",
+                   "<rdf:Alt><rdf:li xml:lang=\"x-default\">", "</rdf:li></rdf:Alt>");
+  xmp_add_property(xmp, docinfo, "Author", "dc:creator",
+                   "<rdf:Seq><rdf:li>", "</rdf:li></rdf:Seq>");
+  xmp_add_property(xmp, docinfo, "Subject", "dc:description",
+                   "<rdf:Alt><rdf:li xml:lang=\"x-default\">", "</rdf:li></rdf:Alt>");
+  xmp_add_property(xmp, docinfo, "Keywords", "pdf:Keywords", "", "");
+  xmp_add_property(xmp, docinfo, "Creator", "xmp:CreatorTool", "", "");
+  xmp_add_property(xmp, docinfo, "Producer", "pdf:Producer", "", "");
+  xmp_add_property(xmp, docinfo, "CreationDate", "xmp:CreateDate", "", "");
+  xmp_add_property(xmp, docinfo, "ModDate", "xmp:ModifyDate", "", "");
+
+  date = pdf_lookup_dict(docinfo, "ModDate");
+  if (date) {
+    xmp_add(xmp, "   <xmp:MetadataDate>");
+    xmp_add_date(xmp, date);
+    xmp_add(xmp, "</xmp:MetadataDate>\n");
+  }
+
+  xmp_add(xmp,
+          "  </rdf:Description>\n"
+          " </rdf:RDF>\n"
+          "</x:xmpmeta>\n"
+          "<?xpacket end=\"w\"?>\n");
+
+  pdf_add_dict(p->root.dict, pdf_new_name("Metadata"), pdf_ref_obj(xmp));
+  pdf_release_obj(xmp);
+}
+
 static void
 pdf_doc_init_docinfo (pdf_doc *p)
 {
@@ -557,6 +832,14 @@ pdf_doc_close_docinfo (pdf_doc *p)
                  pdf_new_string(now, strlen(now)));
   }
 
+  if (pdfa.enabled) {
+    /* PDF/A wants to know when the metadata was last changed. */
+    if (!pdf_lookup_dict(docinfo, "ModDate"))
+      pdf_add_dict(docinfo, pdf_new_name("ModDate"),
+                   pdf_link_obj(pdf_lookup_dict(docinfo, "CreationDate")));
+    pdf_doc_add_xmp_metadata(p, docinfo);
+  }
+
   pdf_release_obj(docinfo);
   p->info = NULL;
 
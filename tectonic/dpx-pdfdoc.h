@@ -47,6 +47,13 @@ void     pdf_close_document (void);
 /* PDF document metadata */
 void     pdf_doc_set_creator (const char *creator);
 
+/* PDF/A-2b output: the ICC profile is used for the output intent. Pass
+ * enable = 0 to turn this off. The profile must stay valid until the
+ * document is closed. */
+void     pdf_doc_set_pdfa (int enable, const char *icc_name,
+                           const unsigned char *icc_profile,
+                           size_t icc_length, int icc_components);
+
 
 /* They just return PDF dictionary object.
  * Callers are completely responsible for doing right thing...
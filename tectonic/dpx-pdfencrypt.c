@@ -89,13 +89,10 @@ void pdf_enc_set_verbose (int level)
 static void
 pdf_enc_init (int use_aes, int encrypt_metadata)
 {
-  time_t current_time;
   struct pdf_sec *p = &sec_data;
 
-  current_time = get_unique_time_if_given();
-  if (current_time == INVALID_EPOCH_VALUE)
-    current_time = time(NULL);
-  srand(current_time); /* For AES IV */
+  /* The random parts of the keys come from dpx_random_bytes(), which the
+   * caller has seeded. */
   p->setting.use_aes = use_aes;
   p->setting.encrypt_metadata = encrypt_metadata;
 }
@@ -378,12 +375,9 @@ compute_owner_password_V5 (struct pdf_sec *p, const char *oplain)
   unsigned char  vsalt[8], ksalt[8], hash[32];
   unsigned char *OE, iv[AES_BLOCKSIZE];
   size_t         OE_len;
-  int  i;
 
-  for (i = 0; i < 8 ; i++) {
-    vsalt[i] = rand() % 256;
-    ksalt[i] = rand() % 256;
-  }
+  dpx_random_bytes(vsalt, 8);
+  dpx_random_bytes(ksalt, 8);
 
   compute_hash_V5(hash, oplain, vsalt, p->U, p->R);
   memcpy(p->O,      hash,  32);
@@ -403,12 +397,9 @@ compute_user_password_V5 (struct pdf_sec *p, const char *uplain)
   unsigned char  vsalt[8], ksalt[8], hash[32];
   unsigned char *UE, iv[AES_BLOCKSIZE];
   size_t         UE_len;
-  int  i;
 
-  for (i = 0; i < 8 ; i++) {
-    vsalt[i] = rand() % 256;
-    ksalt[i] = rand() % 256;
-  }
+  dpx_random_bytes(vsalt, 8);
+  dpx_random_bytes(ksalt, 8);
 
   compute_hash_V5(hash, uplain, vsalt, NULL, p->R);
   memcpy(p->U,      hash,  32);
@@ -577,10 +568,7 @@ pdf_enc_set_passwd (unsigned int bits, unsigned int perm,
     compute_owner_password(p, opasswd, upasswd);
     compute_user_password (p, upasswd);
   } else if (p->V == 5) {
-    int i;
-
-    for (i = 0; i < 32; i++)
-      p->key[i] = rand() % 256;
+    dpx_random_bytes(p->key, 32);
     p->key_size = 32;
     /* Order is important here */
     compute_user_password_V5 (p, upasswd);
@@ -81,7 +81,18 @@ pdf_font_set_dpi (int font_dpi)
 }
 
 
-/* If an environment variable SOURCE_DATE_EPOCH is correctly defined like
+/* The 'current time' given by the caller, which overrides SOURCE_DATE_EPOCH,
+ * or INVALID_EPOCH_VALUE if there isn't one. */
+static time_t unique_time = INVALID_EPOCH_VALUE;
+
+void
+set_unique_time(time_t value)
+{
+  unique_time = value;
+}
+
+/* If the caller has set a time with set_unique_time(), returns it. Otherwise,
+ * if an environment variable SOURCE_DATE_EPOCH is correctly defined like
  * SOURCE_DATE_EPOCH=1456304492, then returns this value, to be used as the
  * 'current time', otherwise returns INVALID_EPOCH_VALUE (= (time_t)-1).
  * In the case of Microsoft Visual Studio 2010, the value should be less
@@ -96,6 +107,9 @@ get_unique_time_if_given(void)
   time_t ret = INVALID_EPOCH_VALUE;
   int got_it;
 
+  if (unique_time != INVALID_EPOCH_VALUE)
+    return unique_time;
+
 #ifdef _WIN32
   /* A getenv() API exists on Windows but it has different semantics than
    * GetEnvironmentVariable() that break the test suite. */
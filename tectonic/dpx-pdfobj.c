@@ -243,6 +243,41 @@ static void release_stream (pdf_stream *stream);
 static int  verbose = 0;
 static char compression_level = 9;
 static char compression_use_predictor = 1;
+static int  check_transparency = 0;
+static int  transparency_found = 0;
+
+void
+pdf_obj_set_transparency_check (int enable)
+{
+    check_transparency = enable;
+}
+
+int
+pdf_obj_transparency_found (void)
+{
+    return transparency_found;
+}
+
+/* Soft masks, constant alpha below one and blend modes other than the normal
+ * one all make for transparency. */
+static void
+note_transparency (pdf_obj *key, pdf_obj *value)
+{
+    const char *name = pdf_name_value(key);
+
+    if (streq_ptr(name, "SMask")) {
+        if (!(PDF_OBJ_NAMETYPE(value) && streq_ptr(pdf_name_value(value), "None")))
+            transparency_found = 1;
+    } else if (streq_ptr(name, "CA") || streq_ptr(name, "ca")) {
+        if (PDF_OBJ_NUMBERTYPE(value) && pdf_number_value(value) < 1.0)
+            transparency_found = 1;
+    } else if (streq_ptr(name, "BM")) {
+        if (!(PDF_OBJ_NAMETYPE(value) &&
+              (streq_ptr(pdf_name_value(value), "Normal") ||
+               streq_ptr(pdf_name_value(value), "Compatible"))))
+            transparency_found = 1;
+    }
+}
 
 void
 pdf_set_compression (int level)
@@ -1260,6 +1295,8 @@ write_dict (pdf_dict *dict, rust_output_handle_t handle)
 {
     pdf_out (handle, "<<", 2);
     while (dict->key != NULL) {
+        if (check_transparency && !transparency_found)
+            note_transparency(dict->key, dict->value);
         pdf_write_obj(dict->key, handle);
         if (pdf_need_white(PDF_NAME, (dict->value)->type)) {
             pdf_out_white(handle);
@@ -3848,4 +3885,6 @@ pdf_obj_reset_global_state(void)
     pdf_output_file_position = 0;
     pdf_output_line_position = 0;
     compression_saved        = 0;
+    check_transparency       = 0;
+    transparency_found       = 0;
 }
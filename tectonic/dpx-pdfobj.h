@@ -169,6 +169,10 @@ int         pdf_compare_reference (pdf_obj *ref1, pdf_obj *ref2);
  */
 
 void      pdf_set_compression (int level);
+
+/* Watch for transparency in the objects written out, as PDF/A cares. */
+void      pdf_obj_set_transparency_check (int enable);
+int       pdf_obj_transparency_found     (void);
 void      pdf_set_use_predictor (int bval);
 
 void      pdf_set_info     (pdf_obj *obj);
@@ -193,6 +197,7 @@ size_t pdfobj_escape_str (char *buffer, size_t size, const unsigned char *s, siz
 pdf_obj *pdf_new_indirect  (pdf_file *pf, unsigned label, unsigned short generation);
 
 time_t get_unique_time_if_given(void);
+void set_unique_time(time_t value);
 #define INVALID_EPOCH_VALUE ((time_t)-1)
 
 #endif  /* _PDFOBJ_H_ */
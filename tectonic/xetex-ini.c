@@ -334,6 +334,7 @@ scaled_t delta;
 int synctex_enabled;
 bool used_tectonic_coda_tokens;
 bool semantic_pagination_enabled;
+time_t build_date;
 bool gave_char_warning_help;
 
 /* These ought to live in xetex-pagebuilder.c but are shared a lot: */
@@ -3902,10 +3903,12 @@ get_strings_started(void)
 
 
 tt_history_t
-tt_run_engine(char *dump_name, char *input_file_name)
+tt_run_engine(char *dump_name, char *input_file_name, time_t build_date_in)
 {
     int32_t font_k;
 
+    build_date = build_date_in;
+
     /* Miscellaneous initializations that were mostly originally done in the
      * main() driver routines. */
 
@@ -21,8 +21,15 @@ get_date_and_time (int32_t *minutes,  int32_t *day,
 {
   struct tm *tmptr;
 
-  time_t myclock = time ((time_t *) 0);
-  tmptr = localtime (&myclock);
+  /* If we've been given a build date, use it, in UTC, so that the output
+   * doesn't depend on when or where we run. */
+  if (build_date != (time_t) -1) {
+    tmptr = gmtime (&build_date);
+  } else {
+    time_t myclock = time ((time_t *) 0);
+    tmptr = localtime (&myclock);
+  }
+
   *minutes = tmptr->tm_hour * 60 + tmptr->tm_min;
   *day = tmptr->tm_mday;
   *month = tmptr->tm_mon + 1;
@@ -691,6 +691,7 @@ extern scaled_t delta;
 extern int synctex_enabled;
 extern bool used_tectonic_coda_tokens;
 extern bool semantic_pagination_enabled;
+extern time_t build_date;
 extern bool gave_char_warning_help;
 
 /*:1683*/
@@ -1095,7 +1096,7 @@ cur_length(void) {
 
 
 /* Tectonic related functions */
-tt_history_t tt_run_engine(char *dump_name, char *input_file_name);
+tt_history_t tt_run_engine(char *dump_name, char *input_file_name, time_t build_date_in);
 
 
 /* formerly xetex.h: */
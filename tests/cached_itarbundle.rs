@@ -12,11 +12,13 @@ use std::io::{self, Write};
 use std::net::SocketAddr;
 use std::ops::Bound;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tectonic::config::PersistentConfig;
 use tectonic::driver::ProcessingSessionBuilder;
-use tectonic::io::OpenResult;
+use tectonic::io::cached_itarbundle::CachedITarBundle;
+use tectonic::io::{Bundle, IoProvider, OpenResult};
 use tectonic::status::termcolor::TermcolorStatusBackend;
 use tectonic::status::ChatterLevel;
 use tokio::runtime::current_thread;
@@ -89,17 +91,37 @@ impl TarIndex {
     }
 }
 
+impl TarIndex {
+    /// Get the names of the files whose data lie in the given range, in
+    /// order.
+    fn names_in_range(&self, start: u64, end: u64) -> Vec<String> {
+        let mut files: Vec<_> = self
+            .map
+            .iter()
+            .filter(|((offset, length), _)| *offset >= start && offset + length <= end)
+            .collect();
+        files.sort();
+        files.into_iter().map(|(_, name)| name.to_owned()).collect()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 enum TectonicRequest {
     Head(String),
     Index,
     File(String),
+    /// A request for data spanning several files.
+    Files(Vec<String>),
 }
 
 struct TarIndexService {
     tar_index: Mutex<TarIndex>,
     requests: Mutex<Vec<TectonicRequest>>,
+    /// The headers of every request that we've received.
+    headers: Mutex<Vec<header::HeaderMap>>,
     local_addr: Mutex<Option<SocketAddr>>,
+    /// If set, every request gets a "503 Service Unavailable".
+    down: AtomicBool,
 }
 
 type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>;
@@ -109,7 +131,9 @@ impl TarIndexService {
         TarIndexService {
             tar_index: Mutex::new(tar_index),
             requests: Mutex::new(Vec::new()),
+            headers: Mutex::new(Vec::new()),
             local_addr: Mutex::new(None),
+            down: AtomicBool::new(false),
         }
     }
 
@@ -121,7 +145,22 @@ impl TarIndexService {
         *self.tar_index.lock().unwrap() = tar_index;
     }
 
+    fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::SeqCst);
+    }
+
     fn response(&self, req: Request<Body>) -> ResponseFuture {
+        self.headers.lock().unwrap().push(req.headers().clone());
+
+        if self.down.load(Ordering::SeqCst) {
+            return Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::empty())
+                    .unwrap(),
+            ));
+        }
+
         match (
             req.method(),
             req.uri().path(),
@@ -148,11 +187,14 @@ impl TarIndexService {
             (&Method::GET, "/bundle.tar", Some(range)) => {
                 if let Some((Bound::Included(l), Bound::Included(h))) = range.iter().next() {
                     let tar_index = self.tar_index.lock().unwrap();
-                    let name = tar_index
-                        .map
-                        .get(&(l, h - l + 1))
-                        .expect("unknown file data requested");
-                    self.log_request(TectonicRequest::File(name.to_owned()));
+                    match tar_index.map.get(&(l, h - l + 1)) {
+                        Some(name) => self.log_request(TectonicRequest::File(name.to_owned())),
+                        None => {
+                            let names = tar_index.names_in_range(l, h + 1);
+                            assert!(names.len() > 1, "unknown file data requested");
+                            self.log_request(TectonicRequest::Files(names));
+                        }
+                    }
                     let mut resp = Response::builder();
                     resp.status(StatusCode::PARTIAL_CONTENT);
                     resp.headers_mut()
@@ -181,6 +223,16 @@ impl TarIndexService {
         }
     }
 
+    /// Get the values of the named header in all of the requests so far.
+    fn header_values(&self, name: header::HeaderName) -> Vec<Option<String>> {
+        self.headers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|h| h.get(&name).map(|v| v.to_str().unwrap().to_owned()))
+            .collect()
+    }
+
     fn log_request(&self, request: TectonicRequest) {
         self.requests.lock().unwrap().push(request);
     }
@@ -380,6 +432,395 @@ fn test_cached_url_provider() {
     check_req_count(&requests, TectonicRequest::File("other.tex".into()), 1);
 }
 
+#[test]
+fn test_lookup_cache() {
+    let tar_index = {
+        let mut builder = TarIndexBuilder::new();
+        builder.push("plain.tex", b"test").push(
+            tectonic::digest::DIGEST_NAME,
+            b"0000000000000000000000000000000000000000000000000000000000000000",
+        );
+        builder.finish()
+    };
+
+    let requests = run_test(Some(tar_index), |_, url| {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+
+        let config = PersistentConfig::default();
+
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+                .unwrap();
+
+            match cache.input_open_name(OsStr::new("plain.tex"), &mut status) {
+                OpenResult::Ok(_) => {}
+                _ => panic!("Failed to open plain.tex"),
+            }
+            match cache.input_open_name(OsStr::new("plain"), &mut status) {
+                OpenResult::NotAvailable => {}
+                _ => panic!("'plain' file exists?"),
+            }
+
+            // Nothing is written out until the bundle is flushed, and the
+            // names that the bundle doesn't have aren't in the manifest.
+            let manifests = tempdir.path().join("manifests");
+            let missing = tempdir.path().join("missing");
+            assert_eq!(fs::read_dir(&manifests).unwrap().count(), 0);
+            assert_eq!(fs::read_dir(&missing).unwrap().count(), 0);
+            cache.flush(&mut status).unwrap();
+            let manifest = fs::read_dir(&manifests).unwrap().next().unwrap().unwrap();
+            let manifest = fs::read_to_string(manifest.path()).unwrap();
+            assert_eq!(manifest.lines().count(), 1);
+            assert!(manifest.starts_with("plain.tex 4 "));
+            let missing = fs::read_dir(&missing).unwrap().next().unwrap().unwrap();
+            assert_eq!(fs::read_to_string(missing.path()).unwrap(), "plain\n");
+        }
+
+        // Break the cached index: we shouldn't need it, since we already
+        // know about all of the names that we're going to look up.
+        for entry in fs::read_dir(tempdir.path().join("indexes")).unwrap() {
+            fs::write(entry.unwrap().path(), "other.tex garbage 0").unwrap();
+        }
+
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+                .unwrap();
+
+            match cache.input_open_name(OsStr::new("plain.tex"), &mut status) {
+                OpenResult::Ok(_) => {}
+                _ => panic!("Failed to open plain.tex"),
+            }
+            match cache.input_open_name(OsStr::new("plain"), &mut status) {
+                OpenResult::NotAvailable => {}
+                _ => panic!("'plain' file exists?"),
+            }
+            match cache.input_open_name(OsStr::new("other.tex"), &mut status) {
+                OpenResult::Err(_) => {}
+                _ => panic!("the broken index should have been read"),
+            }
+        }
+    });
+
+    check_req_count(&requests, TectonicRequest::Index, 1);
+    check_req_count(&requests, TectonicRequest::File("plain.tex".into()), 1);
+}
+
+#[test]
+fn test_companions_and_groups() {
+    let filler = vec![b'%'; 100_000];
+    let tar_index = {
+        let mut builder = TarIndexBuilder::new();
+        builder
+            .push("a.tex", b"a")
+            .push("font.afm", b"afm")
+            .push("font.pfb", b"pfb")
+            .push("font.tfm", b"tfm")
+            .push("big.dat", &filler)
+            .push("other.tex", b"other")
+            .push(
+                tectonic::digest::DIGEST_NAME,
+                b"0000000000000000000000000000000000000000000000000000000000000000",
+            );
+        builder.finish()
+    };
+
+    let requests = run_test(Some(tar_index), |_, url| {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+        let config = PersistentConfig::default();
+
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+                .unwrap();
+
+            // The other files with the same stem come along with this one.
+            match cache.input_open_name(OsStr::new("font.tfm"), &mut status) {
+                OpenResult::Ok(_) => {}
+                _ => panic!("Failed to open font.tfm"),
+            }
+
+            // These are too far apart to get with one request.
+            let names = vec!["a.tex".to_owned(), "other.tex".to_owned()];
+            assert_eq!(cache.prefetch(&names, &mut status).unwrap(), 2);
+        }
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, true, Some(tempdir.path()), &mut status)
+                .unwrap();
+
+            for name in &["a.tex", "font.afm", "font.pfb", "font.tfm", "other.tex"] {
+                match cache.input_open_name(OsStr::new(name), &mut status) {
+                    OpenResult::Ok(_) => {}
+                    _ => panic!("{} should have been downloaded", name),
+                }
+            }
+            match cache.input_open_name(OsStr::new("big.dat"), &mut status) {
+                OpenResult::NotAvailable => {}
+                _ => panic!("big.dat shouldn't have been downloaded"),
+            }
+        }
+    });
+
+    check_req_count(
+        &requests,
+        TectonicRequest::Files(vec![
+            "font.afm".into(),
+            "font.pfb".into(),
+            "font.tfm".into(),
+        ]),
+        1,
+    );
+    check_req_count(&requests, TectonicRequest::File("a.tex".into()), 1);
+    check_req_count(&requests, TectonicRequest::File("other.tex".into()), 1);
+    check_req_count(&requests, TectonicRequest::File("big.dat".into()), 0);
+}
+
+#[test]
+fn test_http_settings() {
+    let tar_index = {
+        let mut builder = TarIndexBuilder::new();
+        builder.push("plain.tex", b"test").push(
+            tectonic::digest::DIGEST_NAME,
+            b"0000000000000000000000000000000000000000000000000000000000000000",
+        );
+        builder.finish()
+    };
+
+    let requests = run_test(Some(tar_index), |service, url| {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+        let mut config = PersistentConfig::default();
+
+        // The server is happy to act as a proxy for a bundle on a host that
+        // doesn't exist.
+        let proxy = url.replace("/tectonic-default", "");
+        let url = "http://bundle.invalid/tectonic-default";
+
+        {
+            let http = config.http_settings_mut();
+            http.proxy = Some(proxy);
+            http.proxy_username = Some("user".to_owned());
+            http.proxy_password = Some("password".to_owned());
+            http.bearer_token = Some("secret".to_owned());
+            http.headers
+                .insert("X-Team".to_owned(), "typesetting".to_owned());
+            http.timeout = Some(10);
+        }
+
+        let mut cache = config
+            .make_cached_url_provider(url, false, Some(tempdir.path()), &mut status)
+            .unwrap();
+
+        match cache.input_open_name(OsStr::new("plain.tex"), &mut status) {
+            OpenResult::Ok(_) => {}
+            _ => panic!("Failed to open plain.tex"),
+        }
+
+        // The server redirects to its real address, which is another host,
+        // so only the first request carries the bundle's credentials. The
+        // proxy's credentials go with all of them.
+        let n_requests = service.header_values(header::AUTHORIZATION).len();
+        assert!(n_requests > 2);
+        let first_only = |value: &str| {
+            let mut expected = vec![None; n_requests];
+            expected[0] = Some(value.to_owned());
+            expected
+        };
+
+        assert_eq!(
+            service.header_values(header::AUTHORIZATION),
+            first_only("Bearer secret")
+        );
+        assert_eq!(
+            service.header_values(header::HeaderName::from_static("x-team")),
+            first_only("typesetting")
+        );
+        assert_eq!(
+            service.header_values(header::PROXY_AUTHORIZATION),
+            vec![Some("Basic dXNlcjpwYXNzd29yZA==".to_owned()); n_requests]
+        );
+    });
+
+    check_req_count(&requests, TectonicRequest::Index, 1);
+    check_req_count(&requests, TectonicRequest::File("plain.tex".into()), 1);
+}
+
+#[test]
+fn test_http_timeout() {
+    // A server that accepts connections but never answers.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/tectonic-default", listener.local_addr().unwrap());
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+    let mut config = PersistentConfig::default();
+    config.http_settings_mut().timeout = Some(1);
+
+    let start = std::time::Instant::now();
+    assert!(config
+        .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+        .is_err());
+    assert!(start.elapsed() < std::time::Duration::from_secs(20));
+}
+
+#[test]
+fn test_mirrors() {
+    let tar_index = |digest: &[u8]| {
+        let mut builder = TarIndexBuilder::new();
+        builder
+            .push("a.tex", b"a")
+            .push("b.tex", b"b")
+            .push(tectonic::digest::DIGEST_NAME, digest);
+        builder.finish()
+    };
+    let zeros = b"0000000000000000000000000000000000000000000000000000000000000000";
+    let ones = b"1111111111111111111111111111111111111111111111111111111111111111";
+
+    // Nothing listens at this address any more.
+    let dead_url = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/tectonic-default", listener.local_addr().unwrap())
+    };
+
+    let mut stale_requests = Vec::new();
+    let mut backup_requests = Vec::new();
+
+    let main_requests = run_test(Some(tar_index(zeros)), |main, main_url| {
+        stale_requests = run_test(Some(tar_index(ones)), |_, stale_url| {
+            backup_requests = run_test(Some(tar_index(zeros)), |_, backup_url| {
+                let tempdir = tempfile::tempdir().unwrap();
+                let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+                let urls = [
+                    dead_url,
+                    main_url.to_owned(),
+                    stale_url.to_owned(),
+                    backup_url.to_owned(),
+                ];
+
+                // The first mirror that works gives us the index; the
+                // others are checked against it.
+                let mut bundle = CachedITarBundle::with_mirrors(
+                    &urls,
+                    false,
+                    Some(tempdir.path()),
+                    &Default::default(),
+                    &mut status,
+                )
+                .unwrap();
+
+                let mut read = |bundle: &mut CachedITarBundle, name: &str| match bundle
+                    .input_open_name(OsStr::new(name), &mut status)
+                {
+                    OpenResult::Ok(_) => {}
+                    _ => panic!("Failed to open {}", name),
+                };
+
+                read(&mut bundle, "a.tex");
+
+                // When it goes down, we carry on with the next mirror that
+                // serves the same bundle.
+                main.set_down(true);
+                read(&mut bundle, "b.tex");
+            });
+        });
+    });
+
+    check_req_count(&main_requests, TectonicRequest::Index, 1);
+    check_req_count(&main_requests, TectonicRequest::File("a.tex".into()), 1);
+    check_req_count(&main_requests, TectonicRequest::File("b.tex".into()), 0);
+
+    check_req_count(&stale_requests, TectonicRequest::Index, 0);
+    check_req_count(
+        &stale_requests,
+        TectonicRequest::File(tectonic::digest::DIGEST_NAME.into()),
+        2,
+    );
+    check_req_count(&stale_requests, TectonicRequest::File("b.tex".into()), 0);
+
+    check_req_count(&backup_requests, TectonicRequest::Index, 0);
+    check_req_count(
+        &backup_requests,
+        TectonicRequest::File(tectonic::digest::DIGEST_NAME.into()),
+        2,
+    );
+    check_req_count(&backup_requests, TectonicRequest::File("a.tex".into()), 0);
+    check_req_count(&backup_requests, TectonicRequest::File("b.tex".into()), 1);
+}
+
+/// A mirror that lags behind the main URL mustn't be taken for it.
+#[test]
+fn test_stale_mirror() {
+    let tar_index = |digest: &[u8]| {
+        let mut builder = TarIndexBuilder::new();
+        builder
+            .push("a.tex", b"a")
+            .push("b.tex", b"b")
+            .push(tectonic::digest::DIGEST_NAME, digest);
+        builder.finish()
+    };
+    let zeros = b"0000000000000000000000000000000000000000000000000000000000000000";
+    let ones = b"1111111111111111111111111111111111111111111111111111111111111111";
+
+    let mut stale_requests = Vec::new();
+
+    run_test(Some(tar_index(zeros)), |main, main_url| {
+        stale_requests = run_test(Some(tar_index(ones)), |_, stale_url| {
+            let tempdir = tempfile::tempdir().unwrap();
+            let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+            let urls = [main_url.to_owned(), stale_url.to_owned()];
+
+            let open = |dir: &Path, status: &mut TermcolorStatusBackend| {
+                CachedITarBundle::with_mirrors(&urls, false, Some(dir), &Default::default(), status)
+            };
+            let digests = |dir: &Path| -> Vec<String> {
+                fs::read_dir(dir.join("urls"))
+                    .unwrap()
+                    .map(|e| fs::read_to_string(e.unwrap().path()).unwrap())
+                    .collect()
+            };
+            let zeros_line = format!("{}\n", String::from_utf8_lossy(zeros));
+
+            {
+                let mut bundle = open(tempdir.path(), &mut status).unwrap();
+                match bundle.input_open_name(OsStr::new("a.tex"), &mut status) {
+                    OpenResult::Ok(_) => {}
+                    _ => panic!("Failed to open a.tex"),
+                }
+            }
+
+            // With the main URL down, the stale mirror can't stand in for
+            // it, and what we know about the main URL stays as it was.
+            main.set_down(true);
+
+            {
+                let mut bundle = open(tempdir.path(), &mut status).unwrap();
+                match bundle.input_open_name(OsStr::new("b.tex"), &mut status) {
+                    OpenResult::Err(_) => {}
+                    _ => panic!("b.tex came from the stale mirror"),
+                }
+            }
+
+            assert_eq!(digests(tempdir.path()), vec![zeros_line]);
+
+            // Starting afresh, the mirror is all that we have, but it still
+            // doesn't tell us what the main URL points to.
+            let fresh = tempfile::tempdir().unwrap();
+            let mut bundle = open(fresh.path(), &mut status).unwrap();
+            match bundle.input_open_name(OsStr::new("b.tex"), &mut status) {
+                OpenResult::Ok(_) => {}
+                _ => panic!("Failed to open b.tex"),
+            }
+            assert!(digests(fresh.path()).is_empty());
+        });
+    });
+
+    check_req_count(&stale_requests, TectonicRequest::File("b.tex".into()), 1);
+}
+
 #[test]
 fn test_bundle_update() {
     let tempdir = tempfile::tempdir().unwrap();
@@ -460,3 +901,98 @@ fn test_bundle_update() {
         }
     });
 }
+
+#[test]
+fn test_prefetch() {
+    let tar_index = {
+        let mut builder = TarIndexBuilder::new();
+        builder
+            .push("a.tex", b"a")
+            .push("b.tex", b"b")
+            .push("c.tex", b"c")
+            .push(
+                tectonic::digest::DIGEST_NAME,
+                b"0000000000000000000000000000000000000000000000000000000000000000",
+            );
+        builder.finish()
+    };
+
+    let requests = run_test(Some(tar_index), |_, url| {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+        let config = PersistentConfig::default();
+        let names = |n: &[&str]| n.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+                .unwrap();
+            let n = cache
+                .prefetch(
+                    &names(&["c.tex", "a.tex", "a.tex", "missing.tex"]),
+                    &mut status,
+                )
+                .unwrap();
+            assert_eq!(n, 2);
+        }
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, true, Some(tempdir.path()), &mut status)
+                .unwrap();
+
+            match cache.input_open_name(OsStr::new("a.tex"), &mut status) {
+                OpenResult::Ok(_) => {}
+                _ => panic!("a.tex should have been prefetched"),
+            }
+            match cache.input_open_name(OsStr::new("b.tex"), &mut status) {
+                OpenResult::NotAvailable => {}
+                _ => panic!("b.tex should not have been prefetched"),
+            }
+
+            // We can't fetch anything if we're only using the cache.
+            assert!(cache.prefetch_all(&mut status).is_err());
+        }
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, false, Some(tempdir.path()), &mut status)
+                .unwrap();
+            assert_eq!(cache.prefetch_all(&mut status).unwrap(), 2);
+            assert_eq!(cache.prefetch_all(&mut status).unwrap(), 0);
+        }
+        {
+            let mut cache = config
+                .make_cached_url_provider(&url, true, Some(tempdir.path()), &mut status)
+                .unwrap();
+
+            for name in &["a.tex", "b.tex", "c.tex"] {
+                match cache.input_open_name(OsStr::new(name), &mut status) {
+                    OpenResult::Ok(_) => {}
+                    _ => panic!("{} should have been prefetched", name),
+                }
+            }
+        }
+    });
+
+    check_req_count(&requests, TectonicRequest::Index, 1);
+    // The digest is checked once in each session that downloads something.
+    check_req_count(
+        &requests,
+        TectonicRequest::File(tectonic::digest::DIGEST_NAME.into()),
+        2,
+    );
+    // One request for each prefetch, spanning the files in between.
+    check_req_count(
+        &requests,
+        TectonicRequest::Files(vec!["a.tex".into(), "b.tex".into(), "c.tex".into()]),
+        1,
+    );
+    check_req_count(
+        &requests,
+        TectonicRequest::Files(vec![
+            "b.tex".into(),
+            "c.tex".into(),
+            tectonic::digest::DIGEST_NAME.into(),
+        ]),
+        1,
+    );
+}
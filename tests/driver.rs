@@ -4,19 +4,488 @@
 //! This test rig is a total hack to quickly exercise `src/driver.rs`.
 //!
 //! I should make it real, but I just want Codecov to stop complaining about
-//! my test coverage. To re-use the `tex-outputs` test artifacts, the
-//! ProcessingSessionBuilder will need to learn how to tell `xdvipdfmx` to
-//! enable the reproducibility options used in the `tex-outputs` test rig.
+//! my test coverage.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
 use tectonic::config::PersistentConfig;
-use tectonic::driver::ProcessingSessionBuilder;
+use tectonic::driver::{OutputFormat, ProcessingSessionBuilder};
+use tectonic::engines::{PdfEncryption, PdfKeyLength};
 use tectonic::status::termcolor::TermcolorStatusBackend;
-use tectonic::status::ChatterLevel;
+use tectonic::status::{ChatterLevel, NoopStatusBackend};
 
 mod util;
 
+/// A plain TeX document that reads its `.aux` file, if there is one, and then
+/// writes it out afresh.
+const AUX_DOCUMENT: &str = r"\openin1=\jobname.aux
+\def\readaux{\ifeof1 \else \read1 to\x \expandafter\readaux\fi}
+\readaux \closein1
+\immediate\openout2=\jobname.aux
+\immediate\write2{stable}
+\immediate\closeout2
+a\bye
+";
+
+/// A plain TeX document that writes a file in its first pass without trying
+/// to read it, and reads it back and writes something else in later ones.
+/// Another file tells the passes apart, and its appearance causes the
+/// second pass.
+const REWRITE_DOCUMENT: &str = r"\openin1=\jobname.pas
+\ifeof1 \def\x{first}
+\immediate\openout3=\jobname.pas \immediate\write3{x}\immediate\closeout3
+\else \openin2=\jobname.tmp \read2 to\y \closein2 \def\x{second}\fi
+\closein1
+\immediate\openout2=\jobname.tmp
+\immediate\write2{\x}
+\immediate\closeout2
+a\bye
+";
+
+/// A plain TeX document that writes a `.bcf` file the way `biblatex` does,
+/// and typesets its `.bbl` file if there is one.
+const BIBER_DOCUMENT: &str = r#"\immediate\openout1=\jobname.bcf
+\immediate\write1{<bcf:datasource type="file">refs.bib</bcf:datasource>}
+\immediate\closeout1
+\openin1=\jobname.bbl
+\ifeof1 \else \read1 to\x \x\fi
+\closein1
+a\bye
+"#;
+
+/// Process a document with the default rerun logic, starting from the given
+/// `.aux` file, and return the explanation given for each TeX pass.
+fn tex_rerun_explanations(tex: &str, aux: Option<&str>) -> Vec<Option<String>> {
+    util::set_test_root();
+
+    let mut status = NoopStatusBackend::new();
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("tectonic_driver_test")
+        .tempdir()
+        .unwrap();
+    let input = tempdir.path().join("doc.tex");
+    fs::write(&input, tex).unwrap();
+
+    if let Some(aux) = aux {
+        fs::write(tempdir.path().join("doc.aux"), aux).unwrap();
+    }
+
+    let mut pbuilder = ProcessingSessionBuilder::default();
+    pbuilder
+        .primary_input_path(&input)
+        .tex_input_name("doc.tex")
+        .format_name("plain")
+        .format_cache_path(util::test_path(&[]))
+        .do_not_write_output_files()
+        .bundle(Box::new(util::TestBundle::default()));
+
+    let mut session = pbuilder
+        .create(&mut status)
+        .expect("couldn't create processing session");
+
+    session
+        .run(&mut status)
+        .expect("failed to execute processing session");
+
+    session
+        .report()
+        .passes
+        .into_iter()
+        .filter(|p| p.engine == "TeX")
+        .map(|p| p.rerun_explanation)
+        .collect()
+}
+
+/// Build a document as PDF/A, as of 2009-02-13 23:31:30 UTC, and return its
+/// XMP metadata packet.
+fn pdfa_xmp(tex: &str) -> String {
+    util::set_test_root();
+
+    let mut status = NoopStatusBackend::new();
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("tectonic_driver_test")
+        .tempdir()
+        .unwrap();
+    let input = tempdir.path().join("doc.tex");
+    fs::write(&input, tex).unwrap();
+
+    // Just enough of an ICC profile header for the output intent.
+    let mut profile = vec![0u8; 132];
+    profile[16..20].copy_from_slice(b"RGB ");
+    profile[36..40].copy_from_slice(b"acsp");
+    fs::write(tempdir.path().join("sRGB.icc"), profile).unwrap();
+
+    let mut pbuilder = ProcessingSessionBuilder::default();
+    pbuilder
+        .primary_input_path(&input)
+        .tex_input_name("doc.tex")
+        .format_name("plain")
+        .format_cache_path(util::test_path(&[]))
+        .output_format(OutputFormat::PdfA)
+        .reproducible(true)
+        .build_date(UNIX_EPOCH + Duration::from_secs(1_234_567_890))
+        .do_not_write_output_files()
+        .bundle(Box::new(util::TestBundle::default()));
+
+    let mut session = pbuilder
+        .create(&mut status)
+        .expect("couldn't create processing session");
+    session
+        .run(&mut status)
+        .expect("failed to execute processing session");
+
+    let pdf = session.into_file_data()[OsStr::new("doc.pdf")].clone();
+    let pdf = String::from_utf8_lossy(&pdf);
+    let start = pdf.find("<?xpacket begin").expect("no XMP metadata");
+    let xmp = &pdf[start..];
+    let end = xmp.find("<?xpacket end").expect("unterminated XMP");
+    xmp[..end].to_owned()
+}
+
 // Keep these alphabetized.
 
+/// A document that uses `biblatex` gets `biber` run on it, and TeX rerun to
+/// pick up the bibliography.
+#[cfg(unix)]
+#[test]
+fn biber_rerun() {
+    use std::os::unix::fs::PermissionsExt;
+
+    util::set_test_root();
+
+    let mut status = NoopStatusBackend::new();
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("tectonic_driver_test")
+        .tempdir()
+        .unwrap();
+    let input = tempdir.path().join("doc.tex");
+    fs::write(&input, BIBER_DOCUMENT).unwrap();
+
+    // A stand-in for `biber` that writes a fixed `.bbl` file.
+    let biber = tempdir.path().join("fake-biber");
+    fs::write(&biber, "#!/bin/sh\nprintf 'b\\n' > \"$1.bbl\"\n").unwrap();
+    fs::set_permissions(&biber, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut pbuilder = ProcessingSessionBuilder::default();
+    pbuilder
+        .primary_input_path(&input)
+        .tex_input_name("doc.tex")
+        .format_name("plain")
+        .format_cache_path(util::test_path(&[]))
+        .do_not_write_output_files()
+        .biber_program(biber.to_str().unwrap())
+        .bundle(Box::new(util::TestBundle::default()));
+
+    let mut session = pbuilder
+        .create(&mut status)
+        .expect("couldn't create processing session");
+    session
+        .run(&mut status)
+        .expect("failed to execute processing session");
+
+    let report = session.report();
+    assert_eq!(
+        report.passes.iter().filter(|p| p.engine == "biber").count(),
+        1
+    );
+    let tex_passes: Vec<_> = report
+        .passes
+        .iter()
+        .filter(|p| p.engine == "TeX")
+        .map(|p| p.rerun_explanation.as_deref())
+        .collect();
+    assert_eq!(tex_passes, vec![None, Some("\"doc.bbl\" changed")]);
+}
+
+#[test]
+fn build_report() {
+    util::set_test_root();
+
+    let mut status = TermcolorStatusBackend::new(ChatterLevel::Minimal);
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("tectonic_driver_test")
+        .tempdir()
+        .unwrap();
+    let report_path = tempdir.path().join("report.json");
+
+    let mut pbuilder = ProcessingSessionBuilder::default();
+    pbuilder
+        .primary_input_path(util::test_path(&["tex-outputs", "the_letter_a.tex"]))
+        .tex_input_name("the_letter_a.tex")
+        .format_name("plain")
+        .format_cache_path(util::test_path(&[]))
+        .output_dir(tempdir.path())
+        .report_path(&report_path)
+        .bundle(Box::new(util::TestBundle::default()));
+
+    let mut session = pbuilder
+        .create(&mut status)
+        .expect("couldn't create processing session");
+
+    session
+        .run(&mut status)
+        .expect("failed to execute processing session");
+
+    let report = session.report();
+    assert!(report.passes.iter().any(|p| p.engine == "TeX"));
+    assert!(report
+        .files
+        .iter()
+        .any(|f| f.name == "the_letter_a.log" && f.write_digest.is_some()));
+    assert!(report
+        .written_files
+        .iter()
+        .any(|p| p.ends_with("the_letter_a.pdf")));
+    assert!(report_path.exists());
+}
+
+/// The build report gets written even if the TeX pass fails.
+#[test]
+fn build_report_on_failure() {
+    util::set_test_root();
+
+    let mut status = NoopStatusBackend::new();
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("tectonic_driver_test")
+        .tempdir()
+        .unwrap();
+    let input = tempdir.path().join("broken.tex");
+    fs::write(&input, "\\input no-such-file.tex\n\\bye\n").unwrap();
+    let report_path = tempdir.path().join("report.json");
+
+    let mut pbuilder = ProcessingSessionBuilder::default();
+    pbuilder
+        .primary_input_path(&input)
+        .tex_input_name("broken.tex")
+        .format_name("plain")
+        .format_cache_path(util::test_path(&[]))
+        .output_dir(tempdir.path())
+        .report_path(&report_path)
+        .bundle(Box::new(util::TestBundle::default()));
+
+    let mut session = pbuilder
+        .create(&mut status)
+        .expect("couldn't create processing session");
+
+    assert!(session.run(&mut status).is_err());
+
+    let report = session.report();
+    assert!(report.passes.iter().any(|p| p.engine == "TeX"));
+    assert!(!report.errors.is_empty());
+
+    let json = fs::read_to_string(&report_path).expect("no build report written");
+    assert!(json.contains("\"errors\""));
+    assert!(!json.contains("\"errors\": []"));
+}
+
+/// Encryption keys are random even in reproducible mode, where the build
+/// date is fixed, since otherwise they would be easy to guess.
+#[test]
+fn encryption_keys_are_random() {
+    util::set_test_root();
+
+    let build = || {
+        let mut status = NoopStatusBackend::new();
+        let mut pbuilder = ProcessingSessionBuilder::default();
+        pbuilder
+            .primary_input_path(util::test_path(&["tex-outputs", "the_letter_a.tex"]))
+            .tex_input_name("the_letter_a.tex")
+            .format_name("plain")
+            .format_cache_path(util::test_path(&[]))
+            .do_not_write_output_files()
+            .reproducible(true)
+            .pdf_encryption(PdfEncryption {
+                owner_password: "owner".to_owned(),
+                key_length: PdfKeyLength::Aes256,
+                ..PdfEncryption::default()
+            })
+            .bundle(Box::new(util::TestBundle::default()));
+
+        let mut session = pbuilder
+            .create(&mut status)
+            .expect("couldn't create processing session");
+        session
+            .run(&mut status)
+            .expect("failed to execute processing session");
+
+        // The /UE entry holds the file key, encrypted with the user password.
+        let pdf = session.into_file_data()[OsStr::new("the_letter_a.pdf")].clone();
+        let start = pdf
+            .windows(3)
+            .position(|w| w == b"/UE")
+            .expect("no /UE entry in the encrypted PDF");
+        pdf[start..start + 40].to_vec()
+    };
+
+    assert_ne!(build(), build());
+}
+
+#[test]
+fn filesystem_inputs_and_reset() {
+    util::set_test_root();
+
+    let mut status = NoopStatusBackend::new();
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("tectonic_driver_test")
+        .tempdir()
+        .unwrap();
+    let main = tempdir.path().join("main.tex");
+    let sub = tempdir.path().join("sub.tex");
+    fs::write(&main, "\\input sub.tex\n\\bye\n").unwrap();
+    fs::write(&sub, "a\n").unwrap();
+
+    let mut pbuilder = ProcessingSessionBuilder::default();
+    pbuilder
+        .primary_input_path(&main)
+        .tex_input_name("main.tex")
+        .format_name("plain")
+        .format_cache_path(util::test_path(&[]))
+        .output_dir(tempdir.path())
+        .bundle(Box::new(util::TestBundle::default()));
+
+    let mut session = pbuilder
+        .create(&mut status)
+        .expect("couldn't create processing session");
+
+    session
+        .run(&mut status)
+        .expect("failed to execute processing session");
+
+    // The files that we wrote next to the inputs don't count as inputs.
+    let inputs = session.filesystem_inputs();
+    assert!(inputs.contains(&main));
+    assert!(inputs.contains(&sub));
+    assert!(tempdir.path().join("main.pdf").exists());
+    assert!(!inputs.contains(&tempdir.path().join("main.pdf")));
+    assert!(!inputs.contains(&tempdir.path().join("main.log")));
+
+    session.reset();
+    let report = session.report();
+    assert!(report.passes.is_empty());
+    assert!(report.files.is_empty());
+
+    // The second run only reads what the document now asks for.
+    fs::write(&main, "b\n\\bye\n").unwrap();
+    session
+        .run(&mut status)
+        .expect("failed to execute processing session");
+
+    let inputs = session.filesystem_inputs();
+    assert!(inputs.contains(&main));
+    assert!(!inputs.contains(&sub));
+    assert!(session.report().passes.iter().any(|p| p.engine == "TeX"));
+}
+
+#[test]
+fn no_rerun_unchanged_aux() {
+    let passes = tex_rerun_explanations(AUX_DOCUMENT, Some("stable\n"));
+    assert_eq!(passes, vec![None]);
+}
+
+#[test]
+fn pdfa_metadata() {
+    let xmp = pdfa_xmp("a\\bye\n");
+    assert!(xmp.contains("<pdfaid:part>2</pdfaid:part>"));
+    assert!(xmp.contains("<pdfaid:conformance>B</pdfaid:conformance>"));
+    assert!(xmp.contains("<xmp:CreateDate>2009-02-13T23:31:30Z</xmp:CreateDate>"));
+    assert!(xmp.contains("<xmp:ModifyDate>2009-02-13T23:31:30Z</xmp:ModifyDate>"));
+    assert!(xmp.contains("<xmp:MetadataDate>2009-02-13T23:31:30Z</xmp:MetadataDate>"));
+}
+
+/// The dates in the DocInfo dictionary, in whatever form the document gives
+/// them, carry over into the XMP metadata.
+#[test]
+fn pdfa_metadata_dates() {
+    let xmp = pdfa_xmp(
+        "\\special{pdf:docinfo << /CreationDate (D:20160224085452+01'00') \
+         /ModDate (D:2016022408-05) >>}a\\bye\n",
+    );
+    assert!(xmp.contains("<xmp:CreateDate>2016-02-24T08:54:52+01:00</xmp:CreateDate>"));
+    assert!(xmp.contains("<xmp:ModifyDate>2016-02-24T08:00-05:00</xmp:ModifyDate>"));
+}
+
+/// In reproducible mode, building the same document twice gives the same PDF.
+#[test]
+fn reproducible_builds() {
+    util::set_test_root();
+
+    let build = || {
+        let mut status = NoopStatusBackend::new();
+        let mut pbuilder = ProcessingSessionBuilder::default();
+        pbuilder
+            .primary_input_path(util::test_path(&["tex-outputs", "the_letter_a.tex"]))
+            .tex_input_name("the_letter_a.tex")
+            .format_name("plain")
+            .format_cache_path(util::test_path(&[]))
+            .do_not_write_output_files()
+            .reproducible(true)
+            .bundle(Box::new(util::TestBundle::default()));
+
+        let mut session = pbuilder
+            .create(&mut status)
+            .expect("couldn't create processing session");
+        session
+            .run(&mut status)
+            .expect("failed to execute processing session");
+
+        session
+            .report()
+            .files
+            .iter()
+            .find(|f| f.name == "the_letter_a.pdf")
+            .and_then(|f| f.write_digest.clone())
+            .expect("no digest for the PDF output")
+    };
+
+    let first = build();
+    // Make sure that the clock has moved on, since it's what xdvipdfmx would
+    // otherwise use for its dates, IDs and font subset tags.
+    thread::sleep(Duration::from_millis(1100));
+    let second = build();
+    assert_eq!(first, second);
+}
+
+#[test]
+fn rerun_changed_aux() {
+    let passes = tex_rerun_explanations(AUX_DOCUMENT, Some("stale\n"));
+    assert_eq!(passes, vec![None, Some("\"doc.aux\" changed".to_owned())]);
+}
+
+#[test]
+fn rerun_log_request() {
+    let tex = "\\immediate\\write-1{Rerun to get cross-references right.}\na\\bye\n";
+    let passes = tex_rerun_explanations(tex, None);
+    assert!(passes.len() > 1);
+    assert_eq!(
+        passes[1].as_deref(),
+        Some("the TeX log says \"Rerun to get cross-references right.\"")
+    );
+}
+
+/// A file that one pass wrote, and the next read and then replaced, calls
+/// for another pass.
+#[test]
+fn rerun_rewritten_file() {
+    let passes = tex_rerun_explanations(REWRITE_DOCUMENT, None);
+    assert_eq!(
+        passes,
+        vec![
+            None,
+            Some("\"doc.pas\" changed".to_owned()),
+            Some("\"doc.tmp\" changed after it was read".to_owned()),
+        ]
+    );
+}
+
 #[test]
 fn the_letter_a() {
     util::set_test_root();
@@ -9,7 +9,7 @@ use std::fs::File;
 use std::io;
 use std::process;
 use std::str;
-use tectonic_xdv::{FileType, XdvError};
+use tectonic_xdv::{FileType, NativeFont, TfmFont, XdvError};
 
 /// We'd like to use String as our error type, but we also would like to
 /// use the `XdvParser::process()` function, which when imposes the requirement
@@ -101,6 +101,23 @@ impl tectonic_xdv::XdvEvents for Stats {
         Ok(())
     }
 
+    fn handle_define_font(&mut self, font_num: i32, font: &TfmFont) -> Result<(), Self::Error> {
+        println!(
+            "font {}: {}{} at {}sp",
+            font_num, font.area, font.name, font.scale_factor
+        );
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font_num: i32,
+        font: &NativeFont,
+    ) -> Result<(), Self::Error> {
+        println!("native font {}: {} at {}sp", font_num, font.name, font.size);
+        Ok(())
+    }
+
     fn handle_char_run(&mut self, chars: &[i32]) -> Result<(), Self::Error> {
         let all_ascii_printable = chars.iter().all(|c| *c > 0x20 && *c < 0x7F);
         println!(
@@ -125,7 +142,7 @@ fn main() {
 
     let path = matches.value_of_os("PATH").unwrap();
 
-    let file = match File::open(&path) {
+    let file = match File::open(path) {
         Ok(f) => f,
         Err(e) => {
             eprintln!(
@@ -12,13 +12,19 @@
 //! (“semantically-paginated XDV”) is essentially the same as XDV, but
 //! expresses output that is not paginated for print — this is what Tectonic
 //! uses to produce its HTML output.
+//!
+//! Files are read with an `XdvParser`, which reports their contents to an
+//! implementation of the `XdvEvents` trait, and written with an `XdvWriter`.
 
 use byteorder::{BigEndian, ByteOrder};
 use std::error;
 use std::fmt::{Debug, Display, Error as FmtError, Formatter};
 use std::io::{Error as IoError, Read};
 use std::marker::PhantomData;
-use std::mem;
+
+mod writer;
+
+pub use writer::XdvWriter;
 
 /// Errors that can occur when parsing XDV/SPX files.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -127,10 +133,190 @@ pub trait XdvEvents {
     }
 
     /// Handle a sequence of characters without intervening commands
+    ///
+    /// Runs are ended by most commands other than font definitions, `nop`s
+    /// and `push`es, and are reported just before the events of the command
+    /// that ends them. Since a `push` doesn't end a run, `handle_push` may be
+    /// called while a run is still being collected.
     #[allow(unused)]
     fn handle_char_run(&mut self, chars: &[i32]) -> Result<(), Self::Error> {
         Ok(())
     }
+
+    /// Handle the same run of characters as `handle_char_run`, with the
+    /// font that it's set in and where each character goes.
+    ///
+    /// The character at index *i* in *chars* is placed at the absolute
+    /// position (*x[i]*, *v*), in TeX scaled points. The horizontal positions
+    /// are only as good as the widths returned by `char_width`.
+    #[allow(unused)]
+    fn handle_tfm_char_run(
+        &mut self,
+        font_num: i32,
+        chars: &[i32],
+        x: &[i32],
+        v: i32,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Get the width of a character in a traditional TeX font, in TeX scaled
+    /// points.
+    ///
+    /// Setting a character moves the current position right by its width,
+    /// which is found in the font's TFM file. The parser can't read those,
+    /// so it asks here. The default implementation returns zero, which leaves
+    /// the position where it was.
+    #[allow(unused)]
+    fn char_width(&mut self, font_num: i32, char_num: i32) -> Result<i32, Self::Error> {
+        Ok(0)
+    }
+
+    /// End the current page.
+    #[allow(unused)]
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the definition of a XeTeX native font.
+    ///
+    /// Fonts are usually defined twice: once before their first use in the
+    /// pages, and again in the postamble.
+    #[allow(unused)]
+    fn handle_define_native_font(
+        &mut self,
+        font_num: i32,
+        font: &NativeFont,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a change of the current font.
+    #[allow(unused)]
+    fn handle_select_font(&mut self, font_num: i32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the definition of a traditional TeX font.
+    ///
+    /// Like native fonts, these are usually defined twice.
+    #[allow(unused)]
+    fn handle_define_font(&mut self, font_num: i32, font: &TfmFont) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a run of glyphs set in a native font.
+    ///
+    /// The glyph at index *i* in *glyphs* is placed at the absolute position
+    /// (*x[i]*, *y[i]*), in TeX scaled points.
+    #[allow(unused)]
+    fn handle_glyph_run(
+        &mut self,
+        font_num: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a filled rectangle whose bottom left corner is at (*h*, *v*).
+    ///
+    /// Only rules with a positive height and width are reported, since the
+    /// others are invisible.
+    #[allow(unused)]
+    fn handle_rule(&mut self, h: i32, v: i32, height: i32, width: i32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a push of the register stack. *state* is the new top of the
+    /// stack, which starts out as a copy of the one below it.
+    #[allow(unused)]
+    fn handle_push(&mut self, state: &State) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a pop of the register stack. *state* is the restored top of the
+    /// stack.
+    #[allow(unused)]
+    fn handle_pop(&mut self, state: &State) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle an explicit movement: a change to any of the *h*, *v*, *w*,
+    /// *x*, *y*, or *z* registers through one of the `right`, `down`, `w`,
+    /// `x`, `y`, or `z` commands. *state* holds the updated registers.
+    #[allow(unused)]
+    fn handle_move(&mut self, state: &State) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a run of Unicode text set in a native font.
+    ///
+    /// The text starts at the position (*h*, *v*) and advances the current
+    /// position horizontally by *width*. These values are in the file's
+    /// units, which are TeX scaled points.
+    #[allow(unused)]
+    fn handle_text_run(
+        &mut self,
+        font_num: i32,
+        text: &str,
+        h: i32,
+        v: i32,
+        width: i32,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A traditional TeX font, as defined in a DVI or XDV file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TfmFont {
+    /// The directory in which the font's TFM file lives. This is almost
+    /// always empty.
+    pub area: String,
+
+    /// The name of the font's TFM file, without its extension.
+    pub name: String,
+
+    /// The checksum of the TFM file, or zero.
+    pub checksum: u32,
+
+    /// The size at which the font is used, in TeX scaled points.
+    pub scale_factor: i32,
+
+    /// The design size of the font, in TeX scaled points.
+    pub design_size: i32,
+}
+
+/// A XeTeX “native” font, as defined in an XDV file.
+///
+/// Fixed-point values are 16.16 numbers, so that 0x10000 represents 1.0.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NativeFont {
+    /// The name of the font, which is usually the name of its file.
+    pub name: String,
+
+    /// The size of the font, in TeX scaled points.
+    pub size: i32,
+
+    /// The index of the face to use within the font file.
+    pub face_index: u32,
+
+    /// Whether the font is set vertically.
+    pub vertical: bool,
+
+    /// The color of the font, as RGBA, if one was specified.
+    pub color_rgba: Option<u32>,
+
+    /// The horizontal stretch factor of the font, as a fixed-point value.
+    pub extend: Option<i32>,
+
+    /// The slant of the font, as a fixed-point value.
+    pub slant: Option<i32>,
+
+    /// The artificial emboldening of the font, as a fixed-point value.
+    pub embolden: Option<i32>,
 }
 
 /// State for parsing an XDV file.
@@ -143,6 +329,8 @@ pub struct XdvParser<T: XdvEvents> {
     cur_font_num: Option<i32>,
     offset: u64,
     cur_char_run: Vec<i32>,
+    cur_char_x: Vec<i32>,
+    cur_char_v: i32,
 }
 
 /// Which type of file is being parsed.
@@ -201,6 +389,8 @@ impl<T: XdvEvents> XdvParser<T> {
             cur_font_num: None,
             offset: 0,
             cur_char_run: Vec::new(),
+            cur_char_x: Vec::new(),
+            cur_char_v: 0,
         }
     }
 
@@ -221,10 +411,7 @@ impl<T: XdvEvents> XdvParser<T> {
     {
         const BUF_SIZE: usize = 4096;
         let mut parser = Self::new(events);
-        let mut buf = Vec::with_capacity(BUF_SIZE);
-        unsafe {
-            buf.set_len(BUF_SIZE);
-        }
+        let mut buf = vec![0u8; BUF_SIZE];
         let mut n_saved_bytes = 0;
 
         loop {
@@ -252,10 +439,7 @@ impl<T: XdvEvents> XdvParser<T> {
                 // We're going to need a bigger buffer in order to handle whatever
                 // we're reading. Let's double it.
                 let len = buf.len();
-                buf.reserve(len);
-                unsafe {
-                    buf.set_len(2 * len);
-                }
+                buf.resize(2 * len, 0);
             }
 
             if n_read == 0 {
@@ -284,23 +468,23 @@ impl<T: XdvEvents> XdvParser<T> {
             }
 
             let opcode = cursor.get_u8().unwrap();
-            let mut char_run_ended = true; // most commands end runs of characters
+
+            // Most commands end runs of characters. The run must be reported
+            // before any events that the command itself generates.
+            if ends_char_run(opcode) && !self.cur_char_run.is_empty() {
+                self.end_char_run()?;
+            }
 
             let rv = match opcode {
                 // This is the least ugly way I've found to map the u8 to the
                 // symbolic enum values.
-                oc if oc == Opcode::Noop as u8 => {
-                    char_run_ended = false;
-                    Ok(())
-                }
+                oc if oc == Opcode::Noop as u8 => Ok(()),
 
                 oc if oc >= Opcode::DefineFont1 as u8 && oc <= Opcode::DefineFont4 as u8 => {
-                    char_run_ended = false;
                     self.do_define_font(oc, &mut cursor)
                 }
 
                 oc if oc == Opcode::DefineNativeFont as u8 => {
-                    char_run_ended = false;
                     self.do_define_native_font(oc, &mut cursor)
                 }
 
@@ -310,10 +494,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
                 oc if oc == Opcode::EndOfPage as u8 => self.do_end_of_page(oc, &mut cursor),
 
-                oc if oc == Opcode::PushStack as u8 => {
-                    char_run_ended = false;
-                    self.do_push_stack(oc, &mut cursor)
-                }
+                oc if oc == Opcode::PushStack as u8 => self.do_push_stack(oc, &mut cursor),
 
                 oc if oc == Opcode::PopStack as u8 => self.do_pop_stack(oc, &mut cursor),
 
@@ -360,15 +541,17 @@ impl<T: XdvEvents> XdvParser<T> {
                 oc if oc >= Opcode::SetCharNumber0 as u8
                     && oc <= Opcode::SetCharNumber127 as u8 =>
                 {
-                    char_run_ended = false;
                     self.do_set_char_number(oc, &mut cursor)
                 }
 
                 oc if oc >= Opcode::SetChar1 as u8 && oc <= Opcode::SetChar4 as u8 => {
-                    char_run_ended = false;
                     self.do_set_char(oc, &mut cursor)
                 }
 
+                oc if oc == Opcode::SetRule as u8 || oc == Opcode::PutRule as u8 => {
+                    self.do_rule(oc, &mut cursor)
+                }
+
                 oc if oc == Opcode::SetGlyphs as u8 => self.do_set_glyphs(oc, &mut cursor),
 
                 oc if oc == Opcode::SetTextAndGlyphs as u8 => {
@@ -403,11 +586,6 @@ impl<T: XdvEvents> XdvParser<T> {
                     return Err(e);
                 }
             }
-
-            if char_run_ended && !self.cur_char_run.is_empty() {
-                self.events.handle_char_run(&self.cur_char_run)?;
-                self.cur_char_run.clear();
-            }
         }
 
         self.offset += cursor.checkpoint as u64;
@@ -447,20 +625,24 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _font_num = cursor.get_compact_i32_smpos(opcode - Opcode::DefineFont1 as u8)?;
-        let _checksum = cursor.get_u32()?;
-        let _scale_factor = cursor.get_u32()?;
-        let _design_size = cursor.get_u32()?;
+        let font_num = cursor.get_compact_i32_smpos(opcode - Opcode::DefineFont1 as u8)?;
+        let checksum = cursor.get_u32()?;
+        let scale_factor = cursor.get_i32()?;
+        let design_size = cursor.get_i32()?;
         let area_len = cursor.get_u8()?;
         let name_len = cursor.get_u8()?;
-        // XXX TEMP
-        use std::str::from_utf8;
-        let _area_str = from_utf8(cursor.get_slice(area_len as usize)?)
-            .unwrap()
-            .to_owned();
-        let _name_str = from_utf8(cursor.get_slice(name_len as usize)?)
-            .unwrap()
-            .to_owned();
+        let area = String::from_utf8_lossy(cursor.get_slice(area_len as usize)?).into_owned();
+        let name = String::from_utf8_lossy(cursor.get_slice(name_len as usize)?).into_owned();
+
+        let font = TfmFont {
+            area,
+            name,
+            checksum,
+            scale_factor,
+            design_size,
+        };
+
+        self.events.handle_define_font(font_num, &font)?;
         Ok(())
     }
 
@@ -473,41 +655,49 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _font_num = cursor.get_i32()?;
-        let _size = cursor.get_i32()?; // fixed-point
+        let font_num = cursor.get_i32()?;
+        let size = cursor.get_i32()?;
         let flags = cursor.get_u16()?;
         let name_len = cursor.get_u8()?;
-        // XXX TEMP
-        use std::str::from_utf8;
-        let _name_str = from_utf8(cursor.get_slice(name_len as usize)?)
-            .unwrap()
-            .to_owned();
-        let _face_index = cursor.get_u32()?;
-
-        let _color_rgba = if flags & NativeFontFlags::Colored as u16 != 0 {
+        let name = String::from_utf8_lossy(cursor.get_slice(name_len as usize)?).into_owned();
+        let face_index = cursor.get_u32()?;
+
+        let color_rgba = if flags & NativeFontFlags::Colored as u16 != 0 {
             Some(cursor.get_u32()?)
         } else {
             None
         };
 
-        let _extend = if flags & NativeFontFlags::Extend as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let extend = if flags & NativeFontFlags::Extend as u16 != 0 {
+            Some(cursor.get_i32()?) // fixed-point
         } else {
             None
         };
 
-        let _slant = if flags & NativeFontFlags::Slant as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let slant = if flags & NativeFontFlags::Slant as u16 != 0 {
+            Some(cursor.get_i32()?) // fixed-point
         } else {
             None
         };
 
-        let _embolden = if flags & NativeFontFlags::Embolden as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let embolden = if flags & NativeFontFlags::Embolden as u16 != 0 {
+            Some(cursor.get_i32()?) // fixed-point
         } else {
             None
         };
 
+        let font = NativeFont {
+            name,
+            size,
+            face_index,
+            vertical: flags & NativeFontFlags::Vertical as u16 != 0,
+            color_rgba,
+            extend,
+            slant,
+            embolden,
+        };
+
+        self.events.handle_define_native_font(font_num, &font)?;
         Ok(())
     }
 
@@ -549,6 +739,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::Malformed(cursor.global_offset()).into_internal());
         }
 
+        self.events.handle_end_page()?;
         self.state = ParserState::BetweenPages;
         Ok(())
     }
@@ -563,6 +754,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let dup = self.stack.last().unwrap().clone();
+        self.events.handle_push(&dup)?;
         self.stack.push(dup);
         Ok(())
     }
@@ -577,6 +769,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         self.stack.pop();
+        self.events.handle_pop(self.stack.last().unwrap())?;
         Ok(())
     }
 
@@ -587,7 +780,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::Right1 as u8)?;
         self.stack.last_mut().unwrap().h += n;
-        Ok(())
+        self.moved()
     }
 
     fn do_right_by_w(
@@ -601,7 +794,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
         let state = self.stack.last_mut().unwrap();
         state.h += state.w;
-        Ok(())
+        self.moved()
     }
 
     fn do_set_w(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -613,7 +806,7 @@ impl<T: XdvEvents> XdvParser<T> {
         let state = self.stack.last_mut().unwrap();
         state.w = n;
         state.h += n;
-        Ok(())
+        self.moved()
     }
 
     fn do_right_by_x(
@@ -627,7 +820,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
         let state = self.stack.last_mut().unwrap();
         state.h += state.x;
-        Ok(())
+        self.moved()
     }
 
     fn do_set_x(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -639,7 +832,7 @@ impl<T: XdvEvents> XdvParser<T> {
         let state = self.stack.last_mut().unwrap();
         state.x = n;
         state.h += n;
-        Ok(())
+        self.moved()
     }
 
     fn do_down(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -649,7 +842,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::Down1 as u8)?;
         self.stack.last_mut().unwrap().v += n;
-        Ok(())
+        self.moved()
     }
 
     fn do_down_by_y(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -659,7 +852,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
         let state = self.stack.last_mut().unwrap();
         state.v += state.y;
-        Ok(())
+        self.moved()
     }
 
     fn do_set_y(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -671,7 +864,7 @@ impl<T: XdvEvents> XdvParser<T> {
         let state = self.stack.last_mut().unwrap();
         state.y = n;
         state.v += n;
-        Ok(())
+        self.moved()
     }
 
     fn do_down_by_z(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -681,7 +874,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
         let state = self.stack.last_mut().unwrap();
         state.v += state.z;
-        Ok(())
+        self.moved()
     }
 
     fn do_set_z(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -693,6 +886,12 @@ impl<T: XdvEvents> XdvParser<T> {
         let state = self.stack.last_mut().unwrap();
         state.z = n;
         state.v += n;
+        self.moved()
+    }
+
+    /// Report the registers after an explicit movement.
+    fn moved(&mut self) -> InternalResult<(), T::Error> {
+        self.events.handle_move(self.stack.last().unwrap())?;
         Ok(())
     }
 
@@ -706,7 +905,9 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        self.cur_font_num = Some(i32::from(opcode - Opcode::SetFontNumber0 as u8));
+        let font_num = i32::from(opcode - Opcode::SetFontNumber0 as u8);
+        self.events.handle_select_font(font_num)?;
+        self.cur_font_num = Some(font_num);
         Ok(())
     }
 
@@ -716,7 +917,9 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        self.cur_font_num = Some(cursor.get_compact_i32_smpos(opcode - Opcode::SetFont1 as u8)?);
+        let font_num = cursor.get_compact_i32_smpos(opcode - Opcode::SetFont1 as u8)?;
+        self.events.handle_select_font(font_num)?;
+        self.cur_font_num = Some(font_num);
         Ok(())
     }
 
@@ -731,8 +934,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let char_num = opcode - Opcode::SetCharNumber0 as u8;
-        self.cur_char_run.push(i32::from(char_num));
-        Ok(())
+        self.set_char(i32::from(char_num), cursor)
     }
 
     /// This variant takes an argument that encodes the character number.
@@ -742,7 +944,42 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let char_num = cursor.get_compact_i32_smpos(opcode - Opcode::SetChar1 as u8)?;
-        self.cur_char_run.push(char_num as i32);
+        self.set_char(char_num, cursor)
+    }
+
+    /// Add a character to the current run and move right by its width.
+    fn set_char(&mut self, char_num: i32, cursor: &Cursor<T>) -> InternalResult<(), T::Error> {
+        let font_num = match self.cur_font_num {
+            Some(n) => n,
+            None => return Err(XdvError::Malformed(cursor.global_offset()).into_internal()),
+        };
+
+        let width = self.events.char_width(font_num, char_num)?;
+        let state = self.stack.last_mut().unwrap();
+
+        if self.cur_char_run.is_empty() {
+            self.cur_char_v = state.v;
+        }
+
+        self.cur_char_run.push(char_num);
+        self.cur_char_x.push(state.h);
+        state.h += width;
+        Ok(())
+    }
+
+    /// Report the current run of characters and start a new one.
+    fn end_char_run(&mut self) -> Result<(), T::Error> {
+        // Runs are ended by font changes, so the font is the current one.
+        let font_num = self.cur_font_num.unwrap();
+        self.events.handle_char_run(&self.cur_char_run)?;
+        self.events.handle_tfm_char_run(
+            font_num,
+            &self.cur_char_run,
+            &self.cur_char_x,
+            self.cur_char_v,
+        )?;
+        self.cur_char_run.clear();
+        self.cur_char_x.clear();
         Ok(())
     }
 
@@ -755,19 +992,8 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _width = cursor.get_i32()?;
-        let n_glyphs = cursor.get_u16()?;
-
-        for _ in 0..n_glyphs {
-            let _x = cursor.get_u32()?;
-            let _y = cursor.get_u32()?;
-        }
-
-        for _ in 0..n_glyphs {
-            let _glyph_id = cursor.get_i16()?;
-        }
-
-        Ok(())
+        let glyphs = read_glyphs(cursor)?;
+        self.set_glyphs(glyphs, None, cursor)
     }
 
     fn do_set_text_and_glyphs(
@@ -786,16 +1012,66 @@ impl<T: XdvEvents> XdvParser<T> {
             chars.push(cursor.get_u16()?);
         }
 
-        let _width = cursor.get_i32()?;
-        let n_glyphs = cursor.get_u16()?;
+        let glyphs = read_glyphs(cursor)?;
+
+        // The text is stored as UTF-16 code units.
+        let text = String::from_utf16_lossy(&chars);
+        self.set_glyphs(glyphs, Some(&text), cursor)
+    }
 
-        for _ in 0..n_glyphs {
-            let _x = cursor.get_u32()?;
-            let _y = cursor.get_u32()?;
+    /// Report a run of glyphs, and perhaps its text, and advance past it.
+    fn set_glyphs(
+        &mut self,
+        glyphs: Glyphs,
+        text: Option<&str>,
+        cursor: &Cursor<T>,
+    ) -> InternalResult<(), T::Error> {
+        let font_num = match self.cur_font_num {
+            Some(n) => n,
+            None => return Err(XdvError::Malformed(cursor.global_offset()).into_internal()),
+        };
+
+        let Glyphs {
+            width,
+            ids,
+            mut x,
+            mut y,
+        } = glyphs;
+        let state = self.stack.last_mut().unwrap();
+
+        if let Some(text) = text {
+            self.events
+                .handle_text_run(font_num, text, state.h, state.v, width)?;
+        }
+
+        for x in &mut x {
+            *x += state.h;
+        }
+
+        for y in &mut y {
+            *y += state.v;
+        }
+
+        self.events.handle_glyph_run(font_num, &ids, &x, &y)?;
+        state.h += width;
+        Ok(())
+    }
+
+    fn do_rule(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
+        if self.state != ParserState::InPage {
+            return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        for _ in 0..n_glyphs {
-            let _glyph_id = cursor.get_i16()?;
+        let height = cursor.get_i32()?;
+        let width = cursor.get_i32()?;
+        let state = self.stack.last_mut().unwrap();
+
+        if height > 0 && width > 0 {
+            self.events.handle_rule(state.h, state.v, height, width)?;
+        }
+
+        if opcode == Opcode::SetRule as u8 {
+            state.h += width;
         }
 
         Ok(())
@@ -850,6 +1126,17 @@ impl<T: XdvEvents> XdvParser<T> {
         Ok(())
     }
 
+    /// Get the registers at the top of the stack, if we're in a page.
+    pub fn current_state(&self) -> Option<&State> {
+        self.stack.last()
+    }
+
+    /// Get the number of the current font, if one has been selected in the
+    /// current page.
+    pub fn current_font(&self) -> Option<i32> {
+        self.cur_font_num
+    }
+
     /// Get the current byte offset of the parsing.
     pub fn current_offset(&self) -> u64 {
         self.offset
@@ -866,18 +1153,39 @@ impl<T: XdvEvents> XdvParser<T> {
     }
 }
 
-/// The states that may be stacked while processing the DVI.
+/// The registers that are stacked while processing the DVI.
+///
+/// All values are in TeX scaled points. Vertical positions increase going
+/// down the page.
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct State {
+pub struct State {
+    /// The current horizontal position.
     pub h: i32,
+
+    /// The current vertical position.
     pub v: i32,
+
+    /// The horizontal spacing register used by the `w` commands.
     pub w: i32,
+
+    /// The horizontal spacing register used by the `x` commands.
     pub x: i32,
+
+    /// The vertical spacing register used by the `y` commands.
     pub y: i32,
+
+    /// The vertical spacing register used by the `z` commands.
     pub z: i32,
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl State {
+    /// Create a new set of registers, all zero.
     pub fn new() -> Self {
         State {
             h: 0,
@@ -890,6 +1198,35 @@ impl State {
     }
 }
 
+/// The contents of a `SetGlyphs` or `SetTextAndGlyphs` command, with glyph
+/// positions relative to the current point.
+struct Glyphs {
+    width: i32,
+    ids: Vec<u16>,
+    x: Vec<i32>,
+    y: Vec<i32>,
+}
+
+fn read_glyphs<T: XdvEvents>(cursor: &mut Cursor<T>) -> InternalResult<Glyphs, T::Error> {
+    let width = cursor.get_i32()?;
+    let n_glyphs = cursor.get_u16()? as usize;
+    let mut x = Vec::with_capacity(n_glyphs);
+    let mut y = Vec::with_capacity(n_glyphs);
+
+    for _ in 0..n_glyphs {
+        x.push(cursor.get_i32()?);
+        y.push(cursor.get_i32()?);
+    }
+
+    let mut ids = Vec::with_capacity(n_glyphs);
+
+    for _ in 0..n_glyphs {
+        ids.push(cursor.get_u16()?);
+    }
+
+    Ok(Glyphs { width, ids, x, y })
+}
+
 /// A simple cursor on a buffer.
 #[derive(Debug)]
 struct Cursor<'a, T: XdvEvents> {
@@ -963,7 +1300,7 @@ impl<'a, T: XdvEvents> Cursor<'a, T> {
             return Err(InternalError::NeedMoreData);
         }
 
-        let rv = unsafe { mem::transmute(self.buf[0]) };
+        let rv = self.buf[0] as i8;
         self.buf = &self.buf[1..];
         self.offset += 1;
         Ok(rv)
@@ -1087,6 +1424,17 @@ impl<'a, T: XdvEvents> Cursor<'a, T> {
     }
 }
 
+/// Whether the given opcode ends a run of characters that will be reported
+/// with `XdvEvents::handle_char_run`.
+fn ends_char_run(opcode: u8) -> bool {
+    !(opcode == Opcode::Noop as u8
+        || (opcode >= Opcode::DefineFont1 as u8 && opcode <= Opcode::DefineFont4 as u8)
+        || opcode == Opcode::DefineNativeFont as u8
+        || opcode == Opcode::PushStack as u8
+        || opcode <= Opcode::SetCharNumber127 as u8
+        || (opcode >= Opcode::SetChar1 as u8 && opcode <= Opcode::SetChar4 as u8))
+}
+
 /// XDV opcodes.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
@@ -1095,6 +1443,8 @@ enum Opcode {
     SetCharNumber127 = 127,
     SetChar1 = 128,
     SetChar4 = 131,
+    SetRule = 132,
+    PutRule = 137,
     Noop = 138,
     BeginningOfPage = 139,
     EndOfPage = 140,
@@ -1143,7 +1493,6 @@ enum IdByte {
 /// Flags for XeTeX native fonts
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u16)]
-#[allow(unused)]
 enum NativeFontFlags {
     Vertical = 0x0100,
     Colored = 0x0200,
@@ -0,0 +1,540 @@
+// Copyright 2019 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Writing XDV and SPX files.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, Write};
+
+use super::{FileType, IdByte, NativeFont, NativeFontFlags, Opcode, TfmFont};
+
+/// The numerator and denominator that define the file's units as TeX scaled
+/// points. These are the only values that the parser accepts.
+const UNIT_NUMERATOR: u32 = 25_400_000;
+const UNIT_DENOMINATOR: u32 = 473_628_672;
+
+/// The magnification that we declare: 1000 means none.
+const MAGNIFICATION: u32 = 1000;
+
+/// A font definition, remembered so that it can be repeated in the
+/// postamble.
+#[derive(Clone, Debug)]
+enum FontDef {
+    Tfm(TfmFont),
+    Native(NativeFont),
+}
+
+/// Writes XDV or SPX data to a stream.
+///
+/// The methods of this type correspond closely to the commands of the file
+/// format, and work in its units, TeX scaled points. Fonts must be defined
+/// before they are selected, and all drawing must happen between
+/// `begin_page()` and `end_page()`. Call `finish()` once all pages are done
+/// to write the postamble; a stream without one can't be parsed.
+///
+/// Unlike the parser's events, the glyph positions given to `set_glyphs()`
+/// and `set_text_and_glyphs()` are relative to the current point, as they are
+/// in the file.
+#[derive(Debug)]
+pub struct XdvWriter<W: Write> {
+    inner: W,
+    filetype: FileType,
+    offset: u64,
+    last_bop: Option<u64>,
+    n_pages: u16,
+    in_page: bool,
+    depth: u16,
+    max_depth: u16,
+    fonts: BTreeMap<i32, FontDef>,
+}
+
+impl<W: Write> XdvWriter<W> {
+    /// Create a new writer, writing the preamble with the given comment to
+    /// *inner*.
+    ///
+    /// The comment can be at most 255 bytes long.
+    pub fn new(inner: W, filetype: FileType, comment: &[u8]) -> IoResult<Self> {
+        if comment.len() > 255 {
+            return Err(invalid("XDV comment is longer than 255 bytes"));
+        }
+
+        let mut w = XdvWriter {
+            inner,
+            filetype,
+            offset: 0,
+            last_bop: None,
+            n_pages: 0,
+            in_page: false,
+            depth: 0,
+            max_depth: 0,
+            fonts: BTreeMap::new(),
+        };
+
+        w.put_u8(Opcode::Preamble as u8)?;
+        w.put_u8(w.id_byte())?;
+        w.put_u32(UNIT_NUMERATOR)?;
+        w.put_u32(UNIT_DENOMINATOR)?;
+        w.put_u32(MAGNIFICATION)?;
+        w.put_u8(comment.len() as u8)?;
+        w.put_bytes(comment)?;
+        Ok(w)
+    }
+
+    /// Begin a new page with the given values of TeX's `\count0` through
+    /// `\count9`.
+    pub fn begin_page(&mut self, counters: &[i32; 10]) -> IoResult<()> {
+        if self.in_page {
+            return Err(invalid("XDV page begun inside another page"));
+        }
+
+        if self.n_pages == u16::MAX {
+            return Err(invalid("too many pages for an XDV file"));
+        }
+
+        let bop = self.offset;
+        self.put_u8(Opcode::BeginningOfPage as u8)?;
+
+        for c in counters {
+            self.put_i32(*c)?;
+        }
+
+        let previous = self.last_bop.map(|o| o as i32).unwrap_or(-1);
+        self.put_i32(previous)?;
+
+        self.last_bop = Some(bop);
+        self.n_pages += 1;
+        self.in_page = true;
+        Ok(())
+    }
+
+    /// End the current page. All pushes must have been matched by pops.
+    pub fn end_page(&mut self) -> IoResult<()> {
+        self.check_in_page()?;
+
+        if self.depth != 0 {
+            return Err(invalid("XDV page ended with unbalanced pushes"));
+        }
+
+        self.put_u8(Opcode::EndOfPage as u8)?;
+        self.in_page = false;
+        Ok(())
+    }
+
+    /// Define a traditional TeX font. The TFM file's area and name together
+    /// can be at most 255 bytes long.
+    pub fn define_font(&mut self, font_num: i32, font: &TfmFont) -> IoResult<()> {
+        if font.area.len() > 255 || font.name.len() > 255 {
+            return Err(invalid("XDV font name is longer than 255 bytes"));
+        }
+
+        self.write_font_def(font_num, &FontDef::Tfm(font.clone()))?;
+        self.fonts.insert(font_num, FontDef::Tfm(font.clone()));
+        Ok(())
+    }
+
+    /// Define a XeTeX native font. The font's name can be at most 255 bytes
+    /// long.
+    pub fn define_native_font(&mut self, font_num: i32, font: &NativeFont) -> IoResult<()> {
+        if font.name.len() > 255 {
+            return Err(invalid("XDV font name is longer than 255 bytes"));
+        }
+
+        self.write_font_def(font_num, &FontDef::Native(font.clone()))?;
+        self.fonts.insert(font_num, FontDef::Native(font.clone()));
+        Ok(())
+    }
+
+    /// Select the current font.
+    pub fn select_font(&mut self, font_num: i32) -> IoResult<()> {
+        self.check_in_page()?;
+
+        if !self.fonts.contains_key(&font_num) {
+            return Err(invalid("selected an undefined XDV font"));
+        }
+
+        if (0..64).contains(&font_num) {
+            self.put_u8(Opcode::SetFontNumber0 as u8 + font_num as u8)
+        } else {
+            self.put_compact_unsigned(Opcode::SetFont1 as u8, font_num as u32)
+        }
+    }
+
+    /// Typeset characters from a traditional TeX font, moving right after
+    /// each one.
+    pub fn set_chars(&mut self, chars: &[i32]) -> IoResult<()> {
+        self.check_in_page()?;
+
+        for c in chars {
+            if *c >= 0 && *c <= Opcode::SetCharNumber127 as i32 {
+                self.put_u8(*c as u8)?;
+            } else {
+                self.put_compact_unsigned(Opcode::SetChar1 as u8, *c as u32)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Typeset glyphs from a native font, then move right by *width*. The
+    /// glyph at index *i* in *glyphs* is placed at (*x[i]*, *y[i]*) relative
+    /// to the current point.
+    pub fn set_glyphs(&mut self, width: i32, glyphs: &[u16], x: &[i32], y: &[i32]) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_u8(Opcode::SetGlyphs as u8)?;
+        self.put_glyphs(width, glyphs, x, y)
+    }
+
+    /// Like `set_glyphs()`, but also recording the text that the glyphs
+    /// represent.
+    pub fn set_text_and_glyphs(
+        &mut self,
+        text: &str,
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> IoResult<()> {
+        self.check_in_page()?;
+
+        let chars: Vec<u16> = text.encode_utf16().collect();
+
+        if chars.len() > usize::from(u16::MAX) {
+            return Err(invalid("too much text in one XDV command"));
+        }
+
+        self.put_u8(Opcode::SetTextAndGlyphs as u8)?;
+        self.put_u16(chars.len() as u16)?;
+
+        for c in chars {
+            self.put_u16(c)?;
+        }
+
+        self.put_glyphs(width, glyphs, x, y)
+    }
+
+    /// Draw a rule whose bottom left corner is at the current point. If
+    /// *advance* is true, move right by its width afterwards.
+    pub fn rule(&mut self, height: i32, width: i32, advance: bool) -> IoResult<()> {
+        self.check_in_page()?;
+        let opcode = if advance {
+            Opcode::SetRule
+        } else {
+            Opcode::PutRule
+        };
+        self.put_u8(opcode as u8)?;
+        self.put_i32(height)?;
+        self.put_i32(width)
+    }
+
+    /// Insert a `\special`.
+    pub fn special(&mut self, contents: &[u8]) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_compact_unsigned(Opcode::Special1 as u8, contents.len() as u32)?;
+        self.put_bytes(contents)
+    }
+
+    /// Push the registers onto the stack.
+    pub fn push(&mut self) -> IoResult<()> {
+        self.check_in_page()?;
+
+        if self.depth == u16::MAX {
+            return Err(invalid("XDV stack is too deep"));
+        }
+
+        self.put_u8(Opcode::PushStack as u8)?;
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+        Ok(())
+    }
+
+    /// Pop the registers off of the stack.
+    pub fn pop(&mut self) -> IoResult<()> {
+        self.check_in_page()?;
+
+        if self.depth == 0 {
+            return Err(invalid("XDV stack popped without a push"));
+        }
+
+        self.put_u8(Opcode::PopStack as u8)?;
+        self.depth -= 1;
+        Ok(())
+    }
+
+    /// Move right by *n*.
+    pub fn right(&mut self, n: i32) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_compact_signed(Opcode::Right1 as u8, n)
+    }
+
+    /// Move right by the *w* register.
+    pub fn right_by_w(&mut self) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_u8(Opcode::RightByW as u8)
+    }
+
+    /// Set the *w* register to *n* and move right by it.
+    pub fn set_w(&mut self, n: i32) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_compact_signed(Opcode::SetW1 as u8, n)
+    }
+
+    /// Move right by the *x* register.
+    pub fn right_by_x(&mut self) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_u8(Opcode::RightByX as u8)
+    }
+
+    /// Set the *x* register to *n* and move right by it.
+    pub fn set_x(&mut self, n: i32) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_compact_signed(Opcode::SetX1 as u8, n)
+    }
+
+    /// Move down by *n*.
+    pub fn down(&mut self, n: i32) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_compact_signed(Opcode::Down1 as u8, n)
+    }
+
+    /// Move down by the *y* register.
+    pub fn down_by_y(&mut self) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_u8(Opcode::DownByY as u8)
+    }
+
+    /// Set the *y* register to *n* and move down by it.
+    pub fn set_y(&mut self, n: i32) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_compact_signed(Opcode::SetY1 as u8, n)
+    }
+
+    /// Move down by the *z* register.
+    pub fn down_by_z(&mut self) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_u8(Opcode::DownByZ as u8)
+    }
+
+    /// Set the *z* register to *n* and move down by it.
+    pub fn set_z(&mut self, n: i32) -> IoResult<()> {
+        self.check_in_page()?;
+        self.put_compact_signed(Opcode::SetZ1 as u8, n)
+    }
+
+    /// Get the number of bytes written so far.
+    pub fn current_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Write the postamble, consume this object, and return the underlying
+    /// stream.
+    ///
+    /// We don't know the sizes of the pages, so the postamble declares them
+    /// to be zero. Consumers of XDV files don't rely on those values.
+    pub fn finish(mut self) -> IoResult<W> {
+        if self.in_page {
+            return Err(invalid("XDV file finished inside a page"));
+        }
+
+        let post = self.offset;
+        self.put_u8(Opcode::Postamble as u8)?;
+        self.put_i32(self.last_bop.map(|o| o as i32).unwrap_or(-1))?;
+        self.put_u32(UNIT_NUMERATOR)?;
+        self.put_u32(UNIT_DENOMINATOR)?;
+        self.put_u32(MAGNIFICATION)?;
+        self.put_u32(0)?; // height plus depth of the tallest page
+        self.put_u32(0)?; // width of the widest page
+        self.put_u16(self.max_depth)?;
+        self.put_u16(self.n_pages)?;
+
+        let fonts = std::mem::take(&mut self.fonts);
+
+        for (font_num, def) in &fonts {
+            self.write_font_def(*font_num, def)?;
+        }
+
+        self.put_u8(Opcode::DoublePostamble as u8)?;
+        self.put_u32(post as u32)?;
+        self.put_u8(self.id_byte())?;
+
+        // At least four 0xDF bytes, padding the file to a multiple of four.
+        let n_pad = 4 + (4 - self.offset % 4) % 4;
+
+        for _ in 0..n_pad {
+            self.put_u8(0xDF)?;
+        }
+
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn id_byte(&self) -> u8 {
+        let id = match self.filetype {
+            FileType::Xdv => IdByte::Xdv,
+            FileType::Spx => IdByte::Spx,
+        };
+        id as u8
+    }
+
+    fn check_in_page(&self) -> IoResult<()> {
+        if self.in_page {
+            Ok(())
+        } else {
+            Err(invalid("XDV drawing command outside of a page"))
+        }
+    }
+
+    fn write_font_def(&mut self, font_num: i32, def: &FontDef) -> IoResult<()> {
+        match def {
+            FontDef::Tfm(font) => {
+                self.put_compact_unsigned(Opcode::DefineFont1 as u8, font_num as u32)?;
+                self.put_u32(font.checksum)?;
+                self.put_i32(font.scale_factor)?;
+                self.put_i32(font.design_size)?;
+                self.put_u8(font.area.len() as u8)?;
+                self.put_u8(font.name.len() as u8)?;
+                self.put_bytes(font.area.as_bytes())?;
+                self.put_bytes(font.name.as_bytes())
+            }
+
+            FontDef::Native(font) => {
+                let mut flags = 0;
+
+                if font.vertical {
+                    flags |= NativeFontFlags::Vertical as u16;
+                }
+
+                if font.color_rgba.is_some() {
+                    flags |= NativeFontFlags::Colored as u16;
+                }
+
+                if font.extend.is_some() {
+                    flags |= NativeFontFlags::Extend as u16;
+                }
+
+                if font.slant.is_some() {
+                    flags |= NativeFontFlags::Slant as u16;
+                }
+
+                if font.embolden.is_some() {
+                    flags |= NativeFontFlags::Embolden as u16;
+                }
+
+                self.put_u8(Opcode::DefineNativeFont as u8)?;
+                self.put_i32(font_num)?;
+                self.put_i32(font.size)?;
+                self.put_u16(flags)?;
+                self.put_u8(font.name.len() as u8)?;
+                self.put_bytes(font.name.as_bytes())?;
+                self.put_u32(font.face_index)?;
+
+                if let Some(c) = font.color_rgba {
+                    self.put_u32(c)?;
+                }
+
+                for v in [font.extend, font.slant, font.embolden].iter().flatten() {
+                    self.put_i32(*v)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn put_glyphs(&mut self, width: i32, glyphs: &[u16], x: &[i32], y: &[i32]) -> IoResult<()> {
+        if x.len() != glyphs.len() || y.len() != glyphs.len() {
+            return Err(invalid("XDV glyph positions don't match the glyphs"));
+        }
+
+        if glyphs.len() > usize::from(u16::MAX) {
+            return Err(invalid("too many glyphs in one XDV command"));
+        }
+
+        self.put_i32(width)?;
+        self.put_u16(glyphs.len() as u16)?;
+
+        for (x, y) in x.iter().zip(y) {
+            self.put_i32(*x)?;
+            self.put_i32(*y)?;
+        }
+
+        for g in glyphs {
+            self.put_u16(*g)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a command that comes in four variants, taking a one-, two-,
+    /// three-, or four-byte unsigned argument, using the smallest one.
+    fn put_compact_unsigned(&mut self, opcode1: u8, n: u32) -> IoResult<()> {
+        if n < 0x100 {
+            self.put_u8(opcode1)?;
+            self.put_u8(n as u8)
+        } else if n < 0x1_0000 {
+            self.put_u8(opcode1 + 1)?;
+            self.put_u16(n as u16)
+        } else if n < 0x100_0000 {
+            self.put_u8(opcode1 + 2)?;
+            self.inner.write_u24::<BigEndian>(n)?;
+            self.offset += 3;
+            Ok(())
+        } else {
+            self.put_u8(opcode1 + 3)?;
+            self.put_u32(n)
+        }
+    }
+
+    /// Like `put_compact_unsigned()`, for signed arguments.
+    fn put_compact_signed(&mut self, opcode1: u8, n: i32) -> IoResult<()> {
+        if (-0x80..0x80).contains(&n) {
+            self.put_u8(opcode1)?;
+            self.put_u8(n as i8 as u8)
+        } else if (-0x8000..0x8000).contains(&n) {
+            self.put_u8(opcode1 + 1)?;
+            self.put_u16(n as i16 as u16)
+        } else if (-0x80_0000..0x80_0000).contains(&n) {
+            self.put_u8(opcode1 + 2)?;
+            self.inner.write_i24::<BigEndian>(n)?;
+            self.offset += 3;
+            Ok(())
+        } else {
+            self.put_u8(opcode1 + 3)?;
+            self.put_i32(n)
+        }
+    }
+
+    fn put_u8(&mut self, b: u8) -> IoResult<()> {
+        self.inner.write_u8(b)?;
+        self.offset += 1;
+        Ok(())
+    }
+
+    fn put_u16(&mut self, n: u16) -> IoResult<()> {
+        self.inner.write_u16::<BigEndian>(n)?;
+        self.offset += 2;
+        Ok(())
+    }
+
+    fn put_u32(&mut self, n: u32) -> IoResult<()> {
+        self.inner.write_u32::<BigEndian>(n)?;
+        self.offset += 4;
+        Ok(())
+    }
+
+    fn put_i32(&mut self, n: i32) -> IoResult<()> {
+        self.inner.write_i32::<BigEndian>(n)?;
+        self.offset += 4;
+        Ok(())
+    }
+
+    fn put_bytes(&mut self, b: &[u8]) -> IoResult<()> {
+        self.inner.write_all(b)?;
+        self.offset += b.len() as u64;
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidInput, msg)
+}
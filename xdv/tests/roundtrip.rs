@@ -0,0 +1,333 @@
+// Copyright 2019 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Write XDV and SPX files with `XdvWriter` and make sure that `XdvParser`
+//! reads back what we wrote.
+
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::io;
+use tectonic_xdv::{
+    FileType, NativeFont, State, TfmFont, XdvError, XdvEvents, XdvParser, XdvWriter,
+};
+
+#[derive(Debug)]
+struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error(e.to_string())
+    }
+}
+
+impl From<XdvError> for Error {
+    fn from(e: XdvError) -> Self {
+        Error(e.to_string())
+    }
+}
+
+/// Records the events generated by the parser as strings.
+#[derive(Default)]
+struct Recorder(Vec<String>);
+
+impl XdvEvents for Recorder {
+    type Error = Error;
+
+    fn handle_header(&mut self, filetype: FileType, comment: &[u8]) -> Result<(), Error> {
+        self.0.push(format!(
+            "header {} {}",
+            filetype,
+            String::from_utf8_lossy(comment)
+        ));
+        Ok(())
+    }
+
+    fn handle_begin_page(&mut self, counters: &[i32], previous_bop: i32) -> Result<(), Error> {
+        self.0
+            .push(format!("bop {} {}", counters[0], previous_bop >= 0));
+        Ok(())
+    }
+
+    fn handle_end_page(&mut self) -> Result<(), Error> {
+        self.0.push("eop".to_owned());
+        Ok(())
+    }
+
+    fn handle_special(&mut self, contents: &[u8]) -> Result<(), Error> {
+        self.0
+            .push(format!("special {}", String::from_utf8_lossy(contents)));
+        Ok(())
+    }
+
+    fn handle_char_run(&mut self, chars: &[i32]) -> Result<(), Error> {
+        self.0.push(format!("chars {:?}", chars));
+        Ok(())
+    }
+
+    fn handle_tfm_char_run(
+        &mut self,
+        font_num: i32,
+        chars: &[i32],
+        x: &[i32],
+        v: i32,
+    ) -> Result<(), Error> {
+        self.0
+            .push(format!("tfm chars {} {:?} {:?} {}", font_num, chars, x, v));
+        Ok(())
+    }
+
+    /// Pretend that every character is ten times as wide as its number.
+    fn char_width(&mut self, _font_num: i32, char_num: i32) -> Result<i32, Error> {
+        Ok(10 * char_num)
+    }
+
+    fn handle_define_font(&mut self, font_num: i32, font: &TfmFont) -> Result<(), Error> {
+        self.0.push(format!(
+            "font {} {} {}",
+            font_num, font.name, font.scale_factor
+        ));
+        Ok(())
+    }
+
+    fn handle_define_native_font(&mut self, font_num: i32, font: &NativeFont) -> Result<(), Error> {
+        self.0.push(format!(
+            "native font {} {} {} {:?} {:?}",
+            font_num, font.name, font.size, font.color_rgba, font.slant
+        ));
+        Ok(())
+    }
+
+    fn handle_select_font(&mut self, font_num: i32) -> Result<(), Error> {
+        self.0.push(format!("select {}", font_num));
+        Ok(())
+    }
+
+    fn handle_text_run(
+        &mut self,
+        font_num: i32,
+        text: &str,
+        h: i32,
+        v: i32,
+        width: i32,
+    ) -> Result<(), Error> {
+        self.0
+            .push(format!("text {} {} {} {} {}", font_num, text, h, v, width));
+        Ok(())
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Error> {
+        self.0
+            .push(format!("glyphs {} {:?} {:?} {:?}", font_num, glyphs, x, y));
+        Ok(())
+    }
+
+    fn handle_rule(&mut self, h: i32, v: i32, height: i32, width: i32) -> Result<(), Error> {
+        self.0
+            .push(format!("rule {} {} {} {}", h, v, height, width));
+        Ok(())
+    }
+
+    fn handle_push(&mut self, state: &State) -> Result<(), Error> {
+        self.0.push(format!("push {} {}", state.h, state.v));
+        Ok(())
+    }
+
+    fn handle_pop(&mut self, state: &State) -> Result<(), Error> {
+        self.0.push(format!("pop {} {}", state.h, state.v));
+        Ok(())
+    }
+
+    fn handle_move(&mut self, state: &State) -> Result<(), Error> {
+        self.0.push(format!(
+            "move {} {} {} {} {} {}",
+            state.h, state.v, state.w, state.x, state.y, state.z
+        ));
+        Ok(())
+    }
+}
+
+fn parse(data: &[u8]) -> Vec<String> {
+    let (recorder, n_bytes) = XdvParser::process(data, Recorder::default()).unwrap();
+    assert_eq!(n_bytes, data.len() as u64);
+    recorder.0
+}
+
+#[test]
+fn xdv_roundtrip() {
+    let cmr = TfmFont {
+        area: String::new(),
+        name: "cmr10".to_owned(),
+        checksum: 0x1234_5678,
+        scale_factor: 10 * 65536,
+        design_size: 10 * 65536,
+    };
+
+    let lmr = NativeFont {
+        name: "lmroman10-regular.otf".to_owned(),
+        size: 12 * 65536,
+        face_index: 0,
+        vertical: false,
+        color_rgba: Some(0xFF00_00FF),
+        extend: None,
+        slant: Some(0x3000),
+        embolden: None,
+    };
+
+    let mut w = XdvWriter::new(Vec::new(), FileType::Xdv, b"hello").unwrap();
+    w.begin_page(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    w.define_font(0, &cmr).unwrap();
+    w.define_native_font(100_000, &lmr).unwrap();
+    w.select_font(0).unwrap();
+    w.push().unwrap();
+    w.down(1_000_000).unwrap();
+    w.right(-5).unwrap();
+    w.set_chars(&[72, 105, 200]).unwrap();
+    w.special(b"color push rgb 1 0 0").unwrap();
+    w.pop().unwrap();
+    w.set_w(300).unwrap();
+    w.right_by_w().unwrap();
+    w.set_x(70_000).unwrap();
+    w.set_y(-40_000).unwrap();
+    w.set_z(9).unwrap();
+    w.down_by_z().unwrap();
+    w.select_font(100_000).unwrap();
+    w.set_text_and_glyphs("Ünï", 4000, &[1, 2, 65535], &[0, 1000, 2000], &[0, 0, -50])
+        .unwrap();
+    w.set_glyphs(500, &[7], &[10], &[20]).unwrap();
+    w.rule(100, 200, true).unwrap();
+    w.rule(100, 0, false).unwrap();
+    w.end_page().unwrap();
+    w.begin_page(&[2, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    w.end_page().unwrap();
+    let data = w.finish().unwrap();
+
+    assert_eq!(data.len() % 4, 0);
+
+    let h = 300 + 70_000;
+    let v = -40_000 + 9 + 9;
+    let expected = vec![
+        "header XDV hello".to_owned(),
+        "bop 1 false".to_owned(),
+        "font 0 cmr10 655360".to_owned(),
+        "native font 100000 lmroman10-regular.otf 786432 Some(4278190335) Some(12288)".to_owned(),
+        "select 0".to_owned(),
+        "push 0 0".to_owned(),
+        "move 0 1000000 0 0 0 0".to_owned(),
+        "move -5 1000000 0 0 0 0".to_owned(),
+        "chars [72, 105, 200]".to_owned(),
+        "tfm chars 0 [72, 105, 200] [-5, 715, 1765] 1000000".to_owned(),
+        "special color push rgb 1 0 0".to_owned(),
+        "pop 0 0".to_owned(),
+        "move 300 0 300 0 0 0".to_owned(),
+        "move 600 0 300 0 0 0".to_owned(),
+        "move 70600 0 300 70000 0 0".to_owned(),
+        "move 70600 -40000 300 70000 -40000 0".to_owned(),
+        "move 70600 -39991 300 70000 -40000 9".to_owned(),
+        "move 70600 -39982 300 70000 -40000 9".to_owned(),
+        "select 100000".to_owned(),
+        format!("text 100000 Ünï {} {} 4000", h + 300, v),
+        format!(
+            "glyphs 100000 [1, 2, 65535] [{}, {}, {}] [{}, {}, {}]",
+            h + 300,
+            h + 1300,
+            h + 2300,
+            v,
+            v,
+            v - 50
+        ),
+        format!("glyphs 100000 [7] [{}] [{}]", h + 4310, v + 20),
+        format!("rule {} {} 100 200", h + 4800, v),
+        "eop".to_owned(),
+        "bop 2 true".to_owned(),
+        "eop".to_owned(),
+        // The postamble repeats the font definitions.
+        "font 0 cmr10 655360".to_owned(),
+        "native font 100000 lmroman10-regular.otf 786432 Some(4278190335) Some(12288)".to_owned(),
+    ];
+
+    assert_eq!(parse(&data), expected);
+}
+
+#[test]
+fn tfm_char_positions() {
+    let cmr = TfmFont {
+        area: String::new(),
+        name: "cmr10".to_owned(),
+        checksum: 0,
+        scale_factor: 10 * 65536,
+        design_size: 10 * 65536,
+    };
+
+    let mut w = XdvWriter::new(Vec::new(), FileType::Xdv, b"").unwrap();
+    w.begin_page(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    w.define_font(3, &cmr).unwrap();
+    w.select_font(3).unwrap();
+    w.down(500).unwrap();
+    w.right(1000).unwrap();
+    w.set_chars(&[65, 300, 66]).unwrap();
+    w.push().unwrap();
+    w.set_chars(&[67]).unwrap();
+    w.pop().unwrap();
+    w.set_chars(&[68]).unwrap();
+    w.rule(100, 200, false).unwrap();
+    w.end_page().unwrap();
+    let data = w.finish().unwrap();
+
+    // Each character moves us right by its width, and a push doesn't end
+    // the run, so it gets reported before the run that it's part of.
+    let h = 1000 + 650 + 3000 + 660;
+    let events = parse(&data);
+    assert_eq!(
+        &events[6..],
+        &[
+            format!("push {} 500", h),
+            "chars [65, 300, 66, 67]".to_owned(),
+            format!(
+                "tfm chars 3 [65, 300, 66, 67] [1000, 1650, 4650, {}] 500",
+                h
+            ),
+            format!("pop {} 500", h),
+            "chars [68]".to_owned(),
+            format!("tfm chars 3 [68] [{}] 500", h),
+            format!("rule {} 500 100 200", h + 680),
+            "eop".to_owned(),
+            "font 3 cmr10 655360".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn spx_roundtrip() {
+    let mut w = XdvWriter::new(Vec::new(), FileType::Spx, b"").unwrap();
+    w.begin_page(&[0; 10]).unwrap();
+    w.special(&[b'x'; 300]).unwrap();
+    w.end_page().unwrap();
+    let data = w.finish().unwrap();
+
+    let events = parse(&data);
+    assert_eq!(events[0], "header SPX ");
+    assert_eq!(events[2], format!("special {}", "x".repeat(300)));
+}
+
+#[test]
+fn writer_misuse() {
+    let mut w = XdvWriter::new(Vec::new(), FileType::Xdv, b"").unwrap();
+    assert!(w.right(1).is_err());
+    w.begin_page(&[0; 10]).unwrap();
+    assert!(w.select_font(3).is_err());
+    assert!(w.pop().is_err());
+    w.push().unwrap();
+    assert!(w.end_page().is_err());
+    assert!(w.set_glyphs(0, &[1, 2], &[0], &[0, 0]).is_err());
+}